@@ -4,36 +4,180 @@
 //! and handles WAV decoding automatically.
 
 use std::io::Cursor;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use rodio::{Decoder, OutputStream, Sink};
+use anyhow::{anyhow, Result};
+use rodio::cpal::traits::HostTrait;
+use rodio::source::Zero;
+use rodio::{Decoder, DeviceTrait, OutputStream, OutputStreamHandle, Sink, Source};
 use kira::{AudioManager, AudioManagerSettings};
+use tracing::warn;
+
+/// Highest accepted playback volume; beyond this the output clips audibly
+pub const MAX_VOLUME: f32 = 2.0;
+
+/// Highest accepted repeat count
+pub const MAX_REPEAT: u32 = 100;
+
+/// Set to any value to treat the machine as having no audio output, e.g. on CI
+pub const NO_AUDIO_ENV: &str = "SIRIUS_NO_AUDIO";
+
+/// How received audio is played back
+#[derive(Debug, Clone)]
+pub struct PlaybackOptions {
+    /// Output device name (or part of it); the default device is used if unset or not found
+    pub device: Option<String>,
+    /// Volume multiplier (0.0-2.0)
+    pub volume: f32,
+    /// How many times each clip is played
+    pub repeat: u32,
+    /// Silence between repeats
+    pub gap: Duration,
+    /// When set, playback stops early as soon as this flips to true
+    pub skip: Option<Arc<AtomicBool>>,
+    /// Treat the machine as having no audio output (`NO_AUDIO_ENV`)
+    pub no_audio: bool,
+}
+
+impl Default for PlaybackOptions {
+    fn default() -> Self {
+        Self {
+            device: None,
+            volume: 1.0,
+            repeat: 1,
+            gap: Duration::from_millis(500),
+            skip: None,
+            no_audio: false,
+        }
+    }
+}
+
+/// Parse and validate a volume multiplier
+pub fn parse_volume(value: &str) -> Result<f32, String> {
+    match value.trim().parse::<f32>() {
+        Ok(v) if (0.0..=MAX_VOLUME).contains(&v) => Ok(v),
+        _ => Err(format!("volume must be a number between 0.0 and {}", MAX_VOLUME)),
+    }
+}
+
+/// Names of the available output devices
+pub fn output_device_names() -> Result<Vec<String>> {
+    let host = rodio::cpal::default_host();
+    Ok(host
+        .output_devices()?
+        .map(|device| device.name().unwrap_or_else(|_| "<unknown>".to_string()))
+        .collect())
+}
+
+/// Parse and validate a repeat count
+pub fn parse_repeat(value: &str) -> Result<u32, String> {
+    match value.trim().parse::<u32>() {
+        Ok(n) if (1..=MAX_REPEAT).contains(&n) => Ok(n),
+        _ => Err(format!("repeat must be a whole number between 1 and {}", MAX_REPEAT)),
+    }
+}
+
+/// Total wall time for `repeat` plays of a clip with `gap` silence in between
+fn total_playback_duration(clip: Duration, repeat: u32, gap: Duration) -> Duration {
+    let repeat = repeat.max(1);
+    clip * repeat + gap * (repeat - 1)
+}
+
+/// Pick the device matching `wanted`: an exact (case-insensitive) name match
+/// wins, otherwise the first device whose name contains `wanted`
+fn match_device(names: &[String], wanted: &str) -> Option<usize> {
+    let wanted = wanted.to_lowercase();
+    names
+        .iter()
+        .position(|name| name.to_lowercase() == wanted)
+        .or_else(|| {
+            names
+                .iter()
+                .position(|name| name.to_lowercase().contains(&wanted))
+        })
+}
+
+/// Error for a machine without usable audio output, pointing at the save options
+fn no_audio_error(reason: impl std::fmt::Display) -> anyhow::Error {
+    anyhow!(
+        "no audio output device available ({}); use --output <file> or --output-dir <dir> to save audio instead",
+        reason
+    )
+}
+
+/// Open the requested output device, falling back to the default one.
+///
+/// This is the only place playback touches the audio subsystem, so save-only
+/// modes work on headless machines.
+fn open_output_stream(device: Option<&str>, no_audio: bool) -> Result<(OutputStream, OutputStreamHandle)> {
+    if no_audio {
+        return Err(no_audio_error(format!("{} is set", NO_AUDIO_ENV)));
+    }
+
+    if let Some(wanted) = device {
+        let host = rodio::cpal::default_host();
+        let devices: Vec<rodio::Device> = host.output_devices()?.collect();
+        let names: Vec<String> = devices
+            .iter()
+            .map(|device| device.name().unwrap_or_default())
+            .collect();
+
+        match match_device(&names, wanted) {
+            Some(index) => return Ok(OutputStream::try_from_device(&devices[index])?),
+            None => warn!("Output device '{}' not found, using the default device", wanted),
+        }
+    }
+
+    OutputStream::try_default().map_err(no_audio_error)
+}
 
 /// Play WAV audio from bytes
-pub fn play_wav_bytes(wav_data: &[u8]) -> Result<()> {
+pub fn play_wav_bytes(wav_data: &[u8], options: &PlaybackOptions) -> Result<()> {
     // Create output stream
-    let (_stream, stream_handle) = OutputStream::try_default()?;
+    let (_stream, stream_handle) = open_output_stream(options.device.as_deref(), options.no_audio)?;
 
     // Create a sink for playback
     let sink = Sink::try_new(&stream_handle)?;
 
     // Decode WAV data
-    let cursor = Cursor::new(wav_data.to_vec());
-    let source = Decoder::new(cursor)?;
+    let source = Decoder::new(Cursor::new(wav_data.to_vec()))?;
+    let (channels, sample_rate) = (source.channels(), source.sample_rate());
 
     // Get duration estimate before playing
     let duration = estimate_wav_duration(wav_data);
 
-    // Play the audio
+    // Queue the clip `repeat` times, separated by silence
+    let repeat = options.repeat.max(1);
+    sink.set_volume(options.volume.clamp(0.0, MAX_VOLUME));
     sink.append(source);
+    for _ in 1..repeat {
+        if !options.gap.is_zero() {
+            sink.append(Zero::<i16>::new(channels, sample_rate).take_duration(options.gap));
+        }
+        sink.append(Decoder::new(Cursor::new(wav_data.to_vec()))?);
+    }
 
     // Wait for playback to complete
     // We use sleep instead of sink.sleep_until_end() for more control
-    if let Some(dur) = duration {
-        std::thread::sleep(dur + Duration::from_millis(100)); // Add small buffer
-    } else {
-        sink.sleep_until_end();
+    match (duration, &options.skip) {
+        (Some(dur), Some(skip)) => {
+            let total = total_playback_duration(dur, repeat, options.gap);
+            let deadline = Instant::now() + total + Duration::from_millis(100);
+            while Instant::now() < deadline {
+                if skip.load(Ordering::Relaxed) {
+                    sink.stop();
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        (Some(dur), None) => {
+            let total = total_playback_duration(dur, repeat, options.gap);
+            std::thread::sleep(total + Duration::from_millis(100)); // Add small buffer
+        }
+        (None, _) => sink.sleep_until_end(),
     }
 
     Ok(())
@@ -119,3 +263,58 @@ pub fn play_wav_bytes_kira(wav_data: &[u8]) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_volume() {
+        assert_eq!(parse_volume("0.5"), Ok(0.5));
+        assert_eq!(parse_volume(" 2.0 "), Ok(2.0));
+        assert_eq!(parse_volume("0"), Ok(0.0));
+        assert!(parse_volume("2.5").is_err());
+        assert!(parse_volume("-1").is_err());
+        assert!(parse_volume("loud").is_err());
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        assert_eq!(parse_repeat("3"), Ok(3));
+        assert_eq!(parse_repeat(" 1 "), Ok(1));
+        assert!(parse_repeat("0").is_err());
+        assert!(parse_repeat("101").is_err());
+        assert!(parse_repeat("2.5").is_err());
+        assert!(parse_repeat("-1").is_err());
+    }
+
+    #[test]
+    fn test_total_playback_duration() {
+        let clip = Duration::from_secs(2);
+        let gap = Duration::from_millis(500);
+
+        assert_eq!(total_playback_duration(clip, 1, gap), clip);
+        assert_eq!(total_playback_duration(clip, 3, gap), Duration::from_millis(7000));
+        assert_eq!(total_playback_duration(clip, 3, Duration::ZERO), Duration::from_secs(6));
+        // A repeat of 0 is treated as a single play
+        assert_eq!(total_playback_duration(clip, 0, gap), clip);
+    }
+
+    #[test]
+    fn test_match_device() {
+        let names: Vec<String> = ["default", "HDA Intel PCH, ALC892 Analog", "USB Audio Device"]
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+
+        assert_eq!(match_device(&names, "default"), Some(0));
+        assert_eq!(match_device(&names, "DEFAULT"), Some(0));
+        assert_eq!(match_device(&names, "usb"), Some(2));
+        assert_eq!(match_device(&names, "alc892"), Some(1));
+        assert_eq!(match_device(&names, "bluetooth"), None);
+
+        // An exact match beats an earlier partial one
+        let names = vec!["USB Audio Device 2".to_string(), "USB Audio Device".to_string()];
+        assert_eq!(match_device(&names, "usb audio device"), Some(1));
+    }
+}
@@ -0,0 +1,138 @@
+//! Batch synthesis helpers
+//!
+//! In `--output-dir` mode every clip is written to a deterministic filename
+//! derived from its inputs, so re-running the same batch only synthesizes
+//! what is missing. Clips are written to a temporary file and renamed into
+//! place, so an interrupted run never leaves a partial clip that would be
+//! skipped from then on.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+/// Read a batch file: one text per line, blank lines skipped
+pub fn read_batch_file(path: &str) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Deterministic clip filename: hex `sha256(text + voice + lang + speed)` with a `.wav` extension.
+///
+/// Fields are NUL-separated so that e.g. ("ab", "c") and ("a", "bc") don't collide.
+pub fn clip_filename(text: &str, voice: &str, lang: &str, speed: f32) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(text.as_bytes());
+    hasher.update([0]);
+    hasher.update(voice.as_bytes());
+    hasher.update([0]);
+    hasher.update(lang.as_bytes());
+    hasher.update([0]);
+    hasher.update(speed.to_string().as_bytes());
+
+    let hex: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}.wav", hex)
+}
+
+/// The texts that still need synthesizing into `dir`, paired with their target path.
+///
+/// Clips whose file already exists are skipped unless `force` is set.
+pub fn pending_clips(
+    texts: &[String],
+    voice: &str,
+    lang: &str,
+    speed: f32,
+    dir: &Path,
+    force: bool,
+) -> Vec<(String, PathBuf)> {
+    texts
+        .iter()
+        .map(|text| (text.clone(), dir.join(clip_filename(text, voice, lang, speed))))
+        .filter(|(_, path)| force || !path.exists())
+        .collect()
+}
+
+/// Write `data` to `path` through a temporary file beside it, so `path`
+/// either doesn't exist or holds all of `data`
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("clip");
+    let temp = path.with_file_name(format!(".{}.{}.part", name, std::process::id()));
+    let written = std::fs::write(&temp, data).and_then(|()| std::fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    Ok(written?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sirius-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_clip_filename_is_deterministic() {
+        let a = clip_filename("Hello", "bm_lewis", "en-gb", 1.0);
+        assert_eq!(a, clip_filename("Hello", "bm_lewis", "en-gb", 1.0));
+        assert_eq!(a.len(), 64 + ".wav".len());
+        assert_ne!(a, clip_filename("Hello", "bm_lewis", "en-gb", 1.1));
+        assert_ne!(a, clip_filename("Hello", "am_onyx", "en-gb", 1.0));
+        assert_ne!(a, clip_filename("Hello", "bm_lewis", "en-us", 1.0));
+        assert_ne!(clip_filename("ab", "c", "en-us", 1.0), clip_filename("a", "bc", "en-us", 1.0));
+    }
+
+    #[test]
+    fn test_second_run_sends_nothing() {
+        let dir = temp_dir("batch");
+        let texts = vec!["One.".to_string(), "Two.".to_string()];
+
+        // First run: everything is pending; "synthesize" by writing the files
+        let first = pending_clips(&texts, "bm_lewis", "en-gb", 1.0, &dir, false);
+        assert_eq!(first.len(), 2);
+        for (_, path) in &first {
+            write_atomically(path, b"RIFF").unwrap();
+        }
+
+        // Second run with the same inputs: no requests needed
+        assert!(pending_clips(&texts, "bm_lewis", "en-gb", 1.0, &dir, false).is_empty());
+
+        // --force re-synthesizes everything; a new voice or lang is a different clip
+        assert_eq!(pending_clips(&texts, "bm_lewis", "en-gb", 1.0, &dir, true).len(), 2);
+        assert_eq!(pending_clips(&texts, "am_onyx", "en-gb", 1.0, &dir, false).len(), 2);
+        assert_eq!(pending_clips(&texts, "bm_lewis", "en-us", 1.0, &dir, false).len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_leaves_no_clip() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("clip.wav");
+        write_atomically(&path, b"RIFF one").unwrap();
+        write_atomically(&path, b"RIFF two").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"RIFF two");
+
+        // A write that can't finish leaves neither the clip nor its temp file
+        let missing = dir.join("no-such-dir").join("clip.wav");
+        assert!(write_atomically(&missing, b"RIFF").is_err());
+        assert!(!missing.exists());
+        let names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["clip.wav"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
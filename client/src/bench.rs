@@ -0,0 +1,305 @@
+//! `--benchmark`: load generation and latency statistics
+//!
+//! `concurrency` connections share `iterations` copies of one request. Each
+//! response is received in full, measured and dropped, so nothing is played
+//! or saved and the numbers reflect the server and the network only.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+use sirius_protocol::{PayloadAssembler, Request, Response, SynthesizeRequest};
+
+/// One successful request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// From sending the request to receiving the last audio byte
+    pub latency: Duration,
+    pub audio_secs: f32,
+    pub size_bytes: usize,
+}
+
+impl Sample {
+    /// Real-time factor: seconds of waiting per second of audio
+    pub fn rtf(&self) -> f64 {
+        if self.audio_secs > 0.0 {
+            self.latency.as_secs_f64() / self.audio_secs as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Nearest-rank percentile `p` (0-100) of `sorted`, which must be ascending
+/// and non-empty
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// The percentiles shown in the summary table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl Percentiles {
+    /// `None` for no values
+    pub fn of(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        Some(Self {
+            p50: percentile(&values, 50.0),
+            p95: percentile(&values, 95.0),
+            p99: percentile(&values, 99.0),
+        })
+    }
+}
+
+/// What a benchmark run measured
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub samples: Vec<Sample>,
+    pub failures: usize,
+    /// Wall-clock time for the whole run
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn latency_ms(&self) -> Option<Percentiles> {
+        Percentiles::of(self.samples.iter().map(|s| s.latency.as_secs_f64() * 1000.0).collect())
+    }
+
+    pub fn rtf(&self) -> Option<Percentiles> {
+        Percentiles::of(self.samples.iter().map(Sample::rtf).collect())
+    }
+
+    /// Successful requests per second of wall-clock time
+    pub fn throughput(&self) -> f64 {
+        self.samples.len() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Seconds of audio produced per second of wall-clock time
+    pub fn audio_throughput(&self) -> f64 {
+        let audio_secs: f64 = self.samples.iter().map(|s| s.audio_secs as f64).sum();
+        audio_secs / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Requests     {} ok, {} failed in {:.2}s",
+            self.samples.len(),
+            self.failures,
+            self.elapsed.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "Throughput   {:.2} req/s, {:.2} audio s/s",
+            self.throughput(),
+            self.audio_throughput()
+        )?;
+        let bytes: usize = self.samples.iter().map(|s| s.size_bytes).sum();
+        writeln!(f, "Received     {} bytes", bytes)?;
+        let (Some(latency), Some(rtf)) = (self.latency_ms(), self.rtf()) else {
+            return Ok(());
+        };
+        writeln!(f, "{:<12} {:>10} {:>10} {:>10}", "", "p50", "p95", "p99")?;
+        writeln!(
+            f,
+            "{:<12} {:>10.1} {:>10.1} {:>10.1}",
+            "Latency ms", latency.p50, latency.p95, latency.p99
+        )?;
+        write!(f, "{:<12} {:>10.3} {:>10.3} {:>10.3}", "RTF", rtf.p50, rtf.p95, rtf.p99)
+    }
+}
+
+/// Send `iterations` copies of `request` over `concurrency` connections to
+/// `server`. Failed requests are counted, not fatal; failing to connect is.
+pub async fn run(
+    server: &str,
+    ws_config: WebSocketConfig,
+    request: SynthesizeRequest,
+    iterations: usize,
+    concurrency: usize,
+) -> Result<Report> {
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+
+    let mut workers = Vec::new();
+    for _ in 0..concurrency.clamp(1, iterations.max(1)) {
+        let (ws_stream, _) =
+            tokio_tungstenite::connect_async_with_config(crate::handshake_request(server)?, Some(ws_config), false)
+                .await?;
+        let next = Arc::clone(&next);
+        let request = request.clone();
+        workers.push(tokio::spawn(async move {
+            let (mut sender, mut receiver) = ws_stream.split();
+            let mut samples = Vec::new();
+            let mut failures = 0;
+            while next.fetch_add(1, Ordering::Relaxed) < iterations {
+                match measure(&mut sender, &mut receiver, &request).await {
+                    Ok(sample) => samples.push(sample),
+                    Err(e) => {
+                        warn!("Benchmark request failed: {}", e);
+                        failures += 1;
+                    }
+                }
+            }
+            let _ = sender.send(Message::Close(None)).await;
+            (samples, failures)
+        }));
+    }
+
+    let mut report = Report {
+        samples: Vec::new(),
+        failures: 0,
+        elapsed: Duration::ZERO,
+    };
+    for worker in workers {
+        let (samples, failures) = worker.await?;
+        report.samples.extend(samples);
+        report.failures += failures;
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Time one request until its audio has fully arrived, then drop the audio
+async fn measure<S, R>(sender: &mut S, receiver: &mut R, request: &SynthesizeRequest) -> Result<Sample>
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let request_json = serde_json::to_string(&Request::Synthesize(request.clone()))?;
+    let start = Instant::now();
+    sender.send(Message::Text(request_json)).await?;
+
+    let mut transfer: Option<(PayloadAssembler, f32)> = None;
+    while let Some(msg) = receiver.next().await {
+        match msg? {
+            Message::Text(text) => match serde_json::from_str(&text)? {
+                Response::AudioReady { duration_secs, size_bytes, .. } => {
+                    transfer = Some((PayloadAssembler::new(size_bytes), duration_secs));
+                }
+                Response::Error { code, message } => {
+                    return Err(anyhow::anyhow!("Server error ({}): {}", code, message));
+                }
+                _ => {}
+            },
+            Message::Binary(chunk) => {
+                let Some((assembler, audio_secs)) = transfer.as_mut() else {
+                    continue;
+                };
+                if let Some(data) = assembler.push(&chunk)? {
+                    return Ok(Sample {
+                        latency: start.elapsed(),
+                        audio_secs: *audio_secs,
+                        size_bytes: data.len(),
+                    });
+                }
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Err(anyhow::anyhow!("Connection closed before the audio arrived"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sirius_protocol::{AudioFormat, BITS_PER_SAMPLE, CHANNELS, SAMPLE_RATE};
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(percentile(&values, 50.0), 50.0);
+        assert_eq!(percentile(&values, 95.0), 95.0);
+        assert_eq!(percentile(&values, 99.0), 99.0);
+        assert_eq!(percentile(&values, 100.0), 100.0);
+        assert_eq!(percentile(&values, 0.0), 1.0);
+
+        // Nearest rank: no interpolation between samples
+        assert_eq!(percentile(&[10.0, 20.0, 30.0], 50.0), 20.0);
+        assert_eq!(percentile(&[10.0, 20.0, 30.0], 95.0), 30.0);
+        assert_eq!(percentile(&[7.0], 99.0), 7.0);
+    }
+
+    #[test]
+    fn test_percentiles_sort_their_input() {
+        let p = Percentiles::of(vec![3.0, 1.0, 2.0, 4.0]).unwrap();
+        assert_eq!((p.p50, p.p95, p.p99), (2.0, 4.0, 4.0));
+        assert_eq!(Percentiles::of(Vec::new()), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let sample = |latency_ms: u64| Sample {
+            latency: Duration::from_millis(latency_ms),
+            audio_secs: 2.0,
+            size_bytes: 1000,
+        };
+        let report = Report {
+            samples: vec![sample(100), sample(200), sample(300), sample(400)],
+            failures: 1,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(report.throughput(), 2.0);
+        assert_eq!(report.audio_throughput(), 4.0);
+        assert_eq!(report.rtf().unwrap().p50, 0.1);
+        assert_eq!(report.latency_ms().unwrap().p99, 400.0);
+
+        let table = report.to_string();
+        assert!(table.contains("4 ok, 1 failed"));
+        assert!(table.contains("Received     4000 bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_measure_drops_audio() {
+        let metadata = Response::AudioReady {
+            duration_secs: 1.0,
+            num_samples: SAMPLE_RATE as u64,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+            size_bytes: 6,
+            format: AudioFormat::Wav,
+            bits_per_sample: BITS_PER_SAMPLE,
+            used_fallback: 0,
+            crc32: None,
+        };
+        let mut receiver = futures_util::stream::iter(vec![
+            Ok(Message::Text(serde_json::to_string(&metadata).unwrap())),
+            Ok(Message::Binary(vec![0; 4])),
+            Ok(Message::Binary(vec![0; 2])),
+        ]);
+        let sample = measure(&mut futures_util::sink::drain(), &mut receiver, &SynthesizeRequest::new("Hi"))
+            .await
+            .unwrap();
+        assert_eq!(sample.size_bytes, 6);
+        assert_eq!(sample.audio_secs, 1.0);
+
+        let err = measure(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(Vec::new()),
+            &SynthesizeRequest::new("Hi"),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("closed"));
+    }
+}
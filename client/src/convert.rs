@@ -0,0 +1,643 @@
+//! Re-encoding of received WAV audio for `--output` files
+//!
+//! The server always sends WAV; the extension of the output path decides
+//! what is written instead:
+//!
+//! - `.mp3`: MP3 through LAME at 96 kbps, mono or stereo only
+//! - `.flac`: native FLAC, encoded here
+//! - `.oga`: the same FLAC stream in an Ogg container (the Ogg FLAC mapping),
+//!   which some players don't expect under that extension; a warning says so
+//! - anything else: the WAV as it came
+//!
+//! `.ogg` is refused rather than written as Ogg FLAC, because players take it
+//! to mean Vorbis and there is no Vorbis or Opus encoder to hand.
+//!
+//! No FLAC encoder crate is available either, so the one here is minimal but
+//! lossless, and compact enough for speech. It supports:
+//!
+//! - integer WAV of up to 24 bits and 1 to 8 channels, as the server sends
+//! - a fixed block size of 4096 samples per channel (the last frame shorter)
+//! - constant, verbatim and fixed-predictor (order 0 to 4) subframes, with
+//!   Rice-coded residuals in a single partition
+//!
+//! It does not do LPC subframes, variable block sizes, inter-channel
+//! decorrelation, escaped or partitioned residuals, or the STREAMINFO MD5
+//! (left as zeros, meaning "not computed"), and it can't read float WAV.
+
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use hound::{SampleFormat, WavReader};
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, InterleavedPcm, MonoPcm, Quality};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use tracing::warn;
+
+/// Samples per channel in each FLAC frame
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+/// Highest fixed-predictor order FLAC defines
+const MAX_FIXED_ORDER: usize = 4;
+
+/// Largest 4-bit Rice parameter (15 is the escape code)
+const MAX_RICE_PARAM: u32 = 14;
+
+/// Ogg logical stream serial number ("SIRI"); there's only one stream per file
+const OGG_SERIAL: u32 = 0x5349_5249;
+
+/// Plenty for speech, and valid at both MPEG-1 and MPEG-2 sample rates
+const MP3_BITRATE: Bitrate = Bitrate::Kbps96;
+
+/// File format written for an output path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Wav,
+    Mp3,
+    Flac,
+    /// FLAC in an Ogg container, written as `.oga`
+    OggFlac,
+}
+
+impl OutputFormat {
+    /// Format implied by `path`'s extension; anything unrecognised stays WAV.
+    /// `.ogg` is an error, since what we'd write there isn't Vorbis.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        let format = match extension.as_deref() {
+            Some("mp3") => OutputFormat::Mp3,
+            Some("flac") => OutputFormat::Flac,
+            Some("oga") => OutputFormat::OggFlac,
+            Some("ogg") => {
+                return Err(anyhow!(
+                    "{}: .ogg output would be taken for Ogg Vorbis, which can't be written; \
+                     use .oga for FLAC in Ogg, or .flac",
+                    path.display()
+                ))
+            }
+            _ => OutputFormat::Wav,
+        };
+        Ok(format)
+    }
+}
+
+/// `wav` re-encoded as `format` (unchanged for `OutputFormat::Wav`)
+pub fn convert_wav(wav: &[u8], format: OutputFormat) -> Result<Vec<u8>> {
+    if format == OutputFormat::Wav {
+        return Ok(wav.to_vec());
+    }
+
+    let reader = WavReader::new(Cursor::new(wav)).context("reading WAV from server")?;
+    let spec = reader.spec();
+    if spec.sample_format != SampleFormat::Int || spec.bits_per_sample > 24 {
+        return Err(anyhow!(
+            "can only convert integer WAV of up to 24 bits, got {:?} {}-bit",
+            spec.sample_format,
+            spec.bits_per_sample
+        ));
+    }
+    let samples = reader.into_samples::<i32>().collect::<Result<Vec<_>, _>>()?;
+    let audio = Pcm {
+        samples,
+        channels: spec.channels as usize,
+        sample_rate: spec.sample_rate,
+        bits_per_sample: spec.bits_per_sample as u32,
+    };
+
+    match format {
+        OutputFormat::Wav => unreachable!(),
+        OutputFormat::Mp3 => encode_mp3(&audio),
+        OutputFormat::Flac => encode_flac(&audio),
+        OutputFormat::OggFlac => {
+            warn!(
+                "Writing FLAC in an Ogg container, not Vorbis; players expecting Vorbis in .oga \
+                 may not play it"
+            );
+            encode_ogg_flac(&audio)
+        }
+    }
+}
+
+/// Interleaved integer samples and their layout
+struct Pcm {
+    samples: Vec<i32>,
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+}
+
+impl Pcm {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1)
+    }
+
+    /// Samples rescaled to 16 bits, for LAME
+    fn to_i16(&self) -> Vec<i16> {
+        self.samples
+            .iter()
+            .map(|&s| match self.bits_per_sample {
+                bits if bits > 16 => (s >> (bits - 16)) as i16,
+                bits => (s << (16 - bits)) as i16,
+            })
+            .collect()
+    }
+}
+
+fn encode_mp3(audio: &Pcm) -> Result<Vec<u8>> {
+    let mut builder = Builder::new().ok_or_else(|| anyhow!("failed to initialise the MP3 encoder"))?;
+    builder.set_num_channels(audio.channels as u8)?;
+    builder.set_sample_rate(audio.sample_rate)?;
+    builder.set_brate(MP3_BITRATE)?;
+    builder.set_quality(Quality::Good)?;
+    let mut encoder = builder.build()?;
+
+    let pcm = audio.to_i16();
+    let mut mp3 = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(audio.frames()));
+    match audio.channels {
+        1 => encoder.encode_to_vec(MonoPcm(&pcm), &mut mp3)?,
+        2 => encoder.encode_to_vec(InterleavedPcm(&pcm), &mut mp3)?,
+        n => return Err(anyhow!("MP3 output needs mono or stereo audio, got {} channels", n)),
+    };
+    // The final flush needs up to 7200 bytes
+    mp3.reserve(7200);
+    encoder.flush_to_vec::<FlushNoGap>(&mut mp3)?;
+    Ok(mp3)
+}
+
+/// A native FLAC file: signature, metadata, then the frames
+fn encode_flac(audio: &Pcm) -> Result<Vec<u8>> {
+    let stream = FlacStream::encode(audio)?;
+    let mut flac = b"fLaC".to_vec();
+    flac.extend(stream.streaminfo_block(false));
+    flac.extend(vorbis_comment_block(true));
+    for frame in &stream.frames {
+        flac.extend(&frame.data);
+    }
+    Ok(flac)
+}
+
+/// The same FLAC stream mapped into Ogg: a header packet carrying the
+/// STREAMINFO, one packet per remaining metadata block, then one per frame
+fn encode_ogg_flac(audio: &Pcm) -> Result<Vec<u8>> {
+    let stream = FlacStream::encode(audio)?;
+    let mut writer = PacketWriter::new(Vec::new());
+
+    // 0x7F "FLAC", mapping version 1.0, one more header packet, native header
+    let mut first = vec![0x7F, b'F', b'L', b'A', b'C', 1, 0, 0, 1];
+    first.extend(b"fLaC");
+    first.extend(stream.streaminfo_block(false));
+    writer.write_packet(first.into_boxed_slice(), OGG_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+    let comment = vorbis_comment_block(true);
+    writer.write_packet(comment.into_boxed_slice(), OGG_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+    let count = stream.frames.len();
+    for (i, frame) in stream.frames.into_iter().enumerate() {
+        let end = if i + 1 == count {
+            PacketWriteEndInfo::EndStream
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        writer.write_packet(frame.data.into_boxed_slice(), OGG_SERIAL, end, frame.end_sample)?;
+    }
+    Ok(writer.into_inner())
+}
+
+struct FlacFrame {
+    data: Vec<u8>,
+    /// Samples per channel up to the end of this frame (the Ogg granule position)
+    end_sample: u64,
+}
+
+struct FlacStream {
+    frames: Vec<FlacFrame>,
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    total_samples: u64,
+}
+
+impl FlacStream {
+    fn encode(audio: &Pcm) -> Result<Self> {
+        if !(1..=8).contains(&audio.channels) {
+            return Err(anyhow!("FLAC supports 1 to 8 channels, got {}", audio.channels));
+        }
+        if audio.sample_rate == 0 || audio.sample_rate >= 1 << 20 {
+            return Err(anyhow!("FLAC can't store a sample rate of {} Hz", audio.sample_rate));
+        }
+
+        let mut frames = Vec::new();
+        let mut end_sample = 0;
+        let block_len = FLAC_BLOCK_SIZE * audio.channels;
+        for (number, block) in audio.samples.chunks(block_len).enumerate() {
+            end_sample += (block.len() / audio.channels) as u64;
+            frames.push(FlacFrame {
+                data: encode_frame(block, number as u64, audio),
+                end_sample,
+            });
+        }
+        Ok(Self {
+            frames,
+            channels: audio.channels,
+            sample_rate: audio.sample_rate,
+            bits_per_sample: audio.bits_per_sample,
+            total_samples: end_sample,
+        })
+    }
+
+    /// STREAMINFO metadata block, header included
+    fn streaminfo_block(&self, last: bool) -> Vec<u8> {
+        // Every frame but the last is a full block
+        let block_size = (self.total_samples as usize).clamp(16, FLAC_BLOCK_SIZE) as u64;
+        let frame_sizes = self.frames.iter().map(|frame| frame.data.len() as u64);
+
+        let mut bits = BitWriter::default();
+        bits.write(block_size, 16);
+        bits.write(block_size, 16);
+        bits.write(frame_sizes.clone().min().unwrap_or(0), 24);
+        bits.write(frame_sizes.max().unwrap_or(0), 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(self.bits_per_sample as u64 - 1, 5);
+        bits.write(self.total_samples, 36);
+        // MD5 of the audio; all zeros means "not computed"
+        for _ in 0..4 {
+            bits.write(0, 32);
+        }
+        metadata_block(0, last, &bits.into_bytes())
+    }
+}
+
+/// VORBIS_COMMENT block with just a vendor string; the Ogg mapping expects
+/// one, and native files conventionally carry it too
+fn vorbis_comment_block(last: bool) -> Vec<u8> {
+    let vendor = concat!("sirius-client ", env!("CARGO_PKG_VERSION"));
+    let mut body = (vendor.len() as u32).to_le_bytes().to_vec();
+    body.extend(vendor.as_bytes());
+    body.extend(0u32.to_le_bytes());
+    metadata_block(4, last, &body)
+}
+
+fn metadata_block(block_type: u8, last: bool, body: &[u8]) -> Vec<u8> {
+    let mut block = vec![(u8::from(last) << 7) | block_type];
+    block.extend(&(body.len() as u32).to_be_bytes()[1..]);
+    block.extend(body);
+    block
+}
+
+/// One frame of interleaved `block` samples, channels coded independently
+fn encode_frame(block: &[i32], number: u64, audio: &Pcm) -> Vec<u8> {
+    let len = block.len() / audio.channels;
+    let mut bits = BitWriter::default();
+
+    // Sync code, fixed block size
+    bits.write(0b1111_1111_1111_1000, 16);
+    // Block size as a 16-bit value at the end of the header
+    bits.write(0b0111, 4);
+    let (rate_code, rate_extra) = sample_rate_code(audio.sample_rate);
+    bits.write(rate_code, 4);
+    bits.write(audio.channels as u64 - 1, 4);
+    bits.write(sample_size_code(audio.bits_per_sample), 3);
+    bits.write(0, 1);
+    for byte in utf8_number(number) {
+        bits.write(byte as u64, 8);
+    }
+    bits.write(len as u64 - 1, 16);
+    if let Some((value, width)) = rate_extra {
+        bits.write(value, width);
+    }
+    let crc = crc8(bits.bytes());
+    bits.write(crc as u64, 8);
+
+    for channel in 0..audio.channels {
+        let samples: Vec<i64> = block
+            .iter()
+            .skip(channel)
+            .step_by(audio.channels)
+            .map(|&s| s as i64)
+            .collect();
+        encode_subframe(&mut bits, &samples, audio.bits_per_sample);
+    }
+
+    bits.align();
+    let crc = crc16(bits.bytes());
+    bits.write(crc as u64, 16);
+    bits.into_bytes()
+}
+
+/// Frame header sample rate code, plus any value stored after the header
+fn sample_rate_code(rate: u32) -> (u64, Option<(u64, u32)>) {
+    let code = match rate {
+        88_200 => 0b0001,
+        176_400 => 0b0010,
+        192_000 => 0b0011,
+        8_000 => 0b0100,
+        16_000 => 0b0101,
+        22_050 => 0b0110,
+        24_000 => 0b0111,
+        32_000 => 0b1000,
+        44_100 => 0b1001,
+        48_000 => 0b1010,
+        96_000 => 0b1011,
+        rate if rate % 1000 == 0 && rate / 1000 < 256 => return (0b1100, Some((rate as u64 / 1000, 8))),
+        rate if rate < 1 << 16 => return (0b1101, Some((rate as u64, 16))),
+        // Left to STREAMINFO
+        _ => 0b0000,
+    };
+    (code, None)
+}
+
+fn sample_size_code(bits_per_sample: u32) -> u64 {
+    match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        // Left to STREAMINFO
+        _ => 0b000,
+    }
+}
+
+/// Frame numbers use the UTF-8 byte layout, extended to 36 bits
+fn utf8_number(n: u64) -> Vec<u8> {
+    if n < 0x80 {
+        return vec![n as u8];
+    }
+    let mut len = 2;
+    while n >= 1 << (5 * len + 1) {
+        len += 1;
+    }
+    let mut out = vec![0u8; len];
+    let mut rest = n;
+    for byte in out[1..].iter_mut().rev() {
+        *byte = 0x80 | (rest & 0x3F) as u8;
+        rest >>= 6;
+    }
+    out[0] = (0xFF00u16 >> len) as u8 | rest as u8;
+    out
+}
+
+/// Write the cheapest of a constant, fixed-predictor or verbatim subframe
+fn encode_subframe(bits: &mut BitWriter, samples: &[i64], bits_per_sample: u32) {
+    let bps = bits_per_sample;
+    if samples.iter().all(|&s| s == samples[0]) {
+        bits.write(0b0000_0000, 8);
+        bits.write_signed(samples[0], bps);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bps as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residuals = fixed_residuals(samples, order);
+            let (param, residual_bits) = best_rice_param(&residuals);
+            let total = order as u64 * bps as u64 + 10 + residual_bits;
+            (order, residuals, param, total)
+        })
+        .min_by_key(|candidate| candidate.3);
+
+    match best {
+        Some((order, residuals, param, total)) if total < verbatim_bits => {
+            bits.write(0b0001_0000 | (order as u64) << 1, 8);
+            for &warm_up in &samples[..order] {
+                bits.write_signed(warm_up, bps);
+            }
+            // Rice coding with 4-bit parameters, a single partition
+            bits.write(0b00, 2);
+            bits.write(0, 4);
+            bits.write(param as u64, 4);
+            for &residual in &residuals {
+                let folded = zigzag(residual);
+                bits.write_unary(folded >> param);
+                bits.write(folded, param);
+            }
+        }
+        _ => {
+            bits.write(0b0000_0010, 8);
+            for &sample in samples {
+                bits.write_signed(sample, bps);
+            }
+        }
+    }
+}
+
+/// Prediction errors of the order-`order` fixed polynomial predictor
+fn fixed_residuals(samples: &[i64], order: usize) -> Vec<i64> {
+    let x = samples;
+    (order..x.len())
+        .map(|i| match order {
+            0 => x[i],
+            1 => x[i] - x[i - 1],
+            2 => x[i] - 2 * x[i - 1] + x[i - 2],
+            3 => x[i] - 3 * x[i - 1] + 3 * x[i - 2] - x[i - 3],
+            _ => x[i] - 4 * x[i - 1] + 6 * x[i - 2] - 4 * x[i - 3] + x[i - 4],
+        })
+        .collect()
+}
+
+/// Rice parameter giving the fewest bits for `residuals`, and that count
+fn best_rice_param(residuals: &[i64]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAM)
+        .map(|param| {
+            let bits = residuals
+                .iter()
+                .map(|&r| (zigzag(r) >> param) + 1 + param as u64)
+                .sum();
+            (param, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// CRC-8, polynomial x^8 + x^2 + x + 1, over a frame header
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// CRC-16, polynomial x^16 + x^15 + x^2 + 1, over a whole frame
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// MSB-first bit packer
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    /// Append the low `width` (at most 56) bits of `value`
+    fn write(&mut self, value: u64, width: u32) {
+        if width == 0 {
+            return;
+        }
+        debug_assert!(width <= 56);
+        self.pending = (self.pending << width) | (value & ((1 << width) - 1));
+        self.pending_bits += width;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    /// Two's complement in `width` bits
+    fn write_signed(&mut self, value: i64, width: u32) {
+        self.write(value as u64, width);
+    }
+
+    /// `count` zeros, then a one
+    fn write_unary(&mut self, mut count: u64) {
+        while count >= 32 {
+            self.write(0, 32);
+            count -= 32;
+        }
+        self.write(1, count as u32 + 1);
+    }
+
+    /// Pad with zeros to a byte boundary
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+
+    /// Bytes completed so far
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16-bit mono WAV: a rising tone, a stretch of silence, then noise
+    fn test_wav(len: usize) -> (Vec<u8>, Vec<i16>) {
+        let mut state = 7u32;
+        let samples: Vec<i16> = (0..len)
+            .map(|i| match i * 3 / len {
+                0 => (8000.0 * (i as f32 * i as f32 * 1e-5).sin()) as i16,
+                1 => 0,
+                _ => {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 16) as i16
+                }
+            })
+            .collect();
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 24_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut wav = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        (wav.into_inner(), samples)
+    }
+
+    #[test]
+    fn test_output_format_from_path() {
+        let format = |path| OutputFormat::from_path(Path::new(path)).unwrap();
+        assert_eq!(format("out.mp3"), OutputFormat::Mp3);
+        assert_eq!(format("a/b.FLAC"), OutputFormat::Flac);
+        assert_eq!(format("out.oga"), OutputFormat::OggFlac);
+        assert_eq!(format("out.wav"), OutputFormat::Wav);
+        assert_eq!(format("out"), OutputFormat::Wav);
+
+        // Not Vorbis, so not .ogg
+        let err = OutputFormat::from_path(Path::new("out.OGG")).unwrap_err();
+        assert!(err.to_string().contains(".oga"), "{}", err);
+    }
+
+    #[test]
+    fn test_flac_output_is_valid_and_lossless() {
+        let (wav, samples) = test_wav(10_000);
+        let flac = convert_wav(&wav, OutputFormat::Flac).unwrap();
+
+        assert_eq!(&flac[..4], b"fLaC");
+        // STREAMINFO first and 34 bytes long, then sample rate, channels,
+        // bit depth and length packed into bytes 18-25
+        assert_eq!(flac[4] & 0x7F, 0);
+        assert_eq!(&flac[5..8], &[0, 0, 34]);
+        let packed = u64::from_be_bytes(flac[18..26].try_into().unwrap());
+        assert_eq!(packed >> 44, 24_000);
+        assert_eq!((packed >> 41) & 0x7, 0);
+        assert_eq!((packed >> 36) & 0x1F, 15);
+        assert_eq!(packed & 0xF_FFFF_FFFF, 10_000);
+        assert!(flac.len() < wav.len());
+
+        let decoded: Vec<i16> = rodio::Decoder::new_flac(Cursor::new(flac)).unwrap().collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn test_ogg_output_wraps_flac() {
+        let (wav, _) = test_wav(10_000);
+        let ogg = convert_wav(&wav, OutputFormat::OggFlac).unwrap();
+        assert_eq!(&ogg[..4], b"OggS");
+
+        let mut reader = ogg::reading::PacketReader::new(Cursor::new(ogg));
+        let first = reader.read_packet_expected().unwrap();
+        assert_eq!(&first.data[..5], b"\x7FFLAC");
+        assert_eq!(&first.data[9..13], b"fLaC");
+
+        let mut last_granule = 0;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            last_granule = packet.absgp_page();
+        }
+        assert_eq!(last_granule, 10_000);
+    }
+
+    #[test]
+    fn test_mp3_output() {
+        let (wav, _) = test_wav(24_000);
+        let mp3 = convert_wav(&wav, OutputFormat::Mp3).unwrap();
+        // MPEG audio frame sync
+        assert_eq!(mp3[0], 0xFF);
+        assert_eq!(mp3[1] & 0xE0, 0xE0);
+    }
+
+    #[test]
+    fn test_wav_output_is_unchanged() {
+        let (wav, _) = test_wav(100);
+        assert_eq!(convert_wav(&wav, OutputFormat::Wav).unwrap(), wav);
+    }
+
+    #[test]
+    fn test_utf8_number() {
+        assert_eq!(utf8_number(0x7F), vec![0x7F]);
+        assert_eq!(utf8_number(0x80), vec![0xC2, 0x80]);
+        assert_eq!(utf8_number(0x800), vec![0xE0, 0xA0, 0x80]);
+    }
+}
@@ -0,0 +1,467 @@
+//! EPUB text extraction for read-aloud
+//!
+//! An EPUB is a zip of XHTML documents. Its package file lists them in
+//! reading order (the spine) and its table of contents says where chapters
+//! start. Only the few tags needed for that are looked at, so this scans
+//! tags rather than parsing XML properly; it copes with what EPUB writers
+//! produce, not with arbitrary XML.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+use zip::ZipArchive;
+
+/// File names and ids that mark front and back matter rather than content
+const NON_CONTENT: &[&str] = &["cover", "copyright", "colophon", "titlepage", "toc", "nav", "contents"];
+
+/// Guide reference types for the same
+const NON_CONTENT_GUIDE: &[&str] = &["cover", "copyright-page", "toc", "title-page", "colophon"];
+
+/// Tags that end a paragraph
+const BLOCK_TAGS: &[&str] = &[
+    "p", "div", "br", "h1", "h2", "h3", "h4", "h5", "h6", "li", "blockquote", "tr", "section", "dd", "dt",
+];
+
+/// Paragraphs of every chapter of the EPUB at `path`, keyed by 1-based
+/// chapter number in reading order.
+///
+/// A chapter starts at each document the table of contents points to, at
+/// any depth; documents it doesn't list (and sections it lists within a
+/// document) continue the chapter before them. Without a table of contents
+/// every document is a chapter. The cover, copyright page, table of contents
+/// and the like are skipped, as are documents with no text.
+pub fn extract_chapters(path: impl AsRef<Path>) -> Result<BTreeMap<usize, Vec<String>>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("failed to open EPUB {}", path.display()))?;
+    let mut archive =
+        ZipArchive::new(file).with_context(|| format!("{} is not an EPUB (zip) file", path.display()))?;
+
+    let container = read_entry(&mut archive, "META-INF/container.xml")?;
+    let package_path = tags(&container)
+        .find(|tag| tag.name == "rootfile" && !tag.closing)
+        .and_then(|tag| tag.attr("full-path"))
+        .ok_or_else(|| anyhow!("META-INF/container.xml names no package file"))?;
+    let package = Package::parse(&read_entry(&mut archive, &package_path)?, parent_dir(&package_path));
+
+    let chapter_starts: HashSet<String> = match &package.toc {
+        Some((toc_path, is_nav)) => {
+            let toc = read_entry(&mut archive, toc_path)?;
+            toc_targets(&toc, *is_nav)
+                .into_iter()
+                .map(|href| resolve(parent_dir(toc_path), &href))
+                .collect()
+        }
+        None => HashSet::new(),
+    };
+
+    let mut chapters = BTreeMap::new();
+    let mut chapter = 0;
+    for item in &package.spine {
+        if package.non_content.contains(item) {
+            continue;
+        }
+        let paragraphs = xhtml_paragraphs(&read_entry(&mut archive, item)?);
+        if paragraphs.is_empty() {
+            continue;
+        }
+        if chapter == 0 || chapter_starts.is_empty() || chapter_starts.contains(item) {
+            chapter += 1;
+        }
+        chapters.entry(chapter).or_insert_with(Vec::new).extend(paragraphs);
+    }
+    Ok(chapters)
+}
+
+/// Read the EPUB at `path` as one text per chapter
+pub fn read_chapters(path: &str) -> Result<Vec<(usize, String)>> {
+    let chapters = extract_chapters(path)?;
+    info!("Found {} chapter(s) in {}", chapters.len(), path);
+    Ok(chapters
+        .into_iter()
+        .map(|(chapter, paragraphs)| (chapter, paragraphs.join(" ")))
+        .collect())
+}
+
+/// What the package (OPF) file says, with paths relative to the archive root
+#[derive(Debug, Default)]
+struct Package {
+    /// Documents in reading order, without those marked `linear="no"`
+    spine: Vec<String>,
+    /// The table of contents and whether it's an EPUB 3 nav document (rather
+    /// than an EPUB 2 NCX)
+    toc: Option<(String, bool)>,
+    /// Spine documents that look like front or back matter
+    non_content: HashSet<String>,
+}
+
+impl Package {
+    fn parse(opf: &str, dir: &str) -> Self {
+        let mut package = Package::default();
+        // id -> (path, media type, properties)
+        let mut manifest: HashMap<String, (String, String, String)> = HashMap::new();
+        let mut ncx_id = None;
+        let mut spine_ids = Vec::new();
+
+        for tag in tags(opf).filter(|tag| !tag.closing) {
+            match tag.name {
+                "item" => {
+                    let (Some(id), Some(href)) = (tag.attr("id"), tag.attr("href")) else {
+                        continue;
+                    };
+                    let media_type = tag.attr("media-type").unwrap_or_default();
+                    let properties = tag.attr("properties").unwrap_or_default();
+                    manifest.insert(id, (resolve(dir, &href), media_type, properties));
+                }
+                "spine" => ncx_id = tag.attr("toc"),
+                "itemref" if tag.attr("linear").as_deref() != Some("no") => {
+                    spine_ids.extend(tag.attr("idref"));
+                }
+                "reference" => {
+                    let guide_type = tag.attr("type").unwrap_or_default().to_lowercase();
+                    if let (true, Some(href)) = (NON_CONTENT_GUIDE.contains(&guide_type.as_str()), tag.attr("href")) {
+                        package.non_content.insert(resolve(dir, &href));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let nav = manifest
+            .values()
+            .find(|(_, _, properties)| properties.split_whitespace().any(|p| p == "nav"))
+            .map(|(path, _, _)| (path.clone(), true));
+        let ncx = ncx_id
+            .and_then(|id| manifest.get(&id))
+            .or_else(|| manifest.values().find(|(_, media_type, _)| media_type == "application/x-dtbncx+xml"))
+            .map(|(path, _, _)| (path.clone(), false));
+        package.toc = nav.or(ncx);
+
+        for id in spine_ids {
+            let Some((path, _, _)) = manifest.get(&id) else {
+                continue;
+            };
+            if looks_like_non_content(&id) || looks_like_non_content(file_stem(path)) {
+                package.non_content.insert(path.clone());
+            }
+            package.spine.push(path.clone());
+        }
+        if let Some((toc_path, _)) = &package.toc {
+            package.non_content.insert(toc_path.clone());
+        }
+        package
+    }
+}
+
+/// Whether `name` has a word like "cover" or "copyright" in it
+fn looks_like_non_content(name: &str) -> bool {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| NON_CONTENT.contains(&word))
+}
+
+/// Link targets of a table of contents, in order, flattened across nesting
+/// levels. In a nav document only the `epub:type="toc"` nav counts.
+fn toc_targets(toc: &str, is_nav: bool) -> Vec<String> {
+    let mut targets = Vec::new();
+    let mut in_toc = !is_nav;
+    for tag in tags(toc) {
+        match (tag.name, tag.closing) {
+            ("nav", false) if is_nav => {
+                in_toc = tag.attr("epub:type").is_some_and(|t| t.split_whitespace().any(|t| t == "toc"));
+            }
+            ("nav", true) if is_nav => in_toc = false,
+            ("a", false) if in_toc && is_nav => targets.extend(tag.attr("href")),
+            ("content", false) if !is_nav => targets.extend(tag.attr("src")),
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// The text of an XHTML document's body as paragraphs, with whitespace
+/// collapsed and entities decoded
+fn xhtml_paragraphs(xhtml: &str) -> Vec<String> {
+    let body = match tags(xhtml).find(|tag| tag.name == "body" && !tag.closing) {
+        Some(tag) => &xhtml[tag.end..],
+        None => xhtml,
+    };
+
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+    let mut skipping: Option<&str> = None;
+    let mut text_start = 0;
+    for tag in tags(body) {
+        if skipping.is_none() {
+            current.push_str(&body[text_start..tag.start]);
+        }
+        text_start = tag.end;
+        match skipping {
+            Some(name) if tag.closing && tag.name == name => skipping = None,
+            Some(_) => {}
+            None if !tag.closing && !tag.self_closing && matches!(tag.name, "script" | "style" | "head") => {
+                skipping = Some(tag.name);
+            }
+            None if BLOCK_TAGS.contains(&tag.name) => push_paragraph(&mut paragraphs, &mut current),
+            None => {}
+        }
+    }
+    if skipping.is_none() {
+        current.push_str(&body[text_start..]);
+    }
+    push_paragraph(&mut paragraphs, &mut current);
+    paragraphs
+}
+
+fn push_paragraph(paragraphs: &mut Vec<String>, raw: &mut String) {
+    let text = decode_entities(&raw.split_whitespace().collect::<Vec<_>>().join(" "));
+    if !text.is_empty() {
+        paragraphs.push(text);
+    }
+    raw.clear();
+}
+
+/// Decode the XML entities plus `&nbsp;`; unknown named entities are kept
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';').filter(|&semi| semi <= 10) else {
+            out.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// One tag found by [`tags`]
+#[derive(Debug)]
+struct Tag<'a> {
+    /// Element name without any namespace prefix
+    name: &'a str,
+    closing: bool,
+    self_closing: bool,
+    attrs: &'a str,
+    /// Byte range of the whole tag in the scanned text
+    start: usize,
+    end: usize,
+}
+
+impl Tag<'_> {
+    /// The decoded value of attribute `key`, matched with its prefix if it
+    /// has one (`epub:type`)
+    fn attr(&self, key: &str) -> Option<String> {
+        let mut rest = self.attrs;
+        while let Some(eq) = rest.find('=') {
+            let name = rest[..eq].trim();
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+            let close = value[1..].find(quote)? + 1;
+            if name == key {
+                return Some(decode_entities(&value[1..close]));
+            }
+            rest = &value[close + 1..];
+        }
+        None
+    }
+}
+
+/// Element tags of `xml` in order, skipping comments, declarations and
+/// processing instructions
+fn tags(xml: &str) -> impl Iterator<Item = Tag<'_>> {
+    let mut pos = 0;
+    std::iter::from_fn(move || loop {
+        let start = pos + xml[pos..].find('<')?;
+        let rest = &xml[start..];
+        if rest.starts_with("<!--") {
+            pos = start + rest.find("-->").map_or(rest.len(), |end| end + 3);
+            continue;
+        }
+        let end = start + rest.find('>')? + 1;
+        pos = end;
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            continue;
+        }
+
+        let inner = &xml[start + 1..end - 1];
+        let (closing, inner) = match inner.strip_prefix('/') {
+            Some(inner) => (true, inner),
+            None => (false, inner),
+        };
+        let (self_closing, inner) = match inner.strip_suffix('/') {
+            Some(inner) => (true, inner),
+            None => (false, inner),
+        };
+        let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+        let name = &inner[..name_end];
+        return Some(Tag {
+            name: name.rsplit(':').next().unwrap_or(name),
+            closing,
+            self_closing,
+            attrs: &inner[name_end..],
+            start,
+            end,
+        });
+    })
+}
+
+/// Read a file of the archive as UTF-8
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<String> {
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("EPUB is missing {}", name))?;
+    let mut text = String::new();
+    entry
+        .read_to_string(&mut text)
+        .with_context(|| format!("reading {} from the EPUB", name))?;
+    Ok(text)
+}
+
+/// The directory part of an archive path ("" at the root)
+fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+fn file_stem(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.split_once('.').map_or(name, |(stem, _)| stem)
+}
+
+/// The archive path of `href` relative to `dir`, without any `#fragment` and
+/// with `%XX` escapes and `..` resolved
+fn resolve(dir: &str, href: &str) -> String {
+    let href = href.split_once('#').map_or(href, |(path, _)| path);
+    let mut parts: Vec<&str> = dir.split('/').filter(|part| !part.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    percent_decode(&parts.join("/"))
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = text.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> &'static str {
+        concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/small.epub")
+    }
+
+    #[test]
+    fn test_fixture_chapters() {
+        // Cover, copyright and contents are skipped; the nested section of
+        // chapter 1 and the unlisted continuation of chapter 2 don't start
+        // chapters of their own
+        let chapters = extract_chapters(fixture()).unwrap();
+        assert_eq!(chapters.len(), 3);
+        assert_eq!(chapters[&1], vec!["Part One"]);
+        assert_eq!(
+            chapters[&2],
+            vec![
+                "Chapter 1",
+                "It was a dark and stormy night.",
+                "Later that day",
+                "Tom & Jerry\u{2019}s house was quiet.",
+            ]
+        );
+        assert_eq!(chapters[&3], vec!["Chapter 2", "The next morning.", "Still the next morning."]);
+
+        let texts = read_chapters(fixture()).unwrap();
+        assert_eq!(texts[2], (3, "Chapter 2 The next morning. Still the next morning.".to_string()));
+    }
+
+    #[test]
+    fn test_ncx_targets() {
+        let ncx = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/">
+  <navMap>
+    <navPoint id="p1"><navLabel><text>One</text></navLabel><content src="one.html"/>
+      <navPoint id="p1-1"><navLabel><text>One A</text></navLabel><content src="one.html#a"/></navPoint>
+    </navPoint>
+    <!-- <navPoint><content src="commented.html"/></navPoint> -->
+    <navPoint id="p2"><navLabel><text>Two</text></navLabel><content src='two%20b.html'/></navPoint>
+  </navMap>
+</ncx>"#;
+        let targets: Vec<String> = toc_targets(ncx, false).iter().map(|href| resolve("OPS", href)).collect();
+        assert_eq!(targets, vec!["OPS/one.html", "OPS/one.html", "OPS/two b.html"]);
+    }
+
+    #[test]
+    fn test_xhtml_paragraphs() {
+        let xhtml = "<html><head><title>Skipped</title></head><body>\
+            <script>var x = 1;</script><p>First &amp; <b>bold</b>\n  line.</p>\
+            Loose text<br/>after a break<div><p>Nested</p></div>&#x2014;&unknown;</body></html>";
+        assert_eq!(
+            xhtml_paragraphs(xhtml),
+            vec!["First & bold line.", "Loose text", "after a break", "Nested", "\u{2014}&unknown;"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_and_non_content() {
+        assert_eq!(resolve("OEBPS/text", "../images/a.png#x"), "OEBPS/images/a.png");
+        assert_eq!(resolve("", "chapter1.xhtml"), "chapter1.xhtml");
+        assert!(looks_like_non_content("cover"));
+        assert!(looks_like_non_content("Copyright_Page"));
+        assert!(!looks_like_non_content("chapter1"));
+        assert!(!looks_like_non_content("discovery"));
+    }
+}
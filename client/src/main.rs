@@ -11,18 +11,56 @@
 //!
 //!   # Save to file instead of playing
 //!   cargo run --release -p sirius-client -- --text "Hello world" --output hello.wav
+//!
+//!   # Raw 16-bit PCM on stdout for another program
+//!   cargo run --release -p sirius-client -- --text "Hello world" --pipe-raw | sox -t s16 -r 24000 -c 1 - hello.wav
+//!
+//!   # A request saved as JSON, with --voice taking precedence over the file's
+//!   cargo run --release -p sirius-client -- --request-file recipe.json --voice bf_emma
+//!
+//!   # Batch mode: one text per line, cached by input hash in a directory
+//!   cargo run --release -p sirius-client -- --batch lines.txt --output-dir clips/
+//!
+//!   # Load test: 100 requests over 8 connections, then a latency/RTF table
+//!   cargo run --release -p sirius-client -- --benchmark --text "Hello world" --iterations 100 --concurrency 8
 
 mod audio;
+mod batch;
+mod bench;
+mod convert;
+mod epubreader;
+mod pdfreader;
+mod progress;
+mod resume;
 
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request as HandshakeRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use sirius_protocol::text::segment_sentences;
+use sirius_protocol::{
+    AudioFormat, CloseReason, PayloadAssembler, Request, Response, SynthesizeRequest, SUBPROTOCOL,
+};
 
-use sirius_protocol::{Request, Response, SynthesizeRequest};
+/// Tracing target for warnings relayed from the server, silenced by `--quiet`
+const SERVER_WARNING_TARGET: &str = "sirius_client::server_warning";
+
+/// `--output` that writes the audio to stdout exactly as received
+const STDOUT_OUTPUT: &str = "-";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Sirius TTS Client")]
@@ -35,10 +73,53 @@ struct Args {
     #[arg(short, long)]
     text: Option<String>,
 
-    /// Output file (if not provided, plays audio directly)
-    #[arg(short, long)]
+    /// Output file (if not provided, plays audio directly); a .mp3, .flac or
+    /// .oga (FLAC in Ogg) extension converts the WAV from the server, .ogg is
+    /// refused, and "-" writes it to stdout
+    #[arg(short, long, conflicts_with = "output_dir")]
     output: Option<String>,
 
+    /// JSON file with a full SynthesizeRequest, plus an optional "output"
+    /// path; --text, --voice, --lang, --speed and --output given on the
+    /// command line take precedence over the file
+    #[arg(long, conflicts_with_all = ["batch", "pdf", "epub", "output_dir", "local_split", "benchmark"])]
+    request_file: Option<String>,
+
+    /// Batch file with one text per line (synthesized in order)
+    #[arg(long, conflicts_with = "text")]
+    batch: Option<String>,
+
+    /// PDF to read aloud, page by page
+    #[arg(long, conflicts_with_all = ["text", "batch", "output"])]
+    pdf: Option<String>,
+
+    /// EPUB to read aloud, chapter by chapter
+    #[arg(long, conflicts_with_all = ["text", "batch", "output", "pdf"])]
+    epub: Option<String>,
+
+    /// With --pdf, the pages to read, e.g. "1-10,15,20-" (default: all)
+    #[arg(long, requires = "pdf", value_parser = pdfreader::PageSelection::parse)]
+    pages: Option<pdfreader::PageSelection>,
+
+    /// With --pdf or --epub, continue after the last page or chapter that
+    /// finished playing last time
+    #[arg(long, conflicts_with_all = ["start_at", "output_dir"])]
+    resume: bool,
+
+    /// With --pdf or --epub, start at this page or chapter, or at a sentence
+    /// within it: "12" or "12:3"
+    #[arg(long, conflicts_with = "output_dir", value_parser = resume::StartAt::parse)]
+    start_at: Option<resume::StartAt>,
+
+    /// Write each clip to this directory as sha256(text+voice+speed).wav,
+    /// skipping clips that already exist
+    #[arg(long)]
+    output_dir: Option<String>,
+
+    /// With --output-dir, re-synthesize clips even if the file exists
+    #[arg(long, requires = "output_dir")]
+    force: bool,
+
     /// Voice to use
     #[arg(short, long, default_value = "am_onyx.4+bm_lewis.6")]
     voice: String,
@@ -50,37 +131,334 @@ struct Args {
     /// Speech speed (0.5-2.0)
     #[arg(long, default_value = "0.99")]
     speed: f32,
+
+    /// Output device to play on (name or part of it)
+    #[arg(long)]
+    device: Option<String>,
+
+    /// Playback volume (0.0-2.0)
+    #[arg(long, default_value = "1.0", value_parser = audio::parse_volume)]
+    volume: f32,
+
+    /// Play each clip this many times
+    #[arg(long, default_value = "1", value_parser = audio::parse_repeat)]
+    repeat: u32,
+
+    /// Silence between repeats, in milliseconds
+    #[arg(long, default_value = "500")]
+    repeat_gap_ms: u64,
+
+    /// Give up on a request, exiting with an error, if its reply hasn't
+    /// fully arrived after this many seconds (time spent playing earlier
+    /// parts of it doesn't count); 0 waits forever
+    #[arg(long, default_value = "120")]
+    timeout: u64,
+
+    /// Largest WebSocket message (and frame) accepted from the server, in MiB
+    #[arg(long, default_value_t = sirius_protocol::DEFAULT_MAX_MESSAGE_MB)]
+    max_message_mb: usize,
+
+    /// Don't log server warnings (e.g. a clamped speed) or draw the
+    /// transfer progress bar
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// With --output, also play the audio once it's saved
+    #[arg(long, requires = "output")]
+    tee: bool,
+
+    /// Split --text into sentences here and send one request per sentence,
+    /// asking for the next while the current one plays, so long text starts
+    /// playing sooner
+    #[arg(long, requires = "text", conflicts_with_all = ["output", "output_dir", "pipe_raw"])]
+    local_split: bool,
+
+    /// Print a JSON summary of each synthesis to stdout instead of log lines
+    /// (an array for --batch, --pdf, --epub and --output-dir)
+    #[arg(long)]
+    json: bool,
+
+    /// Request raw 16-bit PCM and write it to stdout for piping into sox or
+    /// ffmpeg; the sample format is printed to stderr
+    #[arg(long, conflicts_with_all = ["output", "output_dir", "pdf", "epub", "json"])]
+    pipe_raw: bool,
+
+    /// Send --text repeatedly without playing it and print latency,
+    /// throughput and real-time factor percentiles
+    #[arg(long, requires = "text", conflicts_with_all = ["output", "output_dir", "pipe_raw", "json"])]
+    benchmark: bool,
+
+    /// With --benchmark, the number of requests to send
+    #[arg(long, default_value = "100", requires = "benchmark")]
+    iterations: usize,
+
+    /// With --benchmark, the number of connections sending at once
+    #[arg(long, default_value = "1", requires = "benchmark")]
+    concurrency: usize,
+
+    /// List available output devices and exit
+    #[arg(long)]
+    list_devices: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("sirius_client=info".parse()?)
-                .add_directive("tokio_tungstenite=warn".parse()?),
-        )
-        .init();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+
+    // With --json or --pipe-raw, stdout is reserved for the summaries or the
+    // audio; logs go to stderr
+    let level = if args.json { "warn" } else { "info" };
+    let mut filter = tracing_subscriber::EnvFilter::from_default_env()
+        .add_directive(format!("sirius_client={}", level).parse()?)
+        .add_directive("tokio_tungstenite=warn".parse()?);
+    if args.quiet {
+        filter = filter.add_directive(format!("{}=off", SERVER_WARNING_TARGET).parse()?);
+    }
+    if args.json || args.pipe_raw {
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    }
+    progress::enable(!args.json && !args.quiet && io::stderr().is_terminal());
+
+    if args.list_devices {
+        for name in audio::output_device_names()? {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
 
-    let args = Args::parse();
+    let mut playback = audio::PlaybackOptions {
+        device: args.device.clone(),
+        volume: args.volume,
+        repeat: args.repeat,
+        gap: std::time::Duration::from_millis(args.repeat_gap_ms),
+        skip: None,
+        no_audio: std::env::var_os(audio::NO_AUDIO_ENV).is_some(),
+    };
+
+    let document_path = args.pdf.as_deref().or(args.epub.as_deref());
+    if (args.resume || args.start_at.is_some()) && document_path.is_none() {
+        return Err(anyhow::anyhow!("--resume and --start-at need --pdf or --epub"));
+    }
+    if args.pipe_raw && args.text.is_none() && args.batch.is_none() && args.request_file.is_none() {
+        return Err(anyhow::anyhow!("--pipe-raw needs --text, --batch or --request-file"));
+    }
+    let format = if args.pipe_raw { AudioFormat::PcmS16LE } else { AudioFormat::Wav };
+    let pipe_output = args.pipe_raw.then_some(STDOUT_OUTPUT);
+
+    // The one request of single text mode, checked before connecting
+    let single = match (&args.request_file, &args.text) {
+        (Some(path), _) => {
+            let (mut request, output) = request_from_file(path, &args, &matches)?;
+            if args.pipe_raw {
+                request = request.with_format(format);
+            }
+            Some((request, output))
+        }
+        (None, Some(text)) => Some((
+            synthesize_request(text, &args.voice, &args.lang, args.speed).with_format(format),
+            args.output.clone(),
+        )),
+        (None, None) => None,
+    };
+    if let Some((_, Some(output))) = &single {
+        if output != STDOUT_OUTPUT {
+            convert::OutputFormat::from_path(Path::new(output))?;
+        }
+    }
+
+    // Read the document before connecting so bad files and page selections
+    // fail fast
+    let document = match (&args.pdf, &args.epub) {
+        (Some(pdf_path), _) => {
+            let pages = pdfreader::read_pages(pdf_path, args.pages.as_ref())?;
+            Some(("Page", pages.into_iter().map(|(page, text)| (page as usize, text)).collect::<Vec<_>>()))
+        }
+        (None, Some(epub_path)) => Some(("Chapter", epubreader::read_chapters(epub_path)?)),
+        (None, None) => None,
+    };
 
     info!("Connecting to {}", args.server);
-    let (ws_stream, _) = tokio_tungstenite::connect_async(&args.server).await?;
+    let max_message_bytes = args.max_message_mb * 1024 * 1024;
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(max_message_bytes),
+        max_frame_size: Some(max_message_bytes),
+        ..Default::default()
+    };
+
+    if let (true, Some(text)) = (args.benchmark, &args.text) {
+        info!(
+            "Benchmarking: {} requests over {} connection(s)",
+            args.iterations, args.concurrency
+        );
+        let request = synthesize_request(text, &args.voice, &args.lang, args.speed);
+        let report =
+            bench::run(&args.server, ws_config, request, args.iterations, args.concurrency).await?;
+        println!("{}", report);
+        return Ok(());
+    }
+
+    let (ws_stream, _) =
+        tokio_tungstenite::connect_async_with_config(handshake_request(&args.server)?, Some(ws_config), false)
+            .await?;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
     info!("Connected!");
+    let response_timeout = (args.timeout > 0).then(|| Duration::from_secs(args.timeout));
+
+    let texts = match (&args.text, &args.batch, &document) {
+        (Some(text), _, _) => Some(vec![text.clone()]),
+        (None, Some(batch_path), _) => Some(batch::read_batch_file(batch_path)?),
+        (None, None, Some((_, parts))) => Some(parts.iter().map(|(_, text)| text.clone()).collect()),
+        (None, None, None) => None,
+    };
+
+    if let (Some(texts), Some(output_dir)) = (&texts, &args.output_dir) {
+        // Hashed output directory mode
+        let dir = Path::new(output_dir);
+        std::fs::create_dir_all(dir)?;
 
-    if let Some(text) = args.text {
+        let pending = batch::pending_clips(texts, &args.voice, &args.lang, args.speed, dir, args.force);
+        info!(
+            "{} of {} clips already present in {}",
+            texts.len() - pending.len(),
+            texts.len(),
+            output_dir
+        );
+
+        let mut summaries = Vec::new();
+        for (text, path) in pending {
+            summaries.push(
+                synthesize_and_play(
+                    &mut ws_sender,
+                    &mut ws_receiver,
+                    synthesize_request(&text, &args.voice, &args.lang, args.speed),
+                    path.to_str(),
+                    false,
+                    &playback,
+                    response_timeout,
+                )
+                .await?,
+            );
+        }
+        if args.json {
+            println!("{}", serde_json::to_string(&summaries)?);
+        }
+    } else if let (Some((unit, all_parts)), Some(document_path)) = (&document, document_path) {
+        // PDF/EPUB mode: one request per page or chapter, `:skip` on stdin
+        // moves to the next one. Finished parts are recorded for --resume.
+        let resume_file = resume::ResumeFile::for_document(&resume::state_dir(), document_path)?;
+        let start = match (args.start_at, args.resume) {
+            (Some(start), _) => start,
+            (None, true) => match resume_file.load()? {
+                Some(last_played) => {
+                    info!("Resuming after {} {}", unit.to_lowercase(), last_played);
+                    resume::StartAt { part: last_played + 1, sentence: 1 }
+                }
+                None => resume::StartAt::default(),
+            },
+            (None, false) => resume::StartAt::default(),
+        };
+        let mut parts = resume::parts_from(all_parts, start, &args.lang);
+        if parts.is_empty() && args.resume {
+            info!("Finished last time; starting from the beginning");
+            parts = all_parts.clone();
+        }
+        let skip = Arc::new(AtomicBool::new(false));
+        spawn_skip_listener(skip.clone());
+        playback.skip = Some(skip.clone());
+        if !args.json {
+            let unit = unit.to_lowercase();
+            println!(
+                "Reading {} {}(s); type :skip and press Enter to jump to the next {}",
+                parts.len(),
+                unit,
+                unit
+            );
+        }
+
+        let mut summaries = Vec::new();
+        for (number, text) in &parts {
+            if !args.json {
+                println!("{} {}", unit, number);
+            }
+            summaries.push(
+                synthesize_and_play(
+                    &mut ws_sender,
+                    &mut ws_receiver,
+                    synthesize_request(text, &args.voice, &args.lang, args.speed),
+                    None,
+                    false,
+                    &playback,
+                    response_timeout,
+                )
+                .await?,
+            );
+            skip.store(false, Ordering::Relaxed);
+            if let Err(e) = resume_file.save(*number) {
+                warn!("Failed to save the reading position: {}", e);
+            }
+        }
+        if args.json {
+            println!("{}", serde_json::to_string(&summaries)?);
+        }
+    } else if let (Some(texts), Some(_)) = (&texts, &args.batch) {
+        // Batch playback mode
+        let mut summaries = Vec::new();
+        for text in texts {
+            summaries.push(
+                synthesize_and_play(
+                    &mut ws_sender,
+                    &mut ws_receiver,
+                    synthesize_request(text, &args.voice, &args.lang, args.speed).with_format(format),
+                    pipe_output,
+                    false,
+                    &playback,
+                    response_timeout,
+                )
+                .await?,
+            );
+        }
+        if args.json {
+            println!("{}", serde_json::to_string(&summaries)?);
+        }
+    } else if let (Some(text), true) = (&args.text, args.local_split) {
+        // Local split mode: one request per sentence, pipelined
+        let requests = segment_sentences(text, &args.lang)
+            .iter()
+            .map(|sentence| synthesize_request(sentence, &args.voice, &args.lang, args.speed))
+            .collect();
+        let summaries = speak_pipelined(
+            &mut ws_sender,
+            &mut ws_receiver,
+            requests,
+            &playback,
+            audio::play_wav_bytes,
+            response_timeout,
+        )
+        .await?;
+        if args.json {
+            println!("{}", serde_json::to_string(&summaries)?);
+        }
+    } else if let Some((request, output)) = single {
         // Single text mode
-        synthesize_and_play(
+        let summary = synthesize_and_play(
             &mut ws_sender,
             &mut ws_receiver,
-            &text,
-            &args.voice,
-            &args.lang,
-            args.speed,
-            args.output.as_deref(),
+            request,
+            pipe_output.or(output.as_deref()),
+            args.tee,
+            &playback,
+            response_timeout,
         )
         .await?;
+        if args.json {
+            println!("{}", serde_json::to_string(&summary)?);
+        }
     } else {
         // Interactive mode
         println!("Sirius TTS Client - Interactive Mode");
@@ -90,6 +468,10 @@ async fn main() -> Result<()> {
         println!("  :q or :quit - Exit");
         println!("  :v <voice>  - Change voice");
         println!("  :s <speed>  - Change speed (0.5-2.0)");
+        println!("  :vol <n>    - Change playback volume (0.0-2.0)");
+        println!("  :repeat <n> - Play each clip n times");
+        println!("  :preview <text> - Play each voice of the current blend, then the blend");
+        println!("  :echo <text> - Round-trip text through the server without synthesis");
         println!();
 
         let mut voice = args.voice;
@@ -139,19 +521,69 @@ async fn main() -> Result<()> {
                 continue;
             }
 
+            if let Some(new_volume) = line.strip_prefix(":vol ") {
+                match audio::parse_volume(new_volume) {
+                    Ok(v) => {
+                        playback.volume = v;
+                        println!("Volume changed to: {}", playback.volume);
+                    }
+                    Err(e) => println!("Invalid volume: {}", e),
+                }
+                continue;
+            }
+
+            if let Some(new_repeat) = line.strip_prefix(":repeat ") {
+                match audio::parse_repeat(new_repeat) {
+                    Ok(n) => {
+                        playback.repeat = n;
+                        println!("Repeat changed to: {}", playback.repeat);
+                    }
+                    Err(e) => println!("Invalid repeat: {}", e),
+                }
+                continue;
+            }
+
+            if let Some(payload) = line.strip_prefix(":echo ") {
+                match echo(&mut ws_sender, &mut ws_receiver, payload).await {
+                    Ok((echoed, elapsed)) => println!("Echo ({:.1} ms): {}", elapsed.as_secs_f64() * 1000.0, echoed),
+                    Err(e) => error!("Error: {}", e),
+                }
+                continue;
+            }
+
+            if let Some(preview_text) = line.strip_prefix(":preview ") {
+                let voices = preview_voices(&voice);
+                if let Err(e) = preview_and_play(
+                    &mut ws_sender,
+                    &mut ws_receiver,
+                    preview_text.trim(),
+                    &voices,
+                    &lang,
+                    speed,
+                    &playback,
+                )
+                .await
+                {
+                    error!("Error: {}", e);
+                }
+                continue;
+            }
+
             // Synthesize and play
-            if let Err(e) = synthesize_and_play(
+            match synthesize_and_play(
                 &mut ws_sender,
                 &mut ws_receiver,
-                line,
-                &voice,
-                &lang,
-                speed,
+                synthesize_request(line, &voice, &lang, speed),
                 None,
+                false,
+                &playback,
+                response_timeout,
             )
             .await
             {
-                error!("Error: {}", e);
+                Ok(summary) if args.json => println!("{}", serde_json::to_string(&summary)?),
+                Ok(_) => {}
+                Err(e) => error!("Error: {}", e),
             }
         }
     }
@@ -162,77 +594,1073 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// The WebSocket handshake for `server`, offering this protocol version's
+/// subprotocol; the connection fails if the server doesn't accept it
+fn handshake_request(server: &str) -> Result<HandshakeRequest> {
+    let mut request = server.into_client_request()?;
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+    Ok(request)
+}
+
+fn synthesize_request(text: &str, voice: &str, lang: &str, speed: f32) -> SynthesizeRequest {
+    SynthesizeRequest::new(text)
+        .with_voice(voice)
+        .with_lang(lang)
+        .with_speed(speed)
+}
+
+/// The request in `--request-file` `path` and the output path it names,
+/// with the flags given on the command line in place of the file's values.
+/// Fails unless the result passes `SynthesizeRequest::validate`, or if it
+/// asks for audio the client can't receive: anything but WAV sent as
+/// binary messages (`--pipe-raw` picks raw PCM itself).
+fn request_from_file(path: &str, args: &Args, matches: &ArgMatches) -> Result<(SynthesizeRequest, Option<String>)> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("reading request file {}", path))?;
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&contents).with_context(|| format!("parsing request file {}", path))?;
+    let output = match fields.remove("output") {
+        Some(serde_json::Value::String(output)) => Some(output),
+        Some(serde_json::Value::Null) | None => None,
+        Some(_) => return Err(anyhow::anyhow!("{}: \"output\" must be a path", path)),
+    };
+    // The text may come from --text instead
+    fields.entry("text").or_insert_with(|| "".into());
+    let mut request: SynthesizeRequest = serde_json::from_value(fields.into())
+        .with_context(|| format!("parsing request file {}", path))?;
+
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if let Some(text) = &args.text {
+        request.text = text.clone();
+    }
+    if given("voice") {
+        request.voice = args.voice.clone();
+    }
+    if given("lang") {
+        request.lang = args.lang.clone();
+    }
+    if given("speed") {
+        request.speed = args.speed;
+    }
+    let output = if given("output") { args.output.clone() } else { output };
+
+    if request.text.trim().is_empty() {
+        return Err(anyhow::anyhow!("{} has no \"text\"; add one or pass --text", path));
+    }
+    let unsupported = if request.inline_audio {
+        Some("\"inline_audio\"")
+    } else if request.metadata_only {
+        Some("\"metadata_only\"")
+    } else if request.format != AudioFormat::Wav {
+        Some("a \"format\" other than wav (use --pipe-raw for raw PCM)")
+    } else {
+        None
+    };
+    if let Some(field) = unsupported {
+        return Err(anyhow::anyhow!("{}: the client can't receive audio requested with {}", path, field));
+    }
+    request.validate().map_err(|reason| anyhow::anyhow!("{}: {}", path, reason))?;
+    Ok((request, output))
+}
+
+/// What `--json` prints for one synthesis
+#[derive(Debug, Clone, Serialize)]
+struct SynthesisSummary {
+    duration_secs: f32,
+    sample_rate: u32,
+    size_bytes: usize,
+    /// Where the audio was saved; `None` when it was played
+    output: Option<String>,
+    /// Real-time factor: seconds from request to received audio per second of audio
+    rtf: f32,
+}
+
 async fn synthesize_and_play<S, R>(
     sender: &mut S,
     receiver: &mut R,
-    text: &str,
-    voice: &str,
-    lang: &str,
-    speed: f32,
+    request: SynthesizeRequest,
     output: Option<&str>,
-) -> Result<()>
+    tee: bool,
+    playback: &audio::PlaybackOptions,
+    timeout: Option<Duration>,
+) -> Result<SynthesisSummary>
 where
     S: SinkExt<Message> + Unpin,
     S::Error: std::error::Error + Send + Sync + 'static,
     R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
 {
-    // Build and send request
-    let request = Request::Synthesize(
-        SynthesizeRequest::new(text)
-            .with_voice(voice)
-            .with_lang(lang)
-            .with_speed(speed),
-    );
+    let start = Instant::now();
+    send_synthesize(sender, request).await?;
+    receive_audio(receiver, output, start, timeout, |data, output| {
+        deliver_audio(data, output, tee, playback, audio::play_wav_bytes)
+    })
+    .await
+}
 
-    let request_json = serde_json::to_string(&request)?;
+async fn send_synthesize<S>(sender: &mut S, request: SynthesizeRequest) -> Result<()>
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let request_json = serde_json::to_string(&Request::Synthesize(request))?;
     sender.send(Message::Text(request_json)).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(())
+}
 
-    // Wait for response
-    let mut audio_metadata: Option<Response> = None;
+/// Receive the reply to a synthesize request sent at `start`, handing each
+/// payload to `on_payload` with the path it should be saved to. Fails if the
+/// reply takes longer than `timeout`, not counting time in `on_payload`.
+async fn receive_audio<R>(
+    receiver: &mut R,
+    output: Option<&str>,
+    start: Instant,
+    timeout: Option<Duration>,
+    mut on_payload: impl FnMut(&[u8], Option<&str>) -> Result<()>,
+) -> Result<SynthesisSummary>
+where
+    R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    // Each payload may arrive split across several binary messages, and a
+    // segmented reply has several payloads
+    let mut assembler: Option<PayloadAssembler> = None;
+    let mut progress: Option<progress::TransferBar> = None;
+    let mut checksum: Option<u32> = None;
+    let mut summary: Option<SynthesisSummary> = None;
+    let mut expected = 1;
+    let mut received = 0;
+    let mut deadline = timeout.map(|timeout| tokio::time::Instant::from_std(start) + timeout);
 
-    while let Some(msg) = receiver.next().await {
-        let msg = msg?;
+    loop {
+        let next = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, receiver.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    let reason = format!("the {:?} timeout", timeout.unwrap_or_default());
+                    return Err(interrupted(assembler.as_ref(), &reason));
+                }
+            },
+            None => receiver.next().await,
+        };
+        let Some(msg) = next else { break };
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => return Err(interrupted(assembler.as_ref(), &e.to_string())),
+        };
 
         match msg {
             Message::Text(text) => {
                 let response: Response = serde_json::from_str(&text)?;
                 match &response {
-                    Response::AudioReady { duration_secs, size_bytes, .. } => {
+                    Response::AudioReady { duration_secs, num_samples, size_bytes, sample_rate, channels, format, crc32, .. } => {
+                        let duration_secs = audio_duration_secs(*num_samples, *sample_rate, *duration_secs);
                         info!(
                             "Receiving audio: {:.2}s, {} bytes",
                             duration_secs, size_bytes
                         );
-                        audio_metadata = Some(response);
+                        if output == Some(STDOUT_OUTPUT) {
+                            if let Some(description) = raw_format_description(*format, *sample_rate, *channels) {
+                                eprintln!("{}", description);
+                            }
+                        }
+                        assembler = Some(PayloadAssembler::new(*size_bytes));
+                        progress = Some(progress::TransferBar::new(*size_bytes));
+                        checksum = *crc32;
+                        let summary = summary.get_or_insert(SynthesisSummary {
+                            duration_secs: 0.0,
+                            sample_rate: *sample_rate,
+                            size_bytes: 0,
+                            output: output.map(str::to_string),
+                            rtf: 0.0,
+                        });
+                        summary.duration_secs += duration_secs;
+                        summary.size_bytes += size_bytes;
+                    }
+                    Response::SegmentCount { count } => expected = (*count).max(1),
+                    Response::Warning { code, message } => {
+                        warn!(target: SERVER_WARNING_TARGET, "Server warning ({}): {}", code, message);
                     }
-                    Response::Error { message } => {
-                        return Err(anyhow::anyhow!("Server error: {}", message));
+                    Response::Error { code, message } => {
+                        return Err(anyhow::anyhow!("Server error ({}): {}", code, message));
+                    }
+                    Response::Pong
+                    | Response::Echo { .. }
+                    | Response::DefaultsSet { .. }
+                    | Response::Config(_)
+                    | Response::WordMark { .. }
+                    | Response::PreviewReady { .. }
+                    | Response::AudioInline { .. } => {}
+                }
+            }
+            Message::Binary(chunk) => {
+                let Some(payload) = assembler.as_mut() else {
+                    continue;
+                };
+                if let Some(progress) = progress.as_mut() {
+                    progress.advance(chunk.len());
+                }
+                let Some(data) = payload.push(&chunk)? else {
+                    continue;
+                };
+                assembler = None;
+                progress = None;
+                received += 1;
+                info!("Received {} bytes of audio data ({} of {})", data.len(), received, expected);
+                // Catches reassembly bugs as well as damage in transit
+                if let Some(checksum) = checksum.take() {
+                    let actual = sirius_protocol::crc32(&data);
+                    if actual != checksum {
+                        return Err(anyhow::anyhow!(
+                            "Audio failed its integrity check (CRC-32 {:08x}, expected {:08x})",
+                            actual,
+                            checksum
+                        ));
                     }
-                    Response::Pong => {}
                 }
+                let segment_output = output.map(|output| segment_path(output, received, expected));
+                let delivering = Instant::now();
+                on_payload(&data, segment_output.as_deref())?;
+                if let Some(deadline) = deadline.as_mut() {
+                    *deadline += delivering.elapsed();
+                }
+                if received < expected {
+                    continue;
+                }
+
+                let mut summary = summary.take().expect("AudioReady precedes the audio");
+                if summary.duration_secs > 0.0 {
+                    summary.rtf = start.elapsed().as_secs_f32() / summary.duration_secs;
+                }
+                return Ok(summary);
+            }
+            Message::Close(frame) => {
+                return Err(interrupted(assembler.as_ref(), &closed_by_server(frame.as_ref())));
+            }
+            _ => {}
+        }
+    }
+
+    Err(interrupted(assembler.as_ref(), "connection closed"))
+}
+
+/// `--local-split`: synthesize `requests` one after another, asking for the
+/// next before playing the current one so it's synthesized during playback.
+/// A request that fails stops playback there.
+async fn speak_pipelined<S, R>(
+    sender: &mut S,
+    receiver: &mut R,
+    requests: Vec<SynthesizeRequest>,
+    playback: &audio::PlaybackOptions,
+    mut play: impl FnMut(&[u8], &audio::PlaybackOptions) -> Result<()>,
+    timeout: Option<Duration>,
+) -> Result<Vec<SynthesisSummary>>
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let count = requests.len();
+    let mut requests = requests.into_iter();
+    let mut summaries = Vec::new();
+    let Some(first) = requests.next() else {
+        return Ok(summaries);
+    };
+    let mut start = Instant::now();
+    send_synthesize(sender, first).await?;
+
+    for index in 1..=count {
+        let mut payloads = Vec::new();
+        let summary = receive_audio(receiver, None, start, timeout, |data, _| {
+            payloads.push(data.to_vec());
+            Ok(())
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Stopped at sentence {} of {}: {}", index, count, e))?;
+        if let Some(next) = requests.next() {
+            start = Instant::now();
+            send_synthesize(sender, next).await?;
+        }
+        for data in &payloads {
+            play(data, playback)?;
+        }
+        summaries.push(summary);
+    }
+    Ok(summaries)
+}
+
+/// Save received audio to `output`, or play it with `play` when there is no
+/// output; with `tee`, save and then play
+fn deliver_audio(
+    data: &[u8],
+    output: Option<&str>,
+    tee: bool,
+    playback: &audio::PlaybackOptions,
+    play: impl FnOnce(&[u8], &audio::PlaybackOptions) -> Result<()>,
+) -> Result<()> {
+    if let Some(output_path) = output {
+        save_audio(data, output_path, &mut io::stdout().lock())?;
+        if !tee {
+            return Ok(());
+        }
+    }
+    info!("Playing audio...");
+    play(data, playback)
+}
+
+/// Where segment `index` (1-based) of `count` is saved: `output` itself for a
+/// single payload or stdout, else `output` numbered, e.g. `out-2.wav`
+fn segment_path(output: &str, index: usize, count: usize) -> String {
+    if count == 1 || output == STDOUT_OUTPUT {
+        return output.to_string();
+    }
+    let path = Path::new(output);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("segment");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}-{}.{}", stem, index, extension),
+        None => format!("{}-{}", stem, index),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Duration of `AudioReady` audio from its exact sample count, falling back to
+/// the server's `duration_secs` for servers that don't send one
+fn audio_duration_secs(num_samples: u64, sample_rate: u32, duration_secs: f32) -> f32 {
+    if num_samples > 0 && sample_rate > 0 {
+        (num_samples as f64 / sample_rate as f64) as f32
+    } else {
+        duration_secs
+    }
+}
+
+/// Write received audio to `output_path` in the format its extension asks
+/// for, or unconverted to `stdout` for "-"
+fn save_audio(data: &[u8], output_path: &str, stdout: &mut impl Write) -> Result<()> {
+    if output_path == STDOUT_OUTPUT {
+        stdout.write_all(data)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    let format = convert::OutputFormat::from_path(Path::new(output_path))?;
+    batch::write_atomically(Path::new(output_path), &convert::convert_wav(data, format)?)?;
+    info!("Audio saved to: {}", output_path);
+    Ok(())
+}
+
+/// How the next stage of a pipeline should read raw audio, in sox/ffmpeg
+/// terms: "s16le 24000 1ch". WAV describes itself, so it has none.
+fn raw_format_description(format: AudioFormat, sample_rate: u32, channels: u16) -> Option<String> {
+    let sample = match format {
+        AudioFormat::Wav => return None,
+        AudioFormat::PcmS16LE => "s16le",
+        AudioFormat::PcmF32LE => "f32le",
+        AudioFormat::Mulaw => "mulaw",
+        AudioFormat::Alaw => "alaw",
+    };
+    Some(format!("{} {} {}ch", sample, sample_rate, channels))
+}
+
+/// The error for a connection that ended early: "No audio received" before
+/// `AudioReady`, or how far the transfer got after it
+fn interrupted(assembler: Option<&PayloadAssembler>, reason: &str) -> anyhow::Error {
+    match assembler {
+        Some(assembler) => anyhow::anyhow!(
+            "Received {} of {} bytes before {}",
+            assembler.received(),
+            assembler.expected(),
+            reason
+        ),
+        None => anyhow::anyhow!("No audio received ({})", reason),
+    }
+}
+
+/// "connection closed by server", with the reason from its close frame:
+/// one of the `CloseReason`s, or whatever another server sent
+fn closed_by_server(frame: Option<&CloseFrame>) -> String {
+    let Some(frame) = frame else {
+        return "connection closed by server".to_string();
+    };
+    let code = u16::from(frame.code);
+    match CloseReason::from_code(code) {
+        Some(reason) => format!("connection closed by server: {}", reason),
+        None if frame.reason.is_empty() => format!("connection closed by server (code {})", code),
+        None => format!("connection closed by server: {} (code {})", frame.reason, code),
+    }
+}
+
+/// Watch stdin for `:skip` and raise `skip` so the current page stops playing
+fn spawn_skip_listener(skip: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            match line.trim() {
+                ":skip" => {
+                    println!("Skipping to the next page...");
+                    skip.store(true, Ordering::Relaxed);
+                }
+                "" => {}
+                other => println!("Unknown command '{}' (only :skip is available)", other),
             }
-            Message::Binary(data) => {
-                if audio_metadata.is_some() {
-                    info!("Received {} bytes of audio data", data.len());
+        }
+    });
+}
 
-                    if let Some(output_path) = output {
-                        // Save to file
-                        std::fs::write(output_path, &data)?;
-                        println!("Audio saved to: {}", output_path);
-                    } else {
-                        // Play audio
-                        println!("Playing audio...");
-                        audio::play_wav_bytes(&data)?;
+/// Voices to preview for `voice`: each component of a blend followed by the
+/// blend itself, or just the voice when it isn't a blend.
+fn preview_voices(voice: &str) -> Vec<String> {
+    if !voice.contains('+') {
+        return vec![voice.to_string()];
+    }
+
+    let mut voices: Vec<String> = voice
+        .split('+')
+        .map(|part| part.split_once('.').map_or(part, |(name, _)| name))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    voices.push(voice.to_string());
+    voices
+}
+
+async fn preview_and_play<S, R>(
+    sender: &mut S,
+    receiver: &mut R,
+    text: &str,
+    voices: &[String],
+    lang: &str,
+    speed: f32,
+    playback: &audio::PlaybackOptions,
+) -> Result<()>
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let request = Request::VoicePreview {
+        text: text.to_string(),
+        voices: voices.to_vec(),
+        lang: lang.to_string(),
+        speed,
+    };
+
+    let request_json = serde_json::to_string(&request)?;
+    sender.send(Message::Text(request_json)).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let mut played = 0;
+    let mut current: Option<(String, PayloadAssembler)> = None;
+
+    while let Some(msg) = receiver.next().await {
+        let msg = msg?;
+
+        match msg {
+            Message::Text(text) => {
+                let response: Response = serde_json::from_str(&text)?;
+                match response {
+                    Response::PreviewReady { index, voice, duration_secs, size_bytes, .. } => {
+                        info!("Receiving preview {}: {} ({:.2}s)", index, voice, duration_secs);
+                        current = Some((voice, PayloadAssembler::new(size_bytes)));
+                    }
+                    Response::Warning { code, message } => {
+                        warn!(target: SERVER_WARNING_TARGET, "Server warning ({}): {}", code, message);
+                    }
+                    Response::Error { code, message } => {
+                        return Err(anyhow::anyhow!("Server error ({}): {}", code, message));
                     }
+                    Response::AudioReady { .. }
+                    | Response::AudioInline { .. }
+                    | Response::DefaultsSet { .. }
+                    | Response::Config(_)
+                    | Response::WordMark { .. }
+                    | Response::SegmentCount { .. }
+                    | Response::Echo { .. }
+                    | Response::Pong => {}
+                }
+            }
+            Message::Binary(chunk) => {
+                let Some((_, assembler)) = current.as_mut() else {
+                    continue;
+                };
+                if let Some(data) = assembler.push(&chunk)? {
+                    let (voice, _) = current.take().unwrap();
+                    println!("[{}/{}] {}", played + 1, voices.len(), voice);
+                    audio::play_wav_bytes(&data, playback)?;
+                    played += 1;
 
-                    return Ok(());
+                    if played == voices.len() {
+                        return Ok(());
+                    }
                 }
             }
-            Message::Close(_) => {
-                return Err(anyhow::anyhow!("Connection closed by server"));
+            Message::Close(frame) => {
+                return Err(anyhow::anyhow!("Preview failed: {}", closed_by_server(frame.as_ref())));
             }
             _ => {}
         }
     }
 
-    Err(anyhow::anyhow!("No audio received"))
+    Err(anyhow::anyhow!("Preview ended after {} of {} voices", played, voices.len()))
+}
+
+/// Send `payload` in an `Echo` request and wait for it to come back,
+/// returning what came back and the round-trip time
+async fn echo<S, R>(sender: &mut S, receiver: &mut R, payload: &str) -> Result<(String, Duration)>
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let request_json = serde_json::to_string(&Request::Echo { payload: payload.to_string() })?;
+    let start = Instant::now();
+    sender.send(Message::Text(request_json)).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    while let Some(msg) = receiver.next().await {
+        match msg? {
+            Message::Text(text) => match serde_json::from_str(&text)? {
+                Response::Echo { payload } => return Ok((payload, start.elapsed())),
+                Response::Error { code, message } => {
+                    return Err(anyhow::anyhow!("Server error ({}): {}", code, message));
+                }
+                _ => {}
+            },
+            Message::Close(frame) => {
+                return Err(anyhow::anyhow!("Echo failed: {}", closed_by_server(frame.as_ref())));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow::anyhow!("Connection closed before the echo came back"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sirius_protocol::{BITS_PER_SAMPLE, CHANNELS, SAMPLE_RATE};
+
+    /// A server reply to one synthesize request: metadata, then the WAV
+    fn audio_reply(wav: &[u8]) -> Vec<Result<Message, tokio_tungstenite::tungstenite::Error>> {
+        let metadata = Response::AudioReady {
+            duration_secs: 0.0,
+            num_samples: 0,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+            size_bytes: wav.len(),
+            format: AudioFormat::Wav,
+            bits_per_sample: BITS_PER_SAMPLE,
+            used_fallback: 0,
+            crc32: Some(sirius_protocol::crc32(wav)),
+        };
+        vec![
+            Ok(Message::Text(serde_json::to_string(&metadata).unwrap())),
+            Ok(Message::Binary(wav.to_vec())),
+        ]
+    }
+
+    #[test]
+    fn test_request_file_precedence() {
+        let path = std::env::temp_dir().join(format!("sirius-request-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r#"{"text": "Hello", "voice": "bf_emma", "speed": 1.2, "tempo": 1.1, "output": "hello.wav"}"#,
+        )
+        .unwrap();
+        let parse = |flags: &[&str]| {
+            let argv = ["sirius-client", "--request-file", path].into_iter().chain(flags.iter().copied());
+            let matches = Args::command().try_get_matches_from(argv).unwrap();
+            let args = Args::from_arg_matches(&matches).unwrap();
+            request_from_file(path, &args, &matches)
+        };
+
+        // The file's settings beat the flags' defaults
+        let (request, output) = parse(&[]).unwrap();
+        assert_eq!((request.text.as_str(), request.voice.as_str(), request.speed), ("Hello", "bf_emma", 1.2));
+        assert_eq!(request.tempo, Some(1.1));
+        assert_eq!(output.as_deref(), Some("hello.wav"));
+
+        // Flags given on the command line beat the file
+        let (request, output) = parse(&["--voice", "am_adam", "--output", "other.wav"]).unwrap();
+        assert_eq!((request.voice.as_str(), request.speed), ("am_adam", 1.2));
+        assert_eq!(output.as_deref(), Some("other.wav"));
+
+        std::fs::write(path, r#"{"text": "Hello", "tempo": 5.0}"#).unwrap();
+        assert!(parse(&[]).unwrap_err().to_string().contains("tempo must be between"));
+        std::fs::write(path, r#"{"voice": "bf_emma"}"#).unwrap();
+        assert!(parse(&[]).unwrap_err().to_string().contains("--text"));
+
+        // Audio the client would wait for forever, or couldn't play
+        for (fields, reason) in [
+            (r#""inline_audio": true"#, "inline_audio"),
+            (r#""metadata_only": true"#, "metadata_only"),
+            (r#""format": "mulaw""#, "format"),
+        ] {
+            std::fs::write(path, format!(r#"{{"text": "Hello", {}}}"#, fields)).unwrap();
+            let err = parse(&[]).unwrap_err().to_string();
+            assert!(err.contains(reason), "{}", err);
+        }
+        std::fs::write(path, r#"{"text": "Hello", "format": "wav", "inline_audio": false}"#).unwrap();
+        assert!(parse(&[]).is_ok());
+        assert_eq!(parse(&["--text", "Hi"]).unwrap().0.text, "Hi");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_audio_is_reassembled() {
+        let wav: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let mut reply = audio_reply(&wav);
+        reply.pop();
+        for chunk in wav.chunks(1000) {
+            reply.push(Ok(Message::Binary(chunk.to_vec())));
+        }
+        let path = std::env::temp_dir().join(format!("sirius-chunks-{}.wav", std::process::id()));
+
+        synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(reply),
+            SynthesizeRequest::new("Hello"),
+            path.to_str(),
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered_audio_fails_verification() {
+        let wav: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let mut reply = audio_reply(&wav);
+        reply.pop();
+        let mut tampered = wav.clone();
+        tampered[1234] ^= 0x01;
+        for chunk in tampered.chunks(1000) {
+            reply.push(Ok(Message::Binary(chunk.to_vec())));
+        }
+        let path = std::env::temp_dir().join(format!("sirius-tampered-{}.wav", std::process::id()));
+
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(reply),
+            SynthesizeRequest::new("Hello"),
+            path.to_str(),
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("integrity check"), "{}", err);
+        // Nothing is saved
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_local_split_pipelines_requests() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sent = Arc::clone(&events);
+        let mut sender = futures_util::sink::drain().with(move |msg: Message| {
+            if let Message::Text(json) = &msg {
+                if let Ok(Request::Synthesize(request)) = serde_json::from_str(json) {
+                    sent.lock().unwrap().push(format!("send {}", request.text));
+                }
+            }
+            futures_util::future::ready(Ok::<_, std::convert::Infallible>(msg))
+        });
+        let requests = |texts: &[&str]| -> Vec<SynthesizeRequest> {
+            texts.iter().map(|text| SynthesizeRequest::new(*text)).collect()
+        };
+        let play = |data: &[u8], _: &audio::PlaybackOptions| {
+            events.lock().unwrap().push(format!("play {}", String::from_utf8_lossy(data)));
+            Ok(())
+        };
+
+        let mut reply = audio_reply(b"one");
+        reply.extend(audio_reply(b"two"));
+        reply.extend(audio_reply(b"three"));
+        let summaries = speak_pipelined(
+            &mut sender,
+            &mut futures_util::stream::iter(reply),
+            requests(&["One.", "Two.", "Three."]),
+            &audio::PlaybackOptions::default(),
+            play,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summaries.len(), 3);
+        // Each sentence is requested before the one ahead of it plays
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["send One.", "send Two.", "play one", "send Three.", "play two", "play three"]
+        );
+
+        // A failure stops playback with the failing sentence named
+        events.lock().unwrap().clear();
+        let mut reply = audio_reply(b"one");
+        reply.push(Ok(Message::Text(
+            serde_json::to_string(&Response::Error {
+                code: sirius_protocol::ErrorCode::Internal,
+                message: "model crashed".to_string(),
+            })
+            .unwrap(),
+        )));
+        reply.extend(audio_reply(b"three"));
+        let err = speak_pipelined(
+            &mut sender,
+            &mut futures_util::stream::iter(reply),
+            requests(&["One.", "Two.", "Three."]),
+            &audio::PlaybackOptions::default(),
+            play,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("sentence 2 of 3"), "{}", err);
+        assert!(err.to_string().contains("model crashed"), "{}", err);
+        assert_eq!(*events.lock().unwrap(), vec!["send One.", "send Two.", "play one"]);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_when_the_server_never_replies() {
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::pending(),
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &audio::PlaybackOptions::default(),
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "No audio received (the 50ms timeout)");
+
+        // Part of the audio, then silence
+        let metadata = Response::AudioReady {
+            duration_secs: 1.0,
+            num_samples: 24000,
+            sample_rate: 24000,
+            channels: 1,
+            size_bytes: 10,
+            format: AudioFormat::Wav,
+            bits_per_sample: sirius_protocol::BITS_PER_SAMPLE,
+            used_fallback: 0,
+            crc32: None,
+        };
+        let reply = futures_util::stream::iter(vec![
+            Ok(Message::Text(serde_json::to_string(&metadata).unwrap())),
+            Ok(Message::Binary(vec![0; 4])),
+        ])
+        .chain(futures_util::stream::pending());
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut Box::pin(reply),
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &audio::PlaybackOptions::default(),
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Received 4 of 10 bytes before the 50ms timeout");
+    }
+
+    #[tokio::test]
+    async fn test_echo_waits_for_its_reply() {
+        let reply = vec![
+            Ok(Message::Text(serde_json::to_string(&Response::Pong).unwrap())),
+            Ok(Message::Text(
+                serde_json::to_string(&Response::Echo { payload: "héllo 🎙️".to_string() }).unwrap(),
+            )),
+        ];
+        let (payload, _) = echo(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(reply),
+            "héllo 🎙️",
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload, "héllo 🎙️");
+    }
+
+    #[tokio::test]
+    async fn test_segmented_reply_delivers_every_payload() {
+        let (first, second) = (b"RIFF first".to_vec(), b"RIFF second segment".to_vec());
+        let mut reply = vec![Ok(Message::Text(
+            serde_json::to_string(&Response::SegmentCount { count: 2 }).unwrap(),
+        ))];
+        reply.extend(audio_reply(&first));
+        reply.extend(audio_reply(&second));
+        // Never reached: the reply is complete after the second payload
+        reply.extend(audio_reply(b"RIFF extra"));
+        let path = std::env::temp_dir().join(format!("sirius-segments-{}.wav", std::process::id()));
+        let output = path.to_str().unwrap();
+
+        let mut receiver = futures_util::stream::iter(reply);
+        let summary = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut receiver,
+            SynthesizeRequest::new("Hello"),
+            Some(output),
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.size_bytes, first.len() + second.len());
+        for (index, expected) in [(1, first), (2, second)] {
+            let segment = segment_path(output, index, 2);
+            assert_eq!(std::fs::read(&segment).unwrap(), expected);
+            std::fs::remove_file(&segment).unwrap();
+        }
+        assert!(!path.exists());
+        assert_eq!(receiver.count().await, 2);
+
+        assert_eq!(segment_path("out.wav", 1, 1), "out.wav");
+        assert_eq!(segment_path("dir/out.mp3", 2, 3), "dir/out-2.mp3");
+        assert_eq!(segment_path("-", 2, 3), "-");
+    }
+
+    #[tokio::test]
+    async fn test_save_mode_works_without_audio() {
+        let no_audio = audio::PlaybackOptions {
+            no_audio: true,
+            ..Default::default()
+        };
+        let wav = b"RIFF fake wav".to_vec();
+        let path = std::env::temp_dir().join(format!("sirius-save-{}.wav", std::process::id()));
+
+        let mut sender = futures_util::sink::drain();
+        let mut receiver = futures_util::stream::iter(audio_reply(&wav));
+        synthesize_and_play(
+            &mut sender,
+            &mut receiver,
+            SynthesizeRequest::new("Hello"),
+            path.to_str(),
+            false,
+            &no_audio,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        std::fs::remove_file(&path).unwrap();
+
+        // Playing instead explains how to save
+        let mut receiver = futures_util::stream::iter(audio_reply(&wav));
+        let err = synthesize_and_play(
+            &mut sender,
+            &mut receiver,
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &no_audio,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("--output"));
+    }
+
+    #[test]
+    fn test_tee_saves_and_plays() {
+        let wav = b"RIFF fake wav".to_vec();
+        let path = std::env::temp_dir().join(format!("sirius-tee-{}.wav", std::process::id()));
+        let playback = audio::PlaybackOptions::default();
+
+        let mut played = Vec::new();
+        deliver_audio(&wav, path.to_str(), true, &playback, |data, _| {
+            played.push(data.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        assert_eq!(played, vec![wav.clone()]);
+        std::fs::remove_file(&path).unwrap();
+
+        // Without tee, saving replaces playback
+        deliver_audio(&wav, path.to_str(), false, &playback, |_, _| panic!("played")).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_duration_prefers_num_samples() {
+        assert_eq!(audio_duration_secs(36_000, SAMPLE_RATE, 1.6), 1.5);
+        // Older servers leave num_samples at 0
+        assert_eq!(audio_duration_secs(0, SAMPLE_RATE, 1.6), 1.6);
+    }
+
+    #[test]
+    fn test_pipe_raw_writes_pcm_to_stdout() {
+        let num_samples = 240;
+        let pcm: Vec<u8> = (0..num_samples as i16).flat_map(|s| (s * 100).to_le_bytes()).collect();
+
+        let mut stdout = Vec::new();
+        save_audio(&pcm, STDOUT_OUTPUT, &mut stdout).unwrap();
+        assert_eq!(stdout.len(), num_samples * 2);
+        assert_eq!(stdout, pcm);
+
+        assert_eq!(
+            raw_format_description(AudioFormat::PcmS16LE, SAMPLE_RATE, CHANNELS).as_deref(),
+            Some("s16le 24000 1ch")
+        );
+        assert_eq!(raw_format_description(AudioFormat::Wav, SAMPLE_RATE, CHANNELS), None);
+    }
+
+    #[tokio::test]
+    async fn test_json_summary() {
+        let wav = b"RIFF fake wav".to_vec();
+        let mut reply = audio_reply(&wav);
+        reply[0] = Ok(Message::Text(
+            serde_json::to_string(&Response::AudioReady {
+                duration_secs: 1.5,
+                num_samples: 36_000,
+                sample_rate: SAMPLE_RATE,
+                channels: CHANNELS,
+                size_bytes: wav.len(),
+                format: AudioFormat::Wav,
+                bits_per_sample: BITS_PER_SAMPLE,
+                used_fallback: 0,
+                crc32: None,
+            })
+            .unwrap(),
+        ));
+        let path = std::env::temp_dir().join(format!("sirius-json-{}.wav", std::process::id()));
+
+        let summary = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(reply),
+            SynthesizeRequest::new("Hello"),
+            path.to_str(),
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&vec![summary]).unwrap()).unwrap();
+        let entry = &json.as_array().unwrap()[0];
+        assert_eq!(entry["duration_secs"], 1.5);
+        assert_eq!(entry["sample_rate"], SAMPLE_RATE);
+        assert_eq!(entry["size_bytes"], wav.len());
+        assert_eq!(entry["output"], path.to_str().unwrap());
+        let rtf = entry["rtf"].as_f64().unwrap();
+        assert!((0.0..1.0).contains(&rtf), "rtf {}", rtf);
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_mid_transfer() {
+        let wav: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let mut reply = audio_reply(&wav);
+
+        // Closed right after the metadata
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(audio_reply(&wav).into_iter().take(1)),
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Received 0 of 2500 bytes before connection closed");
+
+        // Closed after the first of several chunks
+        reply.truncate(1);
+        reply.push(Ok(Message::Binary(wav[..1000].to_vec())));
+        reply.push(Ok(Message::Close(None)));
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(reply),
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Received 1000 of 2500 bytes before connection closed by server"
+        );
+
+        // Nothing at all
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(Vec::new()),
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().starts_with("No audio received"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_close_reason_is_surfaced() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+        let frame = |code: u16, reason: &str| CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.to_string().into(),
+        };
+        let err = synthesize_and_play(
+            &mut futures_util::sink::drain(),
+            &mut futures_util::stream::iter(vec![Ok(Message::Close(Some(frame(
+                CloseReason::Shutdown.code(),
+                CloseReason::Shutdown.reason(),
+            ))))]),
+            SynthesizeRequest::new("Hello"),
+            None,
+            false,
+            &audio::PlaybackOptions::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No audio received (connection closed by server: server shutting down)"
+        );
+
+        assert_eq!(
+            closed_by_server(Some(&frame(1001, "idle timeout"))),
+            "connection closed by server: idle timeout"
+        );
+        assert_eq!(
+            closed_by_server(Some(&frame(1008, ""))),
+            "connection closed by server: authentication failed"
+        );
+        assert_eq!(
+            closed_by_server(Some(&frame(1002, "protocol error"))),
+            "connection closed by server: protocol error"
+        );
+        assert_eq!(
+            closed_by_server(Some(&frame(4000, "custom"))),
+            "connection closed by server: custom (code 4000)"
+        );
+        assert_eq!(closed_by_server(Some(&frame(1011, ""))), "connection closed by server (code 1011)");
+    }
+
+    #[test]
+    fn test_preview_voices() {
+        assert_eq!(preview_voices("bm_lewis"), vec!["bm_lewis"]);
+        assert_eq!(
+            preview_voices("am_onyx.4+bm_lewis.6"),
+            vec!["am_onyx", "bm_lewis", "am_onyx.4+bm_lewis.6"]
+        );
+    }
 }
@@ -0,0 +1,427 @@
+//! PDF text extraction for read-aloud
+//!
+//! Pages are extracted independently so one broken page doesn't lose the
+//! rest of the document. Scanned pages carry no text layer; with the `ocr`
+//! feature their images are run through tesseract instead.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use lopdf::{Document, ObjectId};
+use tracing::{info, warn};
+
+/// Text extracted from a PDF, keyed by 1-based page number
+#[derive(Debug, Default)]
+pub struct PdfText {
+    /// Paragraphs per page, with soft line breaks and end-of-line hyphens undone
+    pub pages: BTreeMap<u32, Vec<String>>,
+    /// Pages whose text could not be extracted, with the reason
+    pub errors: Vec<(u32, String)>,
+    /// Pages whose text was recovered by OCR rather than the text layer
+    pub ocr_pages: Vec<u32>,
+}
+
+/// Pages selected with `--pages`, e.g. `1-10,15,20-`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageSelection(Vec<PageRange>);
+
+/// One comma-separated part of a [`PageSelection`]; `end: None` means "to the last page"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRange {
+    pub start: u32,
+    pub end: Option<u32>,
+}
+
+impl PageSelection {
+    /// Parse a page list such as `1-10,15,20-` (page numbers are 1-based)
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let parse_page = |page: &str| match page.trim().parse::<u32>() {
+            Ok(0) => Err("page numbers start at 1".to_string()),
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("invalid page number '{}'", page.trim())),
+        };
+
+        let mut ranges = Vec::new();
+        for part in spec.split(',').map(str::trim) {
+            if part.is_empty() {
+                return Err(format!("empty entry in page list '{}'", spec));
+            }
+            let range = match part.split_once('-') {
+                Some((start, "")) => PageRange { start: parse_page(start)?, end: None },
+                Some((start, end)) => {
+                    let (start, end) = (parse_page(start)?, parse_page(end)?);
+                    if start > end {
+                        return Err(format!("page range {}-{} is backwards", start, end));
+                    }
+                    PageRange { start, end: Some(end) }
+                }
+                None => {
+                    let page = parse_page(part)?;
+                    PageRange { start: page, end: Some(page) }
+                }
+            };
+            ranges.push(range);
+        }
+        Ok(Self(ranges))
+    }
+
+    /// The selected page numbers in order, without duplicates.
+    ///
+    /// Open ranges stop at `page_count`; pages past the end are kept so that
+    /// [`extract_text`] reports them.
+    pub fn pages(&self, page_count: u32) -> Vec<u32> {
+        let mut pages = Vec::new();
+        for range in &self.0 {
+            let end = range.end.unwrap_or(page_count.max(range.start));
+            for page in range.start..=end {
+                if !pages.contains(&page) {
+                    pages.push(page);
+                }
+            }
+        }
+        pages
+    }
+}
+
+/// Load a PDF document from disk
+pub fn load(path: impl AsRef<Path>) -> Result<Document> {
+    let path = path.as_ref();
+    Document::load(path).with_context(|| format!("failed to load PDF {}", path.display()))
+}
+
+/// Extract text from the given pages of `doc`.
+///
+/// Every requested page is checked against the document first; if any don't
+/// exist, nothing is extracted and the error lists all of them.
+pub fn extract_text(doc: &Document, page_nums: &[u32]) -> Result<PdfText> {
+    let pages = doc.get_pages();
+    let invalid: Vec<String> = page_nums
+        .iter()
+        .filter(|n| !pages.contains_key(n))
+        .map(|n| n.to_string())
+        .collect();
+    if !invalid.is_empty() {
+        bail!(
+            "invalid page(s) {}: document has {} page{}",
+            invalid.join(", "),
+            pages.len(),
+            if pages.len() == 1 { "" } else { "s" }
+        );
+    }
+
+    let mut text = PdfText::default();
+    for &page_num in page_nums {
+        let page_text = match doc.extract_text(&[page_num]) {
+            Ok(page_text) => page_text,
+            Err(e) => {
+                text.errors.push((page_num, e.to_string()));
+                continue;
+            }
+        };
+
+        let mut paragraphs = unwrap_lines(&page_text);
+        let page_id = pages[&page_num];
+        if paragraphs.is_empty() && has_images(doc, page_id) {
+            match ocr_page(doc, page_id) {
+                Ok(ocr_text) => {
+                    paragraphs = unwrap_lines(&ocr_text);
+                    text.ocr_pages.push(page_num);
+                }
+                Err(e) => text.errors.push((page_num, e.to_string())),
+            }
+        }
+        text.pages.insert(page_num, paragraphs);
+    }
+    Ok(text)
+}
+
+/// Read the selected pages of the PDF at `path` (all pages if `selection` is
+/// `None`) as one text per page, skipping pages with nothing to read.
+///
+/// Pages that fail to extract are logged and skipped rather than aborting.
+pub fn read_pages(path: &str, selection: Option<&PageSelection>) -> Result<Vec<(u32, String)>> {
+    let doc = load(path)?;
+    let page_nums: Vec<u32> = match selection {
+        Some(selection) => selection.pages(doc.get_pages().len() as u32),
+        None => doc.get_pages().into_keys().collect(),
+    };
+
+    let text = extract_text(&doc, &page_nums)?;
+    for (page, error) in &text.errors {
+        warn!("Page {}: {}", page, error);
+    }
+    if !text.ocr_pages.is_empty() {
+        info!("Used OCR for page(s) {:?}", text.ocr_pages);
+    }
+
+    // Keep the requested order rather than the map's page order
+    let mut pages = text.pages;
+    Ok(page_nums
+        .into_iter()
+        .filter_map(|page| pages.remove(&page).map(|paragraphs| (page, paragraphs.join(" "))))
+        .filter(|(_, text)| !text.is_empty())
+        .collect())
+}
+
+/// Rejoin hard-wrapped lines into paragraphs.
+///
+/// Blank lines end a paragraph and list items start a new one; any other line
+/// break is a soft wrap and becomes a space. A word hyphenated across a wrap
+/// ("inter-" / "national") is rejoined without the hyphen; compounds like
+/// "Franco-" / "Prussian" keep it.
+fn unwrap_lines(text: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || is_list_item(line) {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            current.push_str(line);
+            continue;
+        }
+        if current.is_empty() {
+            current.push_str(line);
+        } else if ends_with_split_word(&current) {
+            // Lowercase continuation: a hyphenated word. Otherwise a compound.
+            if line.starts_with(char::is_lowercase) {
+                current.pop();
+            }
+            current.push_str(line);
+        } else {
+            current.push(' ');
+            current.push_str(line);
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
+}
+
+/// A trailing hyphen directly after a letter, e.g. "inter-" but not "A -"
+fn ends_with_split_word(text: &str) -> bool {
+    let mut chars = text.chars().rev();
+    chars.next() == Some('-') && chars.next().is_some_and(char::is_alphabetic)
+}
+
+/// Bullets ("-", "*", "•") and enumerations ("1.", "2)", "a)")
+fn is_list_item(line: &str) -> bool {
+    let Some((marker, rest)) = line.split_once(' ') else {
+        return false;
+    };
+    if rest.trim().is_empty() {
+        return false;
+    }
+    if matches!(marker, "-" | "*" | "•" | "–") {
+        return true;
+    }
+    let Some(label) = marker.strip_suffix('.').or_else(|| marker.strip_suffix(')')) else {
+        return false;
+    };
+    (!label.is_empty() && label.len() <= 3 && label.chars().all(|c| c.is_ascii_digit()))
+        || (label.len() == 1 && label.chars().all(|c| c.is_ascii_lowercase()))
+}
+
+/// Whether the page draws any image XObjects
+fn has_images(doc: &Document, page_id: ObjectId) -> bool {
+    doc.get_page_images(page_id)
+        .map(|images| !images.is_empty())
+        .unwrap_or(false)
+}
+
+#[cfg(not(feature = "ocr"))]
+fn ocr_page(_doc: &Document, _page_id: ObjectId) -> Result<String> {
+    bail!("page has no text layer, only images; rebuild with `--features ocr` to read it")
+}
+
+/// OCR every image on the page and join the results.
+///
+/// JPEG/JPEG 2000 images are handed to leptonica as-is; other images are
+/// decompressed and passed as raw 8-bit gray or RGB frames.
+#[cfg(feature = "ocr")]
+fn ocr_page(doc: &Document, page_id: ObjectId) -> Result<String> {
+    use tesseract::Tesseract;
+
+    let mut text = String::new();
+    for image in doc.get_page_images(page_id)? {
+        let filters = image.filters.clone().unwrap_or_default();
+        let tess = Tesseract::new(None, Some("eng"))?;
+        let tess = if filters.iter().any(|f| f == "DCTDecode" || f == "JPXDecode") {
+            tess.set_image_from_mem(image.content)?
+        } else {
+            let bytes_per_pixel = match image.color_space.as_deref() {
+                Some("DeviceGray") => 1,
+                Some("DeviceRGB") => 3,
+                other => bail!("unsupported image color space {:?}", other),
+            };
+            if image.bits_per_component != Some(8) {
+                bail!(
+                    "unsupported image depth {:?} bits per component",
+                    image.bits_per_component
+                );
+            }
+            let data = doc.get_object(image.id)?.as_stream()?.decompressed_content()?;
+            let width = image.width as i32;
+            tess.set_frame(
+                &data,
+                width,
+                image.height as i32,
+                bytes_per_pixel,
+                width * bytes_per_pixel,
+            )?
+        };
+        text.push_str(&tess.recognize()?.get_text()?);
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use lopdf::content::{Content, Operation};
+    use lopdf::{dictionary, Object, Stream};
+
+    /// Build an in-memory PDF with one page per entry, each showing that text
+    pub(crate) fn text_pdf(page_texts: &[&str]) -> Document {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+
+        let kids: Vec<Object> = page_texts
+            .iter()
+            .map(|text| {
+                let content = Content {
+                    operations: vec![
+                        Operation::new("BT", vec![]),
+                        Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                        Operation::new("Td", vec![100.into(), 600.into()]),
+                        Operation::new("Tj", vec![Object::string_literal(*text)]),
+                        Operation::new("ET", vec![]),
+                    ],
+                };
+                let content_id =
+                    doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                })
+                .into()
+            })
+            .collect();
+
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Count" => kids.len() as i64,
+                "Kids" => kids,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc
+    }
+
+    #[test]
+    fn test_extract_text_per_page() {
+        let doc = text_pdf(&["First page.", "Second page.", "Third page."]);
+        let text = extract_text(&doc, &[1, 3]).unwrap();
+        assert!(text.errors.is_empty());
+        assert_eq!(text.pages.keys().copied().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(text.pages[&3], vec!["Third page.".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_page_is_rejected_up_front() {
+        let doc = text_pdf(&["One.", "Two.", "Three."]);
+        let err = extract_text(&doc, &[1, 999]).unwrap_err().to_string();
+        assert_eq!(err, "invalid page(s) 999: document has 3 pages");
+    }
+
+    #[test]
+    fn test_parse_page_selection() {
+        let selection = PageSelection::parse("1-3, 5,8-").unwrap();
+        assert_eq!(selection.pages(10), vec![1, 2, 3, 5, 8, 9, 10]);
+        // Open range past the end keeps its start so extraction rejects it
+        assert_eq!(PageSelection::parse("20-").unwrap().pages(10), vec![20]);
+        // Overlaps are played once, in first-mentioned order
+        assert_eq!(PageSelection::parse("4,2-4").unwrap().pages(10), vec![4, 2, 3]);
+
+        assert!(PageSelection::parse("").is_err());
+        assert!(PageSelection::parse("1,,2").is_err());
+        assert!(PageSelection::parse("0-2").is_err());
+        assert!(PageSelection::parse("5-2").is_err());
+        assert!(PageSelection::parse("a-b").is_err());
+        assert!(PageSelection::parse("-3").is_err());
+    }
+
+    #[test]
+    fn test_unwrap_rejoins_hyphenated_words() {
+        let text = "The inter-\nnational standard was\nadopted in 1998.\n\nA second para-\ngraph.";
+        assert_eq!(
+            unwrap_lines(text),
+            vec![
+                "The international standard was adopted in 1998.".to_string(),
+                "A second paragraph.".to_string(),
+            ]
+        );
+        // A capitalised continuation is a real compound
+        assert_eq!(unwrap_lines("Franco-\nPrussian war"), vec!["Franco-Prussian war"]);
+    }
+
+    #[test]
+    fn test_unwrap_keeps_list_items_separate() {
+        let text = "Shopping list:\n- eggs\n- flour and\nsugar\n1. first\n2) second\na) third";
+        assert_eq!(
+            unwrap_lines(text),
+            vec![
+                "Shopping list:",
+                "- eggs",
+                "- flour and sugar",
+                "1. first",
+                "2) second",
+                "a) third",
+            ]
+        );
+    }
+
+    fn image_only_pdf() -> Document {
+        load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/image_only.pdf")).unwrap()
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    #[test]
+    fn test_image_only_page_is_reported() {
+        let text = extract_text(&image_only_pdf(), &[1]).unwrap();
+        assert!(text.pages[&1].is_empty());
+        assert!(text.ocr_pages.is_empty());
+        assert_eq!(text.errors.len(), 1);
+        assert!(text.errors[0].1.contains("--features ocr"));
+    }
+
+    #[cfg(feature = "ocr")]
+    #[test]
+    fn test_image_only_page_uses_ocr() {
+        let text = extract_text(&image_only_pdf(), &[1]).unwrap();
+        assert!(text.errors.is_empty(), "{:?}", text.errors);
+        assert_eq!(text.ocr_pages, vec![1]);
+        assert!(text.pages[&1].iter().any(|line| line.contains("HELLO")));
+    }
+}
@@ -0,0 +1,118 @@
+//! Progress bar for audio transfers
+//!
+//! Each payload's `AudioReady` announces its size, so the binary messages
+//! that follow can be shown against it. The bar goes to stderr, and only
+//! when that's a terminal and neither `--json` nor `--quiet` was given.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// Set once at startup; off, as in tests, nothing is drawn
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn drawing on or off for every transfer from now on
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Bytes received against the total announced for one payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    received: usize,
+    total: usize,
+}
+
+impl Transfer {
+    pub fn new(total: usize) -> Self {
+        Self { received: 0, total }
+    }
+
+    /// Count `bytes` more, never past the total
+    pub fn advance(&mut self, bytes: usize) {
+        self.received = (self.received + bytes).min(self.total);
+    }
+
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.total
+    }
+}
+
+/// A `Transfer`, drawn as a bar while it runs if drawing is enabled.
+/// Dropping it clears the bar.
+pub struct TransferBar {
+    transfer: Transfer,
+    bar: Option<ProgressBar>,
+}
+
+impl TransferBar {
+    pub fn new(total: usize) -> Self {
+        let transfer = Transfer::new(total);
+        let bar = ENABLED.load(Ordering::Relaxed).then(|| {
+            let bar = ProgressBar::new(transfer.total() as u64);
+            let style = ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+                .expect("the template is valid");
+            bar.set_style(style);
+            bar
+        });
+        Self { transfer, bar }
+    }
+
+    pub fn advance(&mut self, bytes: usize) {
+        self.transfer.advance(bytes);
+        if let Some(bar) = &self.bar {
+            bar.set_position(self.transfer.received() as u64);
+            if self.transfer.is_complete() {
+                bar.finish_and_clear();
+            }
+        }
+    }
+}
+
+impl Drop for TransferBar {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_tracks_bytes() {
+        let mut transfer = Transfer::new(1000);
+        assert_eq!(transfer.received(), 0);
+        transfer.advance(250);
+        transfer.advance(250);
+        assert_eq!((transfer.received(), transfer.total()), (500, 1000));
+        assert!(!transfer.is_complete());
+
+        // More than announced stops at the total
+        transfer.advance(600);
+        assert_eq!(transfer.received(), 1000);
+        assert!(transfer.is_complete());
+
+        // An empty payload has nothing left to wait for
+        assert!(Transfer::new(0).is_complete());
+    }
+
+    #[test]
+    fn test_bar_without_a_terminal() {
+        // Tests never enable drawing, so only the counts change
+        let mut bar = TransferBar::new(10);
+        bar.advance(4);
+        assert!(bar.bar.is_none());
+        assert_eq!(bar.transfer.received(), 4);
+    }
+}
@@ -0,0 +1,201 @@
+//! Where to pick up `--pdf` and `--epub` playback
+//!
+//! Each page or chapter that finishes is recorded in a small JSON file named
+//! after the hash of the document's contents, so `--resume` continues with
+//! the next one even if the file has been renamed or moved since.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use sirius_protocol::text::segment_sentences;
+
+/// Directory for resume files; defaults to `~/.local/state/sirius`
+pub const STATE_DIR_ENV: &str = "SIRIUS_STATE_DIR";
+
+/// Where to begin reading: `--start-at PART[:SENTENCE]`, both 1-based
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartAt {
+    /// Page or chapter number, as printed while reading
+    pub part: usize,
+    /// Sentence within that part
+    pub sentence: usize,
+}
+
+impl Default for StartAt {
+    fn default() -> Self {
+        Self { part: 1, sentence: 1 }
+    }
+}
+
+impl StartAt {
+    /// Parse `12` or `12:3` (sentence 3 of page or chapter 12)
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let number = |value: &str, what: &str| match value.trim().parse::<usize>() {
+            Ok(0) => Err(format!("{} numbers start at 1", what)),
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("invalid {} number '{}'", what, value.trim())),
+        };
+        match spec.split_once(':') {
+            Some((part, sentence)) => Ok(Self {
+                part: number(part, "page/chapter")?,
+                sentence: number(sentence, "sentence")?,
+            }),
+            None => Ok(Self {
+                part: number(spec, "page/chapter")?,
+                sentence: 1,
+            }),
+        }
+    }
+}
+
+/// Contents of a resume file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ResumeState {
+    /// The document's path when it was last read, for anyone looking at the file
+    document: String,
+    /// The last page or chapter that finished
+    last_played: usize,
+}
+
+/// The resume file of one document
+#[derive(Debug, Clone)]
+pub struct ResumeFile {
+    path: PathBuf,
+    document: String,
+}
+
+impl ResumeFile {
+    /// The resume file in `dir` for the document at `document_path`
+    pub fn for_document(dir: &Path, document_path: &str) -> Result<Self> {
+        let contents = std::fs::read(document_path)
+            .with_context(|| format!("failed to read {}", document_path))?;
+        let hex: String = Sha256::digest(&contents).iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(Self {
+            path: dir.join(format!("{}.json", hex)),
+            document: document_path.to_string(),
+        })
+    }
+
+    /// The last page or chapter that finished, if the document was read before
+    pub fn load(&self) -> Result<Option<usize>> {
+        let json = match std::fs::read_to_string(&self.path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", self.path.display())),
+        };
+        let state: ResumeState = serde_json::from_str(&json)
+            .with_context(|| format!("{} is not a resume file", self.path.display()))?;
+        Ok(Some(state.last_played))
+    }
+
+    /// Record that page or chapter `last_played` finished
+    pub fn save(&self, last_played: usize) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let state = ResumeState {
+            document: self.document.clone(),
+            last_played,
+        };
+        // Written aside and renamed, so an interrupted write can't lose the position
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_string(&state)?)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// `SIRIUS_STATE_DIR`, or `~/.local/state/sirius`
+pub fn state_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var(STATE_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+    match std::env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".local/state/sirius"),
+        Err(_) => std::env::temp_dir().join("sirius-state"),
+    }
+}
+
+/// The parts to read from `start` on. Reading begins at the first part
+/// numbered `start.part` or later, and if that is `start.part` itself, at
+/// sentence `start.sentence` of it (as split for `lang`).
+pub fn parts_from(parts: &[(usize, String)], start: StartAt, lang: &str) -> Vec<(usize, String)> {
+    let mut remaining: Vec<(usize, String)> =
+        parts.iter().filter(|(number, _)| *number >= start.part).cloned().collect();
+    if let Some((number, text)) = remaining.first_mut() {
+        if *number == start.part && start.sentence > 1 {
+            let sentences = segment_sentences(text, lang);
+            *text = sentences.get(start.sentence - 1..).unwrap_or_default().join(" ");
+        }
+    }
+    remaining.retain(|(_, text)| !text.is_empty());
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sirius-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_resume_round_trip() {
+        let dir = temp_dir("resume");
+        std::fs::create_dir_all(&dir).unwrap();
+        let document = dir.join("book.pdf");
+        std::fs::write(&document, b"%PDF pretend").unwrap();
+        let state_dir = dir.join("state");
+
+        let resume = ResumeFile::for_document(&state_dir, document.to_str().unwrap()).unwrap();
+        assert_eq!(resume.load().unwrap(), None);
+        resume.save(3).unwrap();
+        resume.save(4).unwrap();
+        assert_eq!(resume.load().unwrap(), Some(4));
+
+        // Keyed by contents: a moved copy resumes, an edited document doesn't
+        let moved = dir.join("renamed.pdf");
+        std::fs::rename(&document, &moved).unwrap();
+        let resume = ResumeFile::for_document(&state_dir, moved.to_str().unwrap()).unwrap();
+        assert_eq!(resume.load().unwrap(), Some(4));
+        std::fs::write(&moved, b"%PDF second edition").unwrap();
+        let resume = ResumeFile::for_document(&state_dir, moved.to_str().unwrap()).unwrap();
+        assert_eq!(resume.load().unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_start_at() {
+        assert_eq!(StartAt::parse("12"), Ok(StartAt { part: 12, sentence: 1 }));
+        assert_eq!(StartAt::parse("12:3"), Ok(StartAt { part: 12, sentence: 3 }));
+        assert!(StartAt::parse("0").is_err());
+        assert!(StartAt::parse("2:0").is_err());
+        assert!(StartAt::parse("two").is_err());
+    }
+
+    #[test]
+    fn test_parts_from() {
+        let parts = vec![
+            (1, "One.".to_string()),
+            (3, "First. Second. Third.".to_string()),
+            (4, "Four.".to_string()),
+        ];
+        let numbers = |parts: Vec<(usize, String)>| parts.into_iter().map(|(n, _)| n).collect::<Vec<_>>();
+        assert_eq!(numbers(parts_from(&parts, StartAt::default(), "en-us")), vec![1, 3, 4]);
+        // Page 2 had no text, so reading starts at the next one
+        assert_eq!(numbers(parts_from(&parts, StartAt { part: 2, sentence: 1 }, "en-us")), vec![3, 4]);
+
+        let from_sentence = parts_from(&parts, StartAt { part: 3, sentence: 2 }, "en-us");
+        assert_eq!(from_sentence[0], (3, "Second. Third.".to_string()));
+        // Past the last sentence: on to the next part
+        assert_eq!(numbers(parts_from(&parts, StartAt { part: 3, sentence: 9 }, "en-us")), vec![4]);
+        assert!(parts_from(&parts, StartAt { part: 5, sentence: 1 }, "en-us").is_empty());
+    }
+}
@@ -1,37 +1,133 @@
-#[cfg(feature = "cuda")]
-use ort::execution_providers::cuda::CUDAExecutionProvider;
-#[cfg(feature = "coreml")]
+use std::fmt;
+use std::str::FromStr;
+
 use ort::execution_providers::coreml::CoreMLExecutionProvider;
-use ort::execution_providers::cpu::CPUExecutionProvider;
+use ort::execution_providers::cuda::CUDAExecutionProvider;
+use ort::execution_providers::directml::DirectMLExecutionProvider;
+use ort::execution_providers::ExecutionProvider;
 use ort::session::builder::SessionBuilder;
 use ort::session::Session;
 
+/// ONNX Runtime execution provider the model runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Cpu,
+    Cuda,
+    CoreML,
+    DirectML,
+}
+
+impl Default for Provider {
+    /// The accelerator this build was compiled for, else CPU
+    fn default() -> Self {
+        if cfg!(feature = "cuda") {
+            Provider::Cuda
+        } else if cfg!(feature = "coreml") {
+            Provider::CoreML
+        } else if cfg!(feature = "directml") {
+            Provider::DirectML
+        } else {
+            Provider::Cpu
+        }
+    }
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cpu" => Ok(Provider::Cpu),
+            "cuda" => Ok(Provider::Cuda),
+            "coreml" => Ok(Provider::CoreML),
+            "directml" => Ok(Provider::DirectML),
+            _ => Err(format!(
+                "unknown execution provider {:?} (expected cpu, cuda, coreml or directml)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Provider::Cpu => "cpu",
+            Provider::Cuda => "cuda",
+            Provider::CoreML => "coreml",
+            Provider::DirectML => "directml",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How the ONNX Runtime session is set up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// Requested execution provider; CPU is used if it's unavailable
+    pub provider: Provider,
+    /// Threads a single operator may use; `None` keeps ONNX Runtime's default
+    /// (one per physical core)
+    pub intra_threads: Option<usize>,
+    /// Threads running independent operators at once; `None` keeps the default
+    pub inter_threads: Option<usize>,
+}
+
+/// Register `requested` on `builder`. If it can't be used (its Cargo feature
+/// is off, or the drivers are missing) fall back to CPU with a warning.
+/// Returns the provider actually in use.
+pub fn register_provider(builder: &mut SessionBuilder, requested: Provider) -> Provider {
+    choose_provider(requested, |provider| match provider {
+        Provider::Cpu => Ok(()),
+        Provider::Cuda => CUDAExecutionProvider::default().register(builder),
+        Provider::CoreML => CoreMLExecutionProvider::default().register(builder),
+        Provider::DirectML => DirectMLExecutionProvider::default().register(builder),
+    })
+}
+
+/// `requested` if `register` accepts it, else CPU. CPU itself needs no
+/// registration: ONNX Runtime always falls back to it.
+fn choose_provider<E: fmt::Display>(
+    requested: Provider,
+    register: impl FnOnce(Provider) -> Result<(), E>,
+) -> Provider {
+    if requested == Provider::Cpu {
+        return Provider::Cpu;
+    }
+    match register(requested) {
+        Ok(()) => requested,
+        Err(e) => {
+            eprintln!(
+                "warning: {} execution provider unavailable ({}); falling back to cpu",
+                requested, e
+            );
+            Provider::Cpu
+        }
+    }
+}
+
 pub trait OrtBase {
-    fn load_model(&mut self, model_path: String) -> Result<(), String> {
-        #[cfg(feature = "cuda")]
-        let providers = [CUDAExecutionProvider::default().build()];
-
-        #[cfg(feature = "coreml")]
-        let providers = [
-            CoreMLExecutionProvider::default().build(),
-            CPUExecutionProvider::default().build()
-        ];
-
-        #[cfg(all(not(feature = "cuda"), not(feature = "coreml")))]
-        let providers = [CPUExecutionProvider::default().build()];
-
-        match SessionBuilder::new() {
-            Ok(builder) => {
-                let session = builder
-                    .with_execution_providers(providers)
-                    .map_err(|e| format!("Failed to build session: {}", e))?
-                    .commit_from_file(model_path)
-                    .map_err(|e| format!("Failed to commit from file: {}", e))?;
-                self.set_sess(session);
-                Ok(())
-            }
-            Err(e) => Err(format!("Failed to create session builder: {}", e)),
+    /// Load the model with `options` (on CPU if the requested provider is
+    /// unavailable), returning the provider in use
+    fn load_model(&mut self, model_path: String, options: &SessionOptions) -> Result<Provider, String> {
+        let mut builder =
+            SessionBuilder::new().map_err(|e| format!("Failed to create session builder: {}", e))?;
+        let provider = register_provider(&mut builder, options.provider);
+        if let Some(threads) = options.intra_threads {
+            builder = builder
+                .with_intra_threads(threads)
+                .map_err(|e| format!("Failed to set intra-op threads: {}", e))?;
         }
+        if let Some(threads) = options.inter_threads {
+            builder = builder
+                .with_inter_threads(threads)
+                .map_err(|e| format!("Failed to set inter-op threads: {}", e))?;
+        }
+        let session = builder
+            .commit_from_file(model_path)
+            .map_err(|e| format!("Failed to commit from file: {}", e))?;
+        self.set_sess(session);
+        Ok(provider)
     }
 
     fn print_info(&self) {
@@ -61,3 +157,33 @@ pub trait OrtBase {
     fn set_sess(&mut self, sess: Session);
     fn sess(&self) -> Option<&Session>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_provider() {
+        assert_eq!("cpu".parse::<Provider>(), Ok(Provider::Cpu));
+        assert_eq!(" CUDA ".parse::<Provider>(), Ok(Provider::Cuda));
+        assert_eq!("coreml".parse::<Provider>(), Ok(Provider::CoreML));
+        assert_eq!("directml".parse::<Provider>(), Ok(Provider::DirectML));
+
+        let err = "tpu".parse::<Provider>().unwrap_err();
+        assert!(err.contains("\"tpu\""), "{}", err);
+        assert!(err.contains("cpu, cuda, coreml or directml"), "{}", err);
+    }
+
+    #[test]
+    fn test_choose_provider() {
+        // CPU always works, without asking the runtime
+        let cpu = choose_provider(Provider::Cpu, |_| -> Result<(), String> { panic!("CPU needs no registration") });
+        assert_eq!(cpu, Provider::Cpu);
+
+        assert_eq!(choose_provider(Provider::Cuda, |_| Ok::<(), String>(())), Provider::Cuda);
+        assert_eq!(
+            choose_provider(Provider::Cuda, |_| Err("feature `cuda` is not enabled")),
+            Provider::Cpu
+        );
+    }
+}
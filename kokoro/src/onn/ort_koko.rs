@@ -7,10 +7,11 @@ use ort::{
 };
 
 use super::ort_base;
-use ort_base::OrtBase;
+use ort_base::{OrtBase, Provider, SessionOptions};
 
 pub struct OrtKoko {
     sess: Option<Session>,
+    provider: Provider,
 }
 impl ort_base::OrtBase for OrtKoko {
     fn set_sess(&mut self, sess: Session) {
@@ -23,11 +24,22 @@ impl ort_base::OrtBase for OrtKoko {
 }
 impl OrtKoko {
     pub fn new(model_path: String) -> Result<Self, String> {
-        let mut instance = OrtKoko { sess: None };
-        instance.load_model(model_path)?;
+        Self::with_options(model_path, &SessionOptions::default())
+    }
+
+    /// Load the model with `options`, falling back to CPU if the requested
+    /// provider is unavailable
+    pub fn with_options(model_path: String, options: &SessionOptions) -> Result<Self, String> {
+        let mut instance = OrtKoko { sess: None, provider: options.provider };
+        instance.provider = instance.load_model(model_path, options)?;
         Ok(instance)
     }
 
+    /// The execution provider the model actually runs on
+    pub fn provider(&self) -> Provider {
+        self.provider
+    }
+
     pub fn infer(
         &self,
         tokens: Vec<Vec<i64>>,
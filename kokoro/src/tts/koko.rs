@@ -3,6 +3,7 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 
+use crate::onn::ort_base::{Provider, SessionOptions};
 use crate::onn::ort_koko::{self};
 use crate::utils;
 use ndarray::Array3;
@@ -36,6 +37,8 @@ pub struct InitConfig {
     pub model_url: String,
     pub voices_url: String,
     pub sample_rate: u32,
+    /// Execution provider and threading for the ONNX Runtime session
+    pub session: SessionOptions,
 }
 
 impl Default for InitConfig {
@@ -44,6 +47,7 @@ impl Default for InitConfig {
             model_url: "https://github.com/thewh1teagle/kokoro-onnx/releases/download/model-files-v1.0/kokoro-v1.0.onnx".into(),
             voices_url: "https://github.com/thewh1teagle/kokoro-onnx/releases/download/model-files-v1.0/voices-v1.0.bin".into(),
             sample_rate: 24000,
+            session: SessionOptions::default(),
         }
     }
 }
@@ -67,7 +71,7 @@ impl TTSKoko {
         }
 
         let model = Arc::new(
-            ort_koko::OrtKoko::new(model_path.to_string())
+            ort_koko::OrtKoko::with_options(model_path.to_string(), &cfg.session)
                 .expect("Failed to create Kokoro TTS model"),
         );
 
@@ -177,47 +181,101 @@ impl TTSKoko {
 
         for chunk in chunks {
             // Convert chunk to phonemes
-            let phonemes = text_to_phonemes(&chunk, lan, None, true, false)
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?
-                .join("");
+            let phonemes = self.phonemize(&chunk, lan)?;
             println!("phonemes: {}", phonemes);
-            let mut tokens = tokenize(&phonemes);
-
-            for _ in 0..initial_silence.unwrap_or(0) {
-                tokens.insert(0, 30);
-            }
+            let tokens = tokenize(&phonemes);
 
-            // Get style vectors once
-            let styles = self.mix_styles(style_name, tokens.len())?;
+            let chunk_audio = self
+                .infer_tokens(tokens, style_name, speed, initial_silence)
+                .inspect_err(|_| eprintln!("Chunk text was: {:?}", chunk))?;
+            final_audio.extend_from_slice(&chunk_audio);
+        }
 
-            // pad a 0 to start and end of tokens
-            let mut padded_tokens = vec![0];
-            for &token in &tokens {
-                padded_tokens.push(token);
-            }
-            padded_tokens.push(0);
+        Ok(final_audio)
+    }
 
-            let tokens = vec![padded_tokens];
+    /// Run the grapheme-to-phoneme step on its own, so callers can cache it.
+    pub fn phonemize(&self, txt: &str, lan: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(text_to_phonemes(txt, lan, None, true, false)
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?
+            .join(""))
+    }
 
-            match self.model.infer(tokens, styles.clone(), speed) {
-                Ok(chunk_audio) => {
-                    let chunk_audio: Vec<f32> = chunk_audio.iter().cloned().collect();
-                    final_audio.extend_from_slice(&chunk_audio);
-                }
-                Err(e) => {
-                    eprintln!("Error processing chunk: {:?}", e);
-                    eprintln!("Chunk text was: {:?}", chunk);
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Chunk processing failed: {:?}", e),
-                    )));
+    /// Synthesize already-phonemized input, skipping the grapheme-to-phoneme step.
+    ///
+    /// Phonemes are split at word boundaries into chunks that fit the model's token limit.
+    pub fn tts_raw_audio_from_phonemes(
+        &self,
+        phonemes: &str,
+        style_name: &str,
+        speed: f32,
+        initial_silence: Option<usize>,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let mut final_audio = Vec::new();
+        let mut chunk: Vec<i64> = Vec::new();
+        // Leading silence belongs to the start of the utterance, not every chunk
+        let mut initial_silence = initial_silence;
+        let space = tokenize(" ");
+
+        for word in phonemes.split_whitespace() {
+            let word = tokenize(word);
+            let separator = if chunk.is_empty() { 0 } else { space.len() };
+
+            if chunk.len() + separator + word.len() > 500 && !chunk.is_empty() {
+                let tokens = std::mem::replace(&mut chunk, word);
+                let audio = self.infer_tokens(tokens, style_name, speed, initial_silence.take())?;
+                final_audio.extend_from_slice(&audio);
+            } else {
+                if separator > 0 {
+                    chunk.extend_from_slice(&space);
                 }
+                chunk.extend(word);
             }
         }
 
+        if !chunk.is_empty() {
+            let audio = self.infer_tokens(chunk, style_name, speed, initial_silence.take())?;
+            final_audio.extend_from_slice(&audio);
+        }
+
         Ok(final_audio)
     }
 
+    fn infer_tokens(
+        &self,
+        mut tokens: Vec<i64>,
+        style_name: &str,
+        speed: f32,
+        initial_silence: Option<usize>,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        for _ in 0..initial_silence.unwrap_or(0) {
+            tokens.insert(0, 30);
+        }
+
+        // Get style vectors once
+        let styles = self.mix_styles(style_name, tokens.len())?;
+
+        // pad a 0 to start and end of tokens
+        let mut padded_tokens = vec![0];
+        for &token in &tokens {
+            padded_tokens.push(token);
+        }
+        padded_tokens.push(0);
+
+        let tokens = vec![padded_tokens];
+
+        match self.model.infer(tokens, styles.clone(), speed) {
+            Ok(chunk_audio) => Ok(chunk_audio.iter().cloned().collect()),
+            Err(e) => {
+                eprintln!("Error processing chunk: {:?}", e);
+                Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Chunk processing failed: {:?}", e),
+                )))
+            }
+        }
+    }
+
     pub fn tts(
         &self,
         TTSOpts {
@@ -310,6 +368,23 @@ impl TTSKoko {
         }
     }
 
+    /// The execution provider the model actually runs on
+    pub fn provider(&self) -> Provider {
+        self.model.provider()
+    }
+
+    /// Names of all loaded voice styles, sorted
+    pub fn voice_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.styles.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Load the voices of another voices file, replacing loaded voices of the same name
+    pub fn add_voices(&mut self, voices_path: &str) {
+        self.styles.extend(Self::load_voices(voices_path));
+    }
+
     fn load_voices(voices_path: &str) -> HashMap<String, Vec<[[f32; 256]; 1]>> {
         let mut npz = NpzReader::new(File::open(voices_path).unwrap()).unwrap();
         let mut map = HashMap::new();
@@ -4,6 +4,9 @@ use kira::{AudioManager, AudioManagerSettings};
 use kira::backend::cpal::CpalBackend;
 use kira::sound::static_sound::StaticSoundData;
 use kokoro::tts::koko::TTSKoko;
+use rodio::buffer::SamplesBuffer;
+use rodio::{OutputStream, Sink};
+use unicode_segmentation::UnicodeSegmentation;
 
 pub fn save_f32_buffer(save_path: &str, audio: &Vec<f32>, channels: u16, sample_rate: u32) -> Result<(), Box<dyn std::error::Error>> {
     let i16_samples: Vec<i16> = audio
@@ -74,27 +77,123 @@ pub fn play_f32_buffer(
     Ok(())
 }
 
-pub fn generate(tts: &TTSKoko, text: &str, full_audio: &mut Vec<f32>) -> Result<(), Box<dyn std::error::Error>> {
-    let s = std::time::Instant::now();
-    // "af_heart.4+af_bella.6"
-    // "bm_daniel", "bm_fable", "bm_george", "bm_lewis"
+/// A long-lived output: clips appended here play back to back without
+/// blocking the caller.
+pub struct Speaker {
+    _stream: OutputStream,
+    sink: Sink,
+}
+
+impl Speaker {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let (stream, handle) = OutputStream::try_default()?;
+        let sink = Sink::try_new(&handle)?;
+        Ok(Self { _stream: stream, sink })
+    }
+
+    /// Queue samples after whatever is already playing
+    pub fn append_f32(&self, samples: &[f32], channels: u16, sample_rate: u32) {
+        self.sink.append(SamplesBuffer::new(channels, sample_rate, samples.to_vec()));
+    }
+}
+
+/// A WAV file that grows as audio is appended. The header is rewritten on
+/// every append, so the file is playable while recording continues.
+pub struct Recording {
+    writer: WavWriter<std::io::BufWriter<std::fs::File>>,
+}
+
+impl Recording {
+    pub fn create(path: &str, channels: u16, sample_rate: u32) -> Result<Self, hound::Error> {
+        let spec = WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        Ok(Self { writer: WavWriter::create(path, spec)? })
+    }
+
+    pub fn append_f32(&mut self, samples: &[f32]) -> Result<(), hound::Error> {
+        for &s in samples {
+            self.writer.write_sample((s.clamp(-1.0, 1.0) * 32767.0) as i16)?;
+        }
+        self.writer.flush()
+    }
 
+    /// Write the final sizes into the header and close the file
+    pub fn finish(self) -> Result<(), hound::Error> {
+        self.writer.finalize()
+    }
+}
+
+/// Words in `text` for the speed metric. Scripts written without spaces
+/// (Chinese, Japanese) count each grapheme as a word.
+pub fn word_count(text: &str) -> usize {
+    text.unicode_words()
+        .map(|word| {
+            if word.chars().any(is_spaceless_script) {
+                word.graphemes(true).count()
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
+fn is_spaceless_script(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{FF66}'..='\u{FF9F}' // Halfwidth Katakana
+    )
+}
+
+/// Split `text` into sentences at UAX #29 boundaries, trimmed, as the server
+/// does. UAX #29 keeps an opening bracket with the sentence before it
+/// ("？「"), so every CJK full stop (and any closing marks after it) also
+/// ends one, and a piece with nothing to say (the bracket) joins the next.
+pub fn split_sentences(text: &str) -> Vec<String> {
     let mut sentences = Vec::new();
     let mut current = String::new();
-
-    for ch in text.chars() {
-        current.push(ch);
-        if ch == '.' || ch == '!' || ch == '?' {
+    let mut push = |current: &mut String| {
+        if current.chars().any(char::is_alphanumeric) {
             sentences.push(current.trim().to_string());
             current.clear();
         }
-    }
+    };
 
-    if !current.trim().is_empty() {
-        sentences.push(current.trim().to_string());
+    for segment in text.split_sentence_bounds() {
+        let mut chars = segment.chars().peekable();
+        while let Some(ch) = chars.next() {
+            current.push(ch);
+            if is_cjk_terminator(ch) {
+                while let Some(next) = chars.next_if(|&next| is_cjk_terminator(next) || matches!(next, '」' | '』' | '）')) {
+                    current.push(next);
+                }
+                push(&mut current);
+            }
+        }
+        push(&mut current);
     }
+    sentences
+}
 
-    for sentence in sentences {
+fn is_cjk_terminator(c: char) -> bool {
+    matches!(c, '。' | '！' | '？')
+}
+
+/// Synthesize `text` sentence by sentence into `full_audio`.
+///
+/// A failed sentence is reported and skipped; returns how many failed.
+pub fn generate(tts: &TTSKoko, text: &str, full_audio: &mut Vec<f32>) -> Result<usize, Box<dyn std::error::Error>> {
+    let s = std::time::Instant::now();
+    // "af_heart.4+af_bella.6"
+    // "bm_daniel", "bm_fable", "bm_george", "bm_lewis"
+
+    let mut failed = 0;
+    for sentence in split_sentences(text) {
         println!("sentence: {sentence}");
         let trimmed = sentence.trim();
         if trimmed.is_empty() {
@@ -106,13 +205,16 @@ pub fn generate(tts: &TTSKoko, text: &str, full_audio: &mut Vec<f32>) -> Result<
                 full_audio.extend_from_slice(&raw_audio);
                 // eprintln!("Audio buffered up. Ready for another line of text.");
             }
-            Err(e) => eprintln!("Error processing line: {}", e),
+            Err(e) => {
+                eprintln!("Error processing line: {}", e);
+                failed += 1;
+            }
         }
 
         println!("Time taken: {:?}", s.elapsed());
-        let words_per_second = text.split_whitespace().count() as f32 / s.elapsed().as_secs_f32();
+        let words_per_second = word_count(text) as f32 / s.elapsed().as_secs_f32();
         println!("Words per second: {:.2}", words_per_second);
     }
 
-    Ok(())
+    Ok(failed)
 }
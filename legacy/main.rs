@@ -6,5 +6,6 @@ use tokio::io::{AsyncBufReadExt};
 use unicode_segmentation::UnicodeSegmentation;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    sirius::start()
+    let autosave = sirius::parse_args(std::env::args().skip(1))?;
+    sirius::start(autosave)
 }
@@ -1,42 +1,424 @@
 use crate::audio;
+use std::thread::JoinHandle;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use kokoro::tts::koko::TTSKoko;
 
+const MODEL_PATH: &str = "checkpoints/kokoro-v1.0.onnx";
+const VOICES_PATH: &str = "data/voices-v1.0.bin";
 
-pub(crate) fn start() -> Result<(), Box<dyn std::error::Error>> {
-    let rt = tokio::runtime::Runtime::new()?;
+const PLAY: &str = "://play";
+const FLUSH: &str = "://flush";
+const STREAM: &str = "://stream";
+const RECORD: &str = "://record";
+const STOP: &str = "://stop";
+
+/// A line of REPL input
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Empty,
+    Play,
+    Flush,
+    ToggleStream,
+    /// Start appending each line's audio to the WAV file at this path
+    Record(&'a str),
+    Stop,
+    Text(&'a str),
+}
+
+/// Whether typed lines are only buffered (played on `://play`) or also
+/// spoken as soon as they're synthesized
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlaybackMode {
+    Buffered,
+    Stream,
+}
+
+impl PlaybackMode {
+    fn toggle(self) -> Self {
+        match self {
+            PlaybackMode::Buffered => PlaybackMode::Stream,
+            PlaybackMode::Stream => PlaybackMode::Buffered,
+        }
+    }
+}
+
+/// Surrounding whitespace is ignored, so "://play  " still plays
+fn parse_command(line: &str) -> Command<'_> {
+    match line.trim() {
+        "" => Command::Empty,
+        PLAY => Command::Play,
+        FLUSH => Command::Flush,
+        STREAM => Command::ToggleStream,
+        STOP => Command::Stop,
+        text => match text.strip_prefix(RECORD) {
+            Some(path) if path.starts_with(char::is_whitespace) => Command::Record(path.trim()),
+            _ => Command::Text(text),
+        },
+    }
+}
 
-    const PLAY: &str = "://play";
-    const FLUSH: &str = "://flush";
+/// Finalize `recording`, if one is running, and say where it went
+fn stop_recording(recording: &mut Option<(String, audio::Recording)>) {
+    if let Some((path, recording)) = recording.take() {
+        match recording.finish() {
+            Ok(()) => println!("Recording saved to {}", path),
+            Err(e) => eprintln!("Failed to finish recording {}: {}", path, e),
+        }
+    }
+}
+
+/// `--autosave <secs> --autosave-path <path>`: write the session's audio
+/// to disk every so often, so a crash doesn't lose it
+#[derive(Debug, PartialEq)]
+pub(crate) struct AutosaveOptions {
+    pub every: Duration,
+    pub path: String,
+}
+
+/// Parse the binary's arguments, without the program name. Both autosave
+/// flags are needed together; anything else is an error.
+pub(crate) fn parse_args(args: impl Iterator<Item = String>) -> Result<Option<AutosaveOptions>, String> {
+    let mut args = args;
+    let (mut every, mut path) = (None, None);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--autosave" => {
+                let secs = value()?;
+                match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => every = Some(Duration::from_secs(secs)),
+                    _ => return Err(format!("--autosave must be a positive number of seconds, not {}", secs)),
+                }
+            }
+            "--autosave-path" => path = Some(value()?),
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    match (every, path) {
+        (Some(every), Some(path)) => Ok(Some(AutosaveOptions { every, path })),
+        (None, None) => Ok(None),
+        _ => Err("--autosave and --autosave-path go together".to_string()),
+    }
+}
+
+/// Decides when an autosave tick should write, and writes on a background
+/// thread so input handling never waits on the disk
+struct Autosave {
+    path: String,
+    /// Audio was added since the last save
+    dirty: bool,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Autosave {
+    fn new(path: String) -> Self {
+        Self { path, dirty: false, writer: None }
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Whether a tick now should save `audio`: only new audio is worth
+    /// writing, a flushed buffer never overwrites an earlier save, and a
+    /// save still in progress is left to finish.
+    fn due(&self, audio: &[f32]) -> bool {
+        let writing = self.writer.as_ref().is_some_and(|w| !w.is_finished());
+        self.dirty && !audio.is_empty() && !writing
+    }
+
+    /// Start writing a copy of `audio` if it's due; returns whether it was
+    fn tick(&mut self, audio: &[f32]) -> bool {
+        if !self.due(audio) {
+            return false;
+        }
+        self.dirty = false;
+        let (path, audio) = (self.path.clone(), audio.to_vec());
+        self.writer = Some(std::thread::spawn(move || {
+            if let Err(e) = audio::save_f32_buffer(&path, &audio, 1, 24000) {
+                eprintln!("Autosave to {} failed: {}", path, e);
+            }
+        }));
+        true
+    }
+
+    /// Wait for a save in progress
+    fn finish(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The next autosave tick, or never without autosave
+async fn next_tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// `TTSKoko::new` panics when the model can't be downloaded or loaded; run it
+/// on its own task so that surfaces as an error naming the files instead.
+async fn load_model(model_path: &str, voices_path: &str) -> Result<TTSKoko, Box<dyn std::error::Error>> {
+    let (model, voices) = (model_path.to_string(), voices_path.to_string());
+    tokio::spawn(async move { TTSKoko::new(&model, &voices).await })
+        .await
+        .map_err(|e| {
+            let reason = match e.try_into_panic() {
+                Ok(panic) => panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "unknown error".to_string()),
+                Err(e) => e.to_string(),
+            };
+            format!(
+                "failed to load the TTS model ({}, {}): {}",
+                model_path, voices_path, reason
+            )
+            .into()
+        })
+}
+
+pub(crate) fn start(autosave_options: Option<AutosaveOptions>) -> Result<(), Box<dyn std::error::Error>> {
+    let rt = tokio::runtime::Runtime::new()?;
 
     rt.block_on(async {
-        let tts = TTSKoko::new("checkpoints/kokoro-v1.0.onnx", "data/voices-v1.0.bin").await;
+        let tts = load_model(MODEL_PATH, VOICES_PATH).await?;
         let stdin = tokio::io::stdin();
         let reader = BufReader::new(stdin);
         let mut lines = reader.lines();
         eprintln!("Entering streaming mode. Type text and press Enter. Use Ctrl+D to exit.");
 
         let mut full_audio: Vec<f32> = Vec::new();
-        while let Some(line) = lines.next_line().await? {
-            let stripped_line = line.trim();
-            if stripped_line.is_empty() {
-                continue;
-            } else if stripped_line == PLAY {
-                audio::play_f32_buffer(&full_audio, 1, 24000)?;
-            } else if stripped_line == FLUSH {
-                full_audio = Vec::new();
-                println!("Audio buffer is cleared");
-                continue;
-            } else {
-                audio::generate(&tts, stripped_line, &mut full_audio)?;
-                // Optionally play the audio
-                // audio::play_f32_buffer(&full_audio, 1, 24000)?;
-                let output_path = "output/final/final.mp3".to_string();
-                audio::save_f32_buffer(&output_path, &full_audio, 1, 24000)?;
+        let mut failures = 0;
+        let mut mode = PlaybackMode::Buffered;
+        // Opened on first use so buffered-only sessions never touch the device
+        let mut speaker: Option<audio::Speaker> = None;
+        let mut recording: Option<(String, audio::Recording)> = None;
+        // The first tick is a period away, not immediate
+        let mut interval = autosave_options.as_ref().map(|options| {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + options.every, options.every);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+        let mut autosave = autosave_options.map(|options| Autosave::new(options.path));
+        loop {
+            let line = tokio::select! {
+                line = lines.next_line() => line?,
+                _ = next_tick(&mut interval) => {
+                    if let Some(autosave) = &mut autosave {
+                        autosave.tick(&full_audio);
+                    }
+                    continue;
+                }
+            };
+            let Some(line) = line else { break };
+            match parse_command(&line) {
+                Command::Empty => continue,
+                Command::Play => {
+                    if let Err(e) = audio::play_f32_buffer(&full_audio, 1, 24000) {
+                        eprintln!("Playback failed: {}", e);
+                    }
+                }
+                Command::Flush => {
+                    full_audio = Vec::new();
+                    println!("Audio buffer is cleared");
+                    continue;
+                }
+                Command::ToggleStream => {
+                    mode = mode.toggle();
+                    match mode {
+                        PlaybackMode::Stream => println!("Streaming: each line plays as soon as it's ready"),
+                        PlaybackMode::Buffered => println!("Buffering: use {} to play", PLAY),
+                    }
+                    continue;
+                }
+                Command::Record(path) => {
+                    stop_recording(&mut recording);
+                    match audio::Recording::create(path, 1, 24000) {
+                        Ok(r) => {
+                            recording = Some((path.to_string(), r));
+                            println!("Recording to {}; use {} to finish", path, STOP);
+                        }
+                        Err(e) => eprintln!("Can't record to {}: {}", path, e),
+                    }
+                    continue;
+                }
+                Command::Stop => {
+                    if recording.is_none() {
+                        println!("Not recording; use {} <path> to start", RECORD);
+                    }
+                    stop_recording(&mut recording);
+                    continue;
+                }
+                Command::Text(text) => {
+                    let mut line_audio: Vec<f32> = Vec::new();
+                    let failed = audio::generate(&tts, text, &mut line_audio)?;
+                    if mode == PlaybackMode::Stream && !line_audio.is_empty() {
+                        if speaker.is_none() {
+                            match audio::Speaker::new() {
+                                Ok(s) => speaker = Some(s),
+                                Err(e) => eprintln!("Can't open audio output: {}", e),
+                            }
+                        }
+                        if let Some(speaker) = &speaker {
+                            speaker.append_f32(&line_audio, 1, 24000);
+                        }
+                    }
+                    if let Some((path, r)) = &mut recording {
+                        if let Err(e) = r.append_f32(&line_audio) {
+                            eprintln!("Failed to append to recording {}: {}", path, e);
+                        }
+                    }
+                    full_audio.extend_from_slice(&line_audio);
+                    if let Some(autosave) = &mut autosave {
+                        if !line_audio.is_empty() {
+                            autosave.mark_dirty();
+                        }
+                    }
+                    if failed > 0 {
+                        failures += failed;
+                        eprintln!("{} sentence(s) failed to synthesize ({} so far)", failed, failures);
+                    }
+                    // Optionally play the audio
+                    // audio::play_f32_buffer(&full_audio, 1, 24000)?;
+                    let output_path = "output/final/final.mp3".to_string();
+                    if let Err(e) = audio::save_f32_buffer(&output_path, &full_audio, 1, 24000) {
+                        eprintln!("Failed to save {}: {}", output_path, e);
+                    }
+                }
             }
 
         }
 
+        // EOF ends the session; don't leave a recording unfinalized
+        stop_recording(&mut recording);
+        if let Some(autosave) = &mut autosave {
+            autosave.finish();
+            autosave.tick(&full_audio);
+            autosave.finish();
+        }
+        if failures > 0 {
+            eprintln!("{} sentence(s) failed to synthesize this session", failures);
+        }
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("://play"), Command::Play);
+        assert_eq!(parse_command("://play  \t"), Command::Play);
+        assert_eq!(parse_command("  ://flush\r"), Command::Flush);
+        assert_eq!(parse_command("   "), Command::Empty);
+        assert_eq!(parse_command(" Hello there. "), Command::Text("Hello there."));
+        assert_eq!(parse_command("://play now"), Command::Text("://play now"));
+        assert_eq!(parse_command("://stream "), Command::ToggleStream);
+        assert_eq!(parse_command("://record  out/session.wav "), Command::Record("out/session.wav"));
+        assert_eq!(parse_command("://record"), Command::Text("://record"));
+        assert_eq!(parse_command("://recordings"), Command::Text("://recordings"));
+        assert_eq!(parse_command("://stop"), Command::Stop);
+    }
+
+    #[test]
+    fn test_recording_appends_lines() {
+        let path = std::env::temp_dir().join(format!("sirius-record-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let first = vec![0.25f32; 2400];
+        let second = vec![-0.5f32; 1200];
+
+        let mut recording = audio::Recording::create(path, 1, 24000).unwrap();
+        recording.append_f32(&first).unwrap();
+        // Valid mid-session, before the recording is finished
+        assert_eq!(hound::WavReader::open(path).unwrap().len(), 2400);
+        recording.append_f32(&second).unwrap();
+        recording.finish().unwrap();
+
+        let mut reader = hound::WavReader::open(path).unwrap();
+        assert_eq!(reader.spec().sample_rate, 24000);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples.len(), first.len() + second.len());
+        assert_eq!(samples[0], (0.25 * 32767.0) as i16);
+        assert_eq!(samples[2400], (-0.5 * 32767.0) as i16);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = |line: &str| parse_args(line.split_whitespace().map(String::from));
+        assert_eq!(args(""), Ok(None));
+        assert_eq!(
+            args("--autosave 30 --autosave-path out/session.wav"),
+            Ok(Some(AutosaveOptions { every: Duration::from_secs(30), path: "out/session.wav".to_string() }))
+        );
+        assert!(args("--autosave 30").unwrap_err().contains("go together"));
+        assert!(args("--autosave 0 --autosave-path a.wav").is_err());
+        assert!(args("--autosave-path").unwrap_err().contains("needs a value"));
+        assert!(args("--verbose").unwrap_err().contains("unknown"));
+    }
+
+    #[test]
+    fn test_autosave_only_saves_new_audio() {
+        let path = std::env::temp_dir().join(format!("sirius-autosave-{}.wav", std::process::id()));
+        let mut autosave = Autosave::new(path.to_str().unwrap().to_string());
+        let audio = vec![0.25f32; 2400];
+
+        // Nothing typed yet
+        assert!(!autosave.tick(&audio));
+        autosave.mark_dirty();
+        assert!(autosave.tick(&audio));
+        autosave.finish();
+        assert_eq!(hound::WavReader::open(&path).unwrap().len(), 2400);
+        // Unchanged since the last save
+        assert!(!autosave.tick(&audio));
+
+        // A flushed buffer doesn't overwrite the save
+        autosave.mark_dirty();
+        assert!(!autosave.tick(&[]));
+        assert_eq!(hound::WavReader::open(&path).unwrap().len(), 2400);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_playback_mode_toggle() {
+        let mode = PlaybackMode::Buffered;
+        assert_eq!(mode.toggle(), PlaybackMode::Stream);
+        assert_eq!(mode.toggle().toggle(), PlaybackMode::Buffered);
+    }
+
+    #[test]
+    fn test_japanese_splits_on_full_stops() {
+        let paragraph = "今日は晴れです。明日は雨が降るでしょう！本当ですか？「はい。」";
+        assert_eq!(
+            audio::split_sentences(paragraph),
+            vec!["今日は晴れです。", "明日は雨が降るでしょう！", "本当ですか？", "「はい。」"]
+        );
+    }
+
+    #[test]
+    fn test_arabic_keeps_every_character() {
+        let paragraph = "مرحبا بالعالم. كيف حالك؟ أنا بخير، شكرا لك.";
+        let sentences = audio::split_sentences(paragraph);
+        assert_eq!(sentences.len(), 3);
+        assert_eq!(sentences[1], "كيف حالك؟");
+
+        let without_spaces = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        assert_eq!(without_spaces(&sentences.concat()), without_spaces(paragraph));
+    }
+
+    #[test]
+    fn test_word_count() {
+        assert_eq!(audio::word_count("Hello there, world."), 3);
+        assert_eq!(audio::word_count("今日は晴れです。"), 7);
+        assert_eq!(audio::word_count("مرحبا بالعالم"), 2);
+    }
+}
@@ -0,0 +1,170 @@
+//! G.711 companding for the telephony formats, `AudioFormat::Mulaw` and
+//! `AudioFormat::Alaw`
+//!
+//! Each 16-bit sample becomes one byte: a sign, a 3-bit segment (roughly
+//! the exponent) and a 4-bit step within it. This follows the reference
+//! implementation that ships with most telephony stacks, including its bias
+//! and clipping, so the bytes match what PBXes and SIP endpoints expect.
+
+/// μ-law encodes `|sample| + BIAS`, keeping small values off segment zero's floor
+const MULAW_BIAS: i32 = 0x84;
+
+/// Largest magnitude μ-law can encode once biased
+const MULAW_CLIP: i32 = 32635;
+
+/// A-law bytes have their even bits inverted on the wire
+const ALAW_EVEN_BITS: u8 = 0x55;
+
+/// One 16-bit sample as a μ-law byte
+pub fn linear_to_mulaw(sample: i16) -> u8 {
+    let mut magnitude = sample as i32;
+    let sign = if magnitude < 0 {
+        magnitude = -magnitude;
+        0x80
+    } else {
+        0
+    };
+    let biased = magnitude.min(MULAW_CLIP) + MULAW_BIAS;
+    // The bias sets bit 7, so the segment runs from 0 to 7
+    let segment = (31 - biased.leading_zeros()) as i32 - 7;
+    let step = (biased >> (segment + 3)) & 0x0F;
+    !(sign | (segment << 4) as u8 | step as u8)
+}
+
+/// The 16-bit sample a μ-law byte stands for
+pub fn mulaw_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let segment = (byte & 0x70) >> 4;
+    let magnitude = ((((byte & 0x0F) as i32) << 3) + MULAW_BIAS) << segment;
+    if byte & 0x80 != 0 {
+        (MULAW_BIAS - magnitude) as i16
+    } else {
+        (magnitude - MULAW_BIAS) as i16
+    }
+}
+
+/// One 16-bit sample as an A-law byte
+pub fn linear_to_alaw(sample: i16) -> u8 {
+    // A-law works on 13 bits
+    let value = (sample >> 3) as i32;
+    let (magnitude, mask) = if value >= 0 {
+        (value, 0x80 | ALAW_EVEN_BITS)
+    } else {
+        (-value - 1, ALAW_EVEN_BITS)
+    };
+    // Segment 0 and 1 share a step size; each later one doubles it
+    let segment = (32 - (magnitude as u32 >> 5).leading_zeros()).min(7);
+    let step = if segment < 2 {
+        (magnitude >> 1) & 0x0F
+    } else {
+        (magnitude >> segment) & 0x0F
+    };
+    ((segment << 4) as u8 | step as u8) ^ mask
+}
+
+/// The 16-bit sample an A-law byte stands for
+pub fn alaw_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ ALAW_EVEN_BITS;
+    let segment = (byte & 0x70) >> 4;
+    let mut magnitude = ((byte & 0x0F) as i32) << 4;
+    match segment {
+        0 => magnitude += 8,
+        _ => magnitude = (magnitude + 0x108) << (segment - 1),
+    }
+    if byte & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * 32767.0) as i16
+}
+
+/// `samples` as `AudioFormat::Mulaw` bytes, clamped to [-1, 1]
+pub fn encode_mulaw(samples: &[f32]) -> Vec<u8> {
+    samples.iter().map(|&s| linear_to_mulaw(to_i16(s))).collect()
+}
+
+/// Samples from `AudioFormat::Mulaw` bytes, scaled to [-1, 1]
+pub fn decode_mulaw(data: &[u8]) -> Vec<f32> {
+    data.iter().map(|&b| mulaw_to_linear(b) as f32 / 32767.0).collect()
+}
+
+/// `samples` as `AudioFormat::Alaw` bytes, clamped to [-1, 1]
+pub fn encode_alaw(samples: &[f32]) -> Vec<u8> {
+    samples.iter().map(|&s| linear_to_alaw(to_i16(s))).collect()
+}
+
+/// Samples from `AudioFormat::Alaw` bytes, scaled to [-1, 1]
+pub fn decode_alaw(data: &[u8]) -> Vec<f32> {
+    data.iter().map(|&b| alaw_to_linear(b) as f32 / 32767.0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mulaw_reference_values() {
+        // (linear, byte) pairs from the G.711 reference encoder
+        let table: [(i16, u8); 10] = [
+            (0, 0xFF),
+            (-1, 0x7F),
+            (8, 0xFE),
+            (100, 0xF2),
+            (1000, 0xCE),
+            (-1000, 0x4E),
+            (8159, 0x9F),
+            (32767, 0x80),
+            (-32768, 0x00),
+            (-8159, 0x1F),
+        ];
+        for (linear, byte) in table {
+            assert_eq!(linear_to_mulaw(linear), byte, "{}", linear);
+        }
+        // Decoding gives the middle of each step
+        assert_eq!(mulaw_to_linear(0xFF), 0);
+        assert_eq!(mulaw_to_linear(0xCE), 988);
+        assert_eq!(mulaw_to_linear(0x80), 32124);
+        assert_eq!(mulaw_to_linear(0x00), -32124);
+    }
+
+    #[test]
+    fn test_alaw_reference_values() {
+        let table: [(i16, u8); 8] = [
+            (0, 0xD5),
+            (-1, 0x55),
+            (16, 0xD4),
+            (1000, 0xFA),
+            (-1000, 0x7A),
+            (32767, 0xAA),
+            (-32768, 0x2A),
+            (4096, 0x85),
+        ];
+        for (linear, byte) in table {
+            assert_eq!(linear_to_alaw(linear), byte, "{}", linear);
+        }
+        assert_eq!(alaw_to_linear(0xD5), 8);
+        assert_eq!(alaw_to_linear(0x55), -8);
+        assert_eq!(alaw_to_linear(0xAA), 32256);
+        assert_eq!(alaw_to_linear(0xFA), 1008);
+    }
+
+    #[test]
+    fn test_companding_round_trip() {
+        // Every byte survives decoding and re-encoding; μ-law's two zeros
+        // (0x7F and 0xFF) both decode to 0, which encodes as 0xFF
+        for byte in 0..=255u8 {
+            assert_eq!(linear_to_alaw(alaw_to_linear(byte)), byte);
+            if byte != 0x7F {
+                assert_eq!(linear_to_mulaw(mulaw_to_linear(byte)), byte);
+            }
+        }
+        let samples = [0.0, 0.5, -0.25, 1.0];
+        for (decoded, original) in decode_mulaw(&encode_mulaw(&samples)).iter().zip(samples) {
+            assert!((decoded - original).abs() < 0.02, "{} vs {}", decoded, original);
+        }
+    }
+}
@@ -2,21 +2,92 @@
 //!
 //! The protocol is simple:
 //! - Client sends: JSON text message with the text to synthesize
-//! - Server returns: Binary WAV audio data
+//! - Server returns: JSON metadata, then the WAV audio data in one or more
+//!   binary messages (see [`PayloadAssembler`]), or base64 in an
+//!   `AudioInline` JSON message if the request asked for `inline_audio`
 //!
 //! For control messages:
 //! - Client can send commands like "flush" to clear server-side buffers (if any)
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+pub mod g711;
+pub mod text;
+
 /// Request from client to server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
 pub enum Request {
     /// Synthesize text to speech and return audio
     Synthesize(SynthesizeRequest),
+    /// Synthesize the same text once per voice (or blend) for A/B comparison.
+    /// The server answers with one `PreviewReady` + binary pair per voice, in order.
+    /// Between 1 and `MAX_PREVIEW_VOICES` voices, all known to the server.
+    VoicePreview {
+        text: String,
+        voices: Vec<String>,
+        #[serde(default = "default_lang")]
+        lang: String,
+        #[serde(default = "default_speed")]
+        speed: f32,
+    },
+    /// Remember a voice, lang and speed for the rest of this connection.
+    /// Later `Synthesize` (and, for lang and speed, `VoicePreview`) requests
+    /// that leave a field at the protocol default use it instead; fields left
+    /// out here keep their current value. Answered with `DefaultsSet`.
+    SetDefaults {
+        #[serde(default)]
+        voice: Option<String>,
+        #[serde(default)]
+        lang: Option<String>,
+        #[serde(default)]
+        speed: Option<f32>,
+    },
+    /// Synthesize one of the server's canned prompts (`SIRIUS_PROMPTS`),
+    /// with each `{name}` placeholder replaced by `vars["name"]`. Answered
+    /// like `Synthesize`, using the connection's defaults.
+    SynthesizePrompt {
+        id: String,
+        #[serde(default)]
+        vars: HashMap<String, String>,
+    },
+    /// Read the server's runtime settings; needs the server's admin token
+    /// (`SIRIUS_ADMIN_TOKEN`). Answered with `Config`.
+    GetConfig { token: String },
+    /// Change some of the server's runtime settings for every later request,
+    /// on every connection. Keys are `RuntimeConfig` field names; an unknown
+    /// key or a bad value changes nothing. Answered with `Config`.
+    SetConfig {
+        token: String,
+        settings: serde_json::Map<String, serde_json::Value>,
+    },
     /// Ping to keep connection alive
     Ping,
+    /// Sent straight back as `Echo`, without touching the engine; for
+    /// telling transport problems from synthesis ones
+    Echo { payload: String },
+    /// A `type` this version doesn't know, from a newer client, when it
+    /// comes without `data`; with `data` it's a parse error. Either way,
+    /// `Request::unsupported_type` names it.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Request {
+    /// The `type` of a request message if this version doesn't know it,
+    /// so a newer client can be told which request the server can't handle
+    pub fn unsupported_type(json: &str) -> Option<String> {
+        let value: serde_json::Value = serde_json::from_str(json).ok()?;
+        let kind = value.get("type")?.as_str()?;
+        // Without its data a known type is either a unit request or an
+        // error, never `Unknown`
+        match serde_json::from_value(serde_json::json!({ "type": kind })) {
+            Ok(Request::Unknown) => Some(kind.to_string()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,18 +103,264 @@ pub struct SynthesizeRequest {
     /// Speech speed (0.0 to 2.0, default 0.99)
     #[serde(default = "default_speed")]
     pub speed: f32,
+    /// Speaking style (e.g. an emotion) for backends that support one; the
+    /// server rejects styles its backend doesn't list, and warns that the
+    /// style is ignored if the backend has none (Kokoro doesn't)
+    #[serde(default)]
+    pub style: Option<String>,
+    /// Only return the `AudioReady` metadata, without the binary audio
+    #[serde(default)]
+    pub metadata_only: bool,
+    /// Texts to try in order if `text` produces no audio (e.g. characters
+    /// the voice can't read); `AudioReady::used_fallback` says which was used
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Send the audio base64-encoded in an `AudioInline` message instead of
+    /// binary messages, for clients that can only handle text frames
+    #[serde(default)]
+    pub inline_audio: bool,
+    /// Tempo change applied after synthesis (0.5 to 2.0). Unlike `speed`,
+    /// which the model uses to change prosody, this is a pitch-preserving
+    /// time stretch of the finished audio.
+    #[serde(default)]
+    pub tempo: Option<f32>,
+    /// Pitch shift in semitones applied after synthesis, without changing
+    /// duration (clamped to ±12)
+    #[serde(default)]
+    pub pitch_semitones: Option<f32>,
+    /// Band-limiting filter applied just before encoding
+    #[serde(default)]
+    pub filter: Option<FilterSpec>,
+    /// Remove single-sample clicks and gate low-level hiss between words
+    #[serde(default)]
+    pub denoise: bool,
+    /// Start with the server's notification chime (`SIRIUS_EARCON`)
+    #[serde(default)]
+    pub earcon: bool,
+    /// Expand abbreviations ("Dr." to "Doctor") before splitting sentences
+    #[serde(default = "default_true")]
+    pub expand_abbreviations: bool,
+    /// Collapse runs of whitespace and rejoin lines wrapped within a
+    /// paragraph (`text::collapse_whitespace`) before splitting sentences
+    #[serde(default = "default_true")]
+    pub collapse_whitespace: bool,
+    /// How URLs and email addresses are read
+    #[serde(default)]
+    pub links: LinkReading,
+    /// Read punctuation marks aloud by name ("comma", "period")
+    #[serde(default)]
+    pub speak_punctuation: bool,
+    /// Drop `// comment` lines and `[[directive]]` spans (or the server's
+    /// configured markers) instead of reading them aloud
+    #[serde(default)]
+    pub skip_markers: bool,
+    /// Fail the whole request if any sentence fails. By default a failed
+    /// sentence is replaced with a short silence and the rest still play.
+    #[serde(default)]
+    pub strict: bool,
+    /// Encoding of the binary audio
+    #[serde(default)]
+    pub format: AudioFormat,
+    /// Sample encoding inside a `wav` payload (16-bit by default); the raw
+    /// PCM formats name their own
+    #[serde(default)]
+    pub bit_depth: Option<BitDepth>,
+    /// Send a `WordMark` for each word before `AudioReady`, for clients that
+    /// highlight the word being read
+    #[serde(default)]
+    pub word_marks: bool,
+    /// Exact length of the finished audio: shorter speech is padded with
+    /// trailing silence, longer speech is cut (with a warning)
+    #[serde(default)]
+    pub target_duration_secs: Option<f32>,
+    /// What `text` (and each fallback) holds: ordinary text, or phonemes to
+    /// be spoken exactly as written
+    #[serde(default)]
+    pub input_type: InputType,
+}
+
+/// Encoding of the audio payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AudioFormat {
+    /// 16-bit PCM WAV file, header included
+    #[default]
+    #[serde(rename = "wav")]
+    Wav,
+    /// Raw little-endian 32-bit float samples, no header
+    #[serde(rename = "pcm_f32le")]
+    PcmF32LE,
+    /// Raw little-endian 16-bit signed samples, no header
+    #[serde(rename = "pcm_s16le")]
+    PcmS16LE,
+    /// G.711 μ-law at 8 kHz for telephony, one byte per sample, no header
+    #[serde(rename = "mulaw")]
+    Mulaw,
+    /// G.711 A-law at 8 kHz for telephony, one byte per sample, no header
+    #[serde(rename = "alaw")]
+    Alaw,
+}
+
+impl AudioFormat {
+    /// MIME type for `AudioInline` payloads; raw PCM has no registered
+    /// little-endian type, so it goes as plain bytes
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::PcmF32LE | AudioFormat::PcmS16LE => "application/octet-stream",
+            AudioFormat::Mulaw => "audio/basic",
+            AudioFormat::Alaw => "audio/PCMA",
+        }
+    }
+
+    /// Sample rate of audio in this format: the telephony formats are
+    /// resampled to 8 kHz, the rest keep `SAMPLE_RATE`
+    pub fn sample_rate(self) -> u32 {
+        match self {
+            AudioFormat::Mulaw | AudioFormat::Alaw => TELEPHONY_SAMPLE_RATE,
+            AudioFormat::Wav | AudioFormat::PcmF32LE | AudioFormat::PcmS16LE => SAMPLE_RATE,
+        }
+    }
+}
+
+/// What happens to URLs and email addresses in a request's text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkReading {
+    /// Read as people say them: "example dot com slash docs"
+    #[default]
+    Spoken,
+    /// Left out of the speech
+    Omit,
+    /// Passed to the model as written
+    Verbatim,
+}
+
+/// How the server reads a request's text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    /// Text, normalized and converted to phonemes by the server
+    #[default]
+    Text,
+    /// IPA phonemes, as Kokoro's phonemizer writes them (e.g. "həlˈoʊ")
+    Ipa,
+    /// ARPAbet phones separated by spaces, with stress digits and `|`
+    /// between words (e.g. "HH AH0 L OW1 | W ER1 L D")
+    Arpabet,
+}
+
+/// Sample encoding of a WAV payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BitDepth {
+    /// 16-bit signed integer
+    #[default]
+    I16,
+    /// 24-bit signed integer
+    I24,
+    /// 32-bit float, unclipped
+    F32,
+}
+
+impl BitDepth {
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            BitDepth::I16 => 16,
+            BitDepth::I24 => 24,
+            BitDepth::F32 => 32,
+        }
+    }
 }
 
+/// High-pass and/or low-pass cutoffs, e.g. 300-3400 Hz for telephony
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct FilterSpec {
+    /// Remove content below this frequency
+    #[serde(default)]
+    pub high_pass_hz: Option<f32>,
+    /// Remove content above this frequency
+    #[serde(default)]
+    pub low_pass_hz: Option<f32>,
+}
+
+impl FilterSpec {
+    /// The classic telephone band, 300-3400 Hz
+    pub fn telephony() -> Self {
+        Self {
+            high_pass_hz: Some(300.0),
+            low_pass_hz: Some(3400.0),
+        }
+    }
+
+    /// Cutoffs must be inside (0, Nyquist) and leave a non-empty band
+    pub fn validate(&self) -> Result<(), String> {
+        let nyquist = SAMPLE_RATE as f32 / 2.0;
+        for (name, cutoff) in [("high_pass_hz", self.high_pass_hz), ("low_pass_hz", self.low_pass_hz)] {
+            if let Some(cutoff) = cutoff {
+                if !(cutoff > 0.0 && cutoff < nyquist) {
+                    return Err(format!("{} must be between 0 and {} Hz", name, nyquist));
+                }
+            }
+        }
+        if let (Some(high_pass), Some(low_pass)) = (self.high_pass_hz, self.low_pass_hz) {
+            if high_pass >= low_pass {
+                return Err("high_pass_hz must be below low_pass_hz".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Server settings that `SetConfig` can change without a restart
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    /// Replaces `DEFAULT_VOICE` for requests that don't pick a voice
+    pub default_voice: Option<String>,
+    /// Longer request texts are truncated; `None` is no limit
+    pub max_text_chars: Option<usize>,
+    /// Extra attempts for a sentence whose synthesis fails transiently
+    pub retries: u32,
+    /// Scale each clip's speech so its loudest sample peaks just below full scale
+    #[serde(default)]
+    pub normalize: bool,
+    /// Silence between consecutive sentences, in milliseconds
+    #[serde(default)]
+    pub sentence_pause_ms: u32,
+}
+
+/// Voice used when a request doesn't give one (servers may substitute their own)
+pub const DEFAULT_VOICE: &str = "am_onyx.4+bm_lewis.6";
+
 fn default_voice() -> String {
-    "am_onyx.4+bm_lewis.6".to_string()
+    DEFAULT_VOICE.to_string()
 }
 
+/// Language used when a request doesn't give one
+pub const DEFAULT_LANG: &str = "en-us";
+
 fn default_lang() -> String {
-    "en-us".to_string()
+    DEFAULT_LANG.to_string()
 }
 
+/// Speed used when a request doesn't give one
+pub const DEFAULT_SPEED: f32 = 0.99;
+
 fn default_speed() -> f32 {
-    0.99
+    DEFAULT_SPEED
+}
+
+/// Accepted range for the post-synthesis `tempo` factor
+pub const TEMPO_RANGE: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+
+/// Most voices one `VoicePreview` may ask for
+pub const MAX_PREVIEW_VOICES: usize = 16;
+
+fn default_bits_per_sample() -> u16 {
+    BITS_PER_SAMPLE
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl SynthesizeRequest {
@@ -53,6 +370,26 @@ impl SynthesizeRequest {
             voice: default_voice(),
             lang: default_lang(),
             speed: default_speed(),
+            style: None,
+            metadata_only: false,
+            fallbacks: Vec::new(),
+            inline_audio: false,
+            tempo: None,
+            pitch_semitones: None,
+            filter: None,
+            denoise: false,
+            earcon: false,
+            expand_abbreviations: true,
+            collapse_whitespace: true,
+            links: LinkReading::Spoken,
+            speak_punctuation: false,
+            skip_markers: false,
+            strict: false,
+            format: AudioFormat::Wav,
+            bit_depth: None,
+            word_marks: false,
+            target_duration_secs: None,
+            input_type: InputType::Text,
         }
     }
 
@@ -70,14 +407,198 @@ impl SynthesizeRequest {
         self.speed = speed;
         self
     }
+
+    pub fn with_metadata_only(mut self, metadata_only: bool) -> Self {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    pub fn with_fallbacks(mut self, fallbacks: Vec<String>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    pub fn with_inline_audio(mut self, inline_audio: bool) -> Self {
+        self.inline_audio = inline_audio;
+        self
+    }
+
+    pub fn with_tempo(mut self, tempo: f32) -> Self {
+        self.tempo = Some(tempo);
+        self
+    }
+
+    pub fn with_pitch_semitones(mut self, semitones: f32) -> Self {
+        self.pitch_semitones = Some(semitones);
+        self
+    }
+
+    pub fn with_expand_abbreviations(mut self, expand: bool) -> Self {
+        self.expand_abbreviations = expand;
+        self
+    }
+
+    pub fn with_collapse_whitespace(mut self, collapse: bool) -> Self {
+        self.collapse_whitespace = collapse;
+        self
+    }
+
+    pub fn with_links(mut self, links: LinkReading) -> Self {
+        self.links = links;
+        self
+    }
+
+    pub fn with_speak_punctuation(mut self, speak: bool) -> Self {
+        self.speak_punctuation = speak;
+        self
+    }
+
+    pub fn with_skip_markers(mut self, skip: bool) -> Self {
+        self.skip_markers = skip;
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn with_format(mut self, format: AudioFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_denoise(mut self, denoise: bool) -> Self {
+        self.denoise = denoise;
+        self
+    }
+
+    pub fn with_style(mut self, style: impl Into<String>) -> Self {
+        self.style = Some(style.into());
+        self
+    }
+
+    pub fn with_earcon(mut self, earcon: bool) -> Self {
+        self.earcon = earcon;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: FilterSpec) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_bit_depth(mut self, bit_depth: BitDepth) -> Self {
+        self.bit_depth = Some(bit_depth);
+        self
+    }
+
+    pub fn with_word_marks(mut self, word_marks: bool) -> Self {
+        self.word_marks = word_marks;
+        self
+    }
+
+    pub fn with_target_duration_secs(mut self, secs: f32) -> Self {
+        self.target_duration_secs = Some(secs);
+        self
+    }
+
+    pub fn with_input_type(mut self, input_type: InputType) -> Self {
+        self.input_type = input_type;
+        self
+    }
+
+    /// Check the settings that are invalid whatever the server's voices and
+    /// configuration; the error is the reason, e.g. "tempo must be between
+    /// 0.5 and 2"
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(tempo) = self.tempo {
+            if !TEMPO_RANGE.contains(&tempo) {
+                return Err(format!(
+                    "tempo must be between {} and {}",
+                    TEMPO_RANGE.start(),
+                    TEMPO_RANGE.end()
+                ));
+            }
+        }
+        if let Some(filter) = &self.filter {
+            filter.validate()?;
+        }
+        if let Some(secs) = self.target_duration_secs {
+            if !(secs.is_finite() && secs > 0.0) {
+                return Err("target_duration_secs must be a positive number of seconds".to_string());
+            }
+        }
+        if self.bit_depth.is_some() && self.format != AudioFormat::Wav {
+            return Err("bit_depth only applies to the wav format".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A request or response with the id that correlates them, carried next
+/// to `type`: `{"type": "Ping", "trace_id": "a1"}` is answered with
+/// `{"type": "Pong", "trace_id": "a1"}`. Requests may leave it out, and
+/// the server makes one up; a plain `Request` or `Response` ignores it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Traced<T> {
+    #[serde(flatten)]
+    pub message: T,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+impl<T> Traced<T> {
+    pub fn new(message: T, trace_id: impl Into<String>) -> Self {
+        Self {
+            message,
+            trace_id: Some(trace_id.into()),
+        }
+    }
 }
 
 /// Response metadata (sent as JSON before binary audio)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Response {
-    /// Audio is coming next as a binary message
+    /// Audio is coming next as a binary message (unless `metadata_only` was requested)
     AudioReady {
+        /// Duration in seconds
+        duration_secs: f32,
+        /// Number of samples per channel
+        #[serde(default)]
+        num_samples: u64,
+        /// Sample rate
+        sample_rate: u32,
+        /// Number of channels
+        channels: u16,
+        /// Size of the audio data in bytes
+        size_bytes: usize,
+        /// How the audio data is encoded
+        #[serde(default)]
+        format: AudioFormat,
+        /// Bits per sample of the audio data: 16 or 24 for integer samples,
+        /// 32 for float
+        #[serde(default = "default_bits_per_sample")]
+        bits_per_sample: u16,
+        /// Which text was spoken: 0 for `text`, n for `fallbacks[n - 1]`
+        #[serde(default)]
+        used_fallback: usize,
+        /// [`crc32`] of the audio data, to check the reassembled payload
+        /// against; `None` from servers that don't send one
+        #[serde(default)]
+        crc32: Option<u32>,
+    },
+    /// The request is answered in `count` segments, each an `AudioReady`
+    /// followed by its audio; sent before the first. Without one, a request
+    /// gets exactly one payload.
+    SegmentCount { count: usize },
+    /// One voice of a `VoicePreview` is coming next as a binary message
+    PreviewReady {
+        /// Position of this voice in the requested list
+        index: usize,
+        /// The voice (or blend) used for this payload
+        voice: String,
         /// Duration in seconds
         duration_secs: f32,
         /// Sample rate
@@ -87,13 +608,457 @@ pub enum Response {
         /// Size of the WAV data in bytes
         size_bytes: usize,
     },
+    /// The audio for the preceding `AudioReady`, when the request asked for
+    /// `inline_audio`; no binary messages follow
+    AudioInline {
+        /// e.g. "audio/wav"; `data:{mime};base64,{data_base64}` is a data URI
+        mime: String,
+        /// Standard base64 (with padding) of the `size_bytes` audio bytes
+        data_base64: String,
+    },
+    /// A non-fatal issue with the request (e.g. a clamped speed), sent before
+    /// its `AudioReady`; the request still succeeds
+    Warning {
+        code: WarningCode,
+        /// Human-readable description
+        message: String,
+    },
+    /// When a word of the text starts in the audio, sent in order before
+    /// `AudioReady` when the request asked for `word_marks`. The model gives
+    /// no word timings, so they are interpolated: each sentence's duration is
+    /// shared among its words in proportion to their length.
+    WordMark {
+        /// Position of the word among the whitespace-separated words of the
+        /// text as read, i.e. after abbreviations are expanded
+        word_index: usize,
+        /// Offset from the start of the audio
+        start_secs: f32,
+    },
+    /// The connection's defaults after a `SetDefaults`; `None` fields fall
+    /// back to the server's own
+    DefaultsSet {
+        voice: Option<String>,
+        lang: Option<String>,
+        speed: Option<f32>,
+    },
+    /// The server's runtime settings, after a `GetConfig` or `SetConfig`
+    Config(RuntimeConfig),
     /// Pong response to ping
     Pong,
+    /// The payload of an `Echo` request, unchanged
+    Echo { payload: String },
     /// Error occurred
-    Error { message: String },
+    Error {
+        /// Machine-readable category, for clients that react to specific failures
+        #[serde(default)]
+        code: ErrorCode,
+        /// Human-readable description
+        message: String,
+    },
+}
+
+/// Category of a `Response::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request was malformed or can't produce audio
+    BadRequest,
+    /// The requested voice (or a component of a blend) doesn't exist
+    UnknownVoice,
+    /// Too many requests; retry later
+    RateLimited,
+    /// Unexpected server-side failure
+    #[default]
+    Internal,
+    /// Missing or invalid credentials
+    Unauthorized,
+    /// The text, or the audio it makes, exceeds the server's limit
+    TooLong,
+    /// Synthesis didn't finish in time
+    Timeout,
+    /// Every synthesis slot and queue place is taken; retry later or elsewhere
+    Busy,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::UnknownVoice => "unknown_voice",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::TooLong => "too_long",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Busy => "busy",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Category of a `Response::Warning`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCode {
+    /// `speed` was outside the supported range and was clamped
+    SpeedClamped,
+    /// The text was longer than the server's limit and was cut short
+    TextTruncated,
+    /// A `style` was given, but the backend has no styles to apply it to
+    StyleIgnored,
+    /// The weights of a voice blend didn't sum to 1 and were rescaled
+    BlendNormalized,
+    /// The text is written in a script `lang` doesn't use; it was read anyway
+    LangMismatch,
+    /// The speech ran past `target_duration_secs` and was cut off there
+    DurationTruncated,
+}
+
+impl std::fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            WarningCode::SpeedClamped => "speed_clamped",
+            WarningCode::TextTruncated => "text_truncated",
+            WarningCode::StyleIgnored => "style_ignored",
+            WarningCode::BlendNormalized => "blend_normalized",
+            WarningCode::LangMismatch => "lang_mismatch",
+            WarningCode::DurationTruncated => "duration_truncated",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why the server closed a connection. Sent as the code and reason of the
+/// WebSocket close frame; a client that closes the connection itself gets
+/// its own frame echoed back instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The server is stopping; reconnect later
+    Shutdown,
+    /// The client sent something that isn't valid WebSocket
+    ProtocolError,
+    /// Nothing was received for `SIRIUS_IDLE_TIMEOUT_SECS`
+    IdleTimeout,
+    /// Missing or invalid credentials
+    AuthFailed,
+    /// The connection used up `SIRIUS_MAX_REQUESTS_PER_CONN`; reconnect to continue
+    RequestLimit,
+}
+
+impl CloseReason {
+    /// The WebSocket close code (RFC 6455 section 7.4)
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Shutdown => 1012,
+            CloseReason::ProtocolError => 1002,
+            CloseReason::IdleTimeout => 1001,
+            CloseReason::AuthFailed => 1008,
+            CloseReason::RequestLimit => 1013,
+        }
+    }
+
+    /// The close frame's reason text
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::Shutdown => "server shutting down",
+            CloseReason::ProtocolError => "protocol error",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::AuthFailed => "authentication failed",
+            CloseReason::RequestLimit => "request limit reached",
+        }
+    }
+
+    /// The reason a close code stands for, if the server sends it
+    pub fn from_code(code: u16) -> Option<Self> {
+        [
+            CloseReason::Shutdown,
+            CloseReason::ProtocolError,
+            CloseReason::IdleTimeout,
+            CloseReason::AuthFailed,
+            CloseReason::RequestLimit,
+        ]
+        .into_iter()
+        .find(|reason| reason.code() == code)
+    }
 }
 
-/// Audio format constants
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+/// WebSocket subprotocol (`Sec-WebSocket-Protocol`) for this version of the
+/// protocol. Clients that offer subprotocols must include it; clients that
+/// offer none are served this version too.
+pub const SUBPROTOCOL: &str = "sirius.v1";
+
+/// Default WebSocket message/frame size limit on both ends, in MiB. WAV audio
+/// for long text easily exceeds tungstenite's 16 MiB default frame limit.
+pub const DEFAULT_MAX_MESSAGE_MB: usize = 256;
+
+/// Default largest binary message the server sends; longer audio is split
+pub const DEFAULT_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Lookup table for [`crc32`], one entry per byte value
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE, as in zip and PNG) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Reassembles audio sent after `AudioReady`/`PreviewReady`.
+///
+/// The server may split a payload across several binary messages. They are
+/// sent in order, back to back, and concatenate to exactly `size_bytes`.
+#[derive(Debug)]
+pub struct PayloadAssembler {
+    expected: usize,
+    data: Vec<u8>,
+}
+
+impl PayloadAssembler {
+    pub fn new(size_bytes: usize) -> Self {
+        Self {
+            expected: size_bytes,
+            data: Vec::with_capacity(size_bytes),
+        }
+    }
+
+    /// Append the next binary message; returns the payload once complete
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, PayloadOverflow> {
+        if self.data.len() + chunk.len() > self.expected {
+            return Err(PayloadOverflow {
+                expected: self.expected,
+                received: self.data.len() + chunk.len(),
+            });
+        }
+        self.data.extend_from_slice(chunk);
+        if self.data.len() == self.expected {
+            Ok(Some(std::mem::take(&mut self.data)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Bytes received so far
+    pub fn received(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Bytes announced by the metadata
+    pub fn expected(&self) -> usize {
+        self.expected
+    }
+}
+
+/// More binary data arrived than the metadata announced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadOverflow {
+    pub expected: usize,
+    pub received: usize,
+}
+
+impl std::fmt::Display for PayloadOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received {} bytes of audio, but {} were announced",
+            self.received, self.expected
+        )
+    }
+}
+
+impl std::error::Error for PayloadOverflow {}
+
+/// Audio format constants; `BITS_PER_SAMPLE` is the default WAV depth
 pub const SAMPLE_RATE: u32 = 24000;
 pub const CHANNELS: u16 = 1;
 pub const BITS_PER_SAMPLE: u16 = 16;
+
+/// Sample rate of `AudioFormat::Mulaw` and `AudioFormat::Alaw`
+pub const TELEPHONY_SAMPLE_RATE: u32 = 8000;
+
+/// `samples` as `AudioFormat::PcmF32LE` bytes
+pub fn encode_pcm_f32le(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// Samples from `AudioFormat::PcmF32LE` bytes; a trailing partial sample is ignored
+pub fn decode_pcm_f32le(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// `samples` as `AudioFormat::PcmS16LE` bytes, clamped to [-1, 1]
+pub fn encode_pcm_s16le(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|&s| ((s.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes())
+        .collect()
+}
+
+/// Samples from `AudioFormat::PcmS16LE` bytes, scaled to [-1, 1]
+pub fn decode_pcm_s16le(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32767.0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traced_messages() {
+        let json = serde_json::to_string(&Traced::new(Request::Echo { payload: "hi".to_string() }, "a1")).unwrap();
+        assert_eq!(json, r#"{"type":"Echo","data":{"payload":"hi"},"trace_id":"a1"}"#);
+        let traced: Traced<Request> = serde_json::from_str(&json).unwrap();
+        assert!(matches!(traced.message, Request::Echo { payload } if payload == "hi"));
+        assert_eq!(traced.trace_id.as_deref(), Some("a1"));
+
+        // Left out, it's None; a plain message ignores it
+        let traced: Traced<Request> = serde_json::from_str(r#"{"type": "Ping"}"#).unwrap();
+        assert!(matches!(traced.message, Request::Ping));
+        assert_eq!(traced.trace_id, None);
+        let json = serde_json::to_string(&Traced::new(Response::Pong, "a1")).unwrap();
+        assert_eq!(json, r#"{"type":"Pong","trace_id":"a1"}"#);
+        assert!(matches!(serde_json::from_str::<Response>(&json).unwrap(), Response::Pong));
+        let json = serde_json::to_string(&Traced::new(Response::Config(RuntimeConfig::default()), "a1")).unwrap();
+        assert!(matches!(serde_json::from_str::<Response>(&json).unwrap(), Response::Config(_)));
+    }
+
+    #[test]
+    fn test_unknown_request_type() {
+        let json = r#"{"type": "Transcribe", "data": {"audio": "UklGR..."}}"#;
+        assert_eq!(Request::unsupported_type(json).as_deref(), Some("Transcribe"));
+        let json = r#"{"type": "Hibernate"}"#;
+        assert!(matches!(serde_json::from_str::<Request>(json).unwrap(), Request::Unknown));
+        assert_eq!(Request::unsupported_type(json).as_deref(), Some("Hibernate"));
+
+        // Known types, well-formed or not, and messages without a type
+        for json in [
+            r#"{"type": "Ping"}"#,
+            r#"{"type": "Echo", "data": {}}"#,
+            r#"{"type": "Synthesize", "data": {"text": "Hi"}}"#,
+            r#"{"type": 3}"#,
+            "[1, 2]",
+        ] {
+            assert_eq!(Request::unsupported_type(json), None, "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_close_reasons_round_trip() {
+        for reason in [
+            CloseReason::Shutdown,
+            CloseReason::ProtocolError,
+            CloseReason::IdleTimeout,
+            CloseReason::AuthFailed,
+            CloseReason::RequestLimit,
+        ] {
+            assert_eq!(CloseReason::from_code(reason.code()), Some(reason));
+        }
+        assert_eq!(CloseReason::from_code(1000), None);
+        assert_eq!(CloseReason::IdleTimeout.to_string(), "idle timeout");
+    }
+
+    #[test]
+    fn test_payload_reassembly() {
+        let payload: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let mut assembler = PayloadAssembler::new(payload.len());
+
+        let mut chunks = payload.chunks(1000);
+        assert_eq!(assembler.push(chunks.next().unwrap()), Ok(None));
+        assert_eq!(assembler.push(chunks.next().unwrap()), Ok(None));
+        assert_eq!(assembler.push(chunks.next().unwrap()), Ok(Some(payload)));
+    }
+
+    #[test]
+    fn test_pcm_round_trip() {
+        let samples: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.37).sin() * 0.9).collect();
+        let mut edge_cases = vec![0.0, -0.0, 1.0, -1.0, f32::MIN_POSITIVE, 1e-30];
+        edge_cases.extend(&samples);
+
+        let bytes = encode_pcm_f32le(&edge_cases);
+        assert_eq!(bytes.len(), edge_cases.len() * 4);
+        let decoded = decode_pcm_f32le(&bytes);
+        // Bit-exact, not just approximately equal
+        let bits = |s: &[f32]| s.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&decoded), bits(&edge_cases));
+
+        // 16-bit is lossy, but only by quantization
+        let decoded = decode_pcm_s16le(&encode_pcm_s16le(&samples));
+        assert_eq!(decoded.len(), samples.len());
+        for (a, b) in decoded.iter().zip(&samples) {
+            assert!((a - b).abs() <= 1.0 / 32767.0);
+        }
+    }
+
+    #[test]
+    fn test_audio_format_names() {
+        assert_eq!(serde_json::to_string(&AudioFormat::PcmF32LE).unwrap(), "\"pcm_f32le\"");
+        assert_eq!(serde_json::to_string(&AudioFormat::Mulaw).unwrap(), "\"mulaw\"");
+        assert_eq!(AudioFormat::Alaw.sample_rate(), TELEPHONY_SAMPLE_RATE);
+        let req: SynthesizeRequest = serde_json::from_str(r#"{"text": "Hi"}"#).unwrap();
+        assert_eq!(req.format, AudioFormat::Wav);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(SynthesizeRequest::new("Hi").validate(), Ok(()));
+        assert!(SynthesizeRequest::new("Hi").with_tempo(3.0).validate().unwrap_err().contains("tempo"));
+        assert!(SynthesizeRequest::new("Hi").with_target_duration_secs(0.0).validate().is_err());
+        let bad_band = FilterSpec {
+            high_pass_hz: Some(3400.0),
+            low_pass_hz: Some(300.0),
+        };
+        assert!(SynthesizeRequest::new("Hi").with_filter(bad_band).validate().is_err());
+        assert!(SynthesizeRequest::new("Hi").with_filter(FilterSpec::telephony()).validate().is_ok());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_ne!(crc32(b"RIFF audio"), crc32(b"RIFF audjo"));
+    }
+
+    #[test]
+    fn test_payload_progress() {
+        let mut assembler = PayloadAssembler::new(10);
+        assert!(assembler.push(&[0; 4]).unwrap().is_none());
+        assert_eq!((assembler.received(), assembler.expected()), (4, 10));
+    }
+
+    #[test]
+    fn test_payload_overflow() {
+        let mut assembler = PayloadAssembler::new(10);
+        assert_eq!(assembler.push(&[0; 6]), Ok(None));
+        assert_eq!(
+            assembler.push(&[0; 6]),
+            Err(PayloadOverflow {
+                expected: 10,
+                received: 12
+            })
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Sentence segmentation shared by the server and any client that wants to
+//! know how text will be split before it is synthesized.
+//!
+//! Each sentence is synthesized separately, so where the boundaries fall
+//! decides both prosody and which fragments reach the model.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// English abbreviations (lowercase, without the final period) that don't
+/// end a sentence
+const ENGLISH_ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "a.m", "p.m", "prof", "sr", "jr", "st", "mt", "vs", "e.g", "i.e",
+    "etc", "approx", "no", "vol", "fig", "inc", "ltd", "co", "jan", "feb", "mar", "apr", "jun",
+    "jul", "aug", "sep", "sept", "oct", "nov", "dec",
+];
+
+/// Split `text` into sentences, keeping each sentence's terminal punctuation.
+///
+/// Boundaries come from Unicode sentence segmentation (UAX #29), so CJK full
+/// stops, Arabic question marks and the like end a sentence in any script and
+/// a `.` inside a URL or decimal doesn't. On top of that, a `.` after an
+/// abbreviation, an initial or a list number doesn't end a sentence, and a
+/// line break without terminal punctuation is treated as wrapping unless a
+/// blank line or a list item follows. Fragments with nothing speakable (no
+/// letters or digits) are dropped.
+///
+/// `lang` selects the abbreviation list; only English has one so far.
+pub fn segment_sentences(text: &str, lang: &str) -> Vec<String> {
+    let abbreviations: &[&str] = if lang.starts_with("en") {
+        ENGLISH_ABBREVIATIONS
+    } else {
+        &[]
+    };
+
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+
+    let mut segments = text
+        .split_sentence_bounds()
+        .flat_map(split_after_cjk_terminators)
+        .peekable();
+    while let Some(segment) = segments.next() {
+        current.push_str(segment);
+        let open = segments
+            .peek()
+            .is_some_and(|next| continues_into(&current, next, abbreviations));
+        if !open {
+            push_sentence(&mut sentences, &mut current);
+        }
+    }
+    sentences
+}
+
+/// Tidy the spacing of extracted text (PDF, EPUB) before it's split:
+/// runs of spaces and tabs become one space, the lines of a paragraph are
+/// joined, and paragraphs are separated by exactly one blank line. A line
+/// break before a list item is kept, so lists still split per item.
+pub fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank_run = false;
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            blank_run = true;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank_run {
+                "\n\n"
+            } else if starts_list_item(line) {
+                "\n"
+            } else {
+                " "
+            });
+        }
+        blank_run = false;
+        out.push_str(&words.join(" "));
+    }
+    out
+}
+
+/// Words that start a new clause, where a long sentence can be split
+const CONJUNCTIONS: &[&str] = &[
+    "and", "but", "or", "nor", "so", "yet", "because", "while", "whereas", "although", "though", "which",
+    "when", "then",
+];
+
+/// [`segment_sentences`], with any sentence longer than `max_chars`
+/// characters split further by [`split_long_sentence`]
+pub fn segment_sentences_within(text: &str, lang: &str, max_chars: usize) -> Vec<String> {
+    segment_sentences(text, lang)
+        .iter()
+        .flat_map(|sentence| split_long_sentence(sentence, max_chars))
+        .collect()
+}
+
+/// Split a sentence longer than `max_chars` characters into pieces of at
+/// most that many, so run-ons and unpunctuated input don't overwhelm the
+/// model.
+///
+/// Each cut is made at the last clause boundary that fits (after a comma,
+/// semicolon, colon or dash, or before a conjunction such as "and"), as long
+/// as that keeps at least a third of the limit; otherwise at the last word
+/// boundary, and only for a single word longer than the limit in the middle
+/// of a word.
+pub fn split_long_sentence(sentence: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut pieces = Vec::new();
+    let mut rest = sentence.trim();
+    while rest.chars().count() > max_chars {
+        let limit = rest.char_indices().nth(max_chars).map_or(rest.len(), |(i, _)| i);
+        let mut clause_cut = None;
+        let mut word_cut = None;
+        for (i, c) in rest.char_indices().take_while(|&(i, _)| i <= limit) {
+            if !c.is_whitespace() || i == 0 {
+                continue;
+            }
+            word_cut = Some(i);
+            let before = rest[..i].trim_end();
+            let next_word = rest[i..].split_whitespace().next().unwrap_or("").to_lowercase();
+            let at_clause = before.ends_with([',', ';', ':', '—', '–'])
+                || CONJUNCTIONS.contains(&next_word.as_str());
+            if at_clause && before.chars().count() >= max_chars / 3 {
+                clause_cut = Some(i);
+            }
+        }
+        let cut = clause_cut.or(word_cut).unwrap_or(limit);
+        let piece = rest[..cut].trim_end();
+        if !piece.is_empty() {
+            pieces.push(piece.to_string());
+        }
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest.to_string());
+    }
+    pieces
+}
+
+fn push_sentence(sentences: &mut Vec<String>, current: &mut String) {
+    let sentence = current.trim();
+    if sentence.chars().any(char::is_alphanumeric) {
+        sentences.push(sentence.to_string());
+    }
+    current.clear();
+}
+
+/// UAX #29 keeps an opening bracket with the sentence before it ("？「"), but
+/// in CJK text every full stop ends a sentence
+fn split_after_cjk_terminators(segment: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut chars = segment.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if !is_cjk_terminator(c) {
+            continue;
+        }
+        let mut end = i + c.len_utf8();
+        while let Some(&(j, next)) = chars.peek() {
+            if !is_terminator(next) && !is_closing(next) {
+                break;
+            }
+            end = j + next.len_utf8();
+            chars.next();
+        }
+        pieces.push(&segment[start..end]);
+        start = end;
+    }
+    if start < segment.len() {
+        pieces.push(&segment[start..]);
+    }
+    pieces
+}
+
+/// Whether a UAX #29 boundary between `current` and `next` should be ignored
+fn continues_into(current: &str, next: &str, abbreviations: &[&str]) -> bool {
+    let sentence = current.trim_end().trim_end_matches(is_closing);
+    match sentence.chars().last() {
+        Some('.') => ends_with_abbreviation(sentence, abbreviations),
+        Some(c) if is_terminator(c) => false,
+        // Only a line break ended it
+        _ => !next.trim_start_matches([' ', '\t']).starts_with(['\n', '\r']) && !starts_list_item(next),
+    }
+}
+
+fn is_terminator(c: char) -> bool {
+    matches!(c, '.' | '!' | '?' | '…' | '؟' | '۔') || is_cjk_terminator(c)
+}
+
+fn is_cjk_terminator(c: char) -> bool {
+    matches!(c, '。' | '！' | '？' | '｡')
+}
+
+fn is_closing(c: char) -> bool {
+    matches!(c, '"' | '\'' | ')' | ']' | '»' | '”' | '’' | '」' | '』' | '）')
+}
+
+/// Whether the word before the final period is an abbreviation, a single
+/// initial ("J.") or a list number ("2.") rather than the end of a sentence
+fn ends_with_abbreviation(sentence: &str, abbreviations: &[&str]) -> bool {
+    let word = sentence
+        .trim_end_matches(|c: char| is_terminator(c) || is_closing(c))
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or("")
+        .trim_start_matches(['(', '"', '\'', '“', '‘']);
+
+    let mut letters = word.chars();
+    let is_initial = matches!((letters.next(), letters.next()), (Some(c), None) if c.is_uppercase() && c != 'I');
+    let is_list_number = !word.is_empty()
+        && word.chars().all(|c| c.is_ascii_digit())
+        && sentence.trim_start().len() == word.len() + 1;
+
+    is_initial || is_list_number || abbreviations.contains(&word.to_lowercase().as_str())
+}
+
+/// Whether a line starts with a list marker such as "2. ", "3) " or "- "
+fn starts_list_item(line: &str) -> bool {
+    let line = line.trim_start_matches([' ', '\t']);
+    if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("• ") {
+        return true;
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    digits > 0 && {
+        let rest = &line[digits..];
+        rest.starts_with(". ") || rest.starts_with(") ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_sentence_split_at_words() {
+        let words = ["lorem", "ipsum", "dolor", "sit", "amet", "consectetur", "adipiscing", "elit"];
+        let mut sentence = String::new();
+        while sentence.len() < 5000 {
+            sentence.push_str(words[sentence.len() % words.len()]);
+            sentence.push(' ');
+        }
+        let sentence = sentence.trim_end();
+
+        let pieces = split_long_sentence(sentence, 200);
+        assert!(pieces.len() >= 5000 / 200, "{} pieces", pieces.len());
+        for piece in &pieces {
+            assert!(piece.chars().count() <= 200, "{:?}", piece);
+            assert!(piece.split(' ').all(|word| words.contains(&word)), "{:?}", piece);
+        }
+        // Most of each piece is used: it's cut near the limit
+        assert!(pieces[..pieces.len() - 1].iter().all(|piece| piece.len() > 180));
+        assert_eq!(pieces.join(" "), sentence);
+    }
+
+    #[test]
+    fn test_long_sentence_split_at_clauses() {
+        let sentence = "The rain fell on the roofs of the town, the wind rose over the hills and the rivers ran high";
+        assert_eq!(
+            split_long_sentence(sentence, 60),
+            vec!["The rain fell on the roofs of the town,", "the wind rose over the hills and the rivers ran high"]
+        );
+        // Short sentences are untouched, and a word longer than the limit is cut
+        assert_eq!(
+            split_long_sentence(sentence, 35),
+            vec!["The rain fell on the roofs of the", "town, the wind rose over the hills", "and the rivers ran high"]
+        );
+        assert_eq!(split_long_sentence("Short one.", 60), vec!["Short one."]);
+        assert_eq!(split_long_sentence("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+
+        let sentences = segment_sentences_within("First sentence. A much longer, rambling second sentence.", "en-us", 30);
+        assert_eq!(sentences, vec!["First sentence.", "A much longer,", "rambling second sentence."]);
+    }
+
+    #[test]
+    fn test_segment_sentences() {
+        let cases: &[(&str, &[&str])] = &[
+            ("One. Two.", &["One.", "Two."]),
+            ("Really?! Yes... maybe. No.", &["Really?!", "Yes... maybe.", "No."]),
+            (
+                "He said \"stop.\" Then left.",
+                &["He said \"stop.\"", "Then left."],
+            ),
+            // Abbreviations and initials
+            (
+                "Dr. Smith met Mrs. Jones at 5 p.m. today. It went well.",
+                &["Dr. Smith met Mrs. Jones at 5 p.m. today.", "It went well."],
+            ),
+            (
+                "J. R. R. Tolkien wrote it.",
+                &["J. R. R. Tolkien wrote it."],
+            ),
+            (
+                "Bring fruit, e.g. apples. Thanks.",
+                &["Bring fruit, e.g. apples.", "Thanks."],
+            ),
+            // URLs, decimals and version numbers stay whole
+            (
+                "Visit example.com/a.html today. Pi is 3.14.",
+                &["Visit example.com/a.html today.", "Pi is 3.14."],
+            ),
+            // Numbered and bulleted lists
+            (
+                "Steps:\n1. Mix the flour\n2. Add water\n- Bake",
+                &["Steps:", "1. Mix the flour", "2. Add water", "- Bake"],
+            ),
+            (
+                "1. First item. 2. Second item.",
+                &["1. First item.", "2. Second item."],
+            ),
+            // CJK punctuation needs no following space
+            (
+                "今日は晴れです。明日は雨！本当？",
+                &["今日は晴れです。", "明日は雨！", "本当？"],
+            ),
+            // Emoji are kept, but emoji-only fragments aren't speakable
+            (
+                "Great job 🎉! See you 👋.",
+                &["Great job 🎉!", "See you 👋."],
+            ),
+            ("🎉🎉. Hi.", &["Hi."]),
+            // Nothing speakable at all
+            ("...", &[]),
+            ("", &[]),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                segment_sentences(input, "en-us"),
+                *expected,
+                "input: {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        assert_eq!(collapse_whitespace("Too  many   spaces.\tAnd\t\ttabs."), "Too many spaces. And tabs.");
+        assert_eq!(collapse_whitespace("  \n\nPadded.  \n\n"), "Padded.");
+
+        // A paragraph wrapped over three lines is rejoined; blank lines,
+        // however many, separate paragraphs
+        let extracted = "The quick brown\r\nfox jumps over\r\nthe lazy dog.\n\n\n  \nNext  paragraph";
+        assert_eq!(
+            collapse_whitespace(extracted),
+            "The quick brown fox jumps over the lazy dog.\n\nNext paragraph"
+        );
+        assert_eq!(
+            segment_sentences(&collapse_whitespace(extracted), "en"),
+            vec!["The quick brown fox jumps over the lazy dog.", "Next paragraph"]
+        );
+
+        // List items stay on their own lines
+        assert_eq!(collapse_whitespace("Steps:\n1. Mix\n2.  Bake"), "Steps:\n1. Mix\n2. Bake");
+    }
+
+    #[test]
+    fn test_japanese_splits_on_full_stops() {
+        let paragraph = "今日は晴れです。明日は雨が降るでしょう！本当ですか？「はい。」";
+        assert_eq!(
+            segment_sentences(paragraph, "ja"),
+            vec!["今日は晴れです。", "明日は雨が降るでしょう！", "本当ですか？", "「はい。」"]
+        );
+    }
+
+    #[test]
+    fn test_arabic_keeps_every_character() {
+        let paragraph = "مرحبا بالعالم. كيف حالك؟ أنا بخير، شكرا لك.";
+        let sentences = segment_sentences(paragraph, "ar");
+        assert_eq!(sentences.len(), 3);
+        assert_eq!(sentences[1], "كيف حالك؟");
+
+        let without_spaces = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        assert_eq!(without_spaces(&sentences.concat()), without_spaces(paragraph));
+    }
+
+    #[test]
+    fn test_abbreviations_are_english_only() {
+        assert_eq!(segment_sentences("Dr. Smith.", "en-gb"), vec!["Dr. Smith."]);
+        assert_eq!(
+            segment_sentences("Dr. Smith.", "fr-fr"),
+            vec!["Dr.", "Smith."]
+        );
+    }
+}
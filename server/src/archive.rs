@@ -0,0 +1,197 @@
+//! Copies of every synthesized clip on disk, for kiosk and logging setups
+//!
+//! Like the audit log, clips are handed to a background task over a channel
+//! so the response never waits on the disk; if the channel fills up, clips
+//! are dropped with a warning. After each write the oldest clips are deleted
+//! until the directory is back within its size budget.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use sirius_protocol::AudioFormat;
+
+/// Clips queued before new ones are dropped
+const QUEUE_LEN: usize = 64;
+
+/// Size budget when `SIRIUS_ARCHIVE_MAX_MB` isn't set
+const DEFAULT_MAX_MB: u64 = 1024;
+
+/// One clip waiting to be written
+#[derive(Debug)]
+struct Clip {
+    data: Vec<u8>,
+    format: AudioFormat,
+    timestamp_ms: u64,
+}
+
+/// Handle for queueing clips; cheap to clone
+#[derive(Debug, Clone)]
+pub struct Archive {
+    sender: mpsc::Sender<Clip>,
+}
+
+impl Archive {
+    /// Create `dir` if needed and start the writer task. Once the clips in
+    /// `dir` add up to more than `max_bytes`, the oldest are deleted; `None`
+    /// never deletes anything.
+    pub async fn open(dir: impl AsRef<Path>, max_bytes: Option<u64>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("creating archive directory {}", dir.display()))?;
+
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write_clips(dir, max_bytes, receiver));
+        Ok(Self { sender })
+    }
+
+    /// The archive in `SIRIUS_ARCHIVE_DIR`, if set, limited to
+    /// `SIRIUS_ARCHIVE_MAX_MB` (0 for no limit)
+    pub async fn from_env() -> Result<Option<Self>> {
+        let Ok(dir) = std::env::var("SIRIUS_ARCHIVE_DIR") else {
+            return Ok(None);
+        };
+        let max_mb = match std::env::var("SIRIUS_ARCHIVE_MAX_MB") {
+            Ok(value) => value
+                .parse::<u64>()
+                .map_err(|_| anyhow::anyhow!("SIRIUS_ARCHIVE_MAX_MB must be a whole number of MiB"))?,
+            Err(_) => DEFAULT_MAX_MB,
+        };
+        let max_bytes = (max_mb > 0).then_some(max_mb * 1024 * 1024);
+        Ok(Some(Self::open(dir, max_bytes).await?))
+    }
+
+    /// Queue a copy of `data`, stamped with the current time. Never waits.
+    pub fn save(&self, data: &[u8], format: AudioFormat) {
+        let clip = Clip {
+            data: data.to_vec(),
+            format,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        };
+        if self.sender.try_send(clip).is_err() {
+            warn!("Archive queue full or closed; dropping a clip");
+        }
+    }
+}
+
+/// File extension for clips in `format`
+fn extension(format: AudioFormat) -> &'static str {
+    match format {
+        AudioFormat::Wav => "wav",
+        AudioFormat::PcmF32LE => "f32le",
+        AudioFormat::PcmS16LE => "s16le",
+        AudioFormat::Mulaw => "mulaw",
+        AudioFormat::Alaw => "alaw",
+    }
+}
+
+/// Write clips as they arrive, pruning after each one
+async fn write_clips(dir: PathBuf, max_bytes: Option<u64>, mut receiver: mpsc::Receiver<Clip>) {
+    // Clips from the same millisecond are told apart by a sequence number
+    let mut sequence = 0u32;
+    while let Some(clip) = receiver.recv().await {
+        // Zero-padded, so names sort in the order the clips were made
+        let name = format!("{:013}-{:04}.{}", clip.timestamp_ms, sequence, extension(clip.format));
+        sequence = (sequence + 1) % 10_000;
+        if let Err(e) = tokio::fs::write(dir.join(&name), &clip.data).await {
+            error!("Failed to archive {}: {}", name, e);
+            continue;
+        }
+        if let Some(max_bytes) = max_bytes {
+            if let Err(e) = prune(&dir, max_bytes).await {
+                error!("Failed to prune archive {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+/// Delete the oldest files in `dir` until the rest add up to at most
+/// `max_bytes`; the newest is always kept. Returns how many were deleted.
+pub async fn prune(dir: &Path, max_bytes: u64) -> Result<usize> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            files.push((entry.file_name(), metadata.len()));
+        }
+    }
+    files.sort();
+
+    let mut total: u64 = files.iter().map(|(_, len)| len).sum();
+    let mut deleted = 0;
+    for (name, len) in &files[..files.len().saturating_sub(1)] {
+        if total <= max_bytes {
+            break;
+        }
+        tokio::fs::remove_file(dir.join(name)).await?;
+        total -= len;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sirius-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_clips_are_archived() {
+        let dir = temp_dir("archive");
+        let archive = Archive::open(&dir, None).await.unwrap();
+        archive.save(b"RIFF fake wav", AudioFormat::Wav);
+        archive.save(&[0; 8], AudioFormat::PcmS16LE);
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while file_names(&dir).len() < 2 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let names = file_names(&dir);
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[0].ends_with(".wav") && names[1].ends_with(".s16le"), "{:?}", names);
+        assert_eq!(std::fs::read(dir.join(&names[0])).unwrap(), b"RIFF fake wav");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prune_removes_the_oldest() {
+        let dir = temp_dir("archive-prune");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["0000000000001-0000.wav", "0000000000002-0000.wav", "0000000000003-0000.wav"] {
+            std::fs::write(dir.join(name), [0; 100]).unwrap();
+        }
+
+        assert_eq!(prune(&dir, 300).await.unwrap(), 0);
+        assert_eq!(prune(&dir, 250).await.unwrap(), 1);
+        assert_eq!(file_names(&dir), vec!["0000000000002-0000.wav", "0000000000003-0000.wav"]);
+
+        // Even over budget on its own, the newest clip stays
+        assert_eq!(prune(&dir, 10).await.unwrap(), 1);
+        assert_eq!(file_names(&dir), vec!["0000000000003-0000.wav"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
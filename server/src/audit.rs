@@ -0,0 +1,147 @@
+//! Append-only JSONL audit log of synthesize requests
+//!
+//! Separate from the tracing output: one JSON object per line, for analysis
+//! after the fact. Records are handed to a background task over a channel,
+//! so a slow disk never holds up synthesis; if the channel fills up, records
+//! are dropped with a warning rather than waiting.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+/// How often buffered records are flushed to disk
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records queued before new ones are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// How a synthesize request ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Audio was sent
+    Ok,
+    /// The engine was asked and failed
+    Failed,
+    /// Turned away because the queue was full
+    QueueFull,
+    /// Turned away because the circuit breaker was open
+    BreakerOpen,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch when the request finished
+    pub timestamp_ms: u64,
+    pub peer: String,
+    pub voice: String,
+    /// Characters of input text
+    pub chars: usize,
+    /// Seconds of audio produced (0 on failure)
+    pub duration_secs: f32,
+    /// Time from receiving the request to sending the last reply message
+    pub latency_ms: u64,
+    /// Whether `outcome` is `Ok`
+    pub success: bool,
+    pub outcome: Outcome,
+}
+
+impl AuditRecord {
+    pub fn new(peer: impl Display, voice: &str, chars: usize) -> Self {
+        Self {
+            timestamp_ms: 0,
+            peer: peer.to_string(),
+            voice: voice.to_string(),
+            chars,
+            duration_secs: 0.0,
+            latency_ms: 0,
+            success: false,
+            outcome: Outcome::Failed,
+        }
+    }
+
+    pub fn set_outcome(&mut self, outcome: Outcome) {
+        self.outcome = outcome;
+        self.success = outcome == Outcome::Ok;
+    }
+}
+
+/// Handle for queueing audit records; cheap to clone
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    sender: mpsc::Sender<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Open `path` for appending and start the writer task
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("opening audit log {}", path.display()))?;
+
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write_records(BufWriter::new(file), receiver));
+        Ok(Self { sender })
+    }
+
+    /// The log named by `SIRIUS_AUDIT_LOG`, if set
+    pub async fn from_env() -> Result<Option<Self>> {
+        match std::env::var("SIRIUS_AUDIT_LOG") {
+            Ok(path) => Ok(Some(Self::open(path).await?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Queue `record`, stamping it with the current time. Never waits.
+    pub fn record(&self, mut record: AuditRecord) {
+        record.timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        if self.sender.try_send(record).is_err() {
+            warn!("Audit log queue full or closed; dropping a record");
+        }
+    }
+}
+
+/// Write records as they arrive, flushing every `FLUSH_INTERVAL` and once
+/// every sender is gone
+async fn write_records(mut writer: BufWriter<tokio::fs::File>, mut receiver: mpsc::Receiver<AuditRecord>) {
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            record = receiver.recv() => {
+                let Some(record) = record else { break };
+                let mut line = match serde_json::to_vec(&record) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("Failed to serialize audit record: {}", e);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                if let Err(e) = writer.write_all(&line).await {
+                    error!("Failed to write audit log: {}", e);
+                }
+            }
+            _ = flush.tick() => {
+                if let Err(e) = writer.flush().await {
+                    error!("Failed to flush audit log: {}", e);
+                }
+            }
+        }
+    }
+    if let Err(e) = writer.flush().await {
+        error!("Failed to flush audit log: {}", e);
+    }
+}
@@ -0,0 +1,171 @@
+//! Circuit breaker around the engine
+//!
+//! When the backend starts failing on every request (out of GPU memory, a
+//! wedged runtime), letting each request try anyway only piles them up
+//! behind the engine lock. After `failures` engine errors within `window`
+//! the breaker opens, and requests are turned away as `busy` straight off.
+//! Once `cooldown` has passed, one request is let through to test the
+//! engine; its outcome closes the breaker or opens it again.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+/// Window when `SIRIUS_BREAKER_WINDOW_SECS` isn't set
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Cooldown when `SIRIUS_BREAKER_COOLDOWN_SECS` isn't set
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+enum State {
+    /// Requests go through; these are the recent failures, oldest first
+    Closed { failures: VecDeque<Instant> },
+    /// Requests are refused until then
+    Open { until: Instant },
+    /// One trial request is running; the rest are refused
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failures: usize,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// Open after `failures` engine errors within `window`, for `cooldown`
+    pub fn new(failures: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            failures: failures.max(1),
+            window,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: VecDeque::new() }),
+        }
+    }
+
+    /// The breaker `SIRIUS_BREAKER_FAILURES` asks for, if set and not 0,
+    /// with `SIRIUS_BREAKER_WINDOW_SECS` and `SIRIUS_BREAKER_COOLDOWN_SECS`
+    pub fn from_env() -> Result<Option<Self>> {
+        let failures = match std::env::var("SIRIUS_BREAKER_FAILURES") {
+            Ok(value) => value
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("SIRIUS_BREAKER_FAILURES must be a whole number of failures"))?,
+            Err(_) => return Ok(None),
+        };
+        if failures == 0 {
+            return Ok(None);
+        }
+        let secs = |name: &str, default: Duration| match std::env::var(name) {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+                _ => Err(anyhow::anyhow!("{} must be a positive number of seconds", name)),
+            },
+            Err(_) => Ok(default),
+        };
+        Ok(Some(Self::new(
+            failures,
+            secs("SIRIUS_BREAKER_WINDOW_SECS", DEFAULT_WINDOW)?,
+            secs("SIRIUS_BREAKER_COOLDOWN_SECS", DEFAULT_COOLDOWN)?,
+        )))
+    }
+
+    /// Whether a request may use the engine now. A `true` must be followed
+    /// by `record` once the request is done.
+    pub fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    /// Note how a request that was allowed went; only engine failures
+    /// count against the engine
+    pub fn record(&self, engine_failed: bool) {
+        self.record_at(Instant::now(), engine_failed)
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    fn record_at(&self, now: Instant, engine_failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !engine_failed {
+            if matches!(*state, State::HalfOpen) {
+                info!("Circuit breaker closed; the engine recovered");
+            }
+            *state = State::Closed { failures: VecDeque::new() };
+            return;
+        }
+        match &mut *state {
+            State::Closed { failures } => {
+                failures.push_back(now);
+                while failures.front().is_some_and(|&first| now.duration_since(first) > self.window) {
+                    failures.pop_front();
+                }
+                if failures.len() >= self.failures {
+                    warn!(
+                        "Circuit breaker open after {} engine failures; refusing requests for {:?}",
+                        failures.len(),
+                        self.cooldown
+                    );
+                    *state = State::Open { until: now + self.cooldown };
+                }
+            }
+            State::HalfOpen => {
+                warn!("Engine still failing; circuit breaker open for another {:?}", self.cooldown);
+                *state = State::Open { until: now + self.cooldown };
+            }
+            // A request from before the breaker opened
+            State::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_states() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10), Duration::from_secs(5));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // Failures too far apart never add up
+        for secs in [0, 11, 22] {
+            assert!(breaker.allow_at(at(secs)));
+            breaker.record_at(at(secs), true);
+        }
+        assert!(breaker.allow_at(at(23)));
+
+        // Three within the window open it for the cooldown
+        breaker.record_at(at(23), true);
+        breaker.record_at(at(24), true);
+        assert!(!breaker.allow_at(at(25)));
+        assert!(!breaker.allow_at(at(28)));
+
+        // Then one trial goes through; failing it reopens the breaker
+        assert!(breaker.allow_at(at(29)));
+        assert!(!breaker.allow_at(at(29)));
+        breaker.record_at(at(30), true);
+        assert!(!breaker.allow_at(at(34)));
+
+        // A successful trial closes it, forgetting the old failures
+        assert!(breaker.allow_at(at(35)));
+        breaker.record_at(at(35), false);
+        breaker.record_at(at(36), true);
+        assert!(breaker.allow_at(at(36)));
+    }
+}
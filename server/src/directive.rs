@@ -0,0 +1,115 @@
+//! Inline `{voice=...}` and `{speed=...}` directives
+//!
+//! A directive changes the voice or speed from that point until the next
+//! directive for the same setting, so one request can switch readers
+//! mid-text: `{voice=af_bella.10}She said {speed=1.2}"hello"`. Braces that
+//! aren't one of these two directives are left in the text.
+
+use anyhow::Result;
+
+use crate::tts::SynthesisError;
+
+/// A run of text read with the same settings; `None` means the request's own
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span<'a> {
+    pub text: &'a str,
+    pub voice: Option<&'a str>,
+    pub speed: Option<f32>,
+}
+
+/// Split `text` at each directive, dropping the directives themselves.
+///
+/// Spans are returned even if they're empty or only whitespace; a speed that
+/// isn't a positive number or an empty voice is `SynthesisError::InvalidParameter`.
+pub fn parse_directives(text: &str) -> Result<Vec<Span<'_>>> {
+    let mut spans = Vec::new();
+    let mut current = Span {
+        text: "",
+        voice: None,
+        speed: None,
+    };
+    let mut start = 0;
+    let mut search = 0;
+
+    while let Some(open) = text[search..].find('{').map(|i| search + i) {
+        let Some(close) = text[open..].find('}').map(|i| open + i) else {
+            break;
+        };
+        let Some((key, value)) = text[open + 1..close].split_once('=') else {
+            search = open + 1;
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        let mut next = current.clone();
+        match key {
+            "voice" if value.is_empty() => return invalid("voice directive needs a voice"),
+            "voice" => next.voice = Some(value),
+            "speed" => match value.parse::<f32>() {
+                Ok(speed) if speed > 0.0 && speed.is_finite() => next.speed = Some(speed),
+                _ => return invalid(&format!("speed directive {:?} is not a positive number", value)),
+            },
+            _ => {
+                search = open + 1;
+                continue;
+            }
+        }
+
+        current.text = &text[start..open];
+        spans.push(current);
+        current = next;
+        start = close + 1;
+        search = start;
+    }
+
+    current.text = &text[start..];
+    spans.push(current);
+    Ok(spans)
+}
+
+fn invalid<T>(reason: &str) -> Result<T> {
+    Err(SynthesisError::InvalidParameter(reason.to_string()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_directives() {
+        let spans = parse_directives("{voice=af_bella.10}She said {speed=1.2}\"hello\"").unwrap();
+        assert_eq!(
+            spans,
+            vec![
+                Span { text: "", voice: None, speed: None },
+                Span { text: "She said ", voice: Some("af_bella.10"), speed: None },
+                Span { text: "\"hello\"", voice: Some("af_bella.10"), speed: Some(1.2) },
+            ]
+        );
+
+        // No directives: one span with the request's settings
+        assert_eq!(
+            parse_directives("Plain text.").unwrap(),
+            vec![Span { text: "Plain text.", voice: None, speed: None }]
+        );
+    }
+
+    #[test]
+    fn test_other_braces_are_text() {
+        let text = "Use {name} or {x=1}, and { unclosed";
+        let spans = parse_directives(text).unwrap();
+        assert_eq!(spans, vec![Span { text, voice: None, speed: None }]);
+    }
+
+    #[test]
+    fn test_invalid_directives() {
+        for text in ["{speed=fast}Hi", "{speed=-1}Hi", "{voice=}Hi"] {
+            let err = parse_directives(text).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<SynthesisError>(), Some(SynthesisError::InvalidParameter(_))),
+                "input: {:?}",
+                text
+            );
+        }
+    }
+}
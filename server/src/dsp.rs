@@ -0,0 +1,433 @@
+//! Post-synthesis signal processing on mono f32 samples at the protocol rate
+//!
+//! These run after the model, so unlike `speed` (which the model uses to
+//! change prosody) they alter the audio itself.
+
+/// WSOLA frame length: 40ms at 24kHz
+const FRAME_LEN: usize = 960;
+
+/// Synthesis hop; frames overlap by half
+const HOP: usize = FRAME_LEN / 2;
+
+/// How far (in samples) each analysis frame may move to line up with the
+/// previous one: 10ms at 24kHz
+const SEARCH_RADIUS: usize = 240;
+
+/// Pitch-preserving time stretch (WSOLA).
+///
+/// `tempo` > 1.0 plays faster (fewer samples), < 1.0 slower; the output has
+/// about `samples.len() / tempo` samples. Each frame is taken from near its
+/// nominal input position, shifted to the offset whose start best matches
+/// how the previous frame would have continued, then overlap-added under a
+/// Hann window.
+pub fn time_stretch(samples: &[f32], tempo: f32) -> Vec<f32> {
+    if (tempo - 1.0).abs() < f32::EPSILON || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let out_len = (samples.len() as f64 / tempo as f64).round() as usize;
+    let window = hann(FRAME_LEN);
+    let at = |i: usize| samples.get(i).copied().unwrap_or(0.0);
+
+    let mut output = vec![0.0f32; out_len + FRAME_LEN];
+    let mut weight = vec![0.0f32; out_len + FRAME_LEN];
+    let mut prev_pos: Option<usize> = None;
+
+    for frame in 0.. {
+        let out_pos = frame * HOP;
+        if out_pos >= out_len {
+            break;
+        }
+
+        let nominal = (frame as f64 * HOP as f64 * tempo as f64) as usize;
+        let pos = match prev_pos {
+            None => 0,
+            Some(prev) => {
+                // What the previous frame would have played next
+                let natural = prev + HOP;
+                let lo = nominal.saturating_sub(SEARCH_RADIUS);
+                let hi = nominal + SEARCH_RADIUS;
+                (lo..=hi)
+                    .map(|p| (p, (0..HOP).map(|i| at(p + i) * at(natural + i)).sum::<f32>()))
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .map_or(nominal, |(p, _)| p)
+            }
+        };
+
+        for (i, w) in window.iter().enumerate() {
+            output[out_pos + i] += at(pos + i) * w;
+            weight[out_pos + i] += w;
+        }
+        prev_pos = Some(pos);
+    }
+
+    output.truncate(out_len);
+    for (sample, w) in output.iter_mut().zip(&weight) {
+        if *w > 1e-3 {
+            *sample /= w;
+        }
+    }
+    output
+}
+
+/// Largest pitch shift accepted, in either direction
+pub const MAX_PITCH_SEMITONES: f32 = 12.0;
+
+/// Shift pitch by `semitones` (clamped to ±12) without changing duration:
+/// time-stretch by the pitch ratio, then resample back to the original length.
+pub fn pitch_shift(samples: &[f32], semitones: f32) -> Vec<f32> {
+    let semitones = semitones.clamp(-MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES);
+    if semitones.abs() < f32::EPSILON || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let ratio = 2f32.powf(semitones / 12.0);
+    let stretched = time_stretch(samples, 1.0 / ratio);
+    resample_linear(&stretched, samples.len())
+}
+
+/// Linearly interpolate `samples` to exactly `len` samples
+pub fn resample_linear(samples: &[f32], len: usize) -> Vec<f32> {
+    if samples.is_empty() || len == 0 {
+        return vec![0.0; len];
+    }
+    let step = samples.len() as f64 / len as f64;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let index = pos as usize;
+            let frac = (pos - index as f64) as f32;
+            let a = samples[index.min(samples.len() - 1)];
+            let b = samples[(index + 1).min(samples.len() - 1)];
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+/// Cutoff of the anti-aliasing filter, as a fraction of the target rate
+const DOWNSAMPLE_CUTOFF: f32 = 0.45;
+
+/// Resample protocol-rate `samples` down to `rate`, low-passing first so
+/// content above the new Nyquist frequency doesn't alias
+pub fn downsample(samples: &[f32], rate: u32) -> Vec<f32> {
+    let mut filtered = samples.to_vec();
+    band_limit(&mut filtered, None, Some(rate as f32 * DOWNSAMPLE_CUTOFF));
+    let len = (samples.len() as u64 * rate as u64 / sirius_protocol::SAMPLE_RATE as u64) as usize;
+    resample_linear(&filtered, len)
+}
+
+/// Second-order IIR section (RBJ audio EQ cookbook), Direct Form I
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    /// Low- or high-pass section at `cutoff_hz` with quality factor `q`
+    fn pass(cutoff_hz: f32, q: f32, high_pass: bool) -> Self {
+        let w0 = 2.0 * std::f32::consts::PI * cutoff_hz / sirius_protocol::SAMPLE_RATE as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+
+        let (b0, b1) = if high_pass {
+            ((1.0 + cos) / 2.0, -(1.0 + cos))
+        } else {
+            ((1.0 - cos) / 2.0, 1.0 - cos)
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    fn process(&self, samples: &mut [f32]) {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        for sample in samples.iter_mut() {
+            let x = *sample;
+            let y = self.b0 * x + self.b1 * x1 + self.b2 * x2 - self.a1 * y1 - self.a2 * y2;
+            (x2, x1) = (x1, x);
+            (y2, y1) = (y1, y);
+            *sample = y;
+        }
+    }
+}
+
+/// Section Qs of a 4th-order Butterworth filter (24 dB/octave)
+const BUTTERWORTH_4_Q: [f32; 2] = [0.541_196_1, 1.306_563];
+
+/// Band-limit `samples` in place with optional 4th-order Butterworth
+/// high-pass and low-pass stages; cutoffs must lie between 0 and the Nyquist
+/// frequency.
+pub fn band_limit(samples: &mut [f32], high_pass_hz: Option<f32>, low_pass_hz: Option<f32>) {
+    let stages = [(high_pass_hz, true), (low_pass_hz, false)];
+    for (cutoff, high_pass) in stages {
+        if let Some(cutoff) = cutoff {
+            for q in BUTTERWORTH_4_Q {
+                Biquad::pass(cutoff, q, high_pass).process(samples);
+            }
+        }
+    }
+}
+
+/// Neighbourhood (each side) whose average sample-to-sample change sets the
+/// level an outlier must stand out from
+const CLICK_CONTEXT: usize = 16;
+
+/// How many times the local average change a sample must jump to be a click
+const CLICK_RATIO: f32 = 5.0;
+
+/// Jumps smaller than this are never clicks, however quiet the surroundings
+const CLICK_FLOOR: f32 = 0.05;
+
+/// Gate frame: 10ms at 24kHz
+const GATE_FRAME: usize = 240;
+
+/// Frames quieter than this RMS (about -50 dBFS) count as hiss
+const GATE_THRESHOLD: f32 = 0.003;
+
+/// Light clean-up of synthesis artifacts, in place.
+///
+/// First a de-click: a sample that jumps away from both neighbours far more
+/// than the signal around it moves is replaced by the median of its five
+/// closest samples. Then a noise gate silences 10ms frames that are below
+/// -50 dBFS along with both neighbouring frames, fading over a frame so
+/// quiet word onsets and tails aren't cut. Speech sits well above both
+/// thresholds, so it passes through unchanged.
+pub fn denoise(samples: &mut [f32]) {
+    declick(samples);
+    noise_gate(samples);
+}
+
+/// Scale `samples` in place so the loudest one reaches `peak`; silence is
+/// left alone
+pub fn normalize_peak(samples: &mut [f32], peak: f32) {
+    let loudest = samples.iter().fold(0.0f32, |loudest, sample| loudest.max(sample.abs()));
+    if loudest > 0.0 {
+        let gain = peak / loudest;
+        samples.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+fn declick(samples: &mut [f32]) {
+    if samples.len() < 5 {
+        return;
+    }
+    let input = samples.to_vec();
+    let change: Vec<f32> = input.windows(2).map(|pair| (pair[1] - pair[0]).abs()).collect();
+
+    for i in 2..input.len() - 2 {
+        let jump = (input[i] - (input[i - 1] + input[i + 1]) / 2.0).abs();
+        if jump < CLICK_FLOOR {
+            continue;
+        }
+        let context = &change[i.saturating_sub(CLICK_CONTEXT)..(i + CLICK_CONTEXT).min(change.len())];
+        let average = context.iter().sum::<f32>() / context.len() as f32;
+        if jump > CLICK_RATIO * average {
+            let mut window = [input[i - 2], input[i - 1], input[i], input[i + 1], input[i + 2]];
+            window.sort_by(f32::total_cmp);
+            samples[i] = window[2];
+        }
+    }
+}
+
+fn noise_gate(samples: &mut [f32]) {
+    let quiet: Vec<bool> = samples
+        .chunks(GATE_FRAME)
+        .map(|frame| (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt() < GATE_THRESHOLD)
+        .collect();
+    let gated = |frame: usize| {
+        quiet[frame] && quiet[frame.saturating_sub(1)] && quiet.get(frame + 1).copied().unwrap_or(true)
+    };
+
+    let mut previous_gain = if gated(0) { 0.0 } else { 1.0 };
+    for (frame, chunk) in samples.chunks_mut(GATE_FRAME).enumerate() {
+        let gain = if gated(frame) { 0.0 } else { 1.0 };
+        if gain == 1.0 && previous_gain == 1.0 {
+            continue;
+        }
+        let len = chunk.len() as f32;
+        for (i, sample) in chunk.iter_mut().enumerate() {
+            *sample *= previous_gain + (gain - previous_gain) * (i as f32 + 1.0) / len;
+        }
+        previous_gain = gain;
+    }
+}
+
+fn hann(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| {
+            let phase = 2.0 * std::f32::consts::PI * i as f32 / len as f32;
+            0.5 - 0.5 * phase.cos()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency_hz: f32, len: usize) -> Vec<f32> {
+        let step = 2.0 * std::f32::consts::PI * frequency_hz / sirius_protocol::SAMPLE_RATE as f32;
+        (0..len).map(|i| 0.5 * (i as f32 * step).sin()).collect()
+    }
+
+    #[test]
+    fn test_time_stretch_length() {
+        let input = sine(220.0, 48_000);
+
+        let faster = time_stretch(&input, 2.0);
+        assert_eq!(faster.len(), 24_000);
+
+        let slower = time_stretch(&input, 0.5);
+        assert_eq!(slower.len(), 96_000);
+
+        assert_eq!(time_stretch(&input, 1.0), input);
+    }
+
+    /// Frequency estimate from the rate of upward zero crossings
+    fn dominant_frequency(samples: &[f32]) -> f32 {
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        crossings as f32 * sirius_protocol::SAMPLE_RATE as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn test_pitch_shift_changes_frequency_not_length() {
+        let input = sine(440.0, 24_000);
+        assert!((dominant_frequency(&input) - 440.0).abs() < 5.0);
+
+        let up = pitch_shift(&input, 12.0);
+        assert_eq!(up.len(), input.len());
+        let freq = dominant_frequency(&up);
+        assert!((freq - 880.0).abs() < 30.0, "octave up measured {} Hz", freq);
+
+        let down = pitch_shift(&input, -7.0);
+        assert_eq!(down.len(), input.len());
+        let freq = dominant_frequency(&down);
+        assert!((freq - 293.7).abs() < 20.0, "fifth down measured {} Hz", freq);
+
+        // Out-of-range shifts are clamped to an octave
+        assert_eq!(pitch_shift(&input, 40.0), up);
+    }
+
+    /// Power at `frequency_hz` (Goertzel)
+    fn power_at(samples: &[f32], frequency_hz: f32) -> f32 {
+        let w = 2.0 * std::f32::consts::PI * frequency_hz / sirius_protocol::SAMPLE_RATE as f32;
+        let coeff = 2.0 * w.cos();
+        let (mut s1, mut s2) = (0.0f32, 0.0f32);
+        for &x in samples {
+            let s0 = x + coeff * s1 - s2;
+            (s2, s1) = (s1, s0);
+        }
+        s1 * s1 + s2 * s2 - coeff * s1 * s2
+    }
+
+    #[test]
+    fn test_band_limit_attenuates_outside_band() {
+        // Deterministic white noise
+        let mut state = 12345u32;
+        let noise: Vec<f32> = (0..24_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+
+        let mut filtered = noise.clone();
+        band_limit(&mut filtered, Some(300.0), Some(3400.0));
+
+        let band_power = |s: &[f32], freqs: &[f32]| freqs.iter().map(|&f| power_at(s, f)).sum::<f32>();
+        let above = [8000.0, 10000.0, 11000.0];
+        let below = [50.0, 80.0];
+        let inside = [800.0, 1500.0, 2500.0];
+
+        // At least 20 dB down well outside the band, roughly unchanged inside
+        assert!(band_power(&filtered, &above) < band_power(&noise, &above) / 100.0);
+        assert!(band_power(&filtered, &below) < band_power(&noise, &below) / 10.0);
+        let ratio = band_power(&filtered, &inside) / band_power(&noise, &inside);
+        assert!((0.5..2.0).contains(&ratio), "passband ratio {}", ratio);
+    }
+
+    #[test]
+    fn test_downsample_removes_aliases() {
+        let speech_band = sine(1000.0, 24_000);
+        let above_nyquist = sine(6000.0, 24_000);
+        let down = downsample(&speech_band, 8000);
+        assert_eq!(down.len(), 8000);
+        // Still 1 kHz; the helper assumes the protocol rate, three times 8 kHz
+        assert!((dominant_frequency(&down) / 3.0 - 1000.0).abs() < 15.0);
+
+        // 6 kHz would fold down to 2 kHz; the filter leaves next to nothing
+        let folded = downsample(&above_nyquist, 8000);
+        let rms = |s: &[f32]| (s.iter().map(|x| x * x).sum::<f32>() / s.len() as f32).sqrt();
+        assert!(rms(&folded) < rms(&down) / 10.0, "{} vs {}", rms(&folded), rms(&down));
+    }
+
+    #[test]
+    fn test_denoise_removes_clicks_and_hiss() {
+        // A clean sine passes through untouched
+        let clean = sine(440.0, 24_000);
+        let mut output = clean.clone();
+        denoise(&mut output);
+        assert_eq!(output, clean);
+
+        // Single-sample spikes are replaced by their surroundings
+        let spikes = [1_000, 5_123, 12_345, 20_000];
+        let mut clicked = clean.clone();
+        for (n, &i) in spikes.iter().enumerate() {
+            clicked[i] += if n % 2 == 0 { 0.8 } else { -0.8 };
+        }
+        denoise(&mut clicked);
+        for &i in &spikes {
+            assert!((clicked[i] - clean[i]).abs() < 0.05, "spike at {} left as {}", i, clicked[i]);
+        }
+        let max_error = clicked.iter().zip(&clean).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+        assert!(max_error < 0.05, "max error {}", max_error);
+
+        // Low-level hiss between two tones is gated, the tones are not
+        let mut state = 99u32;
+        let hiss: Vec<f32> = (0..4_800)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                ((state >> 8) as f32 / (1 << 24) as f32 - 0.5) * 0.004
+            })
+            .collect();
+        let tone = sine(300.0, 4_800);
+        let mut gapped = [tone.clone(), hiss, tone.clone()].concat();
+        denoise(&mut gapped);
+        // Frames next to the tones fade rather than cut
+        assert!(gapped[4_800 + 2 * GATE_FRAME..9_600 - GATE_FRAME].iter().all(|&s| s == 0.0));
+        assert_eq!(&gapped[..4_800], &tone[..]);
+        assert_eq!(&gapped[9_600..], &tone[..]);
+    }
+
+    #[test]
+    fn test_normalize_peak() {
+        let mut samples = vec![0.1, -0.25, 0.05];
+        normalize_peak(&mut samples, 0.5);
+        assert_eq!(samples, vec![0.2, -0.5, 0.1]);
+
+        let mut silence = vec![0.0; 4];
+        normalize_peak(&mut silence, 0.5);
+        assert_eq!(silence, vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_time_stretch_keeps_level() {
+        // Aligned frames add up coherently instead of cancelling out
+        let input = sine(220.0, 48_000);
+        let rms = |s: &[f32]| (s.iter().map(|x| x * x).sum::<f32>() / s.len() as f32).sqrt();
+        let stretched = time_stretch(&input, 1.5);
+        let body = &stretched[FRAME_LEN..stretched.len() - FRAME_LEN];
+        assert!((rms(body) - rms(&input)).abs() < 0.05, "rms {}", rms(body));
+    }
+}
@@ -0,0 +1,664 @@
+//! Minimal HTTP front-end for one-shot synthesis
+//!
+//! Serves `GET /synthesize?text=...&voice=...&lang=...&speed=...` and returns
+//! the WAV directly, so plain HTTP clients (e.g. an `<audio>` element) can use
+//! the server without speaking WebSocket. Since the whole WAV is generated in
+//! memory, `Range: bytes=` requests are answered with `206 Partial Content`,
+//! which lets media players seek. `GET /voices` lists the voices as JSON for
+//! web UIs, and `SIRIUS_CORS_ORIGIN` lets pages on another origin use both,
+//! preflight `OPTIONS` requests included. Without it no CORS headers are
+//! sent, so browsers keep to same-origin.
+//!
+//! Synthesis goes through the same queue limit, circuit breaker, audit log
+//! and archive as over WebSocket; a request turned away is `503`.
+//!
+//! With `timings=1`, the sentence timings ride along in an
+//! `X-Sirius-Timings` header as base64 JSON. A header keeps the body plain
+//! WAV, so `<audio>` and `Range` requests work as before; the price is a
+//! size limit (proxies commonly refuse headers past 8 KiB), which makes it
+//! a fit for the short texts HTTP is used for.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use base64::prelude::*;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use sirius_protocol::{ErrorCode, Response, SynthesizeRequest};
+
+use crate::synth::{kokoro_voice_lang, SpeechSynthesizer};
+use crate::tts::{SentenceTiming, TtsEngine};
+use crate::ConnectionConfig;
+
+/// Upper bound on the request head we are willing to buffer
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// How long browsers may cache a preflight answer, in seconds
+const PREFLIGHT_MAX_AGE_SECS: u32 = 600;
+
+/// Header carrying the sentence timings asked for with `timings=1`
+const TIMINGS_HEADER: &str = "X-Sirius-Timings";
+
+/// One entry of the `GET /voices` list
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct VoiceInfo {
+    id: String,
+    /// Language the voice was trained on, when its name tells
+    lang: Option<&'static str>,
+    /// Rate the backend synthesizes the voice at; audio is always sent at
+    /// the protocol rate
+    sample_rate: u32,
+}
+
+/// Accept HTTP connections on `addr` until the listener fails. With a
+/// `cors_origin`, every response allows that origin (`*` for any).
+pub async fn serve<S: SpeechSynthesizer>(
+    addr: String,
+    tts: Arc<Mutex<TtsEngine<S>>>,
+    cors_origin: Option<String>,
+    config: ConnectionConfig,
+) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Sirius HTTP endpoint listening on http://{}", addr);
+    accept_loop(listener, tts, cors_origin, config).await
+}
+
+async fn accept_loop<S: SpeechSynthesizer>(
+    listener: TcpListener,
+    tts: Arc<Mutex<TtsEngine<S>>>,
+    cors_origin: Option<String>,
+    config: ConnectionConfig,
+) -> Result<()> {
+    while let Ok((stream, peer_addr)) = listener.accept().await {
+        let tts = Arc::clone(&tts);
+        let cors_origin = cors_origin.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, peer_addr, tts, cors_origin.as_deref(), &config).await {
+                error!("HTTP error from {}: {}", peer_addr, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_http<S: SpeechSynthesizer>(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    tts: Arc<Mutex<TtsEngine<S>>>,
+    cors_origin: Option<&str>,
+    config: &ConnectionConfig,
+) -> Result<()> {
+    let head = match read_head(&mut stream).await? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut req = httparse::Request::new(&mut headers);
+    let (mut response, preflight) = match req.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {
+            let method = req.method.unwrap_or("");
+            let target = req.path.unwrap_or("/");
+            let range = header_value(req.headers, "range");
+            info!("HTTP {} {} from {}", method, target, peer_addr);
+            (route(method, target, range, &tts, config, peer_addr).await, method == "OPTIONS")
+        }
+        _ => (HttpResponse::text(400, "Bad Request", "malformed request"), false),
+    };
+    if let Some(origin) = cors_origin {
+        response = with_cors(response, origin, preflight);
+    }
+
+    stream.write_all(&response.to_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn route<S: SpeechSynthesizer>(
+    method: &str,
+    target: &str,
+    range: Option<&str>,
+    tts: &Arc<Mutex<TtsEngine<S>>>,
+    config: &ConnectionConfig,
+    peer_addr: SocketAddr,
+) -> HttpResponse {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    if path != "/synthesize" && path != "/voices" {
+        return HttpResponse::text(404, "Not Found", "not found");
+    }
+    if method == "OPTIONS" {
+        return HttpResponse::new(204, "No Content", "text/plain; charset=utf-8", Vec::new())
+            .with_header("Allow", "GET, OPTIONS");
+    }
+    if method != "GET" {
+        return HttpResponse::text(405, "Method Not Allowed", "only GET is supported")
+            .with_header("Allow", "GET, OPTIONS");
+    }
+    if path == "/voices" {
+        let tts = tts.lock().await;
+        let voices = tts.voices().into_iter().map(|id| {
+            let rate = tts.native_sample_rate(&id);
+            (id, rate)
+        });
+        return voices_response(voices.collect());
+    }
+
+    let req = match parse_synthesize_query(query) {
+        Some(req) => req,
+        None => return HttpResponse::text(400, "Bad Request", "missing `text` parameter"),
+    };
+
+    let response = crate::synthesize_guarded(tts, config, peer_addr, &req, |result| async move {
+        Ok(synthesis_response(result, query, range))
+    })
+    .await;
+    response.unwrap_or_else(|e| HttpResponse::text(500, "Internal Server Error", &e.to_string()))
+}
+
+/// The WAV (with its timings if the query asks) for a synthesis, or the
+/// status matching its error
+fn synthesis_response(
+    result: Result<crate::tts::SynthesizedAudio, Response>,
+    query: &str,
+    range: Option<&str>,
+) -> HttpResponse {
+    let audio = match result {
+        Ok(audio) => audio,
+        Err(Response::Error { code, message }) => {
+            warn!("HTTP TTS error: {}", message);
+            return match code {
+                ErrorCode::Internal => HttpResponse::text(500, "Internal Server Error", &message),
+                ErrorCode::Busy => HttpResponse::text(503, "Service Unavailable", &message),
+                _ => HttpResponse::text(400, "Bad Request", &message),
+            };
+        }
+        Err(other) => return HttpResponse::text(500, "Internal Server Error", &format!("{:?}", other)),
+    };
+    let timings = wants_timings(query).then(|| timings_header(&audio.sentence_timings));
+    let response = audio_response(audio.data, range);
+    match timings {
+        Some(Ok(timings)) => response.with_header(TIMINGS_HEADER, &timings),
+        Some(Err(e)) => HttpResponse::text(500, "Internal Server Error", &e.to_string()),
+        None => response,
+    }
+}
+
+/// Allow `origin` to read `response`; a `preflight` answer also says which
+/// methods and headers it may send, `Range` being the one worth sending.
+/// Browsers only read the headers they're told they may.
+fn with_cors(response: HttpResponse, origin: &str, preflight: bool) -> HttpResponse {
+    let response = response
+        .with_header("Access-Control-Allow-Origin", origin)
+        .with_header("Access-Control-Expose-Headers", "Accept-Ranges, Content-Range, X-Sirius-Timings");
+    if !preflight {
+        return response;
+    }
+    response
+        .with_header("Access-Control-Allow-Methods", "GET, OPTIONS")
+        .with_header("Access-Control-Allow-Headers", "Range")
+        .with_header("Access-Control-Max-Age", &PREFLIGHT_MAX_AGE_SECS.to_string())
+}
+
+/// The voice list, with each voice's native sample rate, as a JSON array
+/// of `{"id": ..., "lang": ..., "sample_rate": ...}`
+fn voices_response(voices: Vec<(String, u32)>) -> HttpResponse {
+    let list: Vec<VoiceInfo> = voices
+        .into_iter()
+        .map(|(id, sample_rate)| VoiceInfo {
+            lang: kokoro_voice_lang(&id),
+            id,
+            sample_rate,
+        })
+        .collect();
+    match serde_json::to_vec(&list) {
+        Ok(body) => HttpResponse::new(200, "OK", "application/json", body),
+        Err(e) => HttpResponse::text(500, "Internal Server Error", &e.to_string()),
+    }
+}
+
+/// Whether the query asks for `timings=1` (or `true`)
+fn wants_timings(query: &str) -> bool {
+    form_urlencoded::parse(query.as_bytes()).any(|(key, value)| key == "timings" && (value == "1" || value == "true"))
+}
+
+/// `timings` as the `X-Sirius-Timings` value: base64 of a JSON array of
+/// `{"text": ..., "start_secs": ..., "end_secs": ...}`
+fn timings_header(timings: &[SentenceTiming]) -> Result<String> {
+    Ok(BASE64_STANDARD.encode(serde_json::to_vec(timings)?))
+}
+
+/// Build a `SynthesizeRequest` from `text`, `voice`, `lang` and `speed` query parameters
+fn parse_synthesize_query(query: &str) -> Option<SynthesizeRequest> {
+    let mut text = None;
+    let mut voice = None;
+    let mut lang = None;
+    let mut speed = None;
+    let mut tempo = None;
+    let mut pitch = None;
+
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "text" => text = Some(value.into_owned()),
+            "voice" => voice = Some(value.into_owned()),
+            "lang" => lang = Some(value.into_owned()),
+            "speed" => speed = value.parse::<f32>().ok(),
+            "tempo" => tempo = value.parse::<f32>().ok(),
+            "pitch" => pitch = value.parse::<f32>().ok(),
+            _ => {}
+        }
+    }
+
+    let text = text.filter(|t| !t.trim().is_empty())?;
+    let mut req = SynthesizeRequest::new(text);
+    if let Some(voice) = voice {
+        req = req.with_voice(voice);
+    }
+    if let Some(lang) = lang {
+        req = req.with_lang(lang);
+    }
+    if let Some(speed) = speed {
+        req = req.with_speed(speed);
+    }
+    if let Some(tempo) = tempo {
+        req = req.with_tempo(tempo);
+    }
+    if let Some(pitch) = pitch {
+        req = req.with_pitch_semitones(pitch);
+    }
+    Some(req)
+}
+
+/// A byte range resolved against a body of known length
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// No (usable) range requested - serve the whole body
+    Full,
+    /// Inclusive `start..=end` slice
+    Partial { start: usize, end: usize },
+    /// The range lies outside the body
+    Unsatisfiable,
+}
+
+/// Resolve a `Range` header value against a body of `len` bytes.
+///
+/// Only single `bytes=` ranges are honoured; anything else (multiple ranges,
+/// other units, garbage) falls back to serving the full body, as RFC 9110 allows.
+fn parse_range(header: Option<&str>, len: usize) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return ByteRange::Full,
+    };
+
+    if start.is_empty() {
+        // Suffix range: the last N bytes
+        return match end.parse::<usize>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial {
+                start: len.saturating_sub(n),
+                end: len - 1,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let start = match start.parse::<usize>() {
+        Ok(s) => s,
+        Err(_) => return ByteRange::Full,
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    let end = if end.is_empty() {
+        len - 1
+    } else {
+        match end.parse::<usize>() {
+            Ok(e) if e >= start => e.min(len - 1),
+            _ => return ByteRange::Full,
+        }
+    };
+
+    ByteRange::Partial { start, end }
+}
+
+/// Wrap WAV bytes in a response, honouring an optional `Range` header
+fn audio_response(wav_data: Vec<u8>, range: Option<&str>) -> HttpResponse {
+    let len = wav_data.len();
+    match parse_range(range, len) {
+        ByteRange::Full => HttpResponse::new(200, "OK", "audio/wav", wav_data)
+            .with_header("Accept-Ranges", "bytes"),
+        ByteRange::Partial { start, end } => HttpResponse::new(
+            206,
+            "Partial Content",
+            "audio/wav",
+            wav_data[start..=end].to_vec(),
+        )
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len)),
+        ByteRange::Unsatisfiable => {
+            HttpResponse::text(416, "Range Not Satisfiable", "requested range not satisfiable")
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("Content-Range", &format!("bytes */{}", len))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, reason: &'static str, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            reason,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    fn text(status: u16, reason: &'static str, message: &str) -> Self {
+        Self::new(
+            status,
+            reason,
+            "text/plain; charset=utf-8",
+            message.as_bytes().to_vec(),
+        )
+    }
+
+    fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    #[cfg(test)]
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        out.push_str("Connection: close\r\n\r\n");
+
+        let mut bytes = out.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// Read until the end of the request head (`\r\n\r\n`). Returns `None` if the
+/// peer closed the connection before sending anything.
+async fn read_head(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return if buf.is_empty() {
+                Ok(None)
+            } else {
+                Err(anyhow::anyhow!("connection closed mid-request"))
+            };
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(Some(buf));
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err(anyhow::anyhow!("request head too large"));
+        }
+    }
+}
+
+fn header_value<'a>(headers: &'a [httparse::Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body() -> Vec<u8> {
+        (0..100u8).collect()
+    }
+
+    #[test]
+    fn test_full_request() {
+        let resp = audio_response(body(), None);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, body());
+        assert_eq!(resp.header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(resp.header("Content-Range"), None);
+    }
+
+    #[test]
+    fn test_suffix_range() {
+        let resp = audio_response(body(), Some("bytes=-10"));
+        assert_eq!(resp.status, 206);
+        assert_eq!(resp.body, (90..100u8).collect::<Vec<_>>());
+        assert_eq!(resp.header("Content-Range"), Some("bytes 90-99/100"));
+        assert_eq!(resp.header("Accept-Ranges"), Some("bytes"));
+
+        // A suffix longer than the body yields the whole body
+        let resp = audio_response(body(), Some("bytes=-500"));
+        assert_eq!(resp.status, 206);
+        assert_eq!(resp.header("Content-Range"), Some("bytes 0-99/100"));
+    }
+
+    #[test]
+    fn test_bounded_and_open_ranges() {
+        let resp = audio_response(body(), Some("bytes=10-19"));
+        assert_eq!(resp.status, 206);
+        assert_eq!(resp.body, (10..20u8).collect::<Vec<_>>());
+        assert_eq!(resp.header("Content-Range"), Some("bytes 10-19/100"));
+
+        let resp = audio_response(body(), Some("bytes=95-"));
+        assert_eq!(resp.body, (95..100u8).collect::<Vec<_>>());
+
+        // End past the body is clamped
+        let resp = audio_response(body(), Some("bytes=98-1000"));
+        assert_eq!(resp.header("Content-Range"), Some("bytes 98-99/100"));
+    }
+
+    #[test]
+    fn test_out_of_range_is_416() {
+        let resp = audio_response(body(), Some("bytes=100-"));
+        assert_eq!(resp.status, 416);
+        assert_eq!(resp.header("Content-Range"), Some("bytes */100"));
+
+        let resp = audio_response(body(), Some("bytes=-0"));
+        assert_eq!(resp.status, 416);
+    }
+
+    #[test]
+    fn test_unusable_range_serves_full_body() {
+        for header in ["bytes=0-1,5-6", "items=0-1", "bytes=abc", "bytes=20-10"] {
+            assert_eq!(parse_range(Some(header), 100), ByteRange::Full, "{}", header);
+        }
+    }
+
+    /// Send `request` to an endpoint with the mock backend and return the
+    /// response head and body
+    async fn exchange(request: &str, cors_origin: Option<&str>) -> (String, String) {
+        let mut synth = crate::synth::MockSynthesizer::default();
+        synth.voices = vec!["af_bella".to_string(), "bm_lewis".to_string(), "mock".to_string()];
+        synth.native_sample_rate = 16_000;
+        let addr = spawn_endpoint(synth, cors_origin, ConnectionConfig::default()).await;
+        send(addr, request).await
+    }
+
+    async fn spawn_endpoint<S: SpeechSynthesizer>(
+        synth: S,
+        cors_origin: Option<&str>,
+        config: ConnectionConfig,
+    ) -> SocketAddr {
+        let tts = TtsEngine::with_synthesizer(synth);
+        let config = ConnectionConfig { settings: tts.settings(), ..config };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_loop(listener, Arc::new(Mutex::new(tts)), cors_origin.map(str::to_string), config));
+        addr
+    }
+
+    /// Send `request` to the endpoint at `addr` and return the response
+    /// head and body
+    async fn send(addr: SocketAddr, request: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response).into_owned();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_voices_endpoint() {
+        let (head, body) = exchange("GET /voices HTTP/1.1\r\nHost: localhost\r\n\r\n", None).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(head.contains("Content-Type: application/json"), "{}", head);
+
+        let voices: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            voices,
+            vec![
+                serde_json::json!({"id": "af_bella", "lang": "en-us", "sample_rate": 16000}),
+                serde_json::json!({"id": "bm_lewis", "lang": "en-gb", "sample_rate": 16000}),
+                serde_json::json!({"id": "mock", "lang": null, "sample_rate": 16000}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let preflight = "OPTIONS /synthesize?text=Hi HTTP/1.1\r\nHost: localhost\r\n\
+                         Origin: https://app.example\r\nAccess-Control-Request-Method: GET\r\n\
+                         Access-Control-Request-Headers: range\r\n\r\n";
+        let (head, body) = exchange(preflight, Some("https://app.example")).await;
+        assert!(head.starts_with("HTTP/1.1 204 No Content"), "{}", head);
+        assert!(body.is_empty());
+        for header in [
+            "Access-Control-Allow-Origin: https://app.example",
+            "Access-Control-Allow-Methods: GET, OPTIONS",
+            "Access-Control-Allow-Headers: Range",
+            "Access-Control-Max-Age: 600",
+        ] {
+            assert!(head.contains(header), "missing {}: {}", header, head);
+        }
+
+        // Same-origin only by default
+        let (head, _) = exchange(preflight, None).await;
+        assert!(head.starts_with("HTTP/1.1 204"), "{}", head);
+        assert!(!head.contains("Access-Control-"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_cors_allowed_origin_get() {
+        let get = "GET /synthesize?text=Hello&voice=mock HTTP/1.1\r\nHost: localhost\r\n\
+                   Origin: https://app.example\r\n\r\n";
+        let (head, body) = exchange(get, Some("https://app.example")).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(head.contains("Access-Control-Allow-Origin: https://app.example"), "{}", head);
+        assert!(head.contains("Access-Control-Expose-Headers: Accept-Ranges, Content-Range, X-Sirius-Timings"), "{}", head);
+        assert!(!head.contains("Access-Control-Allow-Methods"), "{}", head);
+        assert!(body.starts_with("RIFF"));
+
+        let (head, _) = exchange(get, None).await;
+        assert!(!head.contains("Access-Control-"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_timings_header() {
+        let get = "GET /synthesize?text=Hello+there.+Bye+now%2C+friend.+See+you.&voice=mock&timings=1 HTTP/1.1\r\n\
+                   Host: localhost\r\n\r\n";
+        let (head, body) = exchange(get, None).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(body.starts_with("RIFF"));
+        let value = head
+            .lines()
+            .find_map(|line| line.strip_prefix("X-Sirius-Timings: "))
+            .expect("timings header");
+        let timings: Vec<serde_json::Value> = serde_json::from_slice(&BASE64_STANDARD.decode(value).unwrap()).unwrap();
+        let texts: Vec<&str> = timings.iter().map(|t| t["text"].as_str().unwrap()).collect();
+        assert_eq!(texts, ["Hello there.", "Bye now, friend.", "See you."]);
+
+        // Each range is non-empty and starts where the one before ends
+        let ranges: Vec<(f64, f64)> = timings
+            .iter()
+            .map(|t| (t["start_secs"].as_f64().unwrap(), t["end_secs"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(ranges[0].0, 0.0);
+        assert!(ranges.iter().all(|(start, end)| start < end), "{:?}", ranges);
+        assert!(ranges.windows(2).all(|pair| pair[0].1 == pair[1].0), "{:?}", ranges);
+
+        // Only when asked for
+        let (head, _) = exchange(&get.replace("&timings=1", ""), None).await;
+        assert!(!head.contains("X-Sirius-Timings"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_open_breaker_turns_requests_away() {
+        use std::time::Duration;
+
+        // The engine fails twice, then would work again
+        let synth = crate::synth::MockSynthesizer::default();
+        synth.transient_failures.store(2, std::sync::atomic::Ordering::SeqCst);
+        let breaker = crate::breaker::CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(60));
+        let config = ConnectionConfig {
+            breaker: Some(Arc::new(breaker)),
+            ..Default::default()
+        };
+        let addr = spawn_endpoint(synth, None, config).await;
+        let get = "GET /synthesize?text=Hello&voice=mock HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        // Engine failures are 500s until there are enough to open the breaker;
+        // then requests are refused without reaching the engine
+        for _ in 0..2 {
+            let (head, _) = send(addr, get).await;
+            assert!(head.starts_with("HTTP/1.1 500"), "{}", head);
+        }
+        let (head, body) = send(addr, get).await;
+        assert!(head.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", head);
+        assert!(body.contains("retry later"), "{}", body);
+    }
+
+    #[test]
+    fn test_parse_synthesize_query() {
+        let req = parse_synthesize_query("text=Hello%2C+world&voice=bm_lewis&speed=1.2").unwrap();
+        assert_eq!(req.text, "Hello, world");
+        assert_eq!(req.voice, "bm_lewis");
+        assert_eq!(req.lang, "en-us");
+        assert_eq!(req.speed, 1.2);
+
+        assert!(parse_synthesize_query("voice=bm_lewis").is_none());
+        assert!(parse_synthesize_query("text=+").is_none());
+    }
+}
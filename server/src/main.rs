@@ -5,25 +5,61 @@
 //! Usage:
 //!   cargo run --release -p sirius-server
 //!
-//! The server listens on ws://127.0.0.1:9876 by default.
+//! The server listens on ws://127.0.0.1:9876 by default. Set `SIRIUS_HTTP_ADDR`
+//! to additionally serve `GET /synthesize` over plain HTTP.
 
+mod archive;
+mod audit;
+mod breaker;
+mod directive;
+mod dsp;
+mod http;
+mod normalize;
+mod phoneme;
+mod prompts;
+mod script;
+mod settings;
+mod synth;
 mod tts;
 
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
+use base64::prelude::*;
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::Message;
-use tracing::{error, info, warn};
+use kokoro::onn::ort_base::SessionOptions;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse, Request as HandshakeRequest, Response as HandshakeResponse,
+};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use sirius_protocol::{Request, Response};
-use tts::TtsEngine;
+use archive::Archive;
+use audit::{AuditLog, AuditRecord, Outcome};
+use breaker::CircuitBreaker;
+use prompts::PromptBank;
+use settings::RuntimeSettings;
+use sirius_protocol::{
+    CloseReason, ErrorCode, Request, Response, SynthesizeRequest, Traced, DEFAULT_LANG, DEFAULT_SPEED, DEFAULT_VOICE,
+};
+use synth::SpeechSynthesizer;
+use tts::{SynthesisError, SynthesizedAudio, TtsEngine};
 
 const DEFAULT_ADDR: &str = "127.0.0.1:9876";
 
+/// How long connections get to finish the request in hand after Ctrl-C
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging
@@ -37,49 +73,799 @@ async fn main() -> Result<()> {
 
     let addr = std::env::var("SIRIUS_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string());
 
-    // Initialize TTS engine (this loads the model - may take a moment)
-    info!("Loading TTS model...");
-    let model_path = std::env::var("SIRIUS_MODEL")
-        .unwrap_or_else(|_| "checkpoints/kokoro-v1.0.onnx".to_string());
-    let voices_path = std::env::var("SIRIUS_VOICES")
-        .unwrap_or_else(|_| "data/voices-v1.0.bin".to_string());
-
-    let tts = TtsEngine::new(&model_path, &voices_path).await?;
+    let tts = load_engine().await?;
+    info!("TTS model loaded in {:.2}s", tts.model_load_time().as_secs_f64());
+    // Checked against the loaded voices now rather than on the first request
+    let default_voice = default_voice(std::env::var("SIRIUS_DEFAULT_VOICE").ok(), &tts.voices())?;
+    let tts = tts
+        .with_default_voice(default_voice)
+        .with_abbreviations(normalize::AbbreviationTable::from_env()?)
+        .with_skip_markers(normalize::SkipMarkers::from_env()?)
+        .with_max_text_chars(max_text_chars()?)
+        .with_max_audio_secs(max_audio_secs()?)
+        .with_voice_defaults(voice_defaults()?)
+        .with_earcon(earcon()?)
+        .with_retries(synth_retries()?)
+        .with_normalize(std::env::var("SIRIUS_NORMALIZE").as_deref() == Ok("1"))
+        .with_sentence_pause_ms(sentence_pause_ms()?)
+        .with_sentence_batch_chars(sentence_batch_chars()?)
+        .with_max_sentence_chars(max_sentence_chars()?);
+    // The first synthesis pays for lazy initialization; do it before any
+    // client can, unless SIRIUS_WARMUP=0
+    if std::env::var("SIRIUS_WARMUP").as_deref() != Ok("0") {
+        match tts.warm_up() {
+            Ok(elapsed) => info!("Warmup synthesis took {:?}", elapsed),
+            Err(e) => warn!("Warmup synthesis failed: {}", e),
+        }
+    }
+    let settings = tts.settings();
     let tts = Arc::new(Mutex::new(tts));
 
-    info!("TTS model loaded successfully");
+    let (shutdown, shutdown_signal) = watch::channel(false);
+    let config = ConnectionConfig {
+        ws: ws_config()?,
+        chunk_bytes: chunk_bytes()?,
+        audit: AuditLog::from_env().await?,
+        archive: Archive::from_env().await?,
+        idle_timeout: idle_timeout()?,
+        admission: admission()?,
+        breaker: CircuitBreaker::from_env()?.map(Arc::new),
+        max_requests: max_requests()?,
+        prompts: Arc::new(PromptBank::from_env()?),
+        admin_token: std::env::var("SIRIUS_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+        settings,
+        slow_threshold: slow_threshold()?,
+        shutdown: Some(shutdown_signal),
+    };
+
+    // Optional plain-HTTP endpoint alongside the WebSocket server
+    if let Ok(http_addr) = std::env::var("SIRIUS_HTTP_ADDR") {
+        let tts = Arc::clone(&tts);
+        let config = config.clone();
+        tokio::spawn(async move {
+            let cors_origin = std::env::var("SIRIUS_CORS_ORIGIN").ok();
+            if let Err(e) = http::serve(http_addr, tts, cors_origin, config).await {
+                error!("HTTP endpoint failed: {}", e);
+            }
+        });
+    }
+
+    // Optional Unix domain socket for local clients, alongside TCP
+    if let Ok(uds_path) = std::env::var("SIRIUS_UDS") {
+        #[cfg(unix)]
+        {
+            let listener = bind_unix(&uds_path)?;
+            info!("Sirius TTS server listening on unix:{}", uds_path);
+            tokio::spawn(serve_unix(listener, Arc::clone(&tts), config.clone()));
+        }
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!("SIRIUS_UDS={} is set, but Unix domain sockets need a Unix platform", uds_path));
+    }
 
     // Start WebSocket server
     let listener = TcpListener::bind(&addr).await?;
     info!("Sirius TTS server listening on ws://{}", addr);
+    tokio::select! {
+        _ = serve(listener, tts, config) => {}
+        _ = tokio::signal::ctrl_c() => info!("Shutting down"),
+    }
+    // Connections close once they've finished the request in hand
+    let _ = shutdown.send(true);
+    if tokio::time::timeout(SHUTDOWN_GRACE, shutdown.closed()).await.is_err() {
+        warn!("Connections still open after {:?}; exiting anyway", SHUTDOWN_GRACE);
+    }
+
+    Ok(())
+}
+
+/// Initialize the Kokoro TTS engine (this loads the model - may take a moment)
+#[cfg(not(feature = "test-synth"))]
+async fn load_engine() -> Result<TtsEngine> {
+    info!("Loading TTS model...");
+    let model_path = std::env::var("SIRIUS_MODEL")
+        .unwrap_or_else(|_| "checkpoints/kokoro-v1.0.onnx".to_string());
+    let voices_paths: Vec<String> = std::env::var("SIRIUS_VOICES")
+        .unwrap_or_else(|_| "data/voices-v1.0.bin".to_string())
+        .split(',')
+        .map(|path| path.trim().to_string())
+        .filter(|path| !path.is_empty())
+        .collect();
+
+    let session = session_options(
+        std::env::var("SIRIUS_EP").ok(),
+        std::env::var("SIRIUS_ORT_INTRA").ok(),
+        std::env::var("SIRIUS_ORT_INTER").ok(),
+    )?;
+    TtsEngine::new(&model_path, &voices_paths, voice_collisions()?, session).await
+}
+
+/// How voices files that share a voice name are merged, from `SIRIUS_VOICE_COLLISIONS`
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+fn voice_collisions() -> Result<synth::VoiceCollisions> {
+    match std::env::var("SIRIUS_VOICE_COLLISIONS").as_deref() {
+        Ok("error") | Err(_) => Ok(synth::VoiceCollisions::Error),
+        Ok("last-wins") => Ok(synth::VoiceCollisions::LastWins),
+        Ok(other) => Err(anyhow::anyhow!(
+            "SIRIUS_VOICE_COLLISIONS must be error or last-wins, not {}",
+            other
+        )),
+    }
+}
+
+/// ONNX Runtime session settings from the values of `SIRIUS_EP`,
+/// `SIRIUS_ORT_INTRA` and `SIRIUS_ORT_INTER`.
+///
+/// The engine runs one synthesis at a time, so by default a single operator
+/// may use every core and operators don't run side by side (inter-op 1);
+/// more inter-op threads only add contention for Kokoro's sequential graph.
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+fn session_options(ep: Option<String>, intra: Option<String>, inter: Option<String>) -> Result<SessionOptions> {
+    let provider = match ep {
+        Some(value) => value.parse().map_err(|e| anyhow::anyhow!("SIRIUS_EP: {}", e))?,
+        None => Default::default(),
+    };
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    Ok(SessionOptions {
+        provider,
+        intra_threads: Some(thread_count("SIRIUS_ORT_INTRA", intra)?.unwrap_or(cores)),
+        inter_threads: Some(thread_count("SIRIUS_ORT_INTER", inter)?.unwrap_or(1)),
+    })
+}
+
+/// A positive thread count from `value`, the value of `var` if it's set
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+fn thread_count(var: &str, value: Option<String>) -> Result<Option<usize>> {
+    match value {
+        Some(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(anyhow::anyhow!("{} must be a positive number of threads", var)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// With `test-synth`, skip the model entirely and serve deterministic sine audio
+#[cfg(feature = "test-synth")]
+async fn load_engine() -> Result<TtsEngine<synth::SineSynthesizer>> {
+    warn!("test-synth feature enabled: serving sine-wave audio instead of Kokoro");
+    Ok(TtsEngine::with_synthesizer(synth::SineSynthesizer::default()))
+}
+
+/// Settings applied to every connection; the HTTP endpoint uses the ones
+/// that aren't about WebSocket
+#[derive(Debug, Clone)]
+struct ConnectionConfig {
+    ws: WebSocketConfig,
+    /// Largest binary message; longer audio is split across several
+    chunk_bytes: usize,
+    /// Where synthesize requests are recorded (`SIRIUS_AUDIT_LOG`)
+    audit: Option<AuditLog>,
+    /// Where a copy of every clip is kept (`SIRIUS_ARCHIVE_DIR`)
+    archive: Option<Archive>,
+    /// Close connections that send nothing for this long
+    idle_timeout: Option<Duration>,
+    /// Places for requests using the engine: one running plus
+    /// `SIRIUS_MAX_QUEUE` waiting. `None` waits however long it takes.
+    admission: Option<Arc<Semaphore>>,
+    /// Turns requests away while the engine keeps failing
+    /// (`SIRIUS_BREAKER_FAILURES`); `None` always tries
+    breaker: Option<Arc<CircuitBreaker>>,
+    /// Synthesize and preview requests one connection may make before it's
+    /// closed and the client has to reconnect
+    max_requests: Option<usize>,
+    /// Texts for `SynthesizePrompt` (`SIRIUS_PROMPTS`)
+    prompts: Arc<PromptBank>,
+    /// Token `GetConfig` and `SetConfig` must carry (`SIRIUS_ADMIN_TOKEN`);
+    /// `None` refuses them all
+    admin_token: Option<String>,
+    /// What `GetConfig` and `SetConfig` read and change, shared with the
+    /// engine (`TtsEngine::settings`)
+    settings: RuntimeSettings,
+    /// Log syntheses slower than this at WARN and the rest at DEBUG
+    /// (`SIRIUS_SLOW_MS`); `None` logs every one at INFO
+    slow_threshold: Option<Duration>,
+    /// Flips to true when the server is shutting down; `None` never does
+    shutdown: Option<watch::Receiver<bool>>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            ws: WebSocketConfig::default(),
+            chunk_bytes: sirius_protocol::DEFAULT_CHUNK_BYTES,
+            audit: None,
+            archive: None,
+            idle_timeout: None,
+            admission: None,
+            breaker: None,
+            max_requests: None,
+            prompts: Arc::default(),
+            admin_token: None,
+            settings: RuntimeSettings::default(),
+            slow_threshold: None,
+            shutdown: None,
+        }
+    }
+}
+
+/// Admission limit from `SIRIUS_MAX_QUEUE`
+fn admission() -> Result<Option<Arc<Semaphore>>> {
+    match std::env::var("SIRIUS_MAX_QUEUE") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(n) => Ok(Some(Arc::new(Semaphore::new(n + 1)))),
+            Err(_) => Err(anyhow::anyhow!("SIRIUS_MAX_QUEUE must be a whole number of requests")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Claim a place in the engine queue, or a `Busy` error if every place is
+/// taken. Without a limit there's nothing to claim.
+fn admit(admission: &Option<Arc<Semaphore>>) -> Result<Option<OwnedSemaphorePermit>, Response> {
+    match admission {
+        Some(places) => Arc::clone(places).try_acquire_owned().map(Some).map_err(|_| Response::Error {
+            code: ErrorCode::Busy,
+            message: "Server busy; retry later".to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Ask the circuit breaker, if any, to let a request use the engine, or the
+/// `Busy` error while it's open
+fn breaker_allows(breaker: &Option<Arc<CircuitBreaker>>) -> Result<(), Response> {
+    match breaker {
+        Some(breaker) if !breaker.allow() => Err(Response::Error {
+            code: ErrorCode::Busy,
+            message: "The engine is failing; retry later".to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Tell the circuit breaker, if any, how an allowed request went. Request
+/// errors like an unknown voice mean the engine is working.
+fn breaker_record<T>(breaker: &Option<Arc<CircuitBreaker>>, result: &Result<T>) {
+    if let Some(breaker) = breaker {
+        breaker.record(matches!(result, Err(e) if tts_error_code(e) == ErrorCode::Internal));
+    }
+}
+
+/// Claim a queue place and ask the circuit breaker before `what` (a request
+/// or a preview from `peer`) may use the engine. A refusal comes with why,
+/// for the audit log.
+fn claim_engine(
+    config: &ConnectionConfig,
+    peer: impl std::fmt::Display,
+    what: &str,
+) -> Result<Option<OwnedSemaphorePermit>, (Outcome, Response)> {
+    let permit = admit(&config.admission).map_err(|response| {
+        warn!("Queue full; turning away {} from {}", what, peer);
+        (Outcome::QueueFull, response)
+    })?;
+    breaker_allows(&config.breaker).map_err(|response| {
+        warn!("Circuit breaker open; turning away {} from {}", what, peer);
+        (Outcome::BreakerOpen, response)
+    })?;
+    Ok(permit)
+}
+
+/// Lock the engine and run `f` on it on a blocking thread, so a long
+/// synthesis (retry backoff included) doesn't hold up the async workers
+/// every other connection runs on
+async fn with_engine<S, T, F>(tts: &Arc<Mutex<TtsEngine<S>>>, f: F) -> Result<T>
+where
+    S: SpeechSynthesizer,
+    T: Send + 'static,
+    F: FnOnce(&TtsEngine<S>) -> Result<T> + Send + 'static,
+{
+    let engine = Arc::clone(tts).lock_owned().await;
+    tokio::task::spawn_blocking(move || f(&engine))
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("synthesis task failed: {}", e)))
+}
+
+/// Run `req` from `peer` the way every transport does: claim the engine,
+/// synthesize, log and archive the audio, hand the outcome to `deliver`,
+/// then audit the request, refused or not. A refusal or failure reaches
+/// `deliver` as the error to send.
+async fn synthesize_guarded<S, T, F, Fut>(
+    tts: &Arc<Mutex<TtsEngine<S>>>,
+    config: &ConnectionConfig,
+    peer: impl std::fmt::Display,
+    req: &SynthesizeRequest,
+    deliver: F,
+) -> Result<T>
+where
+    S: SpeechSynthesizer,
+    F: FnOnce(Result<SynthesizedAudio, Response>) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let start = std::time::Instant::now();
+    let mut record = AuditRecord::new(&peer, &req.voice, req.text.chars().count());
+
+    let result = match claim_engine(config, &peer, "request") {
+        Ok(permit) => {
+            let owned_req = req.clone();
+            let result = with_engine(tts, move |engine| engine.synthesize_request(&owned_req)).await;
+            breaker_record(&config.breaker, &result);
+            drop(permit);
+            match result {
+                Ok(audio) => {
+                    record.set_outcome(Outcome::Ok);
+                    record.duration_secs = audio.duration_secs();
+                    archive(config, &audio);
+                    log_synthesis(config.slow_threshold, req, &audio, start.elapsed());
+                    Ok(audio)
+                }
+                Err(e) => {
+                    error!("TTS error: {}", e);
+                    Err(tts_error_response(&e))
+                }
+            }
+        }
+        Err((outcome, response)) => {
+            record.set_outcome(outcome);
+            Err(response)
+        }
+    };
+    let delivered = deliver(result).await;
+    audit(config, record, start);
+    delivered
+}
+
+/// Run a `VoicePreview` of `template` in each of `voices` from `peer` through
+/// the same steps as `synthesize_guarded`, as one request: check it, claim
+/// the engine once for every voice, then archive each clip. The audit
+/// `record` (every voice, comma-separated) gets the outcome and the seconds
+/// of audio in total.
+async fn preview<S: SpeechSynthesizer>(
+    tts: &Arc<Mutex<TtsEngine<S>>>,
+    config: &ConnectionConfig,
+    peer: impl std::fmt::Display,
+    template: &SynthesizeRequest,
+    voices: &[String],
+    record: &mut AuditRecord,
+) -> Result<Vec<(Response, SynthesizedAudio)>, Response> {
+    // Before claiming the engine, so bad requests don't queue for it
+    let requests = preview_requests(template, voices, config.settings.voices()).map_err(|e| tts_error_response(&e))?;
+    let permit = claim_engine(config, &peer, "preview").map_err(|(outcome, response)| {
+        record.set_outcome(outcome);
+        response
+    })?;
+
+    let result = with_engine(tts, move |engine| {
+        preview_payloads(&requests, |req| engine.synthesize(&req.text, &req.lang, &req.voice, req.speed))
+    })
+    .await;
+    drop(permit);
+    breaker_record(&config.breaker, &result);
+
+    match result {
+        Ok(payloads) => {
+            record.set_outcome(Outcome::Ok);
+            for (_, audio) in &payloads {
+                record.duration_secs += audio.duration_secs();
+                archive(config, audio);
+            }
+            Ok(payloads)
+        }
+        Err(e) => {
+            error!("TTS error: {}", e);
+            Err(tts_error_response(&e))
+        }
+    }
+}
+
+/// Keep a copy of `audio` in the archive, if there is one
+fn archive(config: &ConnectionConfig, audio: &SynthesizedAudio) {
+    if let Some(archive) = &config.archive {
+        archive.save(&audio.data, audio.format);
+    }
+}
+
+/// Record a request that came in at `start`, if there's an audit log
+fn audit(config: &ConnectionConfig, mut record: AuditRecord, start: std::time::Instant) {
+    if let Some(audit) = &config.audit {
+        record.latency_ms = start.elapsed().as_millis() as u64;
+        audit.record(record);
+    }
+}
+
+/// Binary chunk size from `SIRIUS_CHUNK_BYTES`
+fn chunk_bytes() -> Result<usize> {
+    match std::env::var("SIRIUS_CHUNK_BYTES") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(anyhow::anyhow!("SIRIUS_CHUNK_BYTES must be a positive number of bytes")),
+        },
+        Err(_) => Ok(sirius_protocol::DEFAULT_CHUNK_BYTES),
+    }
+}
+
+/// Per-voice defaults from the file named by `SIRIUS_VOICE_DEFAULTS`
+fn voice_defaults() -> Result<HashMap<String, tts::VoiceDefaults>> {
+    match std::env::var("SIRIUS_VOICE_DEFAULTS") {
+        Ok(path) => tts::load_voice_defaults(&path),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+/// Notification chime from the WAV file named by `SIRIUS_EARCON`
+fn earcon() -> Result<Option<Vec<f32>>> {
+    match std::env::var("SIRIUS_EARCON") {
+        Ok(path) => Ok(Some(tts::load_earcon(&path)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Server default voice from the value of `SIRIUS_DEFAULT_VOICE`, which must
+/// only name voices in `known`; unset keeps the protocol default
+fn default_voice(value: Option<String>, known: &[String]) -> Result<Option<String>> {
+    match value {
+        Some(voice) => {
+            synth::check_voice(&voice, known)
+                .map_err(|e| anyhow::anyhow!("SIRIUS_DEFAULT_VOICE: {}", e))?;
+            Ok(Some(voice))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Request text limit from `SIRIUS_MAX_TEXT_CHARS`; unset means unlimited
+fn max_text_chars() -> Result<Option<usize>> {
+    match std::env::var("SIRIUS_MAX_TEXT_CHARS") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(anyhow::anyhow!("SIRIUS_MAX_TEXT_CHARS must be a positive number")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Audio length limit from `SIRIUS_MAX_AUDIO_SECS`; unset means unlimited
+fn max_audio_secs() -> Result<Option<f32>> {
+    match std::env::var("SIRIUS_MAX_AUDIO_SECS") {
+        Ok(value) => match value.parse::<f32>() {
+            Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(Some(secs)),
+            _ => Err(anyhow::anyhow!("SIRIUS_MAX_AUDIO_SECS must be a positive number of seconds")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sentence length limit from `SIRIUS_MAX_SENTENCE_CHARS`
+fn max_sentence_chars() -> Result<usize> {
+    match std::env::var("SIRIUS_MAX_SENTENCE_CHARS") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(anyhow::anyhow!("SIRIUS_MAX_SENTENCE_CHARS must be a positive number")),
+        },
+        Err(_) => Ok(tts::DEFAULT_MAX_SENTENCE_CHARS),
+    }
+}
+
+/// Sentence batching budget from `SIRIUS_SENTENCE_BATCH_CHARS`; unset means
+/// one backend call per sentence
+fn sentence_batch_chars() -> Result<Option<usize>> {
+    match std::env::var("SIRIUS_SENTENCE_BATCH_CHARS") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(anyhow::anyhow!("SIRIUS_SENTENCE_BATCH_CHARS must be a positive number")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Silence between sentences from `SIRIUS_SENTENCE_PAUSE_MS` (default none)
+fn sentence_pause_ms() -> Result<u32> {
+    match std::env::var("SIRIUS_SENTENCE_PAUSE_MS") {
+        Ok(value) => match value.parse::<u32>() {
+            Ok(ms) if ms <= settings::MAX_SENTENCE_PAUSE_MS => Ok(ms),
+            _ => Err(anyhow::anyhow!(
+                "SIRIUS_SENTENCE_PAUSE_MS must be a whole number of milliseconds up to {}",
+                settings::MAX_SENTENCE_PAUSE_MS
+            )),
+        },
+        Err(_) => Ok(0),
+    }
+}
+
+/// Retries of transient backend errors from `SIRIUS_SYNTH_RETRIES` (default none)
+fn synth_retries() -> Result<u32> {
+    match std::env::var("SIRIUS_SYNTH_RETRIES") {
+        Ok(value) => value
+            .parse::<u32>()
+            .map_err(|_| anyhow::anyhow!("SIRIUS_SYNTH_RETRIES must be a whole number of retries")),
+        Err(_) => Ok(0),
+    }
+}
 
-    while let Ok((stream, peer_addr)) = listener.accept().await {
+/// Idle timeout from `SIRIUS_IDLE_TIMEOUT_SECS`; unset or 0 disables it
+fn idle_timeout() -> Result<Option<Duration>> {
+    match std::env::var("SIRIUS_IDLE_TIMEOUT_SECS") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(0) => Ok(None),
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(anyhow::anyhow!("SIRIUS_IDLE_TIMEOUT_SECS must be a whole number of seconds")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Slow request threshold from `SIRIUS_SLOW_MS`; unset or 0 disables it
+fn slow_threshold() -> Result<Option<Duration>> {
+    match std::env::var("SIRIUS_SLOW_MS") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(0) => Ok(None),
+            Ok(ms) => Ok(Some(Duration::from_millis(ms))),
+            Err(_) => Err(anyhow::anyhow!("SIRIUS_SLOW_MS must be a whole number of milliseconds")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Per-connection request limit from `SIRIUS_MAX_REQUESTS_PER_CONN`; unset
+/// or 0 disables it
+fn max_requests() -> Result<Option<usize>> {
+    match std::env::var("SIRIUS_MAX_REQUESTS_PER_CONN") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(0) => Ok(None),
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(anyhow::anyhow!(
+                "SIRIUS_MAX_REQUESTS_PER_CONN must be a whole number of requests"
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// WebSocket limits from `SIRIUS_MAX_MESSAGE_MB`
+fn ws_config() -> Result<WebSocketConfig> {
+    let max_mb = match std::env::var("SIRIUS_MAX_MESSAGE_MB") {
+        Ok(value) => value
+            .parse::<usize>()
+            .map_err(|_| anyhow::anyhow!("SIRIUS_MAX_MESSAGE_MB must be a whole number of MiB"))?,
+        Err(_) => sirius_protocol::DEFAULT_MAX_MESSAGE_MB,
+    };
+    Ok(ws_config_with_limit(max_mb * 1024 * 1024))
+}
+
+/// Message and frame limits both set to `max_bytes`
+fn ws_config_with_limit(max_bytes: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_bytes),
+        max_frame_size: Some(max_bytes),
+        ..Default::default()
+    }
+}
+
+/// Warn when a payload is within 20% of the message limit, since clients
+/// configured with the same limit will soon start rejecting them
+fn warn_if_near_limit(size_bytes: usize, config: &WebSocketConfig) {
+    if let Some(limit) = config.max_message_size {
+        if size_bytes >= limit / 5 * 4 {
+            warn!(
+                "Sending {} bytes, close to the {} byte message limit (SIRIUS_MAX_MESSAGE_MB)",
+                size_bytes, limit
+            );
+        }
+    }
+}
+
+/// Accept WebSocket connections until the listener fails or the server shuts
+/// down
+async fn serve<S: SpeechSynthesizer>(
+    listener: TcpListener,
+    tts: Arc<Mutex<TtsEngine<S>>>,
+    config: ConnectionConfig,
+) {
+    let mut shutdown = config.shutdown.clone();
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = shutting_down(&mut shutdown) => break,
+        };
         let tts = Arc::clone(&tts);
+        let config = config.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, peer_addr, tts).await {
+            if let Err(e) = handle_connection(stream, peer_addr, tts, config).await {
                 error!("Connection error from {}: {}", peer_addr, e);
             }
         });
     }
+}
 
-    Ok(())
+/// Accept WebSocket connections on a Unix domain socket until the listener
+/// fails or the server shuts down; peers are logged as `unix:<path>`
+#[cfg(unix)]
+async fn serve_unix<S: SpeechSynthesizer>(
+    listener: tokio::net::UnixListener,
+    tts: Arc<Mutex<TtsEngine<S>>>,
+    config: ConnectionConfig,
+) {
+    let path = listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(|p| p.display().to_string()));
+    let label = format!("unix:{}", path.unwrap_or_default());
+    let mut shutdown = config.shutdown.clone();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => break,
+            },
+            _ = shutting_down(&mut shutdown) => break,
+        };
+        let tts = Arc::clone(&tts);
+        let config = config.clone();
+        let peer = label.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &peer, tts, config).await {
+                error!("Connection error from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// A connection's `SetDefaults`, filling in request fields left at the
+/// protocol defaults
+#[derive(Debug, Clone, Default)]
+struct ConnectionDefaults {
+    voice: Option<String>,
+    lang: Option<String>,
+    speed: Option<f32>,
+}
+
+impl ConnectionDefaults {
+    /// Merge a `SetDefaults`, keeping the current value of fields it leaves
+    /// out. Unknown voices and speeds that aren't positive are rejected
+    /// without changing anything.
+    fn update(
+        &mut self,
+        voice: Option<String>,
+        lang: Option<String>,
+        speed: Option<f32>,
+        known_voices: &[String],
+    ) -> Result<()> {
+        if let Some(voice) = &voice {
+            synth::check_voice(voice, known_voices)?;
+        }
+        if let Some(speed) = speed.filter(|speed| !(*speed > 0.0 && speed.is_finite())) {
+            let reason = format!("default speed {} is not a positive number", speed);
+            return Err(SynthesisError::InvalidParameter(reason).into());
+        }
+        self.voice = voice.or(self.voice.take());
+        self.lang = lang.or(self.lang.take());
+        self.speed = speed.or(self.speed);
+        Ok(())
+    }
+
+    fn apply(&self, req: &mut SynthesizeRequest) {
+        if let Some(voice) = self.voice.as_ref().filter(|_| req.voice == DEFAULT_VOICE) {
+            req.voice = voice.clone();
+        }
+        self.apply_lang_speed(&mut req.lang, &mut req.speed);
+    }
+
+    fn apply_lang_speed(&self, lang: &mut String, speed: &mut f32) {
+        if let Some(default) = self.lang.as_ref().filter(|_| lang == DEFAULT_LANG) {
+            *lang = default.clone();
+        }
+        if let Some(default) = self.speed.filter(|_| *speed == DEFAULT_SPEED) {
+            *speed = default;
+        }
+    }
+
+    fn response(&self) -> Response {
+        Response::DefaultsSet {
+            voice: self.voice.clone(),
+            lang: self.lang.clone(),
+            speed: self.speed,
+        }
+    }
+}
+
+/// Resolves once `shutdown` flips to true; never without a shutdown channel
+async fn shutting_down(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(shutdown) = shutdown {
+        if shutdown.wait_for(|&stopping| stopping).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    peer_addr: SocketAddr,
-    tts: Arc<Mutex<TtsEngine>>,
-) -> Result<()> {
+/// The close frame telling the client why the server hung up
+fn close_message(reason: CloseReason) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::from(reason.code()),
+        reason: reason.reason().into(),
+    }))
+}
+
+/// `response` as a text message, tagged with the request's trace id
+fn text_message(response: &Response, trace_id: &str) -> Result<Message> {
+    Ok(Message::Text(serde_json::to_string(&Traced::new(response, trace_id))?))
+}
+
+/// A trace id for a request that didn't bring one: when the server started,
+/// then a count of the ids handed out since
+fn new_trace_id() -> String {
+    static START: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let start = START.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    });
+    format!("{:x}-{:x}", start, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Bind `path` as a Unix domain socket, replacing a stale socket left by an
+/// earlier run (but never a regular file)
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    tokio::net::UnixListener::bind(path).map_err(|e| anyhow::anyhow!("binding SIRIUS_UDS {}: {}", path, e))
+}
+
+async fn handle_connection<S, T>(
+    stream: T,
+    peer_addr: impl std::fmt::Display,
+    tts: Arc<Mutex<TtsEngine<S>>>,
+    config: ConnectionConfig,
+) -> Result<()>
+where
+    S: SpeechSynthesizer,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
     info!("New connection from: {}", peer_addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+    let ws_stream =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, negotiate_subprotocol, Some(config.ws)).await?;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    while let Some(msg) = ws_receiver.next().await {
+    let idle = tokio::time::sleep(config.idle_timeout.unwrap_or_default());
+    tokio::pin!(idle);
+    let mut shutdown = config.shutdown.clone();
+    let mut defaults = ConnectionDefaults::default();
+    let mut requests = 0;
+
+    loop {
+        // Restart the idle timer once the previous message has been handled
+        if let Some(timeout) = config.idle_timeout {
+            idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+        }
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => msg,
+            _ = &mut idle, if config.idle_timeout.is_some() => {
+                info!("Closing idle connection from {}", peer_addr);
+                ws_sender.send(close_message(CloseReason::IdleTimeout)).await?;
+                break;
+            }
+            _ = shutting_down(&mut shutdown) => {
+                info!("Closing connection from {} for shutdown", peer_addr);
+                ws_sender.send(close_message(CloseReason::Shutdown)).await?;
+                break;
+            }
+        };
+        let Some(msg) = msg else { break };
         let msg = match msg {
             Ok(m) => m,
             Err(e) => {
                 warn!("WebSocket error from {}: {}", peer_addr, e);
+                if matches!(e, WsError::Protocol(_) | WsError::Capacity(_) | WsError::Utf8) {
+                    // Best effort: the connection may not take another frame
+                    let _ = ws_sender.send(close_message(CloseReason::ProtocolError)).await;
+                }
                 break;
             }
         };
@@ -87,80 +873,170 @@ async fn handle_connection(
         match msg {
             Message::Text(text) => {
                 // Parse the request
-                let request: Request = match serde_json::from_str(&text) {
-                    Ok(r) => r,
-                    Err(e) => {
+                let (request, trace_id) = match serde_json::from_str::<Traced<Request>>(&text) {
+                    Ok(traced) if !matches!(traced.message, Request::Unknown) => {
+                        let trace_id = traced.trace_id.unwrap_or_else(new_trace_id);
+                        (traced.message, trace_id)
+                    }
+                    parsed => {
+                        // Name a type from a newer client rather than
+                        // passing on serde's complaint about it
+                        let message = match (Request::unsupported_type(&text), parsed) {
+                            (Some(kind), _) => format!("unsupported request type: {}", kind),
+                            (None, Err(e)) => format!("Invalid request: {}", e),
+                            (None, Ok(_)) => "Invalid request".to_string(),
+                        };
                         let error_response = Response::Error {
-                            message: format!("Invalid request: {}", e),
+                            code: ErrorCode::BadRequest,
+                            message,
                         };
-                        ws_sender
-                            .send(Message::Text(serde_json::to_string(&error_response)?))
-                            .await?;
+                        ws_sender.send(text_message(&error_response, &new_trace_id())?).await?;
                         continue;
                     }
                 };
 
-                match request {
-                    Request::Ping => {
-                        let response = Response::Pong;
-                        ws_sender
-                            .send(Message::Text(serde_json::to_string(&response)?))
-                            .await?;
+                // A prompt is synthesized like any other text once it's filled in
+                let request = match request {
+                    Request::SynthesizePrompt { id, vars } => match config.prompts.render(&id, &vars) {
+                        Ok(text) => Request::Synthesize(SynthesizeRequest::new(text)),
+                        Err(e) => {
+                            ws_sender.send(text_message(&tts_error_response(&e), &trace_id)?).await?;
+                            continue;
+                        }
+                    },
+                    request => request,
+                };
+
+                let span = info_span!("request", trace_id = %trace_id);
+
+                // Only requests that use the engine count towards the limit
+                if matches!(request, Request::Synthesize(_) | Request::VoicePreview { .. }) {
+                    requests += 1;
+                    if config.max_requests.is_some_and(|max| requests > max) {
+                        info!("Closing connection from {} after {} requests", peer_addr, requests - 1);
+                        ws_sender.send(close_message(CloseReason::RequestLimit)).await?;
+                        break;
                     }
-                    Request::Synthesize(req) => {
-                        info!(
-                            "Synthesizing {} chars for {} (voice: {})",
-                            req.text.len(),
-                            peer_addr,
-                            req.voice
-                        );
-
-                        let start = std::time::Instant::now();
-
-                        // Generate audio
-                        let tts_guard = tts.lock().await;
-                        match tts_guard.synthesize(&req.text, &req.lang, &req.voice, req.speed) {
-                            Ok(wav_data) => {
-                                drop(tts_guard); // Release lock before sending
-
-                                let duration_secs = wav_data.len() as f32
-                                    / (sirius_protocol::SAMPLE_RATE as f32
-                                        * sirius_protocol::CHANNELS as f32
-                                        * 2.0); // 2 bytes per sample (16-bit)
-
-                                info!(
-                                    "Generated {:.2}s audio ({} bytes) in {:?}",
-                                    duration_secs,
-                                    wav_data.len(),
-                                    start.elapsed()
-                                );
-
-                                // Send metadata first
-                                let response = Response::AudioReady {
-                                    duration_secs,
-                                    sample_rate: sirius_protocol::SAMPLE_RATE,
-                                    channels: sirius_protocol::CHANNELS,
-                                    size_bytes: wav_data.len(),
-                                };
-                                ws_sender
-                                    .send(Message::Text(serde_json::to_string(&response)?))
-                                    .await?;
+                }
 
-                                // Then send binary audio data
-                                ws_sender.send(Message::Binary(wav_data)).await?;
-                            }
-                            Err(e) => {
-                                error!("TTS error: {}", e);
-                                let response = Response::Error {
-                                    message: format!("TTS error: {}", e),
+                async {
+                    match request {
+                        Request::Ping => {
+                            let response = Response::Pong;
+                            ws_sender.send(text_message(&response, &trace_id)?).await?;
+                        }
+                        Request::Echo { payload } => {
+                            let response = Response::Echo { payload };
+                            ws_sender.send(text_message(&response, &trace_id)?).await?;
+                        }
+                        // Answered while parsing
+                        Request::Unknown => {}
+                        Request::GetConfig { token } => {
+                            let response = match authorize(&config.admin_token, &token) {
+                                Ok(()) => Response::Config(config.settings.get()),
+                                Err(response) => response,
+                            };
+                            ws_sender.send(text_message(&response, &trace_id)?).await?;
+                        }
+                        Request::SetConfig { token, settings } => {
+                            let response = match authorize(&config.admin_token, &token) {
+                                Ok(()) => match config.settings.update(&settings) {
+                                    Ok(runtime_config) => {
+                                        info!("{} changed the runtime config: {:?}", peer_addr, runtime_config);
+                                        Response::Config(runtime_config)
+                                    }
+                                    Err(e) => tts_error_response(&e),
+                                },
+                                Err(response) => {
+                                    warn!("Refused SetConfig from {}", peer_addr);
+                                    response
+                                }
+                            };
+                            ws_sender.send(text_message(&response, &trace_id)?).await?;
+                        }
+                        Request::SetDefaults { voice, lang, speed } => {
+                            let known_voices = tts.lock().await.voices();
+                            let response = match defaults.update(voice, lang, speed, &known_voices) {
+                                Ok(()) => defaults.response(),
+                                Err(e) => tts_error_response(&e),
+                            };
+                            ws_sender.send(text_message(&response, &trace_id)?).await?;
+                        }
+                        Request::Synthesize(mut req) => {
+                            defaults.apply(&mut req);
+                            info!(
+                                "Synthesizing {} chars for {} (voice: {})",
+                                req.text.len(),
+                                peer_addr,
+                                req.voice
+                            );
+
+                            let sender = &mut ws_sender;
+                            let (trace_id, config) = (&trace_id, &config);
+                            synthesize_guarded(&tts, config, &peer_addr, &req, |result| async move {
+                                let audio = match result {
+                                    Ok(audio) => audio,
+                                    Err(response) => {
+                                        sender.send(text_message(&response, trace_id)?).await?;
+                                        return Ok(());
+                                    }
                                 };
-                                ws_sender
-                                    .send(Message::Text(serde_json::to_string(&response)?))
-                                    .await?;
+                                warn_if_near_limit(audio.data.len().min(config.chunk_bytes), &config.ws);
+
+                                // Metadata first, then the binary audio data
+                                let messages = synthesis_messages(
+                                    audio,
+                                    trace_id,
+                                    req.metadata_only,
+                                    req.inline_audio,
+                                    config.chunk_bytes,
+                                )?;
+                                for message in messages {
+                                    sender.send(message).await?;
+                                }
+                                Ok(())
+                            })
+                            .await?;
+                        }
+                        Request::VoicePreview {
+                            text,
+                            voices,
+                            mut lang,
+                            mut speed,
+                        } => {
+                            defaults.apply_lang_speed(&mut lang, &mut speed);
+                            info!(
+                                "Previewing {} voices ({} chars) for {}",
+                                voices.len(),
+                                text.len(),
+                                peer_addr
+                            );
+                            let start = std::time::Instant::now();
+                            let mut record = AuditRecord::new(&peer_addr, &voices.join(","), text.chars().count());
+                            let template = SynthesizeRequest::new(text).with_lang(lang).with_speed(speed);
+                            let result = preview(&tts, &config, &peer_addr, &template, &voices, &mut record).await;
+
+                            match result {
+                                Ok(payloads) => {
+                                    for (response, audio) in payloads {
+                                        ws_sender.send(text_message(&response, &trace_id)?).await?;
+                                        for chunk in binary_messages(audio.data, config.chunk_bytes) {
+                                            ws_sender.send(chunk).await?;
+                                        }
+                                    }
+                                }
+                                Err(response) => {
+                                    ws_sender.send(text_message(&response, &trace_id)?).await?;
+                                }
                             }
+                            audit(&config, record, start);
                         }
+                        Request::SynthesizePrompt { .. } => unreachable!("prompts are filled in above"),
                     }
+                    anyhow::Ok(())
                 }
+                .instrument(span)
+                .await?;
             }
             Message::Binary(_) => {
                 warn!("Received unexpected binary message from {}", peer_addr);
@@ -171,6 +1047,8 @@ async fn handle_connection(
             Message::Pong(_) => {}
             Message::Close(_) => {
                 info!("Client {} disconnected", peer_addr);
+                // Flushes the reply echoing the client's close frame
+                let _ = ws_sender.close().await;
                 break;
             }
             Message::Frame(_) => {}
@@ -180,3 +1058,1431 @@ async fn handle_connection(
     info!("Connection closed: {}", peer_addr);
     Ok(())
 }
+
+/// Client-facing message for a synthesis failure
+fn tts_error_message(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<SynthesisError>() {
+        Some(synthesis_error) => synthesis_error.to_string(),
+        None => format!("TTS error: {}", e),
+    }
+}
+
+/// Protocol error code for a synthesis failure
+fn tts_error_code(e: &anyhow::Error) -> ErrorCode {
+    match e.downcast_ref::<SynthesisError>() {
+        Some(synthesis_error) => synthesis_error.code(),
+        None => ErrorCode::Internal,
+    }
+}
+
+/// Log a finished synthesis that took `elapsed`. With a slow threshold,
+/// only the syntheses over it are logged above DEBUG, with enough detail
+/// to find the input that made them slow.
+fn log_synthesis(
+    slow_threshold: Option<Duration>,
+    req: &SynthesizeRequest,
+    audio: &SynthesizedAudio,
+    elapsed: Duration,
+) {
+    let audio_secs = audio.duration_secs();
+    let Some(threshold) = slow_threshold else {
+        info!("Generated {:.2}s audio ({} bytes) in {:?}", audio_secs, audio.data.len(), elapsed);
+        return;
+    };
+    let rtf = if audio_secs > 0.0 { elapsed.as_secs_f32() / audio_secs } else { 0.0 };
+    if elapsed > threshold {
+        warn!(
+            "Slow synthesis: {:?} for {} chars (voice: {}), {:.2}s audio, RTF {:.2}",
+            elapsed,
+            req.text.chars().count(),
+            req.voice,
+            audio_secs,
+            rtf
+        );
+    } else {
+        debug!("Generated {:.2}s audio ({} bytes) in {:?}, RTF {:.2}", audio_secs, audio.data.len(), elapsed, rtf);
+    }
+}
+
+/// Accept `SUBPROTOCOL` if the client offers it, and reject clients that
+/// only offer others. Clients offering none predate versioning and get v1.
+// The error type is tungstenite's, for its handshake callback
+#[allow(clippy::result_large_err)]
+fn negotiate_subprotocol(
+    request: &HandshakeRequest,
+    mut response: HandshakeResponse,
+) -> Result<HandshakeResponse, ErrorResponse> {
+    let offered: Vec<&str> = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .collect();
+    if offered.is_empty() {
+        return Ok(response);
+    }
+    if !offered.contains(&sirius_protocol::SUBPROTOCOL) {
+        warn!("Rejecting handshake offering only subprotocols {:?}", offered);
+        let mut error = ErrorResponse::new(Some(format!(
+            "unsupported subprotocol; this server speaks {}",
+            sirius_protocol::SUBPROTOCOL
+        )));
+        *error.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(error);
+    }
+    response
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(sirius_protocol::SUBPROTOCOL));
+    Ok(response)
+}
+
+/// `Ok` if `token` matches the server's admin token, else the error to send.
+/// The comparison takes as long however many leading bytes match, so timing
+/// replies doesn't reveal the token a byte at a time.
+fn authorize(admin_token: &Option<String>, token: &str) -> Result<(), Response> {
+    match admin_token {
+        Some(admin_token) if bool::from(admin_token.as_bytes().ct_eq(token.as_bytes())) => Ok(()),
+        Some(_) => Err(Response::Error {
+            code: ErrorCode::Unauthorized,
+            message: "wrong admin token".to_string(),
+        }),
+        None => Err(Response::Error {
+            code: ErrorCode::Unauthorized,
+            message: "admin requests are disabled; set SIRIUS_ADMIN_TOKEN to allow them".to_string(),
+        }),
+    }
+}
+
+fn tts_error_response(e: &anyhow::Error) -> Response {
+    Response::Error {
+        code: tts_error_code(e),
+        message: tts_error_message(e),
+    }
+}
+
+/// The messages answering a synthesis: any warnings and word marks, then
+/// `AudioReady`, then the audio, unless only the metadata was requested.
+/// The audio goes as binary messages of at most `chunk_bytes` each, or with
+/// `inline_audio` as one `AudioInline`.
+fn synthesis_messages(
+    audio: SynthesizedAudio,
+    trace_id: &str,
+    metadata_only: bool,
+    inline_audio: bool,
+    chunk_bytes: usize,
+) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    for (code, message) in &audio.warnings {
+        let warning = Response::Warning {
+            code: *code,
+            message: message.clone(),
+        };
+        messages.push(text_message(&warning, trace_id)?);
+    }
+    for (word_index, &start_secs) in audio.word_marks.iter().enumerate() {
+        let mark = Response::WordMark { word_index, start_secs };
+        messages.push(text_message(&mark, trace_id)?);
+    }
+
+    let response = Response::AudioReady {
+        duration_secs: audio.duration_secs(),
+        num_samples: audio.num_samples as u64,
+        sample_rate: audio.format.sample_rate(),
+        channels: sirius_protocol::CHANNELS,
+        size_bytes: audio.data.len(),
+        format: audio.format,
+        bits_per_sample: audio.bits_per_sample,
+        used_fallback: audio.used_fallback,
+        crc32: Some(sirius_protocol::crc32(&audio.data)),
+    };
+
+    messages.push(text_message(&response, trace_id)?);
+    if metadata_only {
+        return Ok(messages);
+    }
+    if inline_audio {
+        let inline = Response::AudioInline {
+            mime: audio.format.mime_type().to_string(),
+            data_base64: BASE64_STANDARD.encode(&audio.data),
+        };
+        messages.push(text_message(&inline, trace_id)?);
+    } else {
+        messages.extend(binary_messages(audio.data, chunk_bytes));
+    }
+    Ok(messages)
+}
+
+/// `data` as consecutive binary messages of at most `chunk_bytes` each
+fn binary_messages(data: Vec<u8>, chunk_bytes: usize) -> Vec<Message> {
+    if data.len() <= chunk_bytes {
+        return vec![Message::Binary(data)];
+    }
+    data.chunks(chunk_bytes)
+        .map(|chunk| Message::Binary(chunk.to_vec()))
+        .collect()
+}
+
+/// `template` once per voice of a `VoicePreview`, refusing the preview as a
+/// whole if it names no voices, too many, or any the backend doesn't know,
+/// or if a request would be invalid
+fn preview_requests(template: &SynthesizeRequest, voices: &[String], known: &[String]) -> Result<Vec<SynthesizeRequest>> {
+    if voices.is_empty() || voices.len() > sirius_protocol::MAX_PREVIEW_VOICES {
+        return Err(SynthesisError::InvalidParameter(format!(
+            "a preview takes 1 to {} voices, not {}",
+            sirius_protocol::MAX_PREVIEW_VOICES,
+            voices.len()
+        ))
+        .into());
+    }
+    if !tts::SPEED_RANGE.contains(&template.speed) {
+        return Err(SynthesisError::InvalidParameter(format!(
+            "speed must be between {} and {}",
+            tts::SPEED_RANGE.start(),
+            tts::SPEED_RANGE.end()
+        ))
+        .into());
+    }
+    voices
+        .iter()
+        .map(|voice| {
+            let req = template.clone().with_voice(voice);
+            req.validate().map_err(SynthesisError::InvalidParameter)?;
+            synth::check_voice(voice, known)?;
+            Ok(req)
+        })
+        .collect()
+}
+
+/// Synthesize one payload per request, in order, stopping at the first failure
+fn preview_payloads<F>(requests: &[SynthesizeRequest], mut synthesize: F) -> Result<Vec<(Response, SynthesizedAudio)>>
+where
+    F: FnMut(&SynthesizeRequest) -> Result<SynthesizedAudio>,
+{
+    requests
+        .iter()
+        .enumerate()
+        .map(|(index, req)| {
+            let audio = synthesize(req)?;
+            let response = Response::PreviewReady {
+                index,
+                voice: req.voice.clone(),
+                duration_secs: audio.duration_secs(),
+                sample_rate: audio.format.sample_rate(),
+                channels: sirius_protocol::CHANNELS,
+                size_bytes: audio.data.len(),
+            };
+            Ok((response, audio))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use synth::SineSynthesizer;
+
+    /// Start a server backed by the sine synthesizer on an ephemeral port
+    async fn spawn_test_server() -> SocketAddr {
+        spawn_server_with(SineSynthesizer::default(), ConnectionConfig::default()).await
+    }
+
+    async fn spawn_server_with<S: SpeechSynthesizer>(
+        synth: S,
+        config: ConnectionConfig,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tts = TtsEngine::with_synthesizer(synth);
+        let config = ConnectionConfig { settings: tts.settings(), ..config };
+        tokio::spawn(serve(listener, Arc::new(Mutex::new(tts)), config));
+        addr
+    }
+
+    async fn send_request<S>(ws: &mut S, request: &Request)
+    where
+        S: SinkExt<Message> + Unpin,
+        S::Error: std::fmt::Debug,
+    {
+        ws.send(Message::Text(serde_json::to_string(request).unwrap()))
+            .await
+            .unwrap();
+    }
+
+    async fn next_response<S>(ws: &mut S) -> Response
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    async fn next_binary<S>(ws: &mut S) -> Vec<u8>
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => data,
+            other => panic!("expected a binary message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_defaults_fills_in_requests() {
+        let addr = spawn_server_with(synth::MockSynthesizer::default(), ConnectionConfig::default()).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        // Like `{"type": "Synthesize", "data": {"text": "Hello."}}`
+        let bare = Request::Synthesize(SynthesizeRequest::new("Hello."));
+
+        // The protocol's default voice isn't one the mock knows...
+        send_request(&mut ws, &bare).await;
+        match next_response(&mut ws).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::UnknownVoice),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        // ...and neither is this, so nothing changes
+        let set_defaults = |voice: &str| Request::SetDefaults {
+            voice: Some(voice.to_string()),
+            lang: None,
+            speed: Some(1.2),
+        };
+        send_request(&mut ws, &set_defaults("zz_nobody")).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Error { code: ErrorCode::UnknownVoice, .. }));
+
+        send_request(&mut ws, &set_defaults("mock")).await;
+        match next_response(&mut ws).await {
+            Response::DefaultsSet { voice, lang, speed } => {
+                assert_eq!(voice.as_deref(), Some("mock"));
+                assert_eq!(lang, None);
+                assert_eq!(speed, Some(1.2));
+            }
+            other => panic!("expected DefaultsSet, got {:?}", other),
+        }
+        send_request(&mut ws, &bare).await;
+        assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+        ws.next().await.unwrap().unwrap();
+
+        // An explicit voice still wins
+        send_request(&mut ws, &Request::Synthesize(SynthesizeRequest::new("Hello.").with_voice("zz_nobody"))).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Error { code: ErrorCode::UnknownVoice, .. }));
+
+        // Defaults belong to the connection
+        let (mut other, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        send_request(&mut other, &bare).await;
+        assert!(matches!(next_response(&mut other).await, Response::Error { code: ErrorCode::UnknownVoice, .. }));
+    }
+
+    #[test]
+    fn test_connection_defaults_apply() {
+        let mut defaults = ConnectionDefaults::default();
+        defaults
+            .update(None, Some("en-gb".to_string()), Some(1.2), &["mock".to_string()])
+            .unwrap();
+        assert!(defaults.update(None, None, Some(0.0), &[]).is_err());
+
+        let mut req = SynthesizeRequest::new("Hi").with_voice("mock");
+        defaults.apply(&mut req);
+        assert_eq!((req.voice.as_str(), req.lang.as_str(), req.speed), ("mock", "en-gb", 1.2));
+
+        // Explicit values are kept
+        let mut req = SynthesizeRequest::new("Hi").with_lang("fr-fr").with_speed(0.8);
+        defaults.apply(&mut req);
+        assert_eq!((req.voice.as_str(), req.lang.as_str(), req.speed), (DEFAULT_VOICE, "fr-fr", 0.8));
+    }
+
+    #[tokio::test]
+    async fn test_synthesis_does_not_stall_other_connections() {
+        // The test runtime has one thread, so synthesizing on it would stop everything else
+        let mut synth = synth::MockSynthesizer::default();
+        synth.delay = Duration::from_millis(500);
+        let addr = spawn_server_with(synth, ConnectionConfig::default()).await;
+        let (mut slow, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let (mut other, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        send_request(&mut slow, &Request::Synthesize(SynthesizeRequest::new("Hello.").with_voice("mock"))).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = std::time::Instant::now();
+        send_request(&mut other, &Request::Ping).await;
+        assert!(matches!(next_response(&mut other).await, Response::Pong));
+        assert!(start.elapsed() < Duration::from_millis(300), "{:?}", start.elapsed());
+
+        assert!(matches!(next_response(&mut slow).await, Response::AudioReady { .. }));
+        next_binary(&mut slow).await;
+    }
+
+    #[tokio::test]
+    async fn test_breaker_short_circuits_a_failing_engine() {
+        // The engine fails twice, then would work again
+        let synth = synth::MockSynthesizer::default();
+        synth.transient_failures.store(2, std::sync::atomic::Ordering::SeqCst);
+        let config = ConnectionConfig {
+            breaker: Some(Arc::new(CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_millis(200)))),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(synth, config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let request = Request::Synthesize(SynthesizeRequest::new("Hello.").with_voice("mock").with_strict(true));
+        let error_code = |response| match response {
+            Response::Error { code, .. } => code,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        for _ in 0..2 {
+            send_request(&mut ws, &request).await;
+            assert_eq!(error_code(next_response(&mut ws).await), ErrorCode::Internal);
+        }
+        // Open: refused without reaching the engine, which would have worked
+        send_request(&mut ws, &request).await;
+        assert_eq!(error_code(next_response(&mut ws).await), ErrorCode::Busy);
+
+        // After the cooldown a trial request succeeds and closes it
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        for _ in 0..2 {
+            send_request(&mut ws, &request).await;
+            assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+            next_binary(&mut ws).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_native_rate_is_resampled() {
+        // A 16 kHz voice: 100 samples per character at its own rate
+        let mut synth = synth::MockSynthesizer::default();
+        synth.native_sample_rate = 16_000;
+        let addr = spawn_server_with(synth, ConnectionConfig::default()).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+        send_request(&mut ws, &Request::Synthesize(request)).await;
+        match next_response(&mut ws).await {
+            Response::AudioReady { sample_rate, num_samples, duration_secs, .. } => {
+                assert_eq!(sample_rate, sirius_protocol::SAMPLE_RATE);
+                assert_eq!(num_samples, 900);
+                assert!((duration_secs - 600.0 / 16_000.0).abs() < 1e-6);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let data = next_binary(&mut ws).await;
+        assert_eq!(hound::WavReader::new(std::io::Cursor::new(data)).unwrap().len(), 900);
+    }
+
+    #[tokio::test]
+    async fn test_busy_when_queue_full() {
+        // No waiting places: only the request holding the engine gets in
+        let places = Arc::new(Semaphore::new(1));
+        let config = ConnectionConfig {
+            admission: Some(Arc::clone(&places)),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tts = Arc::new(Mutex::new(TtsEngine::with_synthesizer(SineSynthesizer::default())));
+        tokio::spawn(serve(listener, Arc::clone(&tts), config));
+
+        // Hold the engine so the first request sits on it with the only place
+        let engine = tts.lock().await;
+        let request = Request::Synthesize(sirius_protocol::SynthesizeRequest::new("Hello there."));
+        let (mut first, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        send_request(&mut first, &request).await;
+        while places.available_permits() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let (mut second, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        send_request(&mut second, &request).await;
+        match next_response(&mut second).await {
+            Response::Error { code, .. } => assert_eq!(code, ErrorCode::Busy),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // Once the engine frees up the first request completes normally
+        drop(engine);
+        assert!(matches!(next_response(&mut first).await, Response::AudioReady { .. }));
+        assert_eq!(places.available_permits(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_round_trip() {
+        let path = std::env::temp_dir().join(format!("sirius-test-{}.sock", std::process::id()));
+        let listener = bind_unix(path.to_str().unwrap()).unwrap();
+        let tts = Arc::new(Mutex::new(TtsEngine::with_synthesizer(SineSynthesizer::default())));
+        tokio::spawn(serve_unix(listener, tts, ConnectionConfig::default()));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/", stream).await.unwrap();
+        send_request(&mut ws, &Request::Synthesize(sirius_protocol::SynthesizeRequest::new("Hello there."))).await;
+
+        let size_bytes = match next_response(&mut ws).await {
+            Response::AudioReady { size_bytes, .. } => size_bytes,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert_eq!(next_binary(&mut ws).await.len(), size_bytes);
+
+        // Rebinding replaces the stale socket instead of failing
+        drop(ws);
+        assert!(bind_unix(path.to_str().unwrap()).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_synthesize_round_trip() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        let request = Request::Synthesize(sirius_protocol::SynthesizeRequest::new("Hello there."));
+        send_request(&mut ws, &request).await;
+
+        let (num_samples, size_bytes) = match next_response(&mut ws).await {
+            Response::AudioReady {
+                duration_secs,
+                num_samples,
+                size_bytes,
+                sample_rate,
+                channels,
+                ..
+            } => {
+                assert_eq!(sample_rate, sirius_protocol::SAMPLE_RATE);
+                assert_eq!(channels, sirius_protocol::CHANNELS);
+                // The sample count is authoritative; the duration follows from it
+                assert_eq!(num_samples as f32 / sample_rate as f32, duration_secs);
+                (num_samples, size_bytes)
+            }
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert_eq!(
+            num_samples,
+            ("Hello there.".chars().count() * SineSynthesizer::default().samples_per_char) as u64
+        );
+
+        let wav_data = next_binary(&mut ws).await;
+        assert_eq!(wav_data.len(), size_bytes);
+
+        let reader = hound::WavReader::new(std::io::Cursor::new(wav_data)).unwrap();
+        assert_eq!(reader.spec().sample_rate, sirius_protocol::SAMPLE_RATE);
+        assert_eq!(reader.spec().channels, sirius_protocol::CHANNELS);
+        assert_eq!(reader.spec().bits_per_sample, sirius_protocol::BITS_PER_SAMPLE);
+        assert_eq!(reader.len() as u64, num_samples);
+    }
+
+    #[tokio::test]
+    async fn test_payload_over_default_frame_limit() {
+        // ~20 MiB of WAV: over tungstenite's 16 MiB default frame limit
+        let synth = SineSynthesizer {
+            samples_per_char: 1_000_000,
+            ..Default::default()
+        };
+        let limits = ws_config_with_limit(sirius_protocol::DEFAULT_MAX_MESSAGE_MB * 1024 * 1024);
+        let config = ConnectionConfig {
+            ws: limits,
+            // One message, so the frame limit is what's exercised
+            chunk_bytes: usize::MAX,
+            ..Default::default()
+        };
+        let addr = spawn_server_with(synth, config).await;
+        let request = Request::Synthesize(sirius_protocol::SynthesizeRequest::new("Long text"));
+
+        // A client on default limits can't receive it...
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        send_request(&mut ws, &request).await;
+        let size_bytes = match next_response(&mut ws).await {
+            Response::AudioReady { size_bytes, .. } => size_bytes,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert!(size_bytes > 16 << 20);
+        assert!(ws.next().await.unwrap().is_err());
+
+        // ...one with the raised limit gets every byte
+        let (mut ws, _) =
+            tokio_tungstenite::connect_async_with_config(format!("ws://{}", addr), Some(limits), false)
+                .await
+                .unwrap();
+        send_request(&mut ws, &request).await;
+        assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+        assert_eq!(next_binary(&mut ws).await.len(), size_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_large_payload_is_chunked() {
+        let config = ConnectionConfig {
+            chunk_bytes: 1000,
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        let request = Request::Synthesize(sirius_protocol::SynthesizeRequest::new("Hello there."));
+        send_request(&mut ws, &request).await;
+        let size_bytes = match next_response(&mut ws).await {
+            Response::AudioReady { size_bytes, .. } => size_bytes,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert!(size_bytes > 1000);
+
+        let mut assembler = sirius_protocol::PayloadAssembler::new(size_bytes);
+        let mut chunks = 0;
+        let wav_data = loop {
+            let chunk = next_binary(&mut ws).await;
+            assert!(chunk.len() <= 1000);
+            chunks += 1;
+            if let Some(data) = assembler.push(&chunk).unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(chunks, size_bytes.div_ceil(1000));
+        assert_eq!(wav_data.len(), size_bytes);
+        assert!(hound::WavReader::new(std::io::Cursor::new(wav_data)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_closed() {
+        let config = ConnectionConfig {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        // Activity keeps the connection open past the timeout...
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            send_request(&mut ws, &Request::Ping).await;
+            assert!(matches!(next_response(&mut ws).await, Response::Pong));
+        }
+
+        // ...silence gets it closed with a reason
+        let started = std::time::Instant::now();
+        let message = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("server should close an idle connection")
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        match message {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Away);
+                assert_eq!(frame.reason, "idle timeout");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    /// The next message, which must be a close frame, as (code, reason)
+    async fn close_frame<S>(ws: &mut S) -> (CloseCode, String)
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let message = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("server should close the connection")
+            .unwrap()
+            .unwrap();
+        match message {
+            Message::Close(Some(frame)) => (frame.code, frame.reason.into_owned()),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections() {
+        let (shutdown, shutdown_signal) = watch::channel(false);
+        let config = ConnectionConfig {
+            shutdown: Some(shutdown_signal),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        send_request(&mut ws, &Request::Ping).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Pong));
+
+        shutdown.send(true).unwrap();
+        let (code, reason) = close_frame(&mut ws).await;
+        assert_eq!(code, CloseCode::Restart);
+        assert_eq!(CloseReason::from_code(code.into()), Some(CloseReason::Shutdown));
+        assert_eq!(reason, "server shutting down");
+
+        // The listener stops and the connection task ends
+        tokio::time::timeout(Duration::from_secs(5), shutdown.closed())
+            .await
+            .expect("every connection and listener should let go of the shutdown signal");
+    }
+
+    #[tokio::test]
+    async fn test_request_limit_closes_connection() {
+        let config = ConnectionConfig {
+            max_requests: Some(2),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let request = Request::Synthesize(sirius_protocol::SynthesizeRequest::new("Hello there."));
+
+        for _ in 0..2 {
+            send_request(&mut ws, &request).await;
+            assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+            next_binary(&mut ws).await;
+            // Pings don't count
+            send_request(&mut ws, &Request::Ping).await;
+            assert!(matches!(next_response(&mut ws).await, Response::Pong));
+        }
+
+        send_request(&mut ws, &request).await;
+        let (code, reason) = close_frame(&mut ws).await;
+        assert_eq!(code, CloseCode::Again);
+        assert_eq!(CloseReason::from_code(code.into()), Some(CloseReason::RequestLimit));
+        assert_eq!(reason, "request limit reached");
+    }
+
+    #[tokio::test]
+    async fn test_protocol_error_closes_connection() {
+        let config = ConnectionConfig {
+            ws: WebSocketConfig {
+                max_message_size: Some(64),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        send_request(&mut ws, &Request::Synthesize(sirius_protocol::SynthesizeRequest::new("x".repeat(100)))).await;
+        let (code, reason) = close_frame(&mut ws).await;
+        assert_eq!(code, CloseCode::Protocol);
+        assert_eq!(reason, "protocol error");
+    }
+
+    #[tokio::test]
+    async fn test_client_close_is_echoed() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        ws.send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        })))
+        .await
+        .unwrap();
+        let (code, _) = close_frame(&mut ws).await;
+        assert_eq!(code, CloseCode::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_has_one_line_per_request() {
+        let path = std::env::temp_dir().join(format!("sirius-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = ConnectionConfig {
+            audit: Some(AuditLog::open(&path).await.unwrap()),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        // Two successes and a failure (nothing speakable)
+        for text in ["Hello there.", "Again.", "..."] {
+            let request = Request::Synthesize(sirius_protocol::SynthesizeRequest::new(text));
+            send_request(&mut ws, &request).await;
+            if let Response::AudioReady { .. } = next_response(&mut ws).await {
+                next_binary(&mut ws).await;
+            }
+        }
+
+        // Records reach the disk within a flush interval
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let contents = loop {
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() >= 3 || std::time::Instant::now() > deadline {
+                break contents;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["chars"], "Hello there.".len());
+        assert_eq!(records[0]["voice"], "am_onyx.4+bm_lewis.6");
+        assert_eq!(records[1]["success"], true);
+        assert_eq!(records[1]["outcome"], "ok");
+        assert_eq!(records[2]["success"], false);
+        assert_eq!(records[2]["outcome"], "failed");
+        for record in &records {
+            assert!(record["timestamp_ms"].as_u64().unwrap() > 0);
+            assert!(record["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+            assert!(record["latency_ms"].is_u64());
+            assert!(record["duration_secs"].is_f64());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_previews_are_archived_and_audited() {
+        let path = std::env::temp_dir().join(format!("sirius-audit-preview-{}.jsonl", std::process::id()));
+        let dir = std::env::temp_dir().join(format!("sirius-archive-preview-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = ConnectionConfig {
+            audit: Some(AuditLog::open(&path).await.unwrap()),
+            archive: Some(Archive::open(&dir, None).await.unwrap()),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let preview = |voices: &[&str]| Request::VoicePreview {
+            text: "Hello.".to_string(),
+            voices: voices.iter().map(|voice| voice.to_string()).collect(),
+            lang: DEFAULT_LANG.to_string(),
+            speed: DEFAULT_SPEED,
+        };
+
+        send_request(&mut ws, &preview(&["sine", "sine"])).await;
+        for _ in 0..2 {
+            assert!(matches!(next_response(&mut ws).await, Response::PreviewReady { .. }));
+            next_binary(&mut ws).await;
+        }
+        send_request(&mut ws, &preview(&["sine", "zz_nobody"])).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Error { code: ErrorCode::UnknownVoice, .. }));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let (contents, clips) = loop {
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            let clips = std::fs::read_dir(&dir).map(|entries| entries.count()).unwrap_or(0);
+            if (contents.lines().count() >= 2 && clips >= 2) || std::time::Instant::now() > deadline {
+                break (contents, clips);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(clips, 2);
+        let records: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["voice"], "sine,sine");
+        assert_eq!(records[0]["outcome"], "ok");
+        assert!(records[0]["duration_secs"].as_f64().unwrap() > 0.0);
+        assert_eq!(records[1]["outcome"], "failed");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_records_refusals() {
+        let path = std::env::temp_dir().join(format!("sirius-audit-refused-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // No places at all, so every request is turned away
+        let config = ConnectionConfig {
+            audit: Some(AuditLog::open(&path).await.unwrap()),
+            admission: Some(Arc::new(Semaphore::new(0))),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        send_request(&mut ws, &Request::Synthesize(SynthesizeRequest::new("Hello."))).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Error { code: ErrorCode::Busy, .. }));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let contents = loop {
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if !contents.is_empty() || std::time::Instant::now() > deadline {
+                break contents;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        let record: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(record["outcome"], "queue_full");
+        assert_eq!(record["success"], false);
+        assert_eq!(record["chars"], "Hello.".len());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_and_invalid_request() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        send_request(&mut ws, &Request::Ping).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Pong));
+
+        ws.send(Message::Text("{\"type\":\"Nope\"}".to_string()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_response(&mut ws).await,
+            Response::Error {
+                code: ErrorCode::BadRequest,
+                ..
+            }
+        ));
+
+        // A request type from a newer client is named, with or without data
+        for json in [
+            r#"{"type": "Transcribe", "data": {"language": "en"}}"#,
+            r#"{"type": "Transcribe"}"#,
+        ] {
+            ws.send(Message::Text(json.to_string())).await.unwrap();
+            match next_response(&mut ws).await {
+                Response::Error { code, message } => {
+                    assert_eq!(code, ErrorCode::BadRequest);
+                    assert_eq!(message, "unsupported request type: Transcribe");
+                }
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        // Malformed known requests keep serde's detail
+        ws.send(Message::Text(r#"{"type": "Echo", "data": {}}"#.to_string()))
+            .await
+            .unwrap();
+        match next_response(&mut ws).await {
+            Response::Error { message, .. } => assert!(message.contains("payload"), "{}", message),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_round_trips_payloads() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        let payloads = ["", "hello", "héllo 日本語 🎙️", "two\nlines\t\"quoted\" \\ {\"type\":\"Ping\"}"];
+        for payload in payloads {
+            send_request(&mut ws, &Request::Echo { payload: payload.to_string() }).await;
+            match next_response(&mut ws).await {
+                Response::Echo { payload: echoed } => assert_eq!(echoed, payload),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_ids_tag_responses() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        async fn next_traced<S>(ws: &mut S) -> Traced<Response>
+        where
+            S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+        {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("expected a text message, got {:?}", other),
+            }
+        }
+
+        // Every response to a request carries its id, audio metadata included
+        let mut request = SynthesizeRequest::new("Hello");
+        request.speed = 9.0;
+        let json = serde_json::to_string(&Traced::new(Request::Synthesize(request), "call-42")).unwrap();
+        ws.send(Message::Text(json)).await.unwrap();
+        let warning = next_traced(&mut ws).await;
+        assert!(matches!(warning.message, Response::Warning { .. }), "{:?}", warning);
+        assert_eq!(warning.trace_id.as_deref(), Some("call-42"));
+        let ready = next_traced(&mut ws).await;
+        assert!(matches!(ready.message, Response::AudioReady { .. }), "{:?}", ready);
+        assert_eq!(ready.trace_id.as_deref(), Some("call-42"));
+        next_binary(&mut ws).await;
+
+        // Without one, each request gets its own
+        let mut generated = Vec::new();
+        for _ in 0..2 {
+            send_request(&mut ws, &Request::Ping).await;
+            let pong = next_traced(&mut ws).await;
+            assert!(matches!(pong.message, Response::Pong));
+            generated.push(pong.trace_id.unwrap());
+        }
+        assert!(!generated[0].is_empty());
+        assert_ne!(generated[0], generated[1]);
+    }
+
+    #[test]
+    fn test_tts_error_codes() {
+        let cases: Vec<(anyhow::Error, ErrorCode)> = vec![
+            (SynthesisError::NoAudio.into(), ErrorCode::BadRequest),
+            (
+                SynthesisError::UnknownVoice("zz_nobody".to_string()).into(),
+                ErrorCode::UnknownVoice,
+            ),
+            (anyhow::anyhow!("onnx runtime failed"), ErrorCode::Internal),
+        ];
+        for (error, expected) in cases {
+            match tts_error_response(&error) {
+                Response::Error { code, .. } => assert_eq!(code, expected, "{}", error),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+    }
+
+    fn audio(num_samples: usize) -> SynthesizedAudio {
+        SynthesizedAudio {
+            data: vec![0; 44 + num_samples * 2],
+            format: sirius_protocol::AudioFormat::Wav,
+            bits_per_sample: 16,
+            num_samples,
+            warnings: Vec::new(),
+            word_marks: Vec::new(),
+            sentence_timings: Vec::new(),
+            used_fallback: 0,
+        }
+    }
+
+    #[test]
+    fn test_default_voice_from_env() {
+        let known = vec!["af_bella".to_string(), "am_onyx".to_string()];
+        assert_eq!(default_voice(None, &known).unwrap(), None);
+
+        let voice = default_voice(Some("af_bella.7+am_onyx.3".to_string()), &known).unwrap();
+        assert_eq!(voice.as_deref(), Some("af_bella.7+am_onyx.3"));
+
+        let err = default_voice(Some("zz_nobody".to_string()), &known).unwrap_err();
+        assert!(err.to_string().contains("SIRIUS_DEFAULT_VOICE"), "{}", err);
+        assert!(err.to_string().contains("zz_nobody"), "{}", err);
+    }
+
+    #[test]
+    fn test_thread_count() {
+        assert_eq!(thread_count("SIRIUS_ORT_INTRA", None).unwrap(), None);
+        assert_eq!(thread_count("SIRIUS_ORT_INTRA", Some("4".to_string())).unwrap(), Some(4));
+        for bad in ["0", "-1", "two", ""] {
+            let err = thread_count("SIRIUS_ORT_INTER", Some(bad.to_string())).unwrap_err();
+            assert!(err.to_string().starts_with("SIRIUS_ORT_INTER must be"), "{}: {}", bad, err);
+        }
+    }
+
+    #[test]
+    fn test_session_options_from_env() {
+        use kokoro::onn::ort_base::Provider;
+
+        let value = |value: &str| Some(value.to_string());
+        assert_eq!(
+            session_options(value("cpu"), value("3"), value("2")).unwrap(),
+            SessionOptions {
+                provider: Provider::Cpu,
+                intra_threads: Some(3),
+                inter_threads: Some(2),
+            }
+        );
+
+        // Unset: every core for one operator, operators one at a time
+        let options = session_options(value("cpu"), None, None).unwrap();
+        assert_eq!(
+            options.intra_threads,
+            Some(std::thread::available_parallelism().unwrap().get())
+        );
+        assert_eq!(options.inter_threads, Some(1));
+
+        let err = session_options(None, None, value("0")).unwrap_err();
+        assert!(err.to_string().contains("SIRIUS_ORT_INTER"), "{}", err);
+
+        let err = session_options(value("tpu"), None, None).unwrap_err().to_string();
+        assert!(err.starts_with("SIRIUS_EP: unknown execution provider \"tpu\""), "{}", err);
+    }
+
+    #[test]
+    fn test_metadata_only_sends_no_binary() {
+        let messages = synthesis_messages(audio(2400), "t1", true, false, usize::MAX).unwrap();
+        assert_eq!(messages.len(), 1);
+        match &messages[0] {
+            Message::Text(text) => match serde_json::from_str(text).unwrap() {
+                Response::AudioReady {
+                    num_samples,
+                    size_bytes,
+                    ..
+                } => {
+                    assert_eq!(num_samples, 2400);
+                    assert_eq!(size_bytes, 44 + 4800);
+                }
+                other => panic!("unexpected response: {:?}", other),
+            },
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let messages = synthesis_messages(audio(2400), "t1", false, false, usize::MAX).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[1], Message::Binary(data) if data.len() == 44 + 4800));
+    }
+
+    #[test]
+    fn test_inline_audio_round_trip() {
+        let mut original = std::io::Cursor::new(Vec::new());
+        let spec = hound::WavSpec {
+            channels: sirius_protocol::CHANNELS,
+            sample_rate: sirius_protocol::SAMPLE_RATE,
+            bits_per_sample: sirius_protocol::BITS_PER_SAMPLE,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut original, spec).unwrap();
+        for i in 0..240 {
+            writer.write_sample(i as i16 * 100).unwrap();
+        }
+        writer.finalize().unwrap();
+        let original = original.into_inner();
+
+        let wav = SynthesizedAudio {
+            data: original.clone(),
+            ..audio(240)
+        };
+        let messages = synthesis_messages(wav, "t1", false, true, usize::MAX).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| matches!(m, Message::Text(_))));
+
+        let Message::Text(text) = &messages[1] else { unreachable!() };
+        match serde_json::from_str(text).unwrap() {
+            Response::AudioInline { mime, data_base64 } => {
+                assert_eq!(mime, "audio/wav");
+                // 4 base64 characters per 3 bytes
+                assert_eq!(data_base64.len(), original.len().div_ceil(3) * 4);
+                let decoded = BASE64_STANDARD.decode(data_base64).unwrap();
+                assert_eq!(decoded, original);
+                let reader = hound::WavReader::new(std::io::Cursor::new(decoded)).unwrap();
+                assert_eq!(reader.len(), 240);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clamped_speed_is_warned_before_audio() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        let request = sirius_protocol::SynthesizeRequest::new("Hello there.").with_speed(9.0);
+        send_request(&mut ws, &Request::Synthesize(request)).await;
+        match next_response(&mut ws).await {
+            Response::Warning { code, message } => {
+                assert_eq!(code, sirius_protocol::WarningCode::SpeedClamped);
+                assert!(message.contains("speed 9"), "{}", message);
+            }
+            other => panic!("expected a warning first, got {:?}", other),
+        }
+        assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+        next_binary(&mut ws).await;
+    }
+
+    #[tokio::test]
+    async fn test_lang_mismatch_is_warned_but_synthesized() {
+        let addr = spawn_test_server().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        let request = sirius_protocol::SynthesizeRequest::new("Привет, как дела?").with_lang("en-us");
+        send_request(&mut ws, &Request::Synthesize(request)).await;
+        match next_response(&mut ws).await {
+            Response::Warning { code, message } => {
+                assert_eq!(code, sirius_protocol::WarningCode::LangMismatch);
+                assert!(message.contains("Cyrillic"), "{}", message);
+            }
+            other => panic!("expected a warning first, got {:?}", other),
+        }
+        assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+        next_binary(&mut ws).await;
+    }
+
+    /// Log output written to a shared buffer
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_slow_syntheses_warn() {
+        // The test runtime is single-threaded, so the server's logs land here
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut synth = synth::MockSynthesizer::default();
+        synth.delay = Duration::from_millis(40);
+        let config = ConnectionConfig {
+            slow_threshold: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(synth, config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        // One sentence is over the threshold...
+        let request = SynthesizeRequest::new("Take your time.").with_voice("mock");
+        send_request(&mut ws, &Request::Synthesize(request)).await;
+        assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+        next_binary(&mut ws).await;
+        let warned = logs.text();
+        assert!(warned.contains("WARN"), "{}", warned);
+        assert!(warned.contains("Slow synthesis") && warned.contains("15 chars (voice: mock)"), "{}", warned);
+        assert!(warned.contains("RTF"), "{}", warned);
+
+        // ...a fast one logs nothing at WARN
+        let fast = ConnectionConfig {
+            slow_threshold: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(synth::MockSynthesizer::default(), fast).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let request = SynthesizeRequest::new("Quick.").with_voice("mock");
+        send_request(&mut ws, &Request::Synthesize(request)).await;
+        assert!(matches!(next_response(&mut ws).await, Response::AudioReady { .. }));
+        next_binary(&mut ws).await;
+        assert_eq!(logs.text(), warned);
+    }
+
+    #[tokio::test]
+    async fn test_subprotocol_negotiation() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let addr = spawn_test_server().await;
+        let offering = |protocols: &'static str| {
+            let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocols));
+            request
+        };
+
+        let (mut ws, response) = tokio_tungstenite::connect_async(offering("sirius.v2,sirius.v1"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], sirius_protocol::SUBPROTOCOL);
+        send_request(&mut ws, &Request::Ping).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Pong));
+
+        match tokio_tungstenite::connect_async(offering("sirius.v2")).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::BAD_REQUEST),
+            other => panic!("expected the handshake to be rejected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_config_requires_the_admin_token() {
+        let config = ConnectionConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(synth::MockSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let bare = Request::Synthesize(SynthesizeRequest::new("Hello."));
+        let set_config = |token: &str| Request::SetConfig {
+            token: token.to_string(),
+            settings: serde_json::from_str(r#"{"default_voice": "mock"}"#).unwrap(),
+        };
+        let zero_limit = Request::SetConfig {
+            token: "s3cret".to_string(),
+            settings: serde_json::from_str(r#"{"max_text_chars": 0}"#).unwrap(),
+        };
+
+        send_request(&mut ws, &set_config("guess")).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Error { code: ErrorCode::Unauthorized, .. }));
+        // Still the protocol's default voice, which the mock doesn't know
+        send_request(&mut ws, &bare).await;
+        assert!(matches!(next_response(&mut ws).await, Response::Error { code: ErrorCode::UnknownVoice, .. }));
+
+        send_request(&mut ws, &set_config("s3cret")).await;
+        match next_response(&mut ws).await {
+            Response::Config(runtime_config) => assert_eq!(runtime_config.default_voice.as_deref(), Some("mock")),
+            other => panic!("expected Config, got {:?}", other),
+        }
+        // Applies to other connections too
+        let (mut other, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        send_request(&mut other, &bare).await;
+        assert!(matches!(next_response(&mut other).await, Response::AudioReady { .. }));
+        next_binary(&mut other).await;
+
+        send_request(&mut other, &zero_limit).await;
+        assert!(matches!(next_response(&mut other).await, Response::Error { code: ErrorCode::BadRequest, .. }));
+        send_request(&mut other, &Request::GetConfig { token: "s3cret".to_string() }).await;
+        match next_response(&mut other).await {
+            Response::Config(runtime_config) => assert_eq!(runtime_config.max_text_chars, None),
+            other => panic!("expected Config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_admin_requests_disabled_without_a_token() {
+        assert!(authorize(&Some("s3cret".to_string()), "s3cret").is_ok());
+        for wrong in ["", "s3cre", "s3cret!", "S3CRET"] {
+            assert!(authorize(&Some("s3cret".to_string()), wrong).is_err(), "{}", wrong);
+        }
+        match authorize(&None, "") {
+            Err(Response::Error { code, message }) => {
+                assert_eq!(code, ErrorCode::Unauthorized);
+                assert!(message.contains("SIRIUS_ADMIN_TOKEN"), "{}", message);
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_synthesize_prompt() {
+        let config = ConnectionConfig {
+            prompts: Arc::new(PromptBank::new(HashMap::from([(
+                "greeting".to_string(),
+                "Hello {name}.".to_string(),
+            )]))),
+            ..Default::default()
+        };
+        let addr = spawn_server_with(SineSynthesizer::default(), config).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        // The filled-in prompt sounds just like sending its text
+        let num_samples = |response: Response| match response {
+            Response::AudioReady { num_samples, .. } => num_samples,
+            other => panic!("expected AudioReady, got {:?}", other),
+        };
+        send_request(&mut ws, &Request::Synthesize(SynthesizeRequest::new("Hello Ada."))).await;
+        let direct = num_samples(next_response(&mut ws).await);
+        next_binary(&mut ws).await;
+
+        let prompt = |id: &str| Request::SynthesizePrompt {
+            id: id.to_string(),
+            vars: HashMap::from([("name".to_string(), "Ada".to_string())]),
+        };
+        send_request(&mut ws, &prompt("greeting")).await;
+        assert_eq!(num_samples(next_response(&mut ws).await), direct);
+        next_binary(&mut ws).await;
+
+        send_request(&mut ws, &prompt("farewell")).await;
+        match next_response(&mut ws).await {
+            Response::Error { code, message } => {
+                assert_eq!(code, ErrorCode::BadRequest);
+                assert!(message.contains("unknown prompt id: farewell"), "{}", message);
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    fn previews(voices: &[&str]) -> Vec<SynthesizeRequest> {
+        voices.iter().map(|voice| SynthesizeRequest::new("Hello.").with_voice(*voice)).collect()
+    }
+
+    #[test]
+    fn test_preview_requests_are_checked_up_front() {
+        let known = vec!["am_onyx".to_string(), "bm_lewis".to_string()];
+        let voices = |voices: &[&str]| voices.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let template = |speed| SynthesizeRequest::new("Hi.").with_lang("en-gb").with_speed(speed);
+        let requests = preview_requests(&template(1.2), &voices(&["am_onyx", "am_onyx.4+bm_lewis.6"]), &known).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].voice, "am_onyx.4+bm_lewis.6");
+        assert_eq!((requests[1].lang.as_str(), requests[1].speed), ("en-gb", 1.2));
+
+        let too_many = vec!["am_onyx".to_string(); sirius_protocol::MAX_PREVIEW_VOICES + 1];
+        for (voices, speed) in [(voices(&[]), 1.0), (too_many, 1.0), (voices(&["am_onyx"]), 5.0)] {
+            let err = preview_requests(&template(speed), &voices, &known).unwrap_err();
+            assert_eq!(err.downcast_ref::<SynthesisError>().map(SynthesisError::code), Some(ErrorCode::BadRequest));
+        }
+        let err = preview_requests(&template(1.0), &voices(&["am_onyx", "zz_nobody"]), &known).unwrap_err();
+        assert_eq!(err.downcast_ref::<SynthesisError>().map(SynthesisError::code), Some(ErrorCode::UnknownVoice));
+    }
+
+    #[test]
+    fn test_preview_payloads_in_order() {
+        let voices = previews(&["am_onyx", "bm_lewis", "am_onyx.4+bm_lewis.6"]);
+
+        // One second of audio per voice in the list so far
+        let mut calls = 0;
+        let payloads = preview_payloads(&voices, |_| {
+            calls += 1;
+            Ok(audio(calls * sirius_protocol::SAMPLE_RATE as usize))
+        })
+        .unwrap();
+
+        assert_eq!(payloads.len(), voices.len());
+        for (i, (response, audio)) in payloads.iter().enumerate() {
+            let wav_data = &audio.data;
+            assert_eq!(wav_data.len(), 44 + (i + 1) * sirius_protocol::SAMPLE_RATE as usize * 2);
+            match response {
+                Response::PreviewReady {
+                    index,
+                    voice,
+                    size_bytes,
+                    duration_secs,
+                    ..
+                } => {
+                    assert_eq!(*index, i);
+                    assert_eq!(voice, &voices[i].voice);
+                    assert_eq!(*size_bytes, wav_data.len());
+                    // The WAV header isn't audio
+                    assert_eq!(*duration_secs, (i + 1) as f32);
+                }
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_preview_payloads_stops_on_error() {
+        let voices = previews(&["a", "bad", "c"]);
+        let mut calls = 0;
+        let result = preview_payloads(&voices, |req| {
+            calls += 1;
+            if req.voice == "bad" {
+                Err(anyhow::anyhow!("unknown voice"))
+            } else {
+                Ok(audio(2))
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+    }
+}
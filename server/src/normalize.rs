@@ -0,0 +1,480 @@
+//! Text normalization applied before sentence splitting
+//!
+//! Abbreviations are expanded to the words they stand for, so "Dr." is read
+//! as "Doctor" instead of being spelled out or taken as the end of a sentence.
+//! For screen-reader style output, punctuation can also be spoken by name,
+//! and annotated documents can carry directives that are never read aloud.
+//! URLs and email addresses are read the way people say them, or left out.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+
+use sirius_protocol::LinkReading;
+
+/// Built-in English entries; `Saint / Street` means "Saint" before a name
+/// and "Street" elsewhere
+const DEFAULT_ABBREVIATIONS: &str = "\
+Mr. = Mister
+Mrs. = Missus
+Ms. = Miz
+Dr. = Doctor / Drive
+St. = Saint / Street
+Mt. = Mount
+Prof. = Professor
+Jr. = Junior
+Sr. = Senior
+Ave. = Avenue
+Rd. = Road
+e.g. = for example
+i.e. = that is
+etc. = et cetera
+vs. = versus
+";
+
+/// Abbreviation expansions, keyed by the abbreviation as written (case-sensitive)
+#[derive(Debug, Clone)]
+pub struct AbbreviationTable {
+    /// (expansion before a name, expansion elsewhere)
+    entries: HashMap<String, (String, String)>,
+}
+
+impl Default for AbbreviationTable {
+    fn default() -> Self {
+        Self::parse(DEFAULT_ABBREVIATIONS).expect("built-in abbreviation table is valid")
+    }
+}
+
+impl AbbreviationTable {
+    /// Parse `abbreviation = expansion` lines. An expansion may give two forms,
+    /// `title / place`: the first is used before a capitalized word that doesn't
+    /// follow one ("St. John"), the second elsewhere ("John St."). Blank lines
+    /// and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut entries = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (abbreviation, expansion) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {}: expected `abbreviation = expansion`", number + 1))?;
+            let (title, place) = match expansion.split_once('/') {
+                Some((title, place)) => (title.trim(), place.trim()),
+                None => (expansion.trim(), expansion.trim()),
+            };
+            let abbreviation = abbreviation.trim();
+            if abbreviation.is_empty() || title.is_empty() || place.is_empty() {
+                return Err(anyhow!("line {}: empty abbreviation or expansion", number + 1));
+            }
+            entries.insert(abbreviation.to_string(), (title.to_string(), place.to_string()));
+        }
+        Ok(Self { entries })
+    }
+
+    /// The built-in table, extended (or overridden) by the file named in
+    /// `SIRIUS_ABBREVIATIONS`
+    pub fn from_env() -> Result<Self> {
+        let mut table = Self::default();
+        if let Ok(path) = std::env::var("SIRIUS_ABBREVIATIONS") {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading SIRIUS_ABBREVIATIONS file {}", path))?;
+            let custom = Self::parse(&contents).with_context(|| format!("in {}", path))?;
+            table.entries.extend(custom.entries);
+        }
+        Ok(table)
+    }
+
+    /// Replace every abbreviation in `text` with its expansion, keeping the
+    /// period when it also ends a sentence ("on Baker St. Then" becomes
+    /// "on Baker Street. Then")
+    pub fn expand(&self, text: &str) -> String {
+        let words: Vec<&str> = text.split_inclusive(char::is_whitespace).collect();
+        let mut out = String::with_capacity(text.len());
+
+        for (i, piece) in words.iter().enumerate() {
+            let word = piece.trim_end();
+            let rest = word.trim_start_matches(['(', '"', '\'', '“']);
+            let lead = &word[..word.len() - rest.len()];
+            let core = rest.trim_end_matches([',', ';', ':', ')', '"', '\'', '”']);
+            let Some((title, place)) = self.entries.get(core) else {
+                out.push_str(piece);
+                continue;
+            };
+
+            let next = words[i + 1..].iter().map(|w| w.trim()).find(|w| !w.is_empty());
+            let mut earlier = words[..i].iter().rev().map(|w| w.trim()).filter(|w| !w.is_empty());
+            let previous = earlier.next();
+            // A capitalized first word of a sentence isn't necessarily a name
+            let after_name = previous.is_some_and(starts_capitalized)
+                && earlier.next().is_some_and(|w| !w.ends_with(['.', '!', '?']));
+            let next_is_name = next.is_some_and(starts_capitalized);
+            let before_name = next_is_name && !after_name;
+
+            let trailing = &rest[core.len()..];
+            out.push_str(lead);
+            if before_name {
+                out.push_str(title);
+            } else {
+                out.push_str(place);
+                // "St." at the end of a sentence still ends it
+                if core.ends_with('.') && trailing.is_empty() && (next.is_none() || next_is_name) {
+                    out.push('.');
+                }
+            }
+            out.push_str(trailing);
+            out.push_str(&piece[word.len()..]);
+        }
+        out
+    }
+}
+
+/// Markers for text that shouldn't be read aloud: whole lines starting with
+/// `line_prefix` (after indentation) and spans between `open` and `close`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkipMarkers {
+    pub line_prefix: String,
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for SkipMarkers {
+    /// `// comment` lines and `[[stage directions]]`
+    fn default() -> Self {
+        Self {
+            line_prefix: "//".to_string(),
+            open: "[[".to_string(),
+            close: "]]".to_string(),
+        }
+    }
+}
+
+impl SkipMarkers {
+    /// Parse `"<line prefix> <open> <close>"`, e.g. `"# {{ }}"`
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.split_whitespace().collect::<Vec<_>>()[..] {
+            [line_prefix, open, close] => Ok(Self {
+                line_prefix: line_prefix.to_string(),
+                open: open.to_string(),
+                close: close.to_string(),
+            }),
+            _ => Err(anyhow!(
+                "skip markers must be `<line prefix> <open> <close>`, got {:?}",
+                spec
+            )),
+        }
+    }
+
+    /// The default markers, or those given in `SIRIUS_SKIP_MARKERS`
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("SIRIUS_SKIP_MARKERS") {
+            Ok(spec) => Self::parse(&spec).context("in SIRIUS_SKIP_MARKERS"),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// `text` without skip lines and skip spans. An unclosed span is kept
+    /// as written rather than swallowing the rest of the text.
+    pub fn strip(&self, text: &str) -> String {
+        let kept: String = text
+            .split_inclusive('\n')
+            .filter(|line| !line.trim_start().starts_with(&self.line_prefix))
+            .collect();
+
+        let mut out = String::with_capacity(kept.len());
+        let mut rest = kept.as_str();
+        while let Some(start) = rest.find(&self.open) {
+            let Some(len) = rest[start..].find(&self.close) else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            rest = &rest[start + len + self.close.len()..];
+            // Don't leave a double space where the span was
+            if out.ends_with(char::is_whitespace) || out.is_empty() {
+                rest = rest.trim_start_matches([' ', '\t']);
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Spoken names of punctuation marks, per language
+const ENGLISH_PUNCTUATION: &[(char, &str)] = &[
+    (',', "comma"),
+    ('.', "period"),
+    ('!', "exclamation mark"),
+    ('?', "question mark"),
+    (';', "semicolon"),
+    (':', "colon"),
+    ('(', "open paren"),
+    (')', "close paren"),
+    ('"', "quote"),
+    ('…', "ellipsis"),
+];
+const SPANISH_PUNCTUATION: &[(char, &str)] = &[
+    (',', "coma"),
+    ('.', "punto"),
+    ('!', "signo de exclamación"),
+    ('¡', "abre exclamación"),
+    ('?', "signo de interrogación"),
+    ('¿', "abre interrogación"),
+    (';', "punto y coma"),
+    (':', "dos puntos"),
+    ('(', "abre paréntesis"),
+    (')', "cierra paréntesis"),
+    ('"', "comillas"),
+    ('…', "puntos suspensivos"),
+];
+const FRENCH_PUNCTUATION: &[(char, &str)] = &[
+    (',', "virgule"),
+    ('.', "point"),
+    ('!', "point d'exclamation"),
+    ('?', "point d'interrogation"),
+    (';', "point-virgule"),
+    (':', "deux-points"),
+    ('(', "ouvrez la parenthèse"),
+    (')', "fermez la parenthèse"),
+    ('"', "guillemets"),
+    ('«', "guillemets"),
+    ('»', "fermez les guillemets"),
+    ('…', "points de suspension"),
+];
+
+/// Punctuation names for `lang`, falling back to English
+fn punctuation_names(lang: &str) -> &'static [(char, &'static str)] {
+    match lang.split(['-', '_']).next().unwrap_or("") {
+        "es" => SPANISH_PUNCTUATION,
+        "fr" => FRENCH_PUNCTUATION,
+        _ => ENGLISH_PUNCTUATION,
+    }
+}
+
+/// Replace punctuation marks with their spoken names ("Hi, world." becomes
+/// "Hi comma world period"). Marks inside a word or number ("don't", "3.14")
+/// are left alone, and a mark right after its own name ("comma,") isn't
+/// spoken twice.
+pub fn speak_punctuation(text: &str, lang: &str) -> String {
+    let names = punctuation_names(lang);
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len() * 2);
+
+    for (i, &c) in chars.iter().enumerate() {
+        let Some(&(_, name)) = names.iter().find(|(mark, _)| *mark == c) else {
+            out.push(c);
+            continue;
+        };
+        let inside_word = i > 0
+            && chars[i - 1].is_alphanumeric()
+            && chars.get(i + 1).is_some_and(|next| next.is_alphanumeric());
+        if inside_word {
+            out.push(c);
+        } else if !ends_with_word(&out, name) {
+            out.push(' ');
+            out.push_str(name);
+            out.push(' ');
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Whether `text` ends with `word` as a whole word, ignoring case
+fn ends_with_word(text: &str, word: &str) -> bool {
+    let text = text.trim_end().to_lowercase();
+    text.strip_suffix(&word.to_lowercase())
+        .is_some_and(|before| before.is_empty() || before.ends_with(char::is_whitespace))
+}
+
+fn starts_capitalized(word: &str) -> bool {
+    word.trim_start_matches(|c: char| !c.is_alphanumeric())
+        .chars()
+        .next()
+        .is_some_and(char::is_uppercase)
+}
+
+/// Email addresses, and URLs starting with a scheme or `www.`; bare domains
+/// are too easily confused with abbreviations and numbers
+static LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)+|\b(?:[a-z][a-z0-9+.-]*://|www\.)[^\s<>"']+"#)
+        .expect("link pattern is valid")
+});
+
+/// Sentence punctuation that may follow a link without being part of it
+const LINK_TRAILERS: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '\'', '"'];
+
+/// Spoken names of the symbols in links, per language
+const ENGLISH_LINK_SYMBOLS: &[(char, &str)] =
+    &[('.', "dot"), ('/', "slash"), ('@', "at"), ('-', "dash"), ('_', "underscore")];
+const SPANISH_LINK_SYMBOLS: &[(char, &str)] =
+    &[('.', "punto"), ('/', "barra"), ('@', "arroba"), ('-', "guion"), ('_', "guion bajo")];
+const FRENCH_LINK_SYMBOLS: &[(char, &str)] =
+    &[('.', "point"), ('/', "slash"), ('@', "arobase"), ('-', "tiret"), ('_', "tiret bas")];
+
+/// Link symbol names for `lang`, falling back to English
+fn link_symbol_names(lang: &str) -> &'static [(char, &'static str)] {
+    match lang.split(['-', '_']).next().unwrap_or("") {
+        "es" => SPANISH_LINK_SYMBOLS,
+        "fr" => FRENCH_LINK_SYMBOLS,
+        _ => ENGLISH_LINK_SYMBOLS,
+    }
+}
+
+/// Rewrite the URLs and email addresses in `text` as `reading` asks:
+/// "https://example.com/docs" becomes "example dot com slash docs" and
+/// "name@example.com" becomes "name at example dot com"
+pub fn read_links<'a>(text: &'a str, reading: LinkReading, lang: &str) -> Cow<'a, str> {
+    if reading == LinkReading::Verbatim || !LINK.is_match(text) {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len() * 2);
+    let mut last = 0;
+    for found in LINK.find_iter(text) {
+        out.push_str(&text[last..found.start()]);
+        let link = found.as_str().trim_end_matches(LINK_TRAILERS);
+        let trailer = &found.as_str()[link.len()..];
+        if reading == LinkReading::Omit {
+            // The space before the link goes too, so no gap is left behind
+            out.truncate(out.trim_end().len());
+        } else {
+            out.push_str(&speak_link(link, lang));
+        }
+        out.push_str(trailer);
+        last = found.end();
+    }
+    out.push_str(&text[last..]);
+    Cow::Owned(out)
+}
+
+/// One link in words, without its scheme, `www.`, query or fragment
+fn speak_link(link: &str, lang: &str) -> String {
+    let names = link_symbol_names(lang);
+    let link = link.split_once("://").map_or(link, |(_, rest)| rest);
+    let link = link.strip_prefix("www.").or_else(|| link.strip_prefix("WWW.")).unwrap_or(link);
+    let link = link.split(['?', '#']).next().unwrap_or_default().trim_end_matches('/');
+
+    let mut out = String::with_capacity(link.len() * 2);
+    for c in link.chars() {
+        match names.iter().find(|(symbol, _)| *symbol == c) {
+            Some((_, name)) => {
+                out.push(' ');
+                out.push_str(name);
+                out.push(' ');
+            }
+            None if c.is_alphanumeric() => out.push(c),
+            None => out.push(' '),
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title_versus_street() {
+        let table = AbbreviationTable::default();
+        assert_eq!(table.expand("St. John St."), "Saint John Street.");
+        assert_eq!(
+            table.expand("Meet Dr. Lee on Harley St. at noon."),
+            "Meet Doctor Lee on Harley Street at noon."
+        );
+        assert_eq!(
+            table.expand("It's on Baker St. Then turn left, e.g. here."),
+            "It's on Baker Street. Then turn left, for example here."
+        );
+        // Whitespace and unknown words are untouched
+        assert_eq!(table.expand("Fruit, veg,\n  etc."), "Fruit, veg,\n  et cetera.");
+    }
+
+    #[test]
+    fn test_urls_are_read_as_words() {
+        let spoken = |text| read_links(text, LinkReading::Spoken, "en-us");
+        assert_eq!(spoken("See https://example.com/foo."), "See example dot com slash foo.");
+        assert_eq!(
+            spoken("Docs at www.my-site.org/api_v2/?page=3#top, then ask."),
+            "Docs at my dash site dot org slash api underscore v2, then ask."
+        );
+        assert_eq!(read_links("Ver https://ejemplo.es/a", LinkReading::Spoken, "es"), "Ver ejemplo punto es barra a");
+        // Without a scheme or www. it's not a link
+        assert!(matches!(spoken("Pi is 3.14, e.g. roughly."), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_email_at_is_spoken() {
+        assert_eq!(
+            read_links("Write to jane.doe@example.co.uk today.", LinkReading::Spoken, "en-us"),
+            "Write to jane dot doe at example dot co dot uk today."
+        );
+        assert_eq!(read_links("Écrivez à marie@exemple.fr", LinkReading::Spoken, "fr-fr"), "Écrivez à marie arobase exemple point fr");
+    }
+
+    #[test]
+    fn test_links_omitted_or_verbatim() {
+        assert_eq!(
+            read_links("Visit https://example.com for more, or mail a@b.io.", LinkReading::Omit, "en-us"),
+            "Visit for more, or mail."
+        );
+        let text = "See https://example.com/foo.";
+        assert_eq!(read_links(text, LinkReading::Verbatim, "en-us"), text);
+    }
+
+    #[test]
+    fn test_speak_punctuation() {
+        assert_eq!(speak_punctuation("Hi, world.", "en-us"), "Hi comma world period");
+        assert_eq!(
+            speak_punctuation("Wait... really?! (Yes.)", "en-gb"),
+            "Wait period really question mark exclamation mark open paren Yes period close paren"
+        );
+        // Marks inside words and numbers aren't punctuation
+        assert_eq!(speak_punctuation("Don't pay 3.50", "en-us"), "Don't pay 3.50");
+        assert_eq!(speak_punctuation("Bonjour, monde !", "fr-fr"), "Bonjour virgule monde point d'exclamation");
+    }
+
+    #[test]
+    fn test_spoken_punctuation_is_not_doubled() {
+        assert_eq!(
+            speak_punctuation("Type a comma, then a period.", "en-us"),
+            "Type a comma then a period"
+        );
+        // Running the transform twice changes nothing
+        let once = speak_punctuation("Hi, world. Question mark?", "en-us");
+        assert_eq!(once, "Hi comma world period Question mark");
+        assert_eq!(speak_punctuation(&once, "en-us"), once);
+    }
+
+    #[test]
+    fn test_skip_markers() {
+        let markers = SkipMarkers::default();
+        assert_eq!(markers.strip("Hello [[pause]] world."), "Hello world.");
+        assert_eq!(markers.strip("[[softly]]Goodnight."), "Goodnight.");
+        assert_eq!(
+            markers.strip("// Scene 2, read slowly\nIt was late.\n  // aside\nThe end."),
+            "It was late.\nThe end."
+        );
+        // Spans may cross lines; an unclosed one is left alone
+        assert_eq!(markers.strip("A [[long\nnote]] B [[oops"), "A B [[oops");
+        assert_eq!(markers.strip("See http://example.com"), "See http://example.com");
+    }
+
+    #[test]
+    fn test_custom_skip_markers() {
+        let markers = SkipMarkers::parse("# {{ }}").unwrap();
+        assert_eq!(markers.strip("# note\nKeep {{this out}}this [[and this]]."), "Keep this [[and this]].");
+        assert!(SkipMarkers::parse("# {{").is_err());
+    }
+
+    #[test]
+    fn test_custom_table() {
+        let table = AbbreviationTable::parse("# units\nkm = kilometres\n").unwrap();
+        assert_eq!(table.expand("Run 5 km"), "Run 5 kilometres");
+        assert_eq!(table.expand("St. John"), "St. John");
+
+        assert!(AbbreviationTable::parse("no equals sign").is_err());
+        assert!(AbbreviationTable::parse("x = a /").is_err());
+    }
+}
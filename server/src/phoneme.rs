@@ -0,0 +1,119 @@
+//! Phoneme input: ARPAbet to the IPA Kokoro reads
+//!
+//! Requests with `input_type: ipa` go to the backend as written. ARPAbet,
+//! as found in CMUdict, is converted phone by phone first; stress digits
+//! become IPA stress marks in front of the vowel, as espeak writes them.
+
+use anyhow::Result;
+
+use crate::tts::SynthesisError;
+
+/// Punctuation that may stand between ARPAbet words, kept for its pauses
+const PUNCTUATION: [&str; 6] = [",", ".", "!", "?", ";", ":"];
+
+/// IPA for one ARPAbet phone without its stress digit, e.g. `AA` -> `ɑ`
+fn phone_ipa(phone: &str, stress: Option<char>) -> Option<&'static str> {
+    let ipa = match (phone, stress) {
+        // Unstressed AH and ER are reduced vowels
+        ("AH", Some('0')) => "ə",
+        ("ER", Some('0')) => "ɚ",
+        ("AA", _) => "ɑ",
+        ("AE", _) => "æ",
+        ("AH", _) => "ʌ",
+        ("AO", _) => "ɔ",
+        ("AW", _) => "aʊ",
+        ("AY", _) => "aɪ",
+        ("EH", _) => "ɛ",
+        ("ER", _) => "ɜɹ",
+        ("EY", _) => "eɪ",
+        ("IH", _) => "ɪ",
+        ("IY", _) => "i",
+        ("OW", _) => "oʊ",
+        ("OY", _) => "ɔɪ",
+        ("UH", _) => "ʊ",
+        ("UW", _) => "u",
+        ("B", None) => "b",
+        ("CH", None) => "tʃ",
+        ("D", None) => "d",
+        ("DH", None) => "ð",
+        ("F", None) => "f",
+        ("G", None) => "ɡ",
+        ("HH", None) => "h",
+        ("JH", None) => "dʒ",
+        ("K", None) => "k",
+        ("L", None) => "l",
+        ("M", None) => "m",
+        ("N", None) => "n",
+        ("NG", None) => "ŋ",
+        ("P", None) => "p",
+        ("R", None) => "ɹ",
+        ("S", None) => "s",
+        ("SH", None) => "ʃ",
+        ("T", None) => "t",
+        ("TH", None) => "θ",
+        ("V", None) => "v",
+        ("W", None) => "w",
+        ("Y", None) => "j",
+        ("Z", None) => "z",
+        ("ZH", None) => "ʒ",
+        _ => return None,
+    };
+    Some(ipa)
+}
+
+/// Convert ARPAbet phones to IPA: `"HH AH0 L OW1 | W ER1 L D ."` becomes
+/// `"həlˈoʊ wˈɜɹld."`. An unknown phone is `SynthesisError::InvalidParameter`.
+pub fn arpabet_to_ipa(arpabet: &str) -> Result<String> {
+    let mut ipa = String::new();
+    let mut in_word = false;
+    for token in arpabet.split_whitespace() {
+        if token == "|" {
+            in_word = false;
+            continue;
+        }
+        if PUNCTUATION.contains(&token) {
+            ipa.push_str(token);
+            in_word = false;
+            continue;
+        }
+        if !in_word && !ipa.is_empty() {
+            ipa.push(' ');
+        }
+        in_word = true;
+
+        let upper = token.to_ascii_uppercase();
+        let (phone, stress) = match upper.strip_suffix(['0', '1', '2']) {
+            Some(phone) => (phone, upper.chars().last()),
+            None => (upper.as_str(), None),
+        };
+        let phone_ipa = phone_ipa(phone, stress).ok_or_else(|| {
+            SynthesisError::InvalidParameter(format!("unknown ARPAbet phone {}", token))
+        })?;
+        match stress {
+            Some('1') => ipa.push('ˈ'),
+            Some('2') => ipa.push('ˌ'),
+            _ => {}
+        }
+        ipa.push_str(phone_ipa);
+    }
+    Ok(ipa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arpabet_to_ipa() {
+        assert_eq!(arpabet_to_ipa("HH AH0 L OW1 | W ER1 L D .").unwrap(), "həlˈoʊ wˈɜɹld.");
+        assert_eq!(arpabet_to_ipa("t ah1 m ey2 t ow0").unwrap(), "tˈʌmˌeɪtoʊ");
+        assert_eq!(arpabet_to_ipa("Y EH1 S , P L IY1 Z").unwrap(), "jˈɛs, plˈiz");
+
+        // Consonants take no stress, and unknown phones are named
+        for bad in ["HH1 AY1", "HH XX1"] {
+            let err = arpabet_to_ipa(bad).unwrap_err();
+            assert!(err.downcast_ref::<SynthesisError>().is_some(), "{}", bad);
+        }
+        assert!(arpabet_to_ipa("HH XX1").unwrap_err().to_string().contains("XX1"));
+    }
+}
@@ -0,0 +1,125 @@
+//! Prompt bank: canned texts requested by id with `SynthesizePrompt`
+//!
+//! Voice menus and other IVR-style apps say the same few sentences over and
+//! over, with a name or a number changed. The bank is a JSON object of id to
+//! template, where `{name}` is replaced by the request's `name` variable.
+//! Braces around anything that isn't a plain name, like `{voice=bm_lewis}`,
+//! are left for the directive parser. Variables come from the caller, so
+//! their own braces are dropped: a value can't switch the voice.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::tts::SynthesisError;
+
+/// Templates by id; empty when `SIRIUS_PROMPTS` isn't set
+#[derive(Debug, Clone, Default)]
+pub struct PromptBank {
+    templates: HashMap<String, String>,
+}
+
+impl PromptBank {
+    pub fn new(templates: HashMap<String, String>) -> Self {
+        Self { templates }
+    }
+
+    /// Load a JSON object of id to template from `path`
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("reading prompts {}", path))?;
+        let templates = serde_json::from_str(&contents).with_context(|| format!("parsing prompts {}", path))?;
+        Ok(Self::new(templates))
+    }
+
+    /// The bank in the file named by `SIRIUS_PROMPTS`, if set
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("SIRIUS_PROMPTS") {
+            Ok(path) => Self::load(&path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// The text of prompt `id` with its placeholders filled from `vars`, less
+    /// any braces in them. Unknown ids and placeholders without a variable
+    /// are request errors.
+    pub fn render(&self, id: &str, vars: &HashMap<String, String>) -> Result<String> {
+        let template = self
+            .templates
+            .get(id)
+            .ok_or_else(|| SynthesisError::InvalidParameter(format!("unknown prompt id: {}", id)))?;
+
+        let mut text = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(open) = rest.find('{') {
+            text.push_str(&rest[..open]);
+            let after = &rest[open + 1..];
+            let name = after.find('}').map(|close| &after[..close]);
+            match name {
+                Some(name) if is_placeholder(name) => {
+                    let value = vars.get(name).ok_or_else(|| {
+                        SynthesisError::InvalidParameter(format!("prompt {} needs variable {}", id, name))
+                    })?;
+                    text.extend(value.chars().filter(|c| !matches!(c, '{' | '}')));
+                    rest = &after[name.len() + 1..];
+                }
+                _ => {
+                    text.push('{');
+                    rest = after;
+                }
+            }
+        }
+        text.push_str(rest);
+        Ok(text)
+    }
+}
+
+/// Whether `{name}` is a variable rather than, say, a directive
+fn is_placeholder(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank() -> PromptBank {
+        PromptBank::new(HashMap::from([
+            ("greeting".to_string(), "Hello {name}, you have {count} new messages.".to_string()),
+            ("goodbye".to_string(), "{voice=bm_lewis} Goodbye.".to_string()),
+        ]))
+    }
+
+    #[test]
+    fn test_render_substitutes_vars() {
+        let vars = HashMap::from([
+            ("name".to_string(), "Ada".to_string()),
+            ("count".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(bank().render("greeting", &vars).unwrap(), "Hello Ada, you have 3 new messages.");
+        // Directives aren't placeholders and need no variable
+        assert_eq!(bank().render("goodbye", &HashMap::new()).unwrap(), "{voice=bm_lewis} Goodbye.");
+    }
+
+    #[test]
+    fn test_vars_cannot_add_directives() {
+        let vars = HashMap::from([
+            ("name".to_string(), "{voice=af_bella}Eve".to_string()),
+            ("count".to_string(), "{speed=2}3}".to_string()),
+        ]);
+        let text = bank().render("greeting", &vars).unwrap();
+        assert_eq!(text, "Hello voice=af_bellaEve, you have speed=23 new messages.");
+        let spans = crate::directive::parse_directives(&text).unwrap();
+        assert!(spans.iter().all(|span| span.voice.is_none() && span.speed.is_none()));
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let err = bank().render("missing", &HashMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid request: unknown prompt id: missing");
+        assert!(err.downcast_ref::<SynthesisError>().is_some());
+
+        let vars = HashMap::from([("name".to_string(), "Ada".to_string())]);
+        let err = bank().render("greeting", &vars).unwrap_err();
+        assert!(err.to_string().contains("needs variable count"), "{}", err);
+    }
+}
@@ -0,0 +1,140 @@
+//! Writing-system check of input text against the requested `lang`
+//!
+//! The phonemizer reads text by the rules of `lang`, so Cyrillic sent as
+//! `en-us` comes out as noise rather than speech. Counting which Unicode
+//! script the letters belong to is enough to catch that mistake cheaply;
+//! the text is still synthesized, the caller only gets a warning.
+
+use std::fmt;
+
+/// Fewest letters worth judging; shorter text is too easily skewed
+const MIN_LETTERS: usize = 8;
+
+/// Writing systems told apart by `dominant_script`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Han,
+    Kana,
+    Hangul,
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Script::Latin => "Latin",
+            Script::Cyrillic => "Cyrillic",
+            Script::Greek => "Greek",
+            Script::Arabic => "Arabic",
+            Script::Hebrew => "Hebrew",
+            Script::Devanagari => "Devanagari",
+            Script::Han => "Han",
+            Script::Kana => "Kana",
+            Script::Hangul => "Hangul",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Every `Script`, in declaration order
+const SCRIPTS: [Script; 9] = [
+    Script::Latin,
+    Script::Cyrillic,
+    Script::Greek,
+    Script::Arabic,
+    Script::Hebrew,
+    Script::Devanagari,
+    Script::Han,
+    Script::Kana,
+    Script::Hangul,
+];
+
+fn script_of(c: char) -> Option<Script> {
+    match c {
+        'A'..='Z' | 'a'..='z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Some(Script::Latin),
+        '\u{0400}'..='\u{052F}' => Some(Script::Cyrillic),
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Some(Script::Greek),
+        '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' => Some(Script::Arabic),
+        '\u{0590}'..='\u{05FF}' => Some(Script::Hebrew),
+        '\u{0900}'..='\u{097F}' => Some(Script::Devanagari),
+        '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' => Some(Script::Han),
+        '\u{3040}'..='\u{30FF}' => Some(Script::Kana),
+        '\u{AC00}'..='\u{D7AF}' | '\u{1100}'..='\u{11FF}' => Some(Script::Hangul),
+        _ => None,
+    }
+    .filter(|_| c.is_alphabetic())
+}
+
+/// The script most of `text`'s letters are written in, if there are enough
+/// letters to tell and one script has more than half of them
+pub fn dominant_script(text: &str) -> Option<Script> {
+    let mut counts = [0usize; SCRIPTS.len()];
+    for script in text.chars().filter_map(script_of) {
+        counts[script as usize] += 1;
+    }
+    let letters: usize = counts.iter().sum();
+    if letters < MIN_LETTERS {
+        return None;
+    }
+    let (index, &count) = counts.iter().enumerate().max_by_key(|(_, &count)| count)?;
+    (count * 2 > letters).then_some(SCRIPTS[index])
+}
+
+/// The scripts text in `lang` is written in; empty for languages not listed,
+/// which are never reported as mismatched
+fn lang_scripts(lang: &str) -> &'static [Script] {
+    let base = lang.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+    match base.as_str() {
+        "en" | "es" | "fr" | "it" | "pt" | "de" | "nl" | "pl" | "cs" | "sv" | "da" | "nb" | "fi" | "tr"
+        | "ro" | "hu" | "vi" | "id" => &[Script::Latin],
+        "ru" | "uk" | "bg" | "sr" | "be" | "kk" => &[Script::Cyrillic],
+        "el" => &[Script::Greek],
+        "ar" | "fa" | "ur" => &[Script::Arabic],
+        "he" => &[Script::Hebrew],
+        "hi" | "mr" | "ne" => &[Script::Devanagari],
+        "zh" | "cmn" | "yue" => &[Script::Han],
+        // Japanese mixes kanji and kana; Latin words are common in both
+        "ja" => &[Script::Kana, Script::Han],
+        "ko" => &[Script::Hangul, Script::Han],
+        _ => &[],
+    }
+}
+
+/// A warning message if `text` is clearly written in a script `lang` doesn't
+/// use, e.g. Cyrillic text sent as `en-us`
+pub fn lang_mismatch(text: &str, lang: &str) -> Option<String> {
+    let expected = lang_scripts(lang);
+    let found = dominant_script(text)?;
+    if expected.is_empty() || expected.contains(&found) {
+        return None;
+    }
+    Some(format!(
+        "text looks like {} script, which lang {} doesn't use; check the lang setting",
+        found, lang
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lang_mismatch() {
+        assert_eq!(dominant_script("Привет, как дела?"), Some(Script::Cyrillic));
+        assert!(lang_mismatch("Привет, как дела?", "en-us").unwrap().contains("Cyrillic"));
+        assert_eq!(lang_mismatch("Привет, как дела?", "ru"), None);
+        assert_eq!(lang_mismatch("Hello, how are you?", "en-us"), None);
+        assert_eq!(lang_mismatch("こんにちは、元気ですか", "ja"), None);
+        assert!(lang_mismatch("こんにちは、元気ですか", "en-gb").is_some());
+
+        // A few foreign words, short text or an unknown lang don't count
+        assert_eq!(lang_mismatch("The word for peace is мир.", "en-us"), None);
+        assert_eq!(lang_mismatch("Да.", "en-us"), None);
+        assert_eq!(lang_mismatch("Привет, как дела?", "xx"), None);
+    }
+}
@@ -0,0 +1,133 @@
+//! Settings operators can change while the server runs
+//!
+//! `GetConfig` and `SetConfig` read and write a `RuntimeConfig` that the
+//! engine and every connection share. It has its own lock, so neither waits
+//! for a synthesis holding the engine; each synthesis reads the settings once
+//! when it starts, and a change applies from the next one.
+
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use sirius_protocol::RuntimeConfig;
+
+use crate::synth::check_voice;
+use crate::tts::SynthesisError;
+
+/// Longest pause `SetConfig` or `SIRIUS_SENTENCE_PAUSE_MS` may put between sentences
+pub const MAX_SENTENCE_PAUSE_MS: u32 = 5_000;
+
+/// Handle on the shared runtime settings; clones see the same settings
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeSettings {
+    config: Arc<RwLock<RuntimeConfig>>,
+    /// What `default_voice` may name
+    voices: Arc<Vec<String>>,
+}
+
+impl RuntimeSettings {
+    /// Default settings for an engine whose backend knows `voices`
+    pub fn new(voices: Vec<String>) -> Self {
+        Self {
+            config: Arc::default(),
+            voices: Arc::new(voices),
+        }
+    }
+
+    /// Voices the backend knows
+    pub fn voices(&self) -> &[String] {
+        &self.voices
+    }
+
+    /// The current settings
+    pub fn get(&self) -> RuntimeConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Change settings from startup configuration, which is checked where
+    /// it's read
+    pub fn set(&self, change: impl FnOnce(&mut RuntimeConfig)) {
+        change(&mut self.config.write().unwrap());
+    }
+
+    /// Change the settings named in `settings`, leaving the rest. Everything
+    /// is checked first, so on error nothing has changed.
+    pub fn update(&self, settings: &serde_json::Map<String, serde_json::Value>) -> Result<RuntimeConfig> {
+        // Held throughout, so two updates at once can't undo each other
+        let mut current = self.config.write().unwrap();
+        let mut merged = serde_json::to_value(&*current)?;
+        for (key, value) in settings {
+            let Some(slot) = merged.get_mut(key) else {
+                return Err(SynthesisError::InvalidParameter(format!(
+                    "{} is not a runtime setting (expected default_voice, max_text_chars, retries, \
+                     normalize or sentence_pause_ms)",
+                    key
+                ))
+                .into());
+            };
+            *slot = value.clone();
+        }
+        let config: RuntimeConfig = serde_json::from_value(merged)
+            .map_err(|e| SynthesisError::InvalidParameter(format!("bad setting: {}", e)))?;
+        check(&config, &self.voices)?;
+
+        *current = config.clone();
+        Ok(config)
+    }
+}
+
+/// Refuse what the matching environment variables would: a zero text limit,
+/// an overlong pause, or a default voice the backend doesn't have
+fn check(config: &RuntimeConfig, voices: &[String]) -> Result<()> {
+    if config.max_text_chars == Some(0) {
+        return Err(SynthesisError::InvalidParameter("max_text_chars must be a positive number".to_string()).into());
+    }
+    if config.sentence_pause_ms > MAX_SENTENCE_PAUSE_MS {
+        return Err(SynthesisError::InvalidParameter(format!(
+            "sentence_pause_ms must be at most {}",
+            MAX_SENTENCE_PAUSE_MS
+        ))
+        .into());
+    }
+    if let Some(voice) = &config.default_voice {
+        check_voice(voice, voices).map_err(|e| SynthesisError::InvalidParameter(format!("default_voice: {}", e)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sirius_protocol::ErrorCode;
+
+    fn settings(json: &str) -> serde_json::Map<String, serde_json::Value> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_update_checks_everything_first() {
+        let runtime = RuntimeSettings::new(vec!["af_bella".to_string()]);
+        let config = runtime
+            .update(&settings(r#"{"default_voice": "af_bella", "sentence_pause_ms": 250}"#))
+            .unwrap();
+        assert_eq!(config.default_voice.as_deref(), Some("af_bella"));
+        assert_eq!(config.sentence_pause_ms, 250);
+        assert!(!config.normalize);
+
+        for bad in [
+            r#"{"retries": 1, "model": "other.onnx"}"#,
+            r#"{"retries": -1}"#,
+            r#"{"retries": 1, "max_text_chars": 0}"#,
+            r#"{"retries": 1, "sentence_pause_ms": 5001}"#,
+            r#"{"retries": 1, "normalize": "yes"}"#,
+            r#"{"retries": 1, "default_voice": "zz_nobody"}"#,
+        ] {
+            let err = runtime.update(&settings(bad)).unwrap_err();
+            let code = err.downcast_ref::<SynthesisError>().map(SynthesisError::code);
+            assert_eq!(code, Some(ErrorCode::BadRequest), "{}", bad);
+        }
+        assert_eq!(runtime.get(), config);
+
+        // Back to the protocol's default voice
+        assert_eq!(runtime.update(&settings(r#"{"default_voice": null}"#)).unwrap().default_voice, None);
+    }
+}
@@ -0,0 +1,711 @@
+//! Pluggable speech synthesis backends
+//!
+//! `TtsEngine` only needs raw f32 samples per sentence; anything implementing
+//! `SpeechSynthesizer` can provide them. Kokoro is the production backend.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use kokoro::onn::ort_base::SessionOptions;
+use kokoro::tts::koko::{InitConfig, TTSKoko};
+use ndarray::Array3;
+use ndarray_npy::NpzReader;
+use tracing::{debug, info, warn};
+
+use crate::tts::SynthesisError;
+
+/// Maximum number of cached sentence phonemizations before the cache is reset
+const PHONEME_CACHE_CAPACITY: usize = 10_000;
+
+/// Width of the style vectors the Kokoro v1.0 model takes
+const STYLE_DIM: usize = 256;
+
+/// Style vectors Kokoro keeps per voice, one per input length
+const MAX_STYLE_ROWS: usize = 511;
+
+/// A speech backend producing mono f32 samples, at the protocol sample rate
+/// unless `native_sample_rate` says otherwise
+pub trait SpeechSynthesizer: Send + 'static {
+    /// Synthesize a single sentence/fragment
+    fn synthesize_raw(&self, text: &str, lang: &str, voice: &str, speed: f32) -> Result<Vec<f32>>;
+
+    /// Voices (style names) this backend knows about
+    fn voices(&self) -> Vec<String>;
+
+    /// Speaking styles (e.g. emotions) `synthesize_styled` can apply; empty
+    /// for backends without style conditioning
+    fn styles(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Synthesize with an optional style from `styles()`. Backends without
+    /// styles ignore it.
+    fn synthesize_styled(
+        &self,
+        text: &str,
+        lang: &str,
+        voice: &str,
+        speed: f32,
+        _style: Option<&str>,
+    ) -> Result<Vec<f32>> {
+        self.synthesize_raw(text, lang, voice, speed)
+    }
+
+    /// Sample rate `voice` comes out at; anything other than `SAMPLE_RATE`
+    /// is resampled to it. Every voice in Kokoro's voices file shares the
+    /// model's 24 kHz, so for Kokoro this is a no-op.
+    fn native_sample_rate(&self, _voice: &str) -> u32 {
+        sirius_protocol::SAMPLE_RATE
+    }
+
+    /// Synthesize IPA phonemes as written, skipping grapheme-to-phoneme.
+    /// Backends that only take text refuse with `SynthesisError::InvalidParameter`.
+    fn synthesize_phonemes(&self, _phonemes: &str, _voice: &str, _speed: f32) -> Result<Vec<f32>> {
+        Err(SynthesisError::InvalidParameter("this backend doesn't accept phoneme input".to_string()).into())
+    }
+}
+
+/// The model run itself failed, e.g. the runtime ran out of memory. Unlike
+/// errors about the request, these may pass on a retry.
+#[derive(Debug)]
+pub struct EngineError(pub String);
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+/// Kokoro adapter, with a word-level phoneme cache in front of espeak
+pub struct KokoroSynthesizer {
+    tts: TTSKoko,
+    phonemes: Mutex<PhonemeCache>,
+}
+
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+impl KokoroSynthesizer {
+    /// Load the model with `session` options; Kokoro falls back to CPU (with
+    /// a warning) if the requested provider isn't available. Voices come from
+    /// each of `voices_paths` in turn, a later file's replacing an earlier's.
+    pub async fn new(model_path: &str, voices_paths: &[String], session: SessionOptions) -> Result<Self> {
+        let config = InitConfig {
+            session,
+            ..Default::default()
+        };
+        let (first, rest) = voices_paths
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("no voices file given"))?;
+        let mut tts = TTSKoko::from_config(model_path, first, config).await;
+        for voices_path in rest {
+            tts.add_voices(voices_path);
+        }
+        info!(
+            "ONNX execution provider: {} (requested {}), intra-op threads: {:?}, inter-op threads: {:?}",
+            tts.provider(),
+            session.provider,
+            session.intra_threads,
+            session.inter_threads
+        );
+        Ok(Self {
+            tts,
+            phonemes: Mutex::new(PhonemeCache::new()),
+        })
+    }
+}
+
+impl SpeechSynthesizer for KokoroSynthesizer {
+    fn synthesize_raw(&self, text: &str, lang: &str, voice: &str, speed: f32) -> Result<Vec<f32>> {
+        // Kokoro silently drops unknown components of a blend
+        check_voice(voice, &self.tts.voice_names())?;
+
+        let phonemes = {
+            let mut cache = self.phonemes.lock().unwrap();
+            let phonemes = cache.phonemize(text, lang, |chunk| {
+                self.tts
+                    .phonemize(chunk, lang)
+                    .map_err(|e| anyhow::anyhow!("Phonemization error: {}", e))
+            })?;
+            debug!("Phoneme cache hit rate: {:.1}%", cache.hit_rate() * 100.0);
+            phonemes
+        };
+        self.synthesize_phonemes(&phonemes, voice, speed)
+    }
+
+    fn synthesize_phonemes(&self, phonemes: &str, voice: &str, speed: f32) -> Result<Vec<f32>> {
+        check_voice(voice, &self.tts.voice_names())?;
+        // The last argument is leading silence, not a style: Kokoro's only
+        // conditioning is the voice embedding, so it keeps the default no-op
+        // `synthesize_styled`
+        self.tts
+            .tts_raw_audio_from_phonemes(phonemes, voice, speed, None)
+            .map_err(|e| EngineError(format!("TTS generation error: {}", e)).into())
+    }
+
+    fn voices(&self) -> Vec<String> {
+        self.tts.voice_names()
+    }
+}
+
+/// Check that `voices_path` holds voices the model at `model_path` can use:
+/// an npz archive of `[rows, 1, 256]` float arrays. Kokoro panics on anything
+/// else, deep in loading or on the first synthesis, so this runs first to
+/// fail with a message naming both files. A missing file passes; Kokoro
+/// downloads it.
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+pub fn check_voices_file(model_path: &str, voices_path: &str) -> Result<()> {
+    if !Path::new(voices_path).exists() {
+        return Ok(());
+    }
+    let mismatch = |problem: String| {
+        anyhow::anyhow!(
+            "voices file {} doesn't fit model {}: {}. Use the voices file released with the model \
+             (voices-v1.0.bin for kokoro-v1.0.onnx).",
+            voices_path,
+            model_path,
+            problem
+        )
+    };
+    let file = File::open(voices_path).map_err(|e| mismatch(format!("can't open it ({})", e)))?;
+    let mut npz = NpzReader::new(file)
+        .map_err(|e| mismatch(format!("not a voices archive or truncated ({})", e)))?;
+    let names = npz
+        .names()
+        .map_err(|e| mismatch(format!("not a voices archive or truncated ({})", e)))?;
+    if names.is_empty() {
+        return Err(mismatch("it contains no voices".to_string()));
+    }
+    for name in names {
+        let voice: Array3<f32> = npz
+            .by_name(&name)
+            .map_err(|e| mismatch(format!("voice {} is unreadable ({})", name, e)))?;
+        let shape = voice.shape();
+        if shape[0] == 0 || shape[0] > MAX_STYLE_ROWS || shape[1] != 1 || shape[2] != STYLE_DIM {
+            return Err(mismatch(format!(
+                "voice {} has embeddings of shape {:?}, but the model takes [1..={}, 1, {}]",
+                name, shape, MAX_STYLE_ROWS, STYLE_DIM
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What to do when two voices files have a voice of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceCollisions {
+    /// Refuse to start
+    #[default]
+    Error,
+    /// Use the later file's voice, with a warning
+    LastWins,
+}
+
+/// The voices `voices_paths` hold between them, sorted, with any name in
+/// more than one file handled as `collisions` says. Only the first file is
+/// downloaded when missing, so the others have to exist.
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+pub fn merged_voice_names(voices_paths: &[String], collisions: VoiceCollisions) -> Result<Vec<String>> {
+    let mut sources: HashMap<String, &str> = HashMap::new();
+    for (index, voices_path) in voices_paths.iter().enumerate() {
+        if !Path::new(voices_path).exists() {
+            if index == 0 {
+                continue;
+            }
+            return Err(anyhow::anyhow!("voices file {} doesn't exist", voices_path));
+        }
+        let file = File::open(voices_path)?;
+        let names = NpzReader::new(file)
+            .and_then(|mut npz| npz.names())
+            .map_err(|e| anyhow::anyhow!("reading voices file {}: {}", voices_path, e))?;
+        for name in names {
+            if let Some(earlier) = sources.insert(name.clone(), voices_path) {
+                match collisions {
+                    VoiceCollisions::Error => {
+                        return Err(anyhow::anyhow!(
+                            "voice {} is in both {} and {}; rename one, or set SIRIUS_VOICE_COLLISIONS=last-wins",
+                            name,
+                            earlier,
+                            voices_path
+                        ));
+                    }
+                    VoiceCollisions::LastWins => {
+                        warn!("Voice {} from {} replaces the one from {}", name, voices_path, earlier);
+                    }
+                }
+            }
+        }
+    }
+    let mut names: Vec<String> = sources.into_keys().collect();
+    names.sort();
+    Ok(names)
+}
+
+/// The language of a Kokoro voice, from the first letter of its name
+/// ("af_bella" is American English); `None` for names that don't follow
+/// Kokoro's scheme
+pub fn kokoro_voice_lang(voice: &str) -> Option<&'static str> {
+    let (prefix, _) = voice.split_once('_')?;
+    if prefix.len() != 2 {
+        return None;
+    }
+    match prefix.as_bytes()[0] {
+        b'a' => Some("en-us"),
+        b'b' => Some("en-gb"),
+        b'e' => Some("es"),
+        b'f' => Some("fr-fr"),
+        b'h' => Some("hi"),
+        b'i' => Some("it"),
+        b'j' => Some("ja"),
+        b'p' => Some("pt-br"),
+        b'z' => Some("cmn"),
+        _ => None,
+    }
+}
+
+/// Fail with `SynthesisError::UnknownVoice` unless `voice` (a single voice or a
+/// blend like "am_onyx.4+bm_lewis.6") only names voices in `known`
+pub fn check_voice(voice: &str, known: &[String]) -> Result<()> {
+    let unknown = voice
+        .split('+')
+        .map(|part| part.split_once('.').map_or(part, |(name, _)| name).trim())
+        .find(|name| !known.iter().any(|k| k == name));
+    match unknown {
+        Some(name) => Err(SynthesisError::UnknownVoice(name.to_string()).into()),
+        None => Ok(()),
+    }
+}
+
+/// A blend of any number of voices, e.g. "af_bella.2+am_onyx.3+bm_lewis.5".
+///
+/// The digits after a name's `.` are a decimal fraction ("am_onyx.4" is 0.4);
+/// a name without a weight counts as 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceMix {
+    pub components: Vec<(String, f32)>,
+}
+
+impl VoiceMix {
+    /// Parse `voice`; a weight that isn't a fraction is `SynthesisError::InvalidParameter`
+    pub fn parse(voice: &str) -> Result<Self> {
+        let mut components = Vec::new();
+        for part in voice.split('+') {
+            let (name, weight) = match part.split_once('.') {
+                Some((name, digits)) => match format!("0.{}", digits.trim()).parse::<f32>() {
+                    Ok(weight) if digits.trim().bytes().all(|b| b.is_ascii_digit()) => (name, weight),
+                    _ => {
+                        return Err(SynthesisError::InvalidParameter(format!(
+                            "blend weight {:?} is not a fraction",
+                            part
+                        ))
+                        .into())
+                    }
+                },
+                None => (part, 1.0),
+            };
+            components.push((name.trim().to_string(), weight));
+        }
+        Ok(Self { components })
+    }
+
+    pub fn total_weight(&self) -> f32 {
+        self.components.iter().map(|(_, weight)| weight).sum()
+    }
+
+    /// The same voices with weights scaled to sum to 1, or `None` if they sum to 0
+    pub fn normalized(&self) -> Option<Self> {
+        let total = self.total_weight();
+        if total <= 0.0 {
+            return None;
+        }
+        let components = self
+            .components
+            .iter()
+            .map(|(name, weight)| (name.clone(), weight / total))
+            .collect();
+        Some(Self { components })
+    }
+}
+
+/// Kokoro's blend syntax, which reads the text after the first `.` as tenths:
+/// 0.4 is "am_onyx.4" and 1/3 is "am_onyx.3.333333"
+impl std::fmt::Display for VoiceMix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, weight)) in self.components.iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            let tenths = format!("{:.6}", weight * 10.0);
+            write!(f, "{}.{}", name, tenths.trim_end_matches('0').trim_end_matches('.'))?;
+        }
+        Ok(())
+    }
+}
+
+/// Deterministic backend for CI: a sine tone whose length is proportional to
+/// the text length, so the full request/response path runs without model files
+#[cfg(any(test, feature = "test-synth"))]
+pub struct SineSynthesizer {
+    pub samples_per_char: usize,
+    pub frequency_hz: f32,
+}
+
+#[cfg(any(test, feature = "test-synth"))]
+impl Default for SineSynthesizer {
+    fn default() -> Self {
+        Self {
+            // 10ms of audio per character at 24kHz
+            samples_per_char: 240,
+            frequency_hz: 440.0,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-synth"))]
+impl SpeechSynthesizer for SineSynthesizer {
+    fn synthesize_raw(&self, text: &str, _lang: &str, _voice: &str, _speed: f32) -> Result<Vec<f32>> {
+        let len = text.chars().count() * self.samples_per_char;
+        let step = 2.0 * std::f32::consts::PI * self.frequency_hz
+            / sirius_protocol::SAMPLE_RATE as f32;
+        Ok((0..len).map(|i| 0.3 * (i as f32 * step).sin()).collect())
+    }
+
+    fn voices(&self) -> Vec<String> {
+        vec!["sine".to_string()]
+    }
+}
+
+/// Test backend: silence proportional to text length, recording every call
+#[cfg(test)]
+pub struct MockSynthesizer {
+    pub samples_per_char: usize,
+    pub voices: Vec<String>,
+    pub styles: Vec<String>,
+    /// Fail any text containing this, like a model error on one fragment
+    pub fail_on: Option<String>,
+    /// Fail this many calls with a backend error before working normally
+    pub transient_failures: std::sync::atomic::AtomicUsize,
+    /// Sleep this long in every call, like a slow model
+    pub delay: std::time::Duration,
+    /// What `native_sample_rate` reports for every voice
+    pub native_sample_rate: u32,
+    calls: Mutex<Vec<String>>,
+    speeds: Mutex<Vec<f32>>,
+    voice_args: Mutex<Vec<String>>,
+    style_args: Mutex<Vec<Option<String>>>,
+}
+
+#[cfg(test)]
+impl Default for MockSynthesizer {
+    fn default() -> Self {
+        Self {
+            samples_per_char: 100,
+            voices: vec!["mock".to_string()],
+            styles: Vec::new(),
+            fail_on: None,
+            transient_failures: std::sync::atomic::AtomicUsize::new(0),
+            delay: std::time::Duration::ZERO,
+            native_sample_rate: sirius_protocol::SAMPLE_RATE,
+            calls: Mutex::new(Vec::new()),
+            speeds: Mutex::new(Vec::new()),
+            voice_args: Mutex::new(Vec::new()),
+            style_args: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MockSynthesizer {
+    /// A mock that fails any text containing `needle`
+    pub fn failing_on(needle: &str) -> Self {
+        Self {
+            fail_on: Some(needle.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Texts passed to `synthesize_raw`, in call order
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Speeds passed to `synthesize_raw`, in call order
+    pub fn speeds(&self) -> Vec<f32> {
+        self.speeds.lock().unwrap().clone()
+    }
+
+    /// Voices passed to `synthesize_raw`, in call order
+    pub fn voice_args(&self) -> Vec<String> {
+        self.voice_args.lock().unwrap().clone()
+    }
+
+    /// Styles passed to `synthesize_styled`, in call order
+    pub fn style_args(&self) -> Vec<Option<String>> {
+        self.style_args.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl SpeechSynthesizer for MockSynthesizer {
+    fn synthesize_raw(&self, text: &str, _lang: &str, voice: &str, speed: f32) -> Result<Vec<f32>> {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        check_voice(voice, &self.voices)?;
+        if self.fail_on.as_deref().is_some_and(|needle| text.contains(needle)) {
+            return Err(anyhow::anyhow!("mock failure on {:?}", text));
+        }
+        let failures = &self.transient_failures;
+        if failures.fetch_update(SeqCst, SeqCst, |n| n.checked_sub(1)).is_ok() {
+            return Err(EngineError("mock transient failure".to_string()).into());
+        }
+        std::thread::sleep(self.delay);
+        self.calls.lock().unwrap().push(text.to_string());
+        self.speeds.lock().unwrap().push(speed);
+        self.voice_args.lock().unwrap().push(voice.to_string());
+        Ok(vec![0.0; text.chars().count() * self.samples_per_char])
+    }
+
+    fn voices(&self) -> Vec<String> {
+        self.voices.clone()
+    }
+
+    fn styles(&self) -> Vec<String> {
+        self.styles.clone()
+    }
+
+    fn native_sample_rate(&self, _voice: &str) -> u32 {
+        self.native_sample_rate
+    }
+
+    fn synthesize_styled(
+        &self,
+        text: &str,
+        lang: &str,
+        voice: &str,
+        speed: f32,
+        style: Option<&str>,
+    ) -> Result<Vec<f32>> {
+        self.style_args.lock().unwrap().push(style.map(str::to_string));
+        self.synthesize_raw(text, lang, voice, speed)
+    }
+
+    /// Recorded with the text calls, like any other sentence
+    fn synthesize_phonemes(&self, phonemes: &str, voice: &str, speed: f32) -> Result<Vec<f32>> {
+        self.synthesize_raw(phonemes, "", voice, speed)
+    }
+}
+
+/// Grapheme-to-phoneme cache keyed on `(chunk, lang)`.
+///
+/// Whole chunks go to espeak, as in Kokoro's own text path, so numbers,
+/// abbreviations and intonation keep their sentence context. Keys only
+/// collapse whitespace: case changes how espeak reads e.g. "US" and "us".
+pub struct PhonemeCache {
+    entries: HashMap<(String, String), String>,
+    hits: u64,
+    misses: u64,
+}
+
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+impl PhonemeCache {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Phonemize `chunk`, calling `phonemize_chunk` only if it isn't cached
+    pub fn phonemize<F>(&mut self, chunk: &str, lang: &str, phonemize_chunk: F) -> Result<String>
+    where
+        F: FnOnce(&str) -> Result<String>,
+    {
+        let normalized = chunk.split_whitespace().collect::<Vec<_>>().join(" ");
+        let key = (normalized, lang.to_string());
+        if let Some(cached) = self.entries.get(&key) {
+            self.hits += 1;
+            return Ok(cached.clone());
+        }
+
+        self.misses += 1;
+        let phonemes = phonemize_chunk(&key.0)?;
+        if self.entries.len() >= PHONEME_CACHE_CAPACITY {
+            self.entries.clear();
+        }
+        self.entries.insert(key, phonemes.clone());
+        Ok(phonemes)
+    }
+
+    /// Fraction of lookups served from the cache
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voices_file_is_checked() {
+        let dir = std::env::temp_dir().join(format!("sirius-voices-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write_voices = |name: &str, shape: (usize, usize, usize)| {
+            let path = dir.join(name);
+            let mut npz = ndarray_npy::NpzWriter::new(File::create(&path).unwrap());
+            npz.add_array("af_test", &Array3::<f32>::zeros(shape)).unwrap();
+            npz.finish().unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        let good = write_voices("good.bin", (MAX_STYLE_ROWS, 1, STYLE_DIM));
+        assert!(check_voices_file("kokoro.onnx", &good).is_ok());
+        // Missing files are Kokoro's to download
+        assert!(check_voices_file("kokoro.onnx", "/nonexistent/voices.bin").is_ok());
+
+        // Cut short, as by an interrupted download
+        let truncated = dir.join("truncated.bin");
+        let bytes = std::fs::read(&good).unwrap();
+        std::fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+        let err = check_voices_file("kokoro.onnx", truncated.to_str().unwrap()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("truncated.bin") && message.contains("kokoro.onnx"), "{}", message);
+        assert!(message.contains("truncated ("), "{}", message);
+
+        // From a model with narrower style vectors
+        let narrow = write_voices("narrow.bin", (MAX_STYLE_ROWS, 1, 128));
+        let message = check_voices_file("kokoro.onnx", &narrow).unwrap_err().to_string();
+        assert!(message.contains("voice af_test has embeddings of shape [511, 1, 128]"), "{}", message);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_voices_files_are_merged() {
+        let dir = std::env::temp_dir().join(format!("sirius-voices-merge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write_voices = |name: &str, voices: &[&str]| {
+            let path = dir.join(name);
+            let mut npz = ndarray_npy::NpzWriter::new(File::create(&path).unwrap());
+            for voice in voices {
+                npz.add_array(*voice, &Array3::<f32>::zeros((1, 1, STYLE_DIM))).unwrap();
+            }
+            npz.finish().unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let stock = write_voices("stock.bin", &["af_bella", "am_onyx"]);
+        let pack = write_voices("pack.bin", &["bf_custom"]);
+        let clash = write_voices("clash.bin", &["am_onyx", "bm_custom"]);
+
+        let names = merged_voice_names(&[stock.clone(), pack.clone()], VoiceCollisions::Error).unwrap();
+        assert_eq!(names, ["af_bella", "am_onyx", "bf_custom"]);
+
+        let paths = [stock.clone(), clash.clone()];
+        let message = merged_voice_names(&paths, VoiceCollisions::Error).unwrap_err().to_string();
+        assert!(message.contains("voice am_onyx is in both"), "{}", message);
+        assert!(message.contains("stock.bin") && message.contains("clash.bin"), "{}", message);
+        let names = merged_voice_names(&paths, VoiceCollisions::LastWins).unwrap();
+        assert_eq!(names, ["af_bella", "am_onyx", "bm_custom"]);
+
+        // Only the first file may be missing, for Kokoro to download
+        let missing = "/nonexistent/voices.bin".to_string();
+        assert_eq!(merged_voice_names(&[missing.clone(), pack.clone()], VoiceCollisions::Error).unwrap(), ["bf_custom"]);
+        assert!(merged_voice_names(&[stock, missing], VoiceCollisions::Error).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_voice() {
+        let known = vec!["am_onyx".to_string(), "bm_lewis".to_string()];
+        assert!(check_voice("bm_lewis", &known).is_ok());
+        assert!(check_voice("am_onyx.4+bm_lewis.6", &known).is_ok());
+
+        let err = check_voice("am_onyx.4+zz_nobody.6", &known).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SynthesisError>(),
+            Some(SynthesisError::UnknownVoice(name)) if name == "zz_nobody"
+        ));
+    }
+
+    #[test]
+    fn test_voice_mix() {
+        let mix = VoiceMix::parse("af_bella.2+am_onyx.3+bm_lewis.5").unwrap();
+        assert_eq!(
+            mix.components,
+            vec![
+                ("af_bella".to_string(), 0.2),
+                ("am_onyx".to_string(), 0.3),
+                ("bm_lewis".to_string(), 0.5),
+            ]
+        );
+        assert!((mix.total_weight() - 1.0).abs() < 1e-6);
+        assert_eq!(mix.to_string(), "af_bella.2+am_onyx.3+bm_lewis.5");
+
+        let even = VoiceMix::parse("a.3+b.3+c.3").unwrap().normalized().unwrap();
+        assert!((even.total_weight() - 1.0).abs() < 1e-6);
+        assert!(even.components.iter().all(|(_, weight)| (weight - 1.0 / 3.0).abs() < 1e-6));
+
+        // No weight counts as 1; weights that can't be read are rejected
+        assert_eq!(VoiceMix::parse("a+b").unwrap().total_weight(), 2.0);
+        assert!(VoiceMix::parse("a.x+b.5").is_err());
+        assert_eq!(VoiceMix::parse("a.0+b.0").unwrap().normalized(), None);
+    }
+
+    /// Stands in for espeak: the output depends on the whole chunk, so word-by-word
+    /// phonemization would not reproduce it
+    fn fake_phonemize(chunk: &str) -> Result<String> {
+        Ok(format!("{}:{}", chunk.split_whitespace().count(), chunk.to_lowercase()))
+    }
+
+    #[test]
+    fn test_phoneme_cache_matches_uncached() {
+        let mut cache = PhonemeCache::new();
+        let sentences = [
+            "It costs $3.50, not 4.",
+            "Dr. Smith weighs 10 kg!",
+            "Call 555-0100 (ext. 2) before 9:30?",
+            "«Hello», she said; “goodbye.”",
+        ];
+
+        for _ in 0..3 {
+            for sentence in sentences {
+                let cached = cache.phonemize(sentence, "en-us", fake_phonemize).unwrap();
+                assert_eq!(cached, fake_phonemize(sentence).unwrap());
+            }
+        }
+        assert_eq!(cache.hit_rate(), 8.0 / 12.0);
+    }
+
+    #[test]
+    fn test_phoneme_cache_key() {
+        let mut cache = PhonemeCache::new();
+        let mut calls = Vec::new();
+        let mut lookup = |cache: &mut PhonemeCache, chunk: &str, lang: &str| {
+            cache
+                .phonemize(chunk, lang, |chunk| {
+                    calls.push(chunk.to_string());
+                    fake_phonemize(chunk)
+                })
+                .unwrap()
+        };
+
+        lookup(&mut cache, "Hello, world!", "en-us");
+        // Whitespace differences share an entry; case and language don't
+        lookup(&mut cache, "  Hello,\n world! ", "en-us");
+        lookup(&mut cache, "hello, world!", "en-us");
+        lookup(&mut cache, "Hello, world!", "en-gb");
+
+        assert_eq!(calls, vec!["Hello, world!", "hello, world!", "Hello, world!"]);
+        assert_eq!(cache.hit_rate(), 1.0 / 4.0);
+    }
+}
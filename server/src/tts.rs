@@ -1,80 +1,1698 @@
-//! TTS engine wrapper around Kokoro
+//! TTS engine wrapper: sentence splitting and WAV encoding over a `SpeechSynthesizer`
 
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use hound::{WavSpec, WavWriter};
-use kokoro::tts::koko::TTSKoko;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use sirius_protocol::{BITS_PER_SAMPLE, CHANNELS, SAMPLE_RATE};
+use sirius_protocol::g711;
+use sirius_protocol::text::{collapse_whitespace, segment_sentences_within};
+use sirius_protocol::{
+    AudioFormat, BitDepth, ErrorCode, InputType, SynthesizeRequest, WarningCode,
+    CHANNELS, DEFAULT_SPEED, DEFAULT_VOICE, SAMPLE_RATE,
+};
 
-pub struct TtsEngine {
-    tts: TTSKoko,
+use kokoro::onn::ort_base::SessionOptions;
+
+use crate::directive;
+use crate::dsp;
+use crate::normalize::{self, AbbreviationTable, SkipMarkers};
+use crate::phoneme;
+use crate::script;
+use crate::settings::RuntimeSettings;
+use crate::synth::{
+    check_voice, check_voices_file, merged_voice_names, EngineError, KokoroSynthesizer, SpeechSynthesizer, VoiceCollisions,
+    VoiceMix,
+};
+
+/// Speeds the model handles well; others are clamped with a warning
+pub const SPEED_RANGE: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+
+/// Silence standing in for a sentence that failed to synthesize
+const FAILED_SENTENCE_SILENCE_SECS: f32 = 0.3;
+
+/// Peak `normalize` scales speech to, just below full scale (-1 dBFS)
+const NORMALIZED_PEAK: f32 = 0.89;
+
+/// Thrown away at startup so the first real request isn't the slow one
+const WARMUP_TEXT: &str = "Hello.";
+
+/// Longer sentences are split at clause or word boundaries before synthesis
+pub const DEFAULT_MAX_SENTENCE_CHARS: usize = 400;
+
+/// How far from 1 a blend's weights may sum before they are rescaled
+const BLEND_TOLERANCE: f32 = 0.01;
+
+/// Wait before the first retry of a transient backend error; doubles after each
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(20);
+
+/// Result of a synthesis: the encoded audio plus metadata known before transfer
+pub struct SynthesizedAudio {
+    /// WAV file or raw PCM bytes, depending on `format`
+    pub data: Vec<u8>,
+    pub format: AudioFormat,
+    /// Bits per sample of `data`
+    pub bits_per_sample: u16,
+    /// Number of samples per channel
+    pub num_samples: usize,
+    /// Soft issues to report to the client before the audio
+    pub warnings: Vec<(WarningCode, String)>,
+    /// Start of each word in seconds, if the request asked for word marks
+    pub word_marks: Vec<f32>,
+    /// Where each sentence (or batch of them) is heard
+    pub sentence_timings: Vec<SentenceTiming>,
+    /// 0 if the request's text was spoken, n for its `fallbacks[n - 1]`
+    pub used_fallback: usize,
+}
+
+/// One sentence as sent to the backend and the stretch of audio it became
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SentenceTiming {
+    pub text: String,
+    pub start_secs: f32,
+    pub end_secs: f32,
+}
+
+impl SynthesizedAudio {
+    pub fn duration_secs(&self) -> f32 {
+        self.num_samples as f32 / self.format.sample_rate() as f32
+    }
+}
+
+/// Per-voice settings from `SIRIUS_VOICE_DEFAULTS`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VoiceDefaults {
+    /// Used instead of the protocol default when a request doesn't set a speed
+    pub speed: Option<f32>,
+    /// Linear gain applied to the voice's output
+    pub gain: Option<f32>,
+}
+
+/// Load a JSON object mapping voice ids to `VoiceDefaults`, e.g.
+/// `{"bm_lewis": {"speed": 1.1, "gain": 0.8}}`
+pub fn load_voice_defaults(path: &str) -> Result<HashMap<String, VoiceDefaults>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("reading voice defaults {}", path))?;
+    serde_json::from_str(&contents).with_context(|| format!("parsing voice defaults {}", path))
+}
+
+/// Load a WAV file as mono f32 samples at the protocol sample rate, mixing
+/// down extra channels and resampling if the file's rate differs
+pub fn load_earcon(path: &str) -> Result<Vec<f32>> {
+    let mut reader = hound::WavReader::open(path).with_context(|| format!("opening earcon {}", path))?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()
+        }
+    }
+    .with_context(|| format!("decoding earcon {}", path))?;
+
+    let channels = spec.channels.max(1) as usize;
+    let mono: Vec<f32> = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect();
+    if spec.sample_rate == SAMPLE_RATE {
+        return Ok(mono);
+    }
+    let len = (mono.len() as u64 * SAMPLE_RATE as u64 / spec.sample_rate as u64) as usize;
+    Ok(dsp::resample_linear(&mono, len))
+}
+
+pub struct TtsEngine<S = KokoroSynthesizer> {
+    synth: S,
+    abbreviations: AbbreviationTable,
+    skip_markers: SkipMarkers,
+    /// The settings `SetConfig` can change: default voice, text limit,
+    /// retries, normalization and sentence pause
+    settings: RuntimeSettings,
+    /// Chime prepended to requests that ask for it
+    earcon: Option<Vec<f32>>,
+    voice_defaults: HashMap<String, VoiceDefaults>,
+    /// Consecutive sentences are sent to the backend together up to this many characters
+    sentence_batch_chars: Option<usize>,
+    /// Sentences longer than this are split before synthesis
+    max_sentence_chars: usize,
+    /// Requests whose speech runs longer are abandoned
+    max_audio_secs: Option<f32>,
+    /// How long the backend took to load; zero when it was handed over ready
+    model_load: Duration,
 }
 
-impl TtsEngine {
-    pub async fn new(model_path: &str, voices_path: &str) -> Result<Self> {
-        let tts = TTSKoko::new(model_path, voices_path).await;
-        Ok(Self { tts })
+#[cfg_attr(feature = "test-synth", allow(dead_code))]
+impl TtsEngine<KokoroSynthesizer> {
+    /// Load the model with the voices of every file in `voices_paths`
+    pub async fn new(
+        model_path: &str,
+        voices_paths: &[String],
+        collisions: VoiceCollisions,
+        session: SessionOptions,
+    ) -> Result<Self> {
+        for voices_path in voices_paths {
+            check_voices_file(model_path, voices_path)?;
+        }
+        // Before the model, which takes much longer to load
+        merged_voice_names(voices_paths, collisions)?;
+        Self::load(KokoroSynthesizer::new(model_path, voices_paths, session)).await
+    }
+}
+
+impl<S: SpeechSynthesizer> TtsEngine<S> {
+    pub fn with_synthesizer(synth: S) -> Self {
+        Self {
+            settings: RuntimeSettings::new(synth.voices()),
+            synth,
+            abbreviations: AbbreviationTable::default(),
+            skip_markers: SkipMarkers::default(),
+            earcon: None,
+            voice_defaults: HashMap::new(),
+            sentence_batch_chars: None,
+            max_sentence_chars: DEFAULT_MAX_SENTENCE_CHARS,
+            max_audio_secs: None,
+            model_load: Duration::ZERO,
+        }
+    }
+
+    /// Wrap the backend `load` resolves to, timing how long it takes
+    pub async fn load(load: impl std::future::Future<Output = Result<S>>) -> Result<Self> {
+        let start = std::time::Instant::now();
+        let synth = load.await?;
+        Ok(Self {
+            model_load: start.elapsed(),
+            ..Self::with_synthesizer(synth)
+        })
+    }
+
+    /// How long loading the backend took, for cold-start tuning
+    pub fn model_load_time(&self) -> Duration {
+        self.model_load
+    }
+
+    /// Replace the built-in abbreviation table
+    pub fn with_abbreviations(mut self, abbreviations: AbbreviationTable) -> Self {
+        self.abbreviations = abbreviations;
+        self
+    }
+
+    /// Replace the default `//` and `[[ ]]` skip markers
+    pub fn with_skip_markers(mut self, skip_markers: SkipMarkers) -> Self {
+        self.skip_markers = skip_markers;
+        self
+    }
+
+    /// Use `voice` for requests that leave the voice at the protocol default
+    pub fn with_default_voice(self, voice: Option<String>) -> Self {
+        self.settings.set(|config| config.default_voice = voice);
+        self
     }
 
-    /// Synthesize text to WAV audio bytes
+    /// Chime (mono, protocol sample rate) for requests with `earcon` set
+    pub fn with_earcon(mut self, earcon: Option<Vec<f32>>) -> Self {
+        self.earcon = earcon;
+        self
+    }
+
+    /// Truncate request texts longer than `max_chars`
+    pub fn with_max_text_chars(self, max_chars: Option<usize>) -> Self {
+        self.settings.set(|config| config.max_text_chars = max_chars);
+        self
+    }
+
+    /// Per-voice default speed and gain, keyed by voice id (or blend string)
+    pub fn with_voice_defaults(mut self, voice_defaults: HashMap<String, VoiceDefaults>) -> Self {
+        self.voice_defaults = voice_defaults;
+        self
+    }
+
+    /// Retry a sentence up to `retries` times when the backend fails with
+    /// something other than a `SynthesisError`
+    pub fn with_retries(self, retries: u32) -> Self {
+        self.settings.set(|config| config.retries = retries);
+        self
+    }
+
+    /// Peak-normalize the speech of every clip
+    pub fn with_normalize(self, normalize: bool) -> Self {
+        self.settings.set(|config| config.normalize = normalize);
+        self
+    }
+
+    /// Put `pause_ms` milliseconds of silence between sentences
+    pub fn with_sentence_pause_ms(self, pause_ms: u32) -> Self {
+        self.settings.set(|config| config.sentence_pause_ms = pause_ms);
+        self
+    }
+
+    /// Send runs of consecutive sentences with the same settings to the
+    /// backend as one call, up to `max_chars` characters; `None` is one call
+    /// per sentence
+    pub fn with_sentence_batch_chars(mut self, max_chars: Option<usize>) -> Self {
+        self.sentence_batch_chars = max_chars;
+        self
+    }
+
+    /// Split sentences longer than `max_chars` characters (at clauses, else
+    /// words) instead of sending them to the backend whole
+    pub fn with_max_sentence_chars(mut self, max_chars: usize) -> Self {
+        self.max_sentence_chars = max_chars;
+        self
+    }
+
+    /// Give up on a request with `SynthesisError::AudioTooLong` as soon as
+    /// its speech passes `max_secs`, rather than finishing and sending it
+    pub fn with_max_audio_secs(mut self, max_secs: Option<f32>) -> Self {
+        self.max_audio_secs = max_secs;
+        self
+    }
+
+    /// `max_audio_secs` in samples
+    fn max_audio_samples(&self) -> Option<usize> {
+        self.max_audio_secs
+            .map(|secs| (secs as f64 * SAMPLE_RATE as f64) as usize * CHANNELS as usize)
+    }
+
+    /// Handle on the settings `SetConfig` can change, for the connections to share
+    pub fn settings(&self) -> RuntimeSettings {
+        self.settings.clone()
+    }
+
+    /// Voices the backend can synthesize with
+    pub fn voices(&self) -> Vec<String> {
+        self.synth.voices()
+    }
+
+    /// Run one throwaway synthesis so the backend's lazy initialization is
+    /// done before any client is served, returning how long it took. Uses the
+    /// server's default voice, else the backend's first.
+    pub fn warm_up(&self) -> Result<Duration> {
+        let voice = match self.settings.get().default_voice {
+            Some(voice) => voice,
+            None => self.voices().into_iter().next().unwrap_or_else(|| DEFAULT_VOICE.to_string()),
+        };
+        let start = std::time::Instant::now();
+        self.synthesize(WARMUP_TEXT, "en-us", &voice, DEFAULT_SPEED)?;
+        Ok(start.elapsed())
+    }
+
+    /// Sample rate the backend synthesizes `voice` at
+    pub fn native_sample_rate(&self, voice: &str) -> u32 {
+        self.synth.native_sample_rate(voice)
+    }
+
+    /// `samples` of `voice`, resampled to `SAMPLE_RATE` if that isn't the
+    /// voice's native rate
+    fn at_protocol_rate(&self, samples: Vec<f32>, voice: &str) -> Vec<f32> {
+        match self.synth.native_sample_rate(voice) {
+            SAMPLE_RATE => samples,
+            rate => {
+                let len = (samples.len() as u64 * SAMPLE_RATE as u64 / rate as u64) as usize;
+                dsp::resample_linear(&samples, len)
+            }
+        }
+    }
+
+    /// Synthesize text to WAV audio bytes, cut to `max_text_chars` like a request's
     pub fn synthesize(
         &self,
         text: &str,
         lang: &str,
         voice: &str,
         speed: f32,
-    ) -> Result<Vec<u8>> {
-        let mut full_audio: Vec<f32> = Vec::new();
-
-        // Process each sentence
-        let sentences = text.split('.');
-        for sentence in sentences {
-            let trimmed = sentence.trim();
-            if trimmed.is_empty() {
-                continue;
+    ) -> Result<SynthesizedAudio> {
+        let settings = self.settings.get();
+        let text = match settings.max_text_chars {
+            Some(max_chars) => truncate_text(text, max_chars),
+            None => text,
+        };
+        let text = self.abbreviations.expand(text);
+        let voice = normalize_blend(voice, &mut Vec::new())?;
+        let pause = pause_samples(settings.sentence_pause_ms);
+        let max_samples = self.max_audio_samples();
+        synthesize_with(&text, lang, self.max_sentence_chars, max_samples, pause, settings.normalize, |sentence| {
+            with_retries(settings.retries, || self.synth.synthesize_raw(sentence, lang, &voice, speed))
+                .map(|samples| self.at_protocol_rate(samples, &voice))
+        })
+    }
+
+    /// Synthesize a protocol request, applying its post-processing options
+    pub fn synthesize_request(&self, req: &SynthesizeRequest) -> Result<SynthesizedAudio> {
+        req.validate().map_err(SynthesisError::InvalidParameter)?;
+        let mut style_warning = None;
+        if let Some(style) = &req.style {
+            let styles = self.synth.styles();
+            if styles.is_empty() {
+                style_warning = Some(format!("style {:?} ignored; the backend has no styles", style));
+            } else if !styles.contains(style) {
+                return Err(SynthesisError::InvalidParameter(format!(
+                    "unknown style {:?} (known: {})",
+                    style,
+                    styles.join(", ")
+                ))
+                .into());
             }
+        }
+        if req.earcon && self.earcon.is_none() {
+            return Err(SynthesisError::InvalidParameter(
+                "earcon requested, but the server has none configured".to_string(),
+            )
+            .into());
+        }
 
-            match self.tts.tts_raw_audio(trimmed, lang, voice, speed, None) {
-                Ok(raw_audio) => {
-                    full_audio.extend_from_slice(&raw_audio);
+        // The text, then each fallback, until one produces audio
+        let texts = std::iter::once(&req.text).chain(&req.fallbacks);
+        let mut last_error = None;
+        for (index, text) in texts.enumerate() {
+            match self.synthesize_text(req, text) {
+                Ok(mut audio) => {
+                    audio.used_fallback = index;
+                    if let Some(message) = style_warning {
+                        audio.warnings.push((WarningCode::StyleIgnored, message));
+                    }
+                    return Ok(audio);
                 }
                 Err(e) => {
-                    return Err(anyhow::anyhow!("TTS generation error: {}", e));
+                    if index < req.fallbacks.len() {
+                        warn!("Text {} produced no audio, trying the next fallback: {}", index, e);
+                    }
+                    last_error = Some(e);
                 }
             }
         }
+        Err(last_error.expect("the request text is always tried"))
+    }
 
-        // Convert f32 samples to WAV bytes
-        let wav_data = encode_wav(&full_audio)?;
-        Ok(wav_data)
+    /// Synthesize `text` with the rest of `req`'s options
+    fn synthesize_text(&self, req: &SynthesizeRequest, text: &str) -> Result<SynthesizedAudio> {
+        let settings = self.settings.get();
+        let input_chars = text.chars().count();
+        let mut warnings = Vec::new();
+        // Phonemes skip every text pass: markers, directives, abbreviations,
+        // sentence splitting and spoken punctuation
+        let phonemes = req.input_type != InputType::Text;
+        let text = if req.skip_markers && !phonemes {
+            Cow::Owned(self.skip_markers.strip(text))
+        } else {
+            Cow::Borrowed(text)
+        };
+        let full_text = text.as_ref();
+        let text = match settings.max_text_chars {
+            Some(max_chars) => truncate_text(full_text, max_chars),
+            None => full_text,
+        };
+        if text.len() < full_text.len() {
+            warnings.push((
+                WarningCode::TextTruncated,
+                format!(
+                    "text truncated from {} to {} characters",
+                    full_text.chars().count(),
+                    text.chars().count()
+                ),
+            ));
+        }
+        let ipa;
+        let text = match req.input_type {
+            InputType::Arpabet => {
+                ipa = phoneme::arpabet_to_ipa(text)?;
+                ipa.as_str()
+            }
+            InputType::Text | InputType::Ipa => text,
+        };
+        if let Some(message) = script::lang_mismatch(text, &req.lang).filter(|_| !phonemes) {
+            warnings.push((WarningCode::LangMismatch, message));
+        }
+
+        // An explicit voice wins over the server's default
+        let request_voice = match &settings.default_voice {
+            Some(voice) if req.voice == DEFAULT_VOICE => voice.as_str(),
+            _ => req.voice.as_str(),
+        };
+        let spans = if phonemes {
+            vec![directive::Span { text, voice: None, speed: None }]
+        } else {
+            directive::parse_directives(text)?
+        };
+        let mut sentences = Vec::new();
+        for span in spans {
+            if let Some(voice) = span.voice {
+                // Up front, so a bad voice mid-text isn't skipped as a failed sentence
+                check_voice(voice, &self.synth.voices())?;
+            }
+            let voice = span.voice.unwrap_or(request_voice);
+            let defaults = self.voice_defaults.get(voice).copied().unwrap_or_default();
+            let backend_voice = normalize_blend(voice, &mut warnings)?;
+            // An explicit speed wins over the voice's default
+            let requested_speed = match (span.speed, defaults.speed) {
+                (Some(speed), _) => speed,
+                (None, Some(speed)) if req.speed == DEFAULT_SPEED => speed,
+                _ => req.speed,
+            };
+            let speed = requested_speed.clamp(*SPEED_RANGE.start(), *SPEED_RANGE.end());
+            if speed != requested_speed {
+                let message = format!("speed {} is out of range; using {}", requested_speed, speed);
+                if !warnings.iter().any(|(_, m)| *m == message) {
+                    warnings.push((WarningCode::SpeedClamped, message));
+                }
+            }
+
+            if phonemes {
+                sentences.push((span.text.to_string(), backend_voice, speed, defaults.gain));
+                continue;
+            }
+            let collapsed;
+            let text = if req.collapse_whitespace {
+                collapsed = collapse_whitespace(span.text);
+                collapsed.as_str()
+            } else {
+                span.text
+            };
+            // Before abbreviations and sentence splitting, which would take
+            // a link's dots for sentence ends
+            let text = normalize::read_links(text, req.links, &req.lang);
+            let text = if req.expand_abbreviations {
+                self.abbreviations.expand(&text)
+            } else {
+                text.into_owned()
+            };
+            for sentence in segment_sentences_within(&text, &req.lang, self.max_sentence_chars) {
+                // Per sentence, so the spoken names don't hide sentence boundaries
+                let sentence = if req.speak_punctuation {
+                    normalize::speak_punctuation(&sentence, &req.lang)
+                } else {
+                    sentence
+                };
+                sentences.push((sentence, backend_voice.clone(), speed, defaults.gain));
+            }
+        }
+        if let Some(max_chars) = self.sentence_batch_chars {
+            sentences = batch_sentences(sentences, max_chars);
+        }
+
+        let max_samples = self.max_audio_samples();
+        let pause = pause_samples(settings.sentence_pause_ms);
+        let (mut samples, sentence_starts) = synthesize_samples(input_chars, &sentences, req.strict, max_samples, pause, |(sentence, voice, speed, gain)| {
+            let samples = with_retries(settings.retries, || {
+                if phonemes {
+                    self.synth.synthesize_phonemes(sentence, voice, *speed)
+                } else {
+                    self.synth.synthesize_styled(sentence, &req.lang, voice, *speed, req.style.as_deref())
+                }
+            })?;
+            let mut samples = self.at_protocol_rate(samples, voice);
+            if let Some(gain) = gain {
+                samples.iter_mut().for_each(|sample| *sample *= gain);
+            }
+            Ok(samples)
+        })?;
+        let speech_len = samples.len();
+        // Before the other processing smears the clicks out
+        if req.denoise {
+            dsp::denoise(&mut samples);
+        }
+        if let Some(semitones) = req.pitch_semitones {
+            samples = dsp::pitch_shift(&samples, semitones);
+        }
+        if let Some(tempo) = req.tempo {
+            samples = dsp::time_stretch(&samples, tempo);
+        }
+        if let Some(filter) = &req.filter {
+            dsp::band_limit(&mut samples, filter.high_pass_hz, filter.low_pass_hz);
+        }
+        if settings.normalize {
+            dsp::normalize_peak(&mut samples, NORMALIZED_PEAK);
+        }
+        // Word positions stretch along with the speech
+        let scale = samples.len() as f64 / speech_len as f64;
+        let mut lead = 0;
+        // Last, so the chime sounds the same whatever the speech processing
+        if let Some(earcon) = self.earcon.as_ref().filter(|_| req.earcon) {
+            samples.splice(0..0, earcon.iter().copied());
+            lead = earcon.len();
+        }
+        if let Some(secs) = req.target_duration_secs {
+            fit_to_duration(&mut samples, secs, &mut warnings);
+        }
+        // Seconds into the finished audio of a sample of the speech
+        let frame_rate = (SAMPLE_RATE * CHANNELS as u32) as f64;
+        let secs = |sample: f64| ((lead as f64 + sample * scale) / frame_rate) as f32;
+        // Anything cut off by target_duration_secs isn't heard
+        let audible_until = req.target_duration_secs.unwrap_or(f32::INFINITY);
+        let word_marks = if req.word_marks {
+            let texts: Vec<&str> = sentences.iter().map(|(sentence, ..)| sentence.as_str()).collect();
+            interpolate_word_starts(&texts, &sentence_starts, speech_len)
+                .into_iter()
+                .map(secs)
+                .take_while(|&start| start < audible_until)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let sentence_timings = sentences
+            .iter()
+            .zip(&sentence_starts)
+            .enumerate()
+            .map(|(i, ((sentence, ..), &start))| {
+                let end = sentence_starts.get(i + 1).copied().unwrap_or(speech_len);
+                SentenceTiming {
+                    text: sentence.clone(),
+                    start_secs: secs(start as f64),
+                    end_secs: secs(end as f64).min(audible_until),
+                }
+            })
+            .take_while(|timing| timing.start_secs < audible_until)
+            .collect();
+        let mut audio = encode_audio(samples, req.format, req.bit_depth.unwrap_or_default())?;
+        audio.warnings = warnings;
+        audio.word_marks = word_marks;
+        audio.sentence_timings = sentence_timings;
+        Ok(audio)
     }
 }
 
-/// Encode f32 samples as WAV bytes
-fn encode_wav(samples: &[f32]) -> Result<Vec<u8>> {
-    // Convert f32 to i16
-    let i16_samples: Vec<i16> = samples
-        .iter()
-        .map(|&s| (s.clamp(-1.0, 1.0) * 32767.0) as i16)
-        .collect();
+/// Errors from synthesis the server reports verbatim rather than as engine failures
+#[derive(Debug)]
+pub enum SynthesisError {
+    /// Every fragment was empty (e.g. the text was only punctuation)
+    NoAudio,
+    /// The voice, or one component of a blend, isn't known to the backend
+    UnknownVoice(String),
+    /// A request option is out of range
+    InvalidParameter(String),
+    /// The speech ran past the server's limit, in seconds
+    AudioTooLong(f32),
+}
 
+impl SynthesisError {
+    /// Protocol error code reported to clients
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SynthesisError::NoAudio => ErrorCode::BadRequest,
+            SynthesisError::UnknownVoice(_) => ErrorCode::UnknownVoice,
+            SynthesisError::InvalidParameter(_) => ErrorCode::BadRequest,
+            SynthesisError::AudioTooLong(_) => ErrorCode::TooLong,
+        }
+    }
+}
+
+impl std::fmt::Display for SynthesisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SynthesisError::NoAudio => write!(f, "no audio produced for input"),
+            SynthesisError::UnknownVoice(voice) => write!(f, "unknown voice: {}", voice),
+            SynthesisError::InvalidParameter(reason) => write!(f, "invalid request: {}", reason),
+            SynthesisError::AudioTooLong(max_secs) => {
+                write!(f, "audio would last more than the server's {}s limit", max_secs)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SynthesisError {}
+
+/// At most `max_chars` characters of `text`, cut at a word boundary when there is one
+fn truncate_text(text: &str, max_chars: usize) -> &str {
+    let Some((end, _)) = text.char_indices().nth(max_chars) else {
+        return text;
+    };
+    let cut = &text[..end];
+    match cut.rfind(char::is_whitespace) {
+        Some(space) if space > 0 => cut[..space].trim_end(),
+        _ => cut,
+    }
+}
+
+/// Split `text` into sentences, synthesize each with `synthesize_sentence` and
+/// encode the concatenated samples, `pause` samples apart, as WAV
+fn synthesize_with<F>(
+    text: &str,
+    lang: &str,
+    max_sentence_chars: usize,
+    max_samples: Option<usize>,
+    pause: usize,
+    normalize: bool,
+    mut synthesize_sentence: F,
+) -> Result<SynthesizedAudio>
+where
+    F: FnMut(&str) -> Result<Vec<f32>>,
+{
+    let sentences = segment_sentences_within(text, lang, max_sentence_chars);
+    let input_chars = text.chars().count();
+    let (mut samples, _) =
+        synthesize_samples(input_chars, &sentences, false, max_samples, pause, |sentence| synthesize_sentence(sentence))?;
+    if normalize {
+        dsp::normalize_peak(&mut samples, NORMALIZED_PEAK);
+    }
+    encode_audio(samples, AudioFormat::Wav, BitDepth::I16)
+}
+
+/// `pause_ms` of silence in samples
+fn pause_samples(pause_ms: u32) -> usize {
+    (pause_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize * CHANNELS as usize
+}
+
+/// Concatenate what `synthesize_sentence` produces for each of `sentences`
+/// (split as in `sirius_protocol::text` from `input_chars` characters of
+/// text) with `pause` samples of silence between them, along with the sample
+/// each sentence starts at; empty output is `SynthesisError::NoAudio`.
+///
+/// Unless `strict`, a sentence that fails is logged and replaced with a
+/// short silence; the request only fails if every sentence did. Passing
+/// `max_samples` fails it straight away, leaving the rest unsynthesized.
+fn synthesize_samples<T, F>(
+    input_chars: usize,
+    sentences: &[T],
+    strict: bool,
+    max_samples: Option<usize>,
+    pause: usize,
+    mut synthesize_sentence: F,
+) -> Result<(Vec<f32>, Vec<usize>)>
+where
+    F: FnMut(&T) -> Result<Vec<f32>>,
+{
+    let mut full_audio: Vec<f32> = Vec::new();
+    let mut starts = Vec::with_capacity(sentences.len());
+    let silence = (FAILED_SENTENCE_SILENCE_SECS * SAMPLE_RATE as f32) as usize * CHANNELS as usize;
+    let mut first_error = None;
+    let mut failed = 0;
+
+    // Process each sentence
+    for sentence in sentences {
+        if !starts.is_empty() {
+            full_audio.resize(full_audio.len() + pause, 0.0);
+        }
+        starts.push(full_audio.len());
+        match synthesize_sentence(sentence) {
+            Ok(raw_audio) => full_audio.extend_from_slice(&raw_audio),
+            Err(e) if strict => return Err(e),
+            Err(e) => {
+                warn!("Skipping sentence that failed to synthesize: {}", e);
+                full_audio.resize(full_audio.len() + silence, 0.0);
+                failed += 1;
+                first_error.get_or_insert(e);
+            }
+        }
+        if let Some(max_samples) = max_samples.filter(|&max| full_audio.len() > max) {
+            let max_secs = max_samples as f32 / (SAMPLE_RATE as f32 * CHANNELS as f32);
+            warn!(
+                "Abandoning synthesis after {} of {} sentences: over {}s of audio",
+                starts.len(),
+                sentences.len(),
+                max_secs
+            );
+            return Err(SynthesisError::AudioTooLong(max_secs).into());
+        }
+    }
+
+    if let Some(e) = first_error {
+        if failed == sentences.len() {
+            return Err(e);
+        }
+        warn!("{} of {} sentences failed to synthesize", failed, sentences.len());
+    }
+
+    if full_audio.is_empty() {
+        warn!("No audio produced for {} chars of input ({} sentences)", input_chars, sentences.len());
+        return Err(SynthesisError::NoAudio.into());
+    }
+    Ok((full_audio, starts))
+}
+
+/// Pad `samples` with trailing silence, or cut it short with a warning, so
+/// it lasts exactly `secs`
+fn fit_to_duration(samples: &mut Vec<f32>, secs: f32, warnings: &mut Vec<(WarningCode, String)>) {
+    let frames = (secs as f64 * SAMPLE_RATE as f64).round() as usize;
+    let target_len = frames * CHANNELS as usize;
+    if samples.len() > target_len {
+        let speech_secs = samples.len() as f64 / (SAMPLE_RATE as f64 * CHANNELS as f64);
+        warnings.push((
+            WarningCode::DurationTruncated,
+            format!("speech lasts {:.2}s; cut to the {}s target duration", speech_secs, secs),
+        ));
+    }
+    samples.resize(target_len, 0.0);
+}
+
+/// Where each word of `sentences` starts, in samples: sentence `i` runs from
+/// `starts[i]` to the next sentence's start (the last one to `total`), and
+/// that time is shared among its words in proportion to their length
+fn interpolate_word_starts(sentences: &[&str], starts: &[usize], total: usize) -> Vec<f64> {
+    let mut marks = Vec::new();
+    for (i, sentence) in sentences.iter().enumerate() {
+        let start = starts[i] as f64;
+        let end = starts.get(i + 1).copied().unwrap_or(total) as f64;
+        let lengths: Vec<usize> = sentence.split_whitespace().map(|word| word.chars().count()).collect();
+        let chars: usize = lengths.iter().sum();
+        let mut before = 0;
+        for length in lengths {
+            marks.push(start + (end - start) * before as f64 / chars as f64);
+            before += length;
+        }
+    }
+    marks
+}
+
+/// `voice` in the backend's blend syntax. A blend whose weights don't sum to 1
+/// is rescaled so they do, with a warning. A lone voice with a weight, like
+/// "af_bella.10", is just that voice: the backend only reads weights in a
+/// blend and would look for a voice named "af_bella.10".
+fn normalize_blend(voice: &str, warnings: &mut Vec<(WarningCode, String)>) -> Result<String> {
+    let mix = VoiceMix::parse(voice)?;
+    if let [(name, _)] = mix.components.as_slice() {
+        return Ok(name.clone());
+    }
+    let total = mix.total_weight();
+    if (total - 1.0).abs() <= BLEND_TOLERANCE {
+        return Ok(mix.to_string());
+    }
+    let Some(normalized) = mix.normalized() else {
+        return Err(SynthesisError::InvalidParameter(format!("blend {:?} has no weight", voice)).into());
+    };
+    let message = format!("blend weights of {} sum to {}; rescaled to sum to 1", voice, total);
+    if !warnings.iter().any(|(_, m)| *m == message) {
+        warnings.push((WarningCode::BlendNormalized, message));
+    }
+    Ok(normalized.to_string())
+}
+
+/// Join consecutive sentences read with the same voice, speed and gain into
+/// one, as long as the result stays within `max_chars` characters. A sentence
+/// longer than that on its own is left as it is.
+fn batch_sentences(
+    sentences: Vec<(String, String, f32, Option<f32>)>,
+    max_chars: usize,
+) -> Vec<(String, String, f32, Option<f32>)> {
+    let mut batches: Vec<(String, String, f32, Option<f32>)> = Vec::new();
+    for (sentence, voice, speed, gain) in sentences {
+        if let Some((batch, batch_voice, batch_speed, batch_gain)) = batches.last_mut() {
+            let fits = batch.chars().count() + 1 + sentence.chars().count() <= max_chars;
+            if fits && *batch_voice == voice && *batch_speed == speed && *batch_gain == gain {
+                batch.push(' ');
+                batch.push_str(&sentence);
+                continue;
+            }
+        }
+        batches.push((sentence, voice, speed, gain));
+    }
+    batches
+}
+
+/// Run `attempt`, trying again up to `retries` times while it fails
+/// transiently, with a jittered backoff in between. The backoff sleeps the
+/// calling thread, which the server keeps off its async workers.
+fn with_retries<T>(retries: u32, mut attempt: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut tries = 0;
+    loop {
+        match attempt() {
+            Err(e) if tries < retries && is_transient(&e) => {
+                let delay = retry_delay(tries);
+                warn!("Synthesis failed, retrying in {:?}: {}", delay, e);
+                std::thread::sleep(delay);
+                tries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A failed model run (e.g. resource contention) may pass on its own;
+/// anything else, like a bad voice or input espeak can't phonemize, fails
+/// the same way every time
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<EngineError>().is_some()
+}
+
+/// Doubling backoff plus up to half as much again of jitter, so concurrent
+/// retries don't line up
+fn retry_delay(attempt: u32) -> Duration {
+    let base = RETRY_BASE_DELAY * 2u32.pow(attempt.min(6));
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    base + base.mul_f64((nanos % 1000) as f64 / 2000.0)
+}
+
+fn encode_audio(samples: Vec<f32>, format: AudioFormat, bit_depth: BitDepth) -> Result<SynthesizedAudio> {
+    // The telephony formats are narrowband
+    let samples = match format.sample_rate() {
+        SAMPLE_RATE => samples,
+        rate => dsp::downsample(&samples, rate),
+    };
+    let (data, bits_per_sample) = match format {
+        AudioFormat::Wav => (encode_wav(&samples, bit_depth)?, bit_depth.bits_per_sample()),
+        AudioFormat::PcmF32LE => (sirius_protocol::encode_pcm_f32le(&samples), 32),
+        AudioFormat::PcmS16LE => (sirius_protocol::encode_pcm_s16le(&samples), 16),
+        AudioFormat::Mulaw => (g711::encode_mulaw(&samples), 8),
+        AudioFormat::Alaw => (g711::encode_alaw(&samples), 8),
+    };
+    Ok(SynthesizedAudio {
+        data,
+        format,
+        bits_per_sample,
+        num_samples: samples.len() / CHANNELS as usize,
+        warnings: Vec::new(),
+        word_marks: Vec::new(),
+        sentence_timings: Vec::new(),
+        used_fallback: 0,
+    })
+}
+
+/// Encode f32 samples as WAV bytes at `bit_depth`. Integer depths clip to
+/// [-1, 1]; float samples are written as they are.
+fn encode_wav(samples: &[f32], bit_depth: BitDepth) -> Result<Vec<u8>> {
     // Create WAV in memory
     let mut wav_buffer = Cursor::new(Vec::new());
     {
         let spec = WavSpec {
             channels: CHANNELS,
             sample_rate: SAMPLE_RATE,
-            bits_per_sample: BITS_PER_SAMPLE,
-            sample_format: hound::SampleFormat::Int,
+            bits_per_sample: bit_depth.bits_per_sample(),
+            sample_format: match bit_depth {
+                BitDepth::F32 => hound::SampleFormat::Float,
+                BitDepth::I16 | BitDepth::I24 => hound::SampleFormat::Int,
+            },
         };
         let mut writer = WavWriter::new(&mut wav_buffer, spec)?;
-        for sample in i16_samples {
-            writer.write_sample(sample)?;
+        match bit_depth {
+            BitDepth::I16 => {
+                for &s in samples {
+                    writer.write_sample((s.clamp(-1.0, 1.0) * 32767.0) as i16)?;
+                }
+            }
+            BitDepth::I24 => {
+                for &s in samples {
+                    writer.write_sample((s.clamp(-1.0, 1.0) * 8_388_607.0) as i32)?;
+                }
+            }
+            BitDepth::F32 => {
+                for &s in samples {
+                    writer.write_sample(s)?;
+                }
+            }
         }
         writer.finalize()?;
     }
 
     Ok(wav_buffer.into_inner())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::MockSynthesizer;
+    use sirius_protocol::FilterSpec;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_engine_with_mock_synthesizer() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let audio = engine.synthesize("One. Two.", "en-us", "mock", 1.0).unwrap();
+
+        // Default mock: 100 samples per character of each sentence
+        assert_eq!(audio.num_samples, ("One.".len() + "Two.".len()) * 100);
+        assert_eq!(engine.voices(), vec!["mock".to_string()]);
+        assert_eq!(
+            engine.synth.calls(),
+            vec!["One.".to_string(), "Two.".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_whitespace_is_collapsed_before_splitting() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let text = "Chapter  One\n\nIt was\ta dark\nand stormy\nnight.";
+        let request = SynthesizeRequest::new(text).with_voice("mock");
+        engine.synthesize_request(&request).unwrap();
+        assert_eq!(
+            engine.synth.calls(),
+            vec!["Chapter One".to_string(), "It was a dark and stormy night.".to_string()]
+        );
+
+        // Turned off, the text reaches the splitter as written
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        engine
+            .synthesize_request(&request.with_collapse_whitespace(false))
+            .unwrap();
+        assert_eq!(engine.synth.calls().len(), 2);
+        assert!(engine.synth.calls()[1].contains("was\ta dark\nand"), "{:?}", engine.synth.calls());
+    }
+
+    #[test]
+    fn test_abbreviation_expansion_can_be_disabled() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("St. John St.").with_voice("mock");
+        engine.synthesize_request(&request).unwrap();
+        engine
+            .synthesize_request(&request.clone().with_expand_abbreviations(false))
+            .unwrap();
+        assert_eq!(
+            engine.synth.calls(),
+            vec!["Saint John Street.".to_string(), "St. John St.".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_speak_punctuation_request() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hi, world. Bye!")
+            .with_voice("mock")
+            .with_speak_punctuation(true);
+        engine.synthesize_request(&request).unwrap();
+        assert_eq!(
+            engine.synth.calls(),
+            vec!["Hi comma world period".to_string(), "Bye exclamation mark".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_failed_sentence_is_skipped() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::failing_on("Two"));
+        let request = SynthesizeRequest::new("One. Two. Three.").with_voice("mock");
+
+        let audio = engine.synthesize_request(&request).unwrap();
+        assert_eq!(
+            engine.synth.calls(),
+            vec!["One.".to_string(), "Three.".to_string()]
+        );
+        let silence = (FAILED_SENTENCE_SILENCE_SECS * SAMPLE_RATE as f32) as usize;
+        assert_eq!(
+            audio.num_samples,
+            ("One.".len() + "Three.".len()) * 100 + silence
+        );
+
+        // Strict requests fail as a whole
+        assert!(engine.synthesize_request(&request.with_strict(true)).is_err());
+
+        // So does a request where every sentence fails
+        let request = SynthesizeRequest::new("Two. Two!").with_voice("mock");
+        let err = engine.synthesize_request(&request).err().unwrap();
+        assert!(err.to_string().contains("mock failure"));
+    }
+
+    #[test]
+    fn test_telephony_output() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+
+        let wav = engine.synthesize_request(&request).unwrap();
+        for format in [AudioFormat::Mulaw, AudioFormat::Alaw] {
+            let audio = engine.synthesize_request(&request.clone().with_format(format)).unwrap();
+            // One byte per 8 kHz sample, lasting as long as the WAV
+            assert_eq!(audio.num_samples, wav.num_samples / 3);
+            assert_eq!(audio.data.len(), audio.num_samples);
+            assert_eq!(audio.bits_per_sample, 8);
+            assert!((audio.duration_secs() - wav.duration_secs()).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_raw_pcm_output() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+
+        let wav = engine.synthesize_request(&request).unwrap();
+        let f32le = engine
+            .synthesize_request(&request.clone().with_format(AudioFormat::PcmF32LE))
+            .unwrap();
+        let s16le = engine
+            .synthesize_request(&request.with_format(AudioFormat::PcmS16LE))
+            .unwrap();
+
+        assert_eq!(f32le.num_samples, wav.num_samples);
+        assert_eq!(f32le.format, AudioFormat::PcmF32LE);
+        assert_eq!(f32le.data.len(), wav.num_samples * 4);
+        // The same samples as the WAV, minus its 44-byte header
+        assert_eq!(s16le.data, wav.data[44..]);
+    }
+
+    #[test]
+    fn test_wav_bit_depths() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+
+        let cases = [
+            (BitDepth::I16, 16, hound::SampleFormat::Int),
+            (BitDepth::I24, 24, hound::SampleFormat::Int),
+            (BitDepth::F32, 32, hound::SampleFormat::Float),
+        ];
+        for (bit_depth, bits, sample_format) in cases {
+            let audio = engine
+                .synthesize_request(&request.clone().with_bit_depth(bit_depth))
+                .unwrap();
+            assert_eq!(audio.bits_per_sample, bits);
+            let reader = hound::WavReader::new(Cursor::new(audio.data)).unwrap();
+            assert_eq!(reader.spec().bits_per_sample, bits);
+            assert_eq!(reader.spec().sample_format, sample_format);
+            assert_eq!(reader.len() as usize, audio.num_samples);
+        }
+
+        // Float samples are written as they are, without clipping
+        let loud = encode_wav(&[1.5, -0.25], BitDepth::F32).unwrap();
+        let samples: Vec<f32> = hound::WavReader::new(Cursor::new(loud))
+            .unwrap()
+            .samples::<f32>()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(samples, vec![1.5, -0.25]);
+
+        let err = engine
+            .synthesize_request(&request.with_format(AudioFormat::PcmF32LE).with_bit_depth(BitDepth::I24))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("bit_depth"));
+    }
+
+    #[test]
+    fn test_audio_cap_stops_synthesis_early() {
+        // Five seconds of audio per sentence
+        let mut mock = MockSynthesizer::default();
+        mock.samples_per_char = SAMPLE_RATE as usize;
+        let engine = TtsEngine::with_synthesizer(mock).with_max_audio_secs(Some(8.0));
+        let request = SynthesizeRequest::new("Ahoy. Ahoy. Ahoy. Ahoy.").with_voice("mock");
+
+        let err = engine.synthesize_request(&request).err().unwrap();
+        let synthesis_error = err.downcast_ref::<SynthesisError>().unwrap();
+        assert_eq!(synthesis_error.code(), ErrorCode::TooLong);
+        assert_eq!(err.to_string(), "audio would last more than the server's 8s limit");
+        // The third and fourth sentences were never synthesized
+        assert_eq!(engine.synth.calls().len(), 2);
+
+        // Under the cap nothing changes
+        let audio = engine.synthesize_request(&SynthesizeRequest::new("Ahoy.").with_voice("mock")).unwrap();
+        assert_eq!(audio.duration_secs(), 5.0);
+    }
+
+    #[test]
+    fn test_speed_clamp_warning() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+
+        let audio = engine.synthesize_request(&request.clone().with_speed(1.5)).unwrap();
+        assert!(audio.warnings.is_empty());
+
+        let audio = engine.synthesize_request(&request.with_speed(5.0)).unwrap();
+        assert_eq!(audio.warnings.len(), 1);
+        assert_eq!(audio.warnings[0].0, WarningCode::SpeedClamped);
+        assert!(audio.warnings[0].1.contains("using 2"), "{}", audio.warnings[0].1);
+    }
+
+    #[test]
+    fn test_truncation_warning() {
+        let engine =
+            TtsEngine::with_synthesizer(MockSynthesizer::default()).with_max_text_chars(Some(16));
+        let request = SynthesizeRequest::new("Short one. And more text.").with_voice("mock");
+        let audio = engine.synthesize_request(&request).unwrap();
+        assert_eq!(engine.synth.calls(), vec!["Short one.".to_string(), "And".to_string()]);
+        assert_eq!(
+            audio.warnings,
+            vec![(
+                WarningCode::TextTruncated,
+                "text truncated from 25 to 14 characters".to_string()
+            )]
+        );
+
+        assert_eq!(truncate_text("abcdef", 3), "abc");
+        assert_eq!(truncate_text("ab cd", 10), "ab cd");
+        assert_eq!(truncate_text("naïve café", 8), "naïve");
+    }
+
+    #[test]
+    fn test_previews_are_truncated_too() {
+        let engine =
+            TtsEngine::with_synthesizer(MockSynthesizer::default()).with_max_text_chars(Some(16));
+        engine.synthesize("Short one. And more text.", "en-us", "mock", 1.0).unwrap();
+        assert_eq!(engine.synth.calls(), vec!["Short one.".to_string(), "And".to_string()]);
+    }
+
+    #[test]
+    fn test_voice_default_speed() {
+        let defaults = HashMap::from([(
+            "mock".to_string(),
+            VoiceDefaults {
+                speed: Some(1.3),
+                gain: None,
+            },
+        )]);
+        let engine =
+            TtsEngine::with_synthesizer(MockSynthesizer::default()).with_voice_defaults(defaults);
+
+        // Omitted speed: the voice's default
+        let request: SynthesizeRequest =
+            serde_json::from_str(r#"{"text": "Hello.", "voice": "mock"}"#).unwrap();
+        engine.synthesize_request(&request).unwrap();
+        // Explicit speed: the request's
+        engine.synthesize_request(&request.with_speed(0.8)).unwrap();
+
+        assert_eq!(engine.synth.speeds(), vec![1.3, 0.8]);
+    }
+
+    #[test]
+    fn test_server_default_voice() {
+        let mut synth = MockSynthesizer::default();
+        synth.voices = vec!["af_bella".to_string(), "am_onyx".to_string()];
+        let engine = TtsEngine::with_synthesizer(synth).with_default_voice(Some("af_bella".to_string()));
+
+        engine.synthesize_request(&SynthesizeRequest::new("Hi.")).unwrap();
+        engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("am_onyx"))
+            .unwrap();
+        assert_eq!(engine.synth.voice_args(), vec!["af_bella".to_string(), "am_onyx".to_string()]);
+    }
+
+    #[test]
+    fn test_phoneme_input_bypasses_normalization() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let text = "dˈɑktɚ. Mr. hˈaʊs\n// a note";
+        let req = SynthesizeRequest::new(text).with_voice("mock");
+        let mut skipping = req.clone();
+        skipping.skip_markers = true;
+
+        // As text, it's split, expanded and stripped...
+        engine.synthesize_request(&skipping).unwrap();
+        assert_eq!(engine.synth.calls(), vec!["dˈɑktɚ.".to_string(), "Mister hˈaʊs".to_string()]);
+        // ...as phonemes, it reaches the backend exactly as written
+        engine.synthesize_request(&skipping.with_input_type(InputType::Ipa)).unwrap();
+        assert_eq!(engine.synth.calls()[2], text);
+
+        engine
+            .synthesize_request(&SynthesizeRequest::new("HH AH0 L OW1").with_voice("mock").with_input_type(InputType::Arpabet))
+            .unwrap();
+        assert_eq!(engine.synth.calls()[3], "həlˈoʊ");
+        let err = engine
+            .synthesize_request(&req.with_input_type(InputType::Arpabet))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unknown ARPAbet phone"), "{}", err);
+
+        // Backends without a phoneme path say so
+        let engine = TtsEngine::with_synthesizer(crate::synth::SineSynthesizer::default());
+        let req = SynthesizeRequest::new("hˈaʊs").with_voice("sine").with_input_type(InputType::Ipa);
+        let err = engine.synthesize_request(&req).err().unwrap();
+        assert_eq!(err.downcast_ref::<SynthesisError>().map(SynthesisError::code), Some(ErrorCode::BadRequest));
+        assert!(err.to_string().contains("doesn't accept phoneme input"), "{}", err);
+    }
+
+    #[test]
+    fn test_runtime_settings_apply_to_later_requests() {
+        let mut synth = MockSynthesizer::default();
+        synth.voices = vec!["af_bella".to_string(), "am_onyx".to_string()];
+        let engine = TtsEngine::with_synthesizer(synth).with_default_voice(Some("af_bella".to_string()));
+        let settings = |json: &str| serde_json::from_str::<serde_json::Map<_, _>>(json).unwrap();
+        let req = SynthesizeRequest::new("Hi there. Bye.").with_format(AudioFormat::PcmF32LE);
+        let before = engine.synthesize_request(&req).unwrap();
+
+        let config = engine
+            .settings()
+            .update(&settings(r#"{"default_voice": "am_onyx", "sentence_pause_ms": 100}"#))
+            .unwrap();
+        assert_eq!(config.default_voice.as_deref(), Some("am_onyx"));
+        let audio = engine.synthesize_request(&req).unwrap();
+        assert_eq!(engine.synth.voice_args(), vec!["af_bella", "af_bella", "am_onyx", "am_onyx"]);
+        // 100ms between the two sentences
+        assert_eq!(audio.num_samples, before.num_samples + 2400);
+
+        engine.settings().update(&settings(r#"{"max_text_chars": 2}"#)).unwrap();
+        let audio = engine.synthesize_request(&req).unwrap();
+        assert_eq!(audio.warnings[0].0, WarningCode::TextTruncated);
+    }
+
+    #[test]
+    fn test_normalize_setting() {
+        let engine = TtsEngine::with_synthesizer(crate::synth::SineSynthesizer::default());
+        let req = SynthesizeRequest::new("Hello.").with_voice("sine").with_format(AudioFormat::PcmF32LE);
+        let peak = |engine: &TtsEngine<_>| {
+            let audio = engine.synthesize_request(&req).unwrap();
+            audio
+                .data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()).abs())
+                .fold(0.0f32, f32::max)
+        };
+
+        assert!((peak(&engine) - 0.3).abs() < 1e-3);
+        let engine = engine.with_normalize(true);
+        assert!((peak(&engine) - NORMALIZED_PEAK).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_load_voice_defaults() {
+        let path = std::env::temp_dir().join(format!("sirius-voices-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"bm_lewis": {"speed": 1.1, "gain": 0.8}, "af_bella": {}}"#).unwrap();
+        let defaults = load_voice_defaults(path.to_str().unwrap()).unwrap();
+        assert_eq!(defaults["bm_lewis"].speed, Some(1.1));
+        assert_eq!(defaults["bm_lewis"].gain, Some(0.8));
+        assert_eq!(defaults["af_bella"], VoiceDefaults::default());
+
+        std::fs::write(&path, r#"{"bm_lewis": {"sped": 1.1}}"#).unwrap();
+        assert!(load_voice_defaults(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Write `samples` as a 16-bit mono WAV at `sample_rate`
+    fn write_wav(path: &std::path::Path, samples: &[f32], sample_rate: u32) {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample((sample * 32768.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_earcon_is_prepended() {
+        let path = std::env::temp_dir().join(format!("sirius-earcon-{}.wav", std::process::id()));
+        let chime: Vec<f32> = (0..480).map(|i| (i % 48) as f32 / 64.0 - 0.25).collect();
+        write_wav(&path, &chime, SAMPLE_RATE);
+        let earcon = load_earcon(path.to_str().unwrap()).unwrap();
+        assert_eq!(earcon, chime);
+
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default()).with_earcon(Some(earcon.clone()));
+        let request = SynthesizeRequest::new("Hi.")
+            .with_voice("mock")
+            .with_format(AudioFormat::PcmF32LE);
+        let plain = engine.synthesize_request(&request).unwrap();
+        let chimed = engine.synthesize_request(&request.with_earcon(true)).unwrap();
+        assert_eq!(chimed.num_samples, plain.num_samples + earcon.len());
+        let samples = sirius_protocol::decode_pcm_f32le(&chimed.data);
+        assert_eq!(&samples[..earcon.len()], &earcon[..]);
+
+        // A file at another rate is resampled to the protocol rate
+        write_wav(&path, &chime, SAMPLE_RATE / 2);
+        assert_eq!(load_earcon(path.to_str().unwrap()).unwrap().len(), chime.len() * 2);
+        std::fs::remove_file(&path).unwrap();
+
+        // Asking for one the server doesn't have is an error
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hi.").with_voice("mock").with_earcon(true);
+        assert!(engine.synthesize_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_skip_markers_request() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let text = "// Chapter one, slowly\nHello [[pause]] there.";
+        let request = SynthesizeRequest::new(text).with_voice("mock");
+        engine.synthesize_request(&request.clone().with_skip_markers(true)).unwrap();
+        assert_eq!(engine.synth.calls(), vec!["Hello there.".to_string()]);
+
+        // Off by default: the markers are just text
+        engine.synthesize_request(&request).unwrap();
+        assert_eq!(engine.synth.calls().len(), 2);
+        assert!(engine.synth.calls()[1].contains("[[pause]]"));
+    }
+
+    #[test]
+    fn test_inline_directives() {
+        let mut synth = MockSynthesizer::default();
+        synth.voices = vec!["af_bella".to_string(), "am_onyx".to_string()];
+        let engine = TtsEngine::with_synthesizer(synth);
+        let request = SynthesizeRequest::new("Hello there. {voice=af_bella.10}She said {speed=1.2}\"hi.\"")
+            .with_voice("am_onyx")
+            .with_speed(1.0);
+        let audio = engine.synthesize_request(&request).unwrap();
+
+        assert_eq!(
+            engine.synth.calls(),
+            vec!["Hello there.".to_string(), "She said".to_string(), "\"hi.\"".to_string()]
+        );
+        assert_eq!(
+            engine.synth.voice_args(),
+            vec!["am_onyx".to_string(), "af_bella".to_string(), "af_bella".to_string()]
+        );
+        assert_eq!(engine.synth.speeds(), vec![1.0, 1.0, 1.2]);
+        assert_eq!(audio.num_samples, ("Hello there.".len() + "She said".len() + 5) * 100);
+
+        // Previews too
+        engine.synthesize("Hi.", "en-us", "af_bella.10", 1.0).unwrap();
+        assert_eq!(engine.synth.voice_args().last().map(String::as_str), Some("af_bella"));
+
+        // A directive voice the backend doesn't know fails the request
+        let request = SynthesizeRequest::new("Hi. {voice=zz_nobody}Bye.").with_voice("am_onyx");
+        let err = engine
+            .synthesize_request(&request)
+            .err()
+            .expect("unknown directive voice should fail");
+        assert_eq!(err.to_string(), "unknown voice: zz_nobody");
+    }
+
+    #[test]
+    fn test_fallback_text() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::failing_on("¤"));
+        let request = SynthesizeRequest::new("")
+            .with_voice("mock")
+            .with_fallbacks(vec!["Price ¤5.".to_string(), "Hello.".to_string(), "Unused.".to_string()]);
+        let audio = engine.synthesize_request(&request).unwrap();
+        assert_eq!(audio.used_fallback, 2);
+        assert_eq!(audio.num_samples, "Hello.".len() * 100);
+
+        // The primary text wins when it works
+        let audio = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("mock").with_fallbacks(vec!["Bye.".to_string()]))
+            .unwrap();
+        assert_eq!(audio.used_fallback, 0);
+
+        // Every text failing reports the last failure
+        let request = SynthesizeRequest::new("").with_voice("mock").with_fallbacks(vec!["...".to_string()]);
+        let err = engine.synthesize_request(&request).err().unwrap();
+        assert!(matches!(err.downcast_ref::<SynthesisError>(), Some(SynthesisError::NoAudio)));
+    }
+
+    #[test]
+    fn test_style_reaches_backend() {
+        let mut synth = MockSynthesizer::default();
+        synth.styles = vec!["cheerful".to_string()];
+        let engine = TtsEngine::with_synthesizer(synth);
+        let audio = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi. Bye.").with_voice("mock").with_style("cheerful"))
+            .unwrap();
+        assert!(audio.warnings.is_empty());
+        assert_eq!(engine.synth.style_args(), vec![Some("cheerful".to_string()); 2]);
+
+        let err = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("mock").with_style("gloomy"))
+            .err()
+            .unwrap();
+        assert_eq!(err.downcast_ref::<SynthesisError>().unwrap().code(), ErrorCode::BadRequest);
+
+        // A backend without styles synthesizes anyway and says so
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let audio = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("mock").with_style("cheerful"))
+            .unwrap();
+        assert_eq!(audio.warnings.len(), 1);
+        assert_eq!(audio.warnings[0].0, WarningCode::StyleIgnored);
+    }
+
+    #[test]
+    fn test_voice_blends() {
+        let mut synth = MockSynthesizer::default();
+        synth.voices = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let engine = TtsEngine::with_synthesizer(synth);
+
+        // Three voices whose weights already sum to 1 reach the backend as sent
+        let audio = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("a.2+b.3+c.5"))
+            .unwrap();
+        assert!(audio.warnings.is_empty());
+
+        // .3+.3+.3 is rescaled to thirds, in the tenths Kokoro's mixer reads
+        let audio = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("a.3+b.3+c.3"))
+            .unwrap();
+        assert_eq!(audio.warnings.len(), 1);
+        assert_eq!(audio.warnings[0].0, WarningCode::BlendNormalized);
+
+        let voices = engine.synth.voice_args();
+        assert_eq!(voices[0], "a.2+b.3+c.5");
+        let kokoro_weights: Vec<f32> = voices[1]
+            .split('+')
+            .map(|part| part.split_once('.').unwrap().1.parse::<f32>().unwrap() * 0.1)
+            .collect();
+        assert_eq!(kokoro_weights.len(), 3);
+        assert!((kokoro_weights.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!(kokoro_weights.iter().all(|weight| (weight - 1.0 / 3.0).abs() < 1e-4));
+    }
+
+    /// A backend whose first call is slow, like a cold model
+    struct ColdStartSynthesizer {
+        warm: std::sync::atomic::AtomicBool,
+    }
+
+    impl SpeechSynthesizer for ColdStartSynthesizer {
+        fn synthesize_raw(&self, text: &str, _lang: &str, _voice: &str, _speed: f32) -> Result<Vec<f32>> {
+            if !self.warm.swap(true, Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            Ok(vec![0.0; text.len() * 100])
+        }
+
+        fn voices(&self) -> Vec<String> {
+            vec!["cold".to_string()]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_time_is_recorded() {
+        let engine = TtsEngine::load(async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(MockSynthesizer::default())
+        })
+        .await
+        .unwrap();
+        assert!(engine.model_load_time() >= Duration::from_millis(20));
+        assert_eq!(TtsEngine::with_synthesizer(MockSynthesizer::default()).model_load_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_warm_up_absorbs_cold_start() {
+        let engine = TtsEngine::with_synthesizer(ColdStartSynthesizer {
+            warm: std::sync::atomic::AtomicBool::new(false),
+        });
+        let warmup = engine.warm_up().unwrap();
+        assert!(warmup >= Duration::from_millis(300));
+
+        let start = std::time::Instant::now();
+        engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("cold"))
+            .unwrap();
+        assert!(start.elapsed() < warmup / 2, "{:?} after a {:?} warmup", start.elapsed(), warmup);
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock").with_strict(true);
+
+        let synth = MockSynthesizer::default();
+        synth.transient_failures.store(1, Ordering::SeqCst);
+        let engine = TtsEngine::with_synthesizer(synth);
+        assert!(engine.synthesize_request(&request).is_err());
+
+        let synth = MockSynthesizer::default();
+        synth.transient_failures.store(1, Ordering::SeqCst);
+        let engine = TtsEngine::with_synthesizer(synth).with_retries(1);
+        let audio = engine.synthesize_request(&request).unwrap();
+        assert_eq!(audio.num_samples, "Hello.".len() * 100);
+        assert_eq!(engine.synth.calls(), vec!["Hello."]);
+
+        // Request errors aren't retried
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default()).with_retries(3);
+        let err = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi.").with_voice("zz_nobody").with_strict(true))
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref::<SynthesisError>(), Some(SynthesisError::UnknownVoice(_))));
+
+        // Nor are backend errors other than a failed model run, like a voice
+        // only the backend finds bad
+        let attempts = std::cell::Cell::new(0);
+        let result = with_retries(3, || {
+            attempts.set(attempts.get() + 1);
+            Err::<(), _>(anyhow::anyhow!("can not found from styles_map: af_bella.10"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_long_sentences_are_split() {
+        let sentence = "word ".repeat(1000);
+        let request = SynthesizeRequest::new(sentence.trim_end()).with_voice("mock");
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default()).with_max_sentence_chars(100);
+        engine.synthesize_request(&request).unwrap();
+        let calls = engine.synth.calls();
+        assert_eq!(calls.len(), 50);
+        assert!(calls.iter().all(|call| call.len() <= 100 && !call.ends_with(' ')));
+    }
+
+    #[test]
+    fn test_sentence_batching() {
+        let text = "One. Two. Three. Four. Five. Six. Seven. Eight. Nine. Ten.";
+        let request = SynthesizeRequest::new(text).with_voice("mock");
+
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default()).with_sentence_batch_chars(Some(1000));
+        let audio = engine.synthesize_request(&request).unwrap();
+        assert_eq!(engine.synth.calls(), vec![text]);
+        assert_eq!(audio.num_samples, text.len() * 100);
+
+        // Unbatched by default
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        engine.synthesize_request(&request).unwrap();
+        assert_eq!(engine.synth.calls().len(), 10);
+
+        // A batch never grows past the budget, and settings changes split it
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default()).with_sentence_batch_chars(Some(10));
+        engine.synthesize_request(&request).unwrap();
+        assert_eq!(engine.synth.calls()[..3], ["One. Two.", "Three.", "Four."]);
+        assert!(engine.synth.calls().iter().all(|call| call.len() <= 10));
+
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default()).with_sentence_batch_chars(Some(1000));
+        engine
+            .synthesize_request(&SynthesizeRequest::new("One. Two. {speed=1.5}Three. Four.").with_voice("mock"))
+            .unwrap();
+        assert_eq!(engine.synth.calls(), vec!["One. Two.", "Three. Four."]);
+    }
+
+    #[test]
+    fn test_tempo_shortens_audio() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let plain = engine
+            .synthesize_request(&SynthesizeRequest::new("Hello there").with_voice("mock"))
+            .unwrap();
+        let fast = engine
+            .synthesize_request(
+                &SynthesizeRequest::new("Hello there")
+                    .with_voice("mock")
+                    .with_tempo(2.0),
+            )
+            .unwrap();
+        assert_eq!(fast.num_samples, plain.num_samples / 2);
+
+        let err = engine
+            .synthesize_request(&SynthesizeRequest::new("Hi").with_voice("mock").with_tempo(5.0))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<SynthesisError>().unwrap().code(),
+            ErrorCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_word_marks_span_the_sentences() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello there. Bye now, friend.").with_voice("mock");
+        assert!(engine.synthesize_request(&request).unwrap().word_marks.is_empty());
+
+        let audio = engine
+            .synthesize_request(&request.clone().with_word_marks(true))
+            .unwrap();
+        let marks = &audio.word_marks;
+        assert_eq!(marks.len(), 5);
+        assert_eq!(marks[0], 0.0);
+        assert!(marks.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", marks);
+        assert!(marks[4] < audio.duration_secs());
+        // The second sentence's first word starts where the first sentence ends
+        let first_sentence_secs = "Hello there.".len() as f32 * 100.0 / SAMPLE_RATE as f32;
+        assert!((marks[2] - first_sentence_secs).abs() < 1e-6, "{:?}", marks);
+
+        // Marks follow the speech when the tempo changes
+        let fast = engine
+            .synthesize_request(&request.with_word_marks(true).with_tempo(2.0))
+            .unwrap();
+        assert!((fast.word_marks[2] - first_sentence_secs / 2.0).abs() < 1e-3);
+        assert!(fast.word_marks[4] < fast.duration_secs());
+    }
+
+    #[test]
+    fn test_target_duration_pads_with_silence() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+        let plain = engine.synthesize_request(&request).unwrap();
+
+        let padded = engine
+            .synthesize_request(&request.clone().with_target_duration_secs(0.5))
+            .unwrap();
+        assert_eq!(padded.num_samples, SAMPLE_RATE as usize / 2);
+        assert!(padded.warnings.is_empty());
+        let samples: Vec<i16> = hound::WavReader::new(Cursor::new(padded.data))
+            .unwrap()
+            .samples::<i16>()
+            .map(Result::unwrap)
+            .collect();
+        assert!(samples[plain.num_samples..].iter().all(|&s| s == 0));
+
+        let err = engine
+            .synthesize_request(&request.with_target_duration_secs(-1.0))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("target_duration_secs"));
+    }
+
+    #[test]
+    fn test_target_duration_truncates_with_warning() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        // 100 samples per character: about 0.13s of speech, cut to 0.05s
+        let request = SynthesizeRequest::new("Hello there, friend.")
+            .with_voice("mock")
+            .with_word_marks(true)
+            .with_target_duration_secs(0.05);
+        let audio = engine.synthesize_request(&request).unwrap();
+        assert_eq!(audio.num_samples, 1200);
+        assert_eq!(audio.warnings.len(), 1);
+        assert_eq!(audio.warnings[0].0, WarningCode::DurationTruncated);
+        // Only the words that start before the cut are marked
+        assert_eq!(audio.word_marks.len(), 2);
+    }
+
+    #[test]
+    fn test_filter_validation() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let request = SynthesizeRequest::new("Hello.").with_voice("mock");
+        assert!(engine.synthesize_request(&request.clone().with_filter(FilterSpec::telephony())).is_ok());
+        assert!(engine.synthesize_request(&request.clone().with_filter(FilterSpec::default())).is_ok());
+
+        let backwards = FilterSpec {
+            high_pass_hz: Some(3400.0),
+            low_pass_hz: Some(300.0),
+        };
+        let above_nyquist = FilterSpec {
+            low_pass_hz: Some(20_000.0),
+            ..Default::default()
+        };
+        for filter in [backwards, above_nyquist] {
+            let err = engine.synthesize_request(&request.clone().with_filter(filter)).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<SynthesisError>(),
+                Some(SynthesisError::InvalidParameter(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unknown_voice_is_reported() {
+        let engine = TtsEngine::with_synthesizer(MockSynthesizer::default());
+        let err = engine
+            .synthesize("Hello.", "en-us", "mock.5+ghost.5", 1.0)
+            .err()
+            .expect("unknown blend component should fail");
+        let synthesis_error = err.downcast_ref::<SynthesisError>().unwrap();
+        assert_eq!(synthesis_error.code(), ErrorCode::UnknownVoice);
+        assert_eq!(err.to_string(), "unknown voice: ghost");
+    }
+
+    #[test]
+    fn test_punctuation_only_input_is_an_error() {
+        let result = synthesize_with("...", "en-us", DEFAULT_MAX_SENTENCE_CHARS, None, 0, false, |_| panic!("nothing should be synthesized"));
+        let err = result.err().expect("expected an error, not an empty WAV");
+        assert!(matches!(
+            err.downcast_ref::<SynthesisError>(),
+            Some(SynthesisError::NoAudio)
+        ));
+        assert_eq!(err.to_string(), "no audio produced for input");
+
+        // Fragments that synthesize to nothing are also reported
+        assert!(synthesize_with("Hi. There.", "en-us", DEFAULT_MAX_SENTENCE_CHARS, None, 0, false, |_| Ok(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn test_sentences_are_concatenated() {
+        let audio = synthesize_with("One. Two.", "en-us", DEFAULT_MAX_SENTENCE_CHARS, None, 0, false, |_| Ok(vec![0.0; 10])).unwrap();
+        assert_eq!(audio.num_samples, 20);
+        assert_eq!(audio.data.len(), 44 + 20 * 2);
+    }
+}